@@ -1,20 +1,43 @@
-use anyhow::{Context, Result};
-use indoc::indoc;
+use anyhow::{bail, Context, Result};
+use indoc::{formatdoc, indoc};
 use log::debug;
 use sha2::{Digest, Sha256};
 use std::io::{IsTerminal, Read, Write};
-use std::process::{Command, Stdio};
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 use strum::{Display, EnumString};
 
+use crate::agent_input::{InputOptions, MessageInput};
+use crate::agent_jobs::{job_command, JobAction, JOBS_DIR};
 use crate::anthropic::{
-    CacheControl, Client, ContentBlock, CustomTool, FetchToolType, Message, MessagesRequest, Role,
-    ServerTool, StopReason, SystemBlock, SystemPrompt, Tool, WebSearchToolType,
+    CacheControl, Client, ContentBlock, ContentDelta, CustomTool, FetchToolType, Message,
+    MessagesRequest, Role, ServerTool, StopReason, StreamEvent, SystemBlock, SystemPrompt,
+    ThinkingConfig, Tool, Usage, WebSearchToolType,
 };
+use crate::backend::{Container, ExecOutput};
+use crate::compaction;
 use crate::config::Model;
 use crate::llm_cache::LlmCache;
+use crate::transcript::{RecordedConversation, Transcript};
+use crate::usage::{format_cost, SessionUsage, TokenCounts, UsageLog, UsageTotals};
 
-const MAX_TOKENS: u32 = 4096;
+/// Most output tokens per response, unless configured otherwise.
+pub const DEFAULT_MAX_TOKENS: u32 = 4096;
+
+/// Seconds a bash command may run, unless configured otherwise.
+pub const DEFAULT_BASH_TIMEOUT_SECS: u64 = 120;
+
+/// The smallest thinking budget the API accepts.
+pub const MIN_THINKING_BUDGET: u32 = 1024;
+
+/// Extended thinking settings.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Thinking {
+    /// Output tokens the model may think with, or `None` to not think.
+    pub budget_tokens: Option<u32>,
+    /// Print the thinking as it streams.
+    pub show: bool,
+}
 const AGENTS_MD_PATH: &str = "AGENTS.md";
 
 const BASE_SYSTEM_PROMPT: &str = "You are a helpful assistant running inside a sandboxed environment. You can execute bash commands to help the user.";
@@ -23,16 +46,32 @@ const BASE_SYSTEM_PROMPT: &str = "You are a helpful assistant running inside a s
 #[strum(serialize_all = "lowercase")]
 enum AgentToolName {
     Bash,
+    Read,
     Edit,
     Write,
+    Glob,
+    Grep,
+    Job,
 }
 
+/// Tool output larger than this is saved to a file in the sandbox instead.
+const MAX_OUTPUT_SIZE: usize = 30000;
+
+/// Lines returned by the read tool unless a limit is given.
+const DEFAULT_READ_LIMIT: usize = 2000;
+
+/// Lines longer than this are shortened by the read tool.
+const MAX_READ_LINE_LENGTH: usize = 2000;
+
 fn bash_tool() -> Tool {
     Tool::Custom(CustomTool {
         name: AgentToolName::Bash.to_string(),
         description: indoc! {"
             Execute a bash command inside the sandbox and return the output.
             The working directory is the project root.
+            Commands are killed when they time out. Run servers, watchers and other
+            long-running commands in the background, and use the job tool to read
+            their output or stop them.
         "}
         .trim()
         .to_string(),
@@ -42,6 +81,14 @@ fn bash_tool() -> Tool {
                 "command": {
                     "type": "string",
                     "description": "The bash command to execute"
+                },
+                "timeout": {
+                    "type": "integer",
+                    "description": "Seconds after which the command is killed, instead of the default"
+                },
+                "run_in_background": {
+                    "type": "boolean",
+                    "description": "Start the command as a background job and return its ID"
                 }
             },
             "required": ["command"]
@@ -50,6 +97,131 @@ fn bash_tool() -> Tool {
     })
 }
 
+fn job_tool() -> Tool {
+    Tool::Custom(CustomTool {
+        name: AgentToolName::Job.to_string(),
+        description: indoc! {"
+            Manage background jobs started with the bash tool. `output` returns the
+            job's status and the output since the previous call, `kill` stops the job
+            and its child processes, `list` shows all jobs.
+        "}
+        .trim()
+        .to_string(),
+        input_schema: serde_json::json!({
+            "type": "object",
+            "properties": {
+                "action": {
+                    "type": "string",
+                    "enum": ["output", "kill", "list"]
+                },
+                "id": {
+                    "type": "string",
+                    "description": "The job ID (for output and kill)"
+                }
+            },
+            "required": ["action"],
+            "additionalProperties": false
+        }),
+        cache_control: None,
+    })
+}
+
+fn read_tool() -> Tool {
+    Tool::Custom(CustomTool {
+        name: AgentToolName::Read.to_string(),
+        description: formatdoc! {"
+            Read a file, with line numbers. Returns up to {} lines from the given offset;
+            read large files in parts. Prefer this to running cat.
+        ", DEFAULT_READ_LIMIT}
+        .trim()
+        .to_string(),
+        input_schema: serde_json::json!({
+            "type": "object",
+            "properties": {
+                "file_path": {
+                    "type": "string",
+                    "description": "The path to the file to read (relative to repo root)"
+                },
+                "offset": {
+                    "type": "integer",
+                    "description": "The line number to start reading from (1-based)"
+                },
+                "limit": {
+                    "type": "integer",
+                    "description": "The number of lines to read"
+                }
+            },
+            "required": ["file_path"],
+            "additionalProperties": false
+        }),
+        cache_control: None,
+    })
+}
+
+fn glob_tool() -> Tool {
+    Tool::Custom(CustomTool {
+        name: AgentToolName::Glob.to_string(),
+        description: indoc! {"
+            Find files by name with a glob pattern, like `src/**/*.rs`. `**` matches any
+            number of directories. Returns the matching paths.
+        "}
+        .trim()
+        .to_string(),
+        input_schema: serde_json::json!({
+            "type": "object",
+            "properties": {
+                "pattern": {
+                    "type": "string",
+                    "description": "The glob pattern to match files against"
+                },
+                "path": {
+                    "type": "string",
+                    "description": "The directory to search in (default: repo root)"
+                }
+            },
+            "required": ["pattern"],
+            "additionalProperties": false
+        }),
+        cache_control: None,
+    })
+}
+
+fn grep_tool() -> Tool {
+    Tool::Custom(CustomTool {
+        name: AgentToolName::Grep.to_string(),
+        description: indoc! {"
+            Search file contents with an extended regular expression. Returns matching
+            lines as `path:line:text`. Skips binary files and .git.
+        "}
+        .trim()
+        .to_string(),
+        input_schema: serde_json::json!({
+            "type": "object",
+            "properties": {
+                "pattern": {
+                    "type": "string",
+                    "description": "The regular expression to search for"
+                },
+                "path": {
+                    "type": "string",
+                    "description": "The file or directory to search in (default: repo root)"
+                },
+                "include": {
+                    "type": "string",
+                    "description": "Only search files whose name matches this glob, like `*.rs`"
+                },
+                "ignore_case": {
+                    "type": "boolean",
+                    "description": "Match case-insensitively"
+                }
+            },
+            "required": ["pattern"],
+            "additionalProperties": false
+        }),
+        cache_control: None,
+    })
+}
+
 fn edit_tool() -> Tool {
     Tool::Custom(CustomTool {
         name: AgentToolName::Edit.to_string(),
@@ -121,16 +293,11 @@ fn fetch_tool() -> Tool {
 }
 
 /// Read AGENTS.md from the sandbox if it exists.
-fn read_agents_md(container_name: &str) -> Option<String> {
+fn read_agents_md(container: Container) -> Option<String> {
     debug!("Reading {} from sandbox", AGENTS_MD_PATH);
-    let output = Command::new("docker")
-        .args(["exec", container_name, "cat", AGENTS_MD_PATH])
-        .stdout(Stdio::piped())
-        .stderr(Stdio::null())
-        .output()
-        .ok()?;
-
-    if !output.status.success() {
+    let output = container.exec(&["cat", AGENTS_MD_PATH]).ok()?;
+
+    if !output.success() {
         debug!("{} not found or not readable", AGENTS_MD_PATH);
         return None;
     }
@@ -147,21 +314,18 @@ fn build_system_prompt(agents_md: Option<&str>) -> String {
 }
 
 fn execute_edit_in_sandbox(
-    container_name: &str,
+    container: Container,
     file_path: &str,
     old_string: &str,
     new_string: &str,
 ) -> Result<(String, bool)> {
     debug!("Reading file for edit: {}", file_path);
-    let output = Command::new("docker")
-        .args(["exec", container_name, "cat", file_path])
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .output()
+    let output = container
+        .exec(&["cat", file_path])
         .context("Failed to read file in sandbox")?;
     debug!("File read completed");
 
-    if !output.status.success() {
+    if !output.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
         return Ok((format!("Error reading file: {}", stderr), false));
     }
@@ -191,30 +355,15 @@ fn execute_edit_in_sandbox(
 
     debug!("Writing edited file: {}", file_path);
     let write_cmd = format!("cat > '{}'", file_path.replace('\'', "'\\''"));
-    let mut write_process = Command::new("docker")
-        .args(["exec", "-i", container_name, "bash", "-c", &write_cmd])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
+    let output = container
+        .exec_with_stdin(&["bash", "-c", &write_cmd], new_content.as_bytes())
         .context("Failed to write file in sandbox")?;
+    debug!(
+        "Write process completed with exit code: {:?}",
+        output.exit_code
+    );
 
-    let mut stdin = write_process
-        .stdin
-        .take()
-        .expect("Process was launched with piped stdin");
-    stdin
-        .write_all(new_content.as_bytes())
-        .context("Failed to write to stdin")?;
-    drop(stdin);
-
-    debug!("Waiting for write process to complete");
-    let output = write_process
-        .wait_with_output()
-        .context("Failed to wait for write process")?;
-    debug!("Write process completed with status: {:?}", output.status);
-
-    if !output.status.success() {
+    if !output.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
         return Ok((format!("Error writing file: {}", stderr), false));
     }
@@ -223,17 +372,16 @@ fn execute_edit_in_sandbox(
 }
 
 fn execute_write_in_sandbox(
-    container_name: &str,
+    container: Container,
     file_path: &str,
     content: &str,
 ) -> Result<(String, bool)> {
     debug!("Checking if file exists: {}", file_path);
-    let output = Command::new("docker")
-        .args(["exec", container_name, "test", "-e", file_path])
-        .output()
+    let output = container
+        .exec(&["test", "-e", file_path])
         .context("Failed to check if file exists")?;
 
-    if output.status.success() {
+    if output.success() {
         return Ok((format!("File {} already exists", file_path), false));
     }
 
@@ -244,38 +392,21 @@ fn execute_write_in_sandbox(
                 parent.display().to_string().replace('\'', "'\\''")
             );
             debug!("Creating parent directories for: {}", file_path);
-            let _ = Command::new("docker")
-                .args(["exec", container_name, "bash", "-c", &mkdir_cmd])
-                .output();
+            let _ = container.exec(&["bash", "-c", &mkdir_cmd]);
         }
     }
 
     debug!("Writing new file: {}", file_path);
     let write_cmd = format!("cat > '{}'", file_path.replace('\'', "'\\''"));
-    let mut write_process = Command::new("docker")
-        .args(["exec", "-i", container_name, "bash", "-c", &write_cmd])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
+    let output = container
+        .exec_with_stdin(&["bash", "-c", &write_cmd], content.as_bytes())
         .context("Failed to write file in sandbox")?;
+    debug!(
+        "Write process completed with exit code: {:?}",
+        output.exit_code
+    );
 
-    let mut stdin = write_process
-        .stdin
-        .take()
-        .expect("Process was launched with piped stdin");
-    stdin
-        .write_all(content.as_bytes())
-        .context("Failed to write to stdin")?;
-    drop(stdin);
-
-    debug!("Waiting for write process to complete");
-    let output = write_process
-        .wait_with_output()
-        .context("Failed to wait for write process")?;
-    debug!("Write process completed with status: {:?}", output.status);
-
-    if !output.status.success() {
+    if !output.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
         return Ok((format!("Error writing file: {}", stderr), false));
     }
@@ -283,7 +414,136 @@ fn execute_write_in_sandbox(
     Ok((format!("Successfully wrote {}", file_path), true))
 }
 
-fn save_output_to_file(container_name: &str, data: &[u8]) -> Result<String> {
+/// Lines `offset..offset + limit` of `content` (1-based), numbered like `cat -n`.
+fn number_lines(content: &str, offset: usize, limit: usize) -> String {
+    let mut numbered = String::new();
+    for (index, line) in content.lines().enumerate().skip(offset - 1).take(limit) {
+        let line = match line.char_indices().nth(MAX_READ_LINE_LENGTH) {
+            Some((end, _)) => format!("{}... [line truncated]", &line[..end]),
+            None => line.to_string(),
+        };
+        numbered.push_str(&format!("{:>6}\t{}\n", index + 1, line));
+    }
+    numbered
+}
+
+fn execute_read_in_sandbox(
+    container: Container,
+    file_path: &str,
+    offset: Option<usize>,
+    limit: Option<usize>,
+) -> Result<(String, bool)> {
+    debug!("Reading file: {}", file_path);
+    let output = container
+        .exec(&["cat", "--", file_path])
+        .context("Failed to read file in sandbox")?;
+
+    if !output.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Ok((format!("Error reading file: {}", stderr), false));
+    }
+
+    let content = match String::from_utf8(output.stdout) {
+        Ok(s) => s,
+        Err(_) => return Ok(("File contains invalid UTF-8".to_string(), false)),
+    };
+
+    let line_count = content.lines().count();
+    let offset = offset.unwrap_or(1).max(1);
+    let limit = limit.unwrap_or(DEFAULT_READ_LIMIT).max(1);
+    if line_count == 0 {
+        return Ok((format!("{} is empty", file_path), true));
+    }
+    if offset > line_count {
+        return Ok((
+            format!(
+                "Offset {} is past the end of {} ({} lines)",
+                offset, file_path, line_count
+            ),
+            false,
+        ));
+    }
+
+    let mut numbered = number_lines(&content, offset, limit);
+    if numbered.len() > MAX_OUTPUT_SIZE {
+        return Ok((
+            format!(
+                "Lines {}-{} of {} exceed {} bytes. Read fewer lines at a time.",
+                offset,
+                offset.saturating_add(limit - 1).min(line_count),
+                file_path,
+                MAX_OUTPUT_SIZE
+            ),
+            false,
+        ));
+    }
+    let last = offset.saturating_add(limit - 1);
+    if last < line_count {
+        numbered.push_str(&format!(
+            "[{} of {} lines shown, continue with offset {}]\n",
+            last - offset + 1,
+            line_count,
+            last + 1
+        ));
+    }
+    Ok((numbered, true))
+}
+
+fn execute_glob_in_sandbox(
+    container: Container,
+    pattern: &str,
+    path: Option<&str>,
+) -> Result<(String, bool)> {
+    // The pattern is expanded by bash without word splitting, as a positional
+    // argument so that it isn't otherwise interpreted
+    const SCRIPT: &str = r#"cd -- "$1" || exit
+        shopt -s globstar nullglob dotglob
+        IFS=
+        for file in $2; do
+            if [[ -f $file && $file != .git/* && $file != */.git/* ]]; then
+                printf '%s\n' "$file"
+            fi
+        done"#;
+
+    debug!("Globbing in sandbox: {}", pattern);
+    let output = container
+        .exec(&["bash", "-c", SCRIPT, "glob", path.unwrap_or("."), pattern])
+        .context("Failed to run glob in sandbox")?;
+    if output.success() && output.stdout.is_empty() {
+        return Ok((format!("No files match {}", pattern), true));
+    }
+    tool_output(container, output)
+}
+
+fn execute_grep_in_sandbox(
+    container: Container,
+    pattern: &str,
+    path: Option<&str>,
+    include: Option<&str>,
+    ignore_case: bool,
+) -> Result<(String, bool)> {
+    let mut command = vec!["grep", "-rnIE", "--exclude-dir=.git"];
+    let include_arg = include.map(|glob| format!("--include={}", glob));
+    if let Some(ref arg) = include_arg {
+        command.push(arg);
+    }
+    if ignore_case {
+        command.push("-i");
+    }
+    command.extend(["-e", pattern, "--", path.unwrap_or(".")]);
+
+    debug!("Grepping in sandbox: {}", pattern);
+    let output = container
+        .exec(&command)
+        .context("Failed to run grep in sandbox")?;
+    // grep exits with 1 when nothing matches
+    if output.exit_code == Some(1) && output.stderr.is_empty() {
+        return Ok((format!("No matches for {}", pattern), true));
+    }
+    tool_output(container, output)
+}
+
+fn save_output_to_file(container: Container, data: &[u8]) -> Result<String> {
     // Generate deterministic ID from content hash for reproducible cache keys
     let mut hasher = Sha256::new();
     hasher.update(data);
@@ -295,111 +555,95 @@ fn save_output_to_file(container_name: &str, data: &[u8]) -> Result<String> {
 
     // Create /agent directory if it doesn't exist
     debug!("Creating /agent directory");
-    Command::new("docker")
-        .args(["exec", container_name, "bash", "-c", "mkdir -p /agent"])
-        .output()
+    container
+        .exec(&["bash", "-c", "mkdir -p /agent"])
         .context("Failed to create /agent directory")?;
 
     // Write the output to file
     debug!("Writing output data ({} bytes)", data.len());
     let write_cmd = format!("cat > {}", output_file);
-    let mut write_process = Command::new("docker")
-        .args(["exec", "-i", container_name, "bash", "-c", &write_cmd])
-        .stdin(Stdio::piped())
-        .spawn()
+    container
+        .exec_with_stdin(&["bash", "-c", &write_cmd], data)
         .context("Failed to write output to file")?;
-
-    let mut stdin = write_process
-        .stdin
-        .take()
-        .expect("Process was launched with piped stdin");
-    stdin.write_all(data).context("Failed to write to stdin")?;
-    drop(stdin);
-
-    debug!("Waiting for output save process to complete");
-    write_process
-        .wait()
-        .context("Failed to wait for write process")?;
     debug!("Output saved to file");
 
     Ok(output_file)
 }
 
-/// Prompts user to confirm exit when they submit empty input.
-/// Returns true if user wants to exit (Enter or 'y'), false otherwise.
-fn confirm_exit() -> Result<bool> {
-    eprintln!("Exit? [Y/n] ");
-    std::io::stderr().flush()?;
-
-    let mut buf = [0u8; 1];
-    let bytes_read = std::io::stdin().read(&mut buf)?;
+/// Run the bash tool's command in the sandbox, stopping it after `timeout`. Returns the
+/// output for the model and whether the command succeeded.
+pub fn execute_bash_in_sandbox(
+    container: Container,
+    command: &str,
+    timeout: Duration,
+) -> Result<(String, bool)> {
+    // Seconds between asking a timed out command to stop and killing it
+    const KILL_AFTER_SECS: &str = "5";
 
-    if bytes_read == 0 || buf[0] == b'\n' || buf[0] == b'y' || buf[0] == b'Y' {
-        return Ok(true);
+    debug!("Executing bash in sandbox: {}", command);
+    // timeout runs the command in a new process group and signals the whole group
+    let seconds = timeout.as_secs().to_string();
+    let start = Instant::now();
+    let output = container
+        .exec(&[
+            "timeout",
+            "-k",
+            KILL_AFTER_SECS,
+            &seconds,
+            "bash",
+            "-c",
+            command,
+        ])
+        .context("Failed to execute command in sandbox")?;
+    debug!(
+        "Bash command completed with exit code: {:?}",
+        output.exit_code
+    );
+
+    // 124 when stopped by SIGTERM, 137 when it had to be killed
+    let timed_out = matches!(output.exit_code, Some(124 | 137)) && start.elapsed() >= timeout;
+    let (output, success) = tool_output(container, output)?;
+    if timed_out {
+        let message = format!("[Timed out after {} seconds]", seconds);
+        let output = if output.starts_with("exited with status") {
+            message
+        } else {
+            format!("{}\n{}", output, message)
+        };
+        return Ok((output, false));
     }
-
-    // Discard remaining input so it doesn't leak to the next prompt
-    let mut discard = String::new();
-    std::io::stdin().read_line(&mut discard)?;
-
-    Ok(false)
+    Ok((output, success))
 }
 
-/// Get user input by launching vim on a temp file containing the chat history.
-/// Returns the new message (content after the chat history prefix).
-/// If the user doesn't preserve the chat history prefix, prompts to retry.
-fn get_input_via_vim(chat_history: &str) -> Result<String> {
-    use std::fs;
-
-    loop {
-        let temp_dir = std::env::temp_dir();
-        let temp_file = temp_dir.join(format!("sandbox-chat-{}.txt", std::process::id()));
-
-        fs::write(&temp_file, chat_history).context("Failed to write temp file for vim")?;
-
-        let status = Command::new("vim")
-            .arg(&temp_file)
-            .stdin(Stdio::inherit())
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .status()
-            .context("Failed to launch vim")?;
-
-        if !status.success() {
-            anyhow::bail!("vim exited with non-zero status");
-        }
-
-        let edited_content = fs::read_to_string(&temp_file).context("Failed to read temp file")?;
-        let _ = fs::remove_file(&temp_file);
-
-        // Prevent accidental editing of history
-        if !edited_content.starts_with(chat_history) {
-            eprintln!("Error: The chat history prefix was modified. Please keep it intact.");
-            eprint!("Press Enter to try again...");
-            std::io::stderr().flush()?;
-
-            let mut buf = [0u8; 1];
-            let _ = std::io::stdin().read(&mut buf);
-            continue;
-        }
-
-        let new_message = edited_content[chat_history.len()..].trim().to_string();
-        return Ok(new_message);
+fn start_job_in_sandbox(container: Container, command: &str) -> Result<(String, bool)> {
+    debug!("Starting background job in sandbox: {}", command);
+    let output = container
+        .exec(&job_command(JOBS_DIR, JobAction::Start(command)))
+        .context("Failed to start job in sandbox")?;
+    if !output.success() {
+        return tool_output(container, output);
     }
+    let id = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    Ok((
+        format!(
+            "Started job {}. Its output is in {}/{}/output.",
+            id, JOBS_DIR, id
+        ),
+        true,
+    ))
 }
 
-fn execute_bash_in_sandbox(container_name: &str, command: &str) -> Result<(String, bool)> {
-    const MAX_OUTPUT_SIZE: usize = 30000;
-
-    debug!("Executing bash in sandbox: {}", command);
-    let output = Command::new("docker")
-        .args(["exec", container_name, "bash", "-c", command])
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .output()
-        .context("Failed to execute command in sandbox")?;
-    debug!("Bash command completed with status: {:?}", output.status);
+fn execute_job_in_sandbox(container: Container, action: JobAction) -> Result<(String, bool)> {
+    debug!("Running job action in sandbox: {:?}", action);
+    let output = container
+        .exec(&job_command(JOBS_DIR, action))
+        .context("Failed to manage jobs in sandbox")?;
+    tool_output(container, output)
+}
 
+/// The result of a command run by a tool: its output, or a reference to the file it's
+/// saved to if too large, and whether it succeeded.
+fn tool_output(container: Container, output: ExecOutput) -> Result<(String, bool)> {
     // Combine stdout and stderr as raw bytes
     let combined_bytes = if output.stderr.is_empty() {
         output.stdout.clone()
@@ -414,7 +658,7 @@ fn execute_bash_in_sandbox(container_name: &str, command: &str) -> Result<(Strin
 
     // Check if output exceeds limit - save to file if so
     if combined_bytes.len() > MAX_OUTPUT_SIZE {
-        let output_file = save_output_to_file(container_name, &combined_bytes)?;
+        let output_file = save_output_to_file(container, &combined_bytes)?;
         let error_msg = format!("Full output available at {}", output_file);
         return Ok((error_msg, false));
     }
@@ -423,7 +667,7 @@ fn execute_bash_in_sandbox(container_name: &str, command: &str) -> Result<(Strin
     let combined = match String::from_utf8(combined_bytes.clone()) {
         Ok(s) => s,
         Err(_) => {
-            let output_file = save_output_to_file(container_name, &combined_bytes)?;
+            let output_file = save_output_to_file(container, &combined_bytes)?;
             let error_msg = format!(
                 "Output is not valid UTF-8. Full output available at {}",
                 output_file
@@ -432,13 +676,12 @@ fn execute_bash_in_sandbox(container_name: &str, command: &str) -> Result<(Strin
         }
     };
 
-    let success = output.status.success();
+    let success = output.success();
 
     // If command failed with no output, report the exit status
     if !success && combined.is_empty() {
         let exit_code = output
-            .status
-            .code()
+            .exit_code
             .map(|c| c.to_string())
             .unwrap_or_else(|| "unknown".to_string());
         return Ok((format!("exited with status {}", exit_code), false));
@@ -461,100 +704,491 @@ macro_rules! chat_println {
     }};
 }
 
-pub fn run_agent(container_name: &str, model: Model, cache: Option<LlmCache>) -> Result<()> {
+/// Prints the text blocks of a streamed response as they arrive, each ending with a
+/// newline like the other chat output, and optionally the thinking blocks, dimmed.
+/// Thinking is only shown, not added to the chat history.
+struct TextRenderer {
+    show_thinking: bool,
+    in_text_block: bool,
+    in_thinking_block: bool,
+}
+
+impl TextRenderer {
+    fn new(show_thinking: bool) -> Self {
+        TextRenderer {
+            show_thinking,
+            in_text_block: false,
+            in_thinking_block: false,
+        }
+    }
+
+    fn render(&mut self, event: &StreamEvent) {
+        let mut stdout = std::io::stdout();
+        let (dim, reset) = if stdout.is_terminal() {
+            ("\x1b[2m", "\x1b[0m")
+        } else {
+            ("", "")
+        };
+        match event {
+            StreamEvent::ContentBlockStart {
+                content_block: ContentBlock::Text { text, .. },
+                ..
+            } => {
+                self.in_text_block = true;
+                let _ = write!(stdout, "{}", text);
+            }
+            StreamEvent::ContentBlockDelta {
+                delta: ContentDelta::TextDelta { text },
+                ..
+            } => {
+                let _ = write!(stdout, "{}", text);
+            }
+            StreamEvent::ContentBlockStart {
+                content_block: ContentBlock::Thinking { thinking, .. },
+                ..
+            } if self.show_thinking => {
+                self.in_thinking_block = true;
+                let _ = write!(stdout, "{}[thinking] {}", dim, thinking);
+            }
+            StreamEvent::ContentBlockDelta {
+                delta: ContentDelta::ThinkingDelta { thinking },
+                ..
+            } if self.show_thinking => {
+                let _ = write!(stdout, "{}", thinking);
+            }
+            StreamEvent::ContentBlockStart {
+                content_block: ContentBlock::RedactedThinking { .. },
+                ..
+            } if self.show_thinking => {
+                let _ = writeln!(stdout, "{}[thinking] (redacted){}", dim, reset);
+            }
+            StreamEvent::ContentBlockStop { .. } if self.in_text_block => {
+                self.in_text_block = false;
+                let _ = writeln!(stdout);
+            }
+            StreamEvent::ContentBlockStop { .. } if self.in_thinking_block => {
+                self.in_thinking_block = false;
+                let _ = writeln!(stdout, "{}", reset);
+            }
+            _ => return,
+        }
+        let _ = stdout.flush();
+    }
+}
+
+/// The conversation so far, recorded to a transcript as it grows.
+struct Conversation {
+    messages: Vec<Message>,
+    chat_history: String,
+    /// Length of the prefix of `chat_history` already in the transcript.
+    recorded_chat: usize,
+    transcript: Transcript,
+    /// Number of messages and their size in tokens, as of the latest response.
+    measured: Option<(usize, u64)>,
+}
+
+impl Conversation {
+    /// Continue the conversation recorded in `transcript`, if any.
+    fn load(mut transcript: Transcript) -> Result<Self> {
+        let RecordedConversation {
+            messages,
+            chat_history,
+        } = transcript.load()?;
+        Ok(Conversation {
+            messages,
+            recorded_chat: chat_history.len(),
+            chat_history,
+            transcript,
+            measured: None,
+        })
+    }
+
+    fn push(&mut self, message: Message) -> Result<()> {
+        self.transcript
+            .append(&message, &self.chat_history[self.recorded_chat..])?;
+        self.recorded_chat = self.chat_history.len();
+        self.messages.push(message);
+        Ok(())
+    }
+
+    /// Replace the messages after compaction.
+    fn replace(&mut self, messages: Vec<Message>) -> Result<()> {
+        self.transcript
+            .append_compaction(&messages, &self.chat_history[self.recorded_chat..])?;
+        self.recorded_chat = self.chat_history.len();
+        self.messages = messages;
+        self.measured = None;
+        Ok(())
+    }
+
+    /// Record the size of the conversation, given the usage of the response just added.
+    fn measure(&mut self, usage: &Usage) {
+        let tokens = TokenCounts::from(usage);
+        self.measured = Some((
+            self.messages.len(),
+            tokens.total_input_tokens() + tokens.output_tokens,
+        ));
+    }
+
+    /// Estimated size of the next request in tokens: as measured by the latest
+    /// response, plus the messages added since.
+    fn estimated_tokens(&self, system_prompt: &str) -> u64 {
+        match self.measured {
+            Some((count, tokens)) => tokens + compaction::estimate_tokens(&&self.messages[count..]),
+            None => {
+                compaction::estimate_tokens(&system_prompt)
+                    + compaction::estimate_tokens(&tools())
+                    + compaction::estimate_tokens(&self.messages)
+            }
+        }
+    }
+}
+
+/// Size in tokens above which the conversation is compacted before a request.
+fn compaction_threshold(model: Model) -> u64 {
+    (model.context_window() as f64 * compaction::COMPACT_THRESHOLD) as u64
+}
+
+fn tools() -> Vec<Tool> {
+    vec![
+        bash_tool(),
+        read_tool(),
+        edit_tool(),
+        write_tool(),
+        glob_tool(),
+        grep_tool(),
+        job_tool(),
+        websearch_tool(),
+        fetch_tool(),
+    ]
+}
+
+fn build_request(
+    model: Model,
+    max_tokens: u32,
+    thinking_budget: Option<u32>,
+    system_prompt: &str,
+    messages: &[Message],
+) -> MessagesRequest {
+    // Cache conversation history by marking the last content block.
+    // Single breakpoint at the end is optimal for non-rewinding multi-turn agents.
+    let mut request_messages = messages.to_vec();
+    if let Some(last_msg) = request_messages.last_mut() {
+        if last_msg.role == Role::User {
+            if let Some(last_content) = last_msg.content.last_mut() {
+                match last_content {
+                    ContentBlock::Text { cache_control, .. } => {
+                        *cache_control = Some(CacheControl::default());
+                    }
+                    ContentBlock::ToolResult { cache_control, .. } => {
+                        *cache_control = Some(CacheControl::default());
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    MessagesRequest {
+        model: model.api_model_id().to_string(),
+        max_tokens,
+        system: Some(SystemPrompt::Blocks(vec![SystemBlock::Text {
+            text: system_prompt.to_string(),
+            cache_control: Some(CacheControl::default()),
+        }])),
+        messages: request_messages,
+        tools: Some(tools()),
+        temperature: None,
+        top_p: None,
+        top_k: None,
+        stream: None,
+        thinking: thinking_budget.map(|budget_tokens| ThinkingConfig::Enabled { budget_tokens }),
+    }
+}
+
+/// Summarize the older messages and move large tool outputs to files, see
+/// [`crate::compaction`]. `request` is the one about to be sent.
+fn compact(
+    client: &Client,
+    container: Container,
+    mut request: MessagesRequest,
+    conversation: &mut Conversation,
+    usage: &mut SessionUsage,
+) -> Result<()> {
+    let Some(end) =
+        compaction::summary_end(&conversation.messages, compaction::KEEP_RECENT_MESSAGES)
+    else {
+        return Ok(());
+    };
+
+    // The instruction is added after the cache breakpoint, so that the conversation is
+    // read from the cache
+    let instruction = ContentBlock::Text {
+        text: compaction::SUMMARY_PROMPT.to_string(),
+        cache_control: None,
+    };
+    match request.messages.last_mut() {
+        Some(last_msg) if last_msg.role == Role::User => last_msg.content.push(instruction),
+        _ => request.messages.push(Message {
+            role: Role::User,
+            content: vec![instruction],
+        }),
+    }
+    let response = client.messages(request)?;
+    usage.record(&response.usage)?;
+
+    let summary = response
+        .content
+        .iter()
+        .filter_map(|block| match block {
+            ContentBlock::Text { text, .. } => Some(text.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    if summary.trim().is_empty() {
+        bail!("The model returned no summary to compact the conversation with");
+    }
+
+    let mut messages = compaction::replace_with_summary(&conversation.messages, end, &summary);
+    let offloaded = compaction::offload_tool_outputs(&mut messages, |output| {
+        save_output_to_file(container, output.as_bytes())
+    })?;
+    chat_println!(
+        conversation.chat_history,
+        "[compact] Summarized {} messages, moved {} tool outputs to files",
+        end,
+        offloaded
+    );
+    conversation.replace(messages)
+}
+
+/// End the run at a budget limit, with a final message in the chat.
+fn stop_at_budget(
+    conversation: &mut Conversation,
+    usage: &mut SessionUsage,
+    exceeded: BudgetExceeded,
+) -> Result<()> {
+    chat_println!(conversation.chat_history, "[budget] {}", exceeded);
+    eprintln!("{}", usage.end_turn());
+    Err(exceeded.into())
+}
+
+/// Exit code of the agent when it stops because a [`Budget`] limit was reached.
+pub const BUDGET_EXCEEDED_EXIT_CODE: u8 = 3;
+
+/// Limits on an agent run, counted from when the agent starts.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Budget {
+    pub max_turns: Option<u32>,
+    pub max_tool_calls: Option<u32>,
+    pub max_input_tokens: Option<u64>,
+    pub max_cost: Option<f64>,
+}
+
+/// Error returned when the agent stops at a [`Budget`] limit.
+#[derive(Debug)]
+pub struct BudgetExceeded {
+    pub limit: &'static str,
+    pub value: String,
+}
+
+impl std::fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Agent stopped: reached {} = {}", self.limit, self.value)
+    }
+}
+
+impl std::error::Error for BudgetExceeded {}
+
+impl Budget {
+    /// The limit reached, if any, before making another request.
+    fn check_request(&self, turns: u32, usage: &UsageTotals) -> Option<BudgetExceeded> {
+        if let Some(max) = self.max_turns.filter(|max| turns >= *max) {
+            return Some(BudgetExceeded {
+                limit: "max-turns",
+                value: max.to_string(),
+            });
+        }
+        let input_tokens = usage.tokens.total_input_tokens();
+        if let Some(max) = self.max_input_tokens.filter(|max| input_tokens >= *max) {
+            return Some(BudgetExceeded {
+                limit: "max-input-tokens",
+                value: max.to_string(),
+            });
+        }
+        if let Some(max) = self.max_cost.filter(|max| usage.cost >= *max) {
+            return Some(BudgetExceeded {
+                limit: "max-cost",
+                value: format_cost(max),
+            });
+        }
+        None
+    }
+
+    /// The limit that running `requested` more tool calls would exceed, if any.
+    fn check_tool_calls(&self, tool_calls: u32, requested: u32) -> Option<BudgetExceeded> {
+        let max = self
+            .max_tool_calls
+            .filter(|max| tool_calls + requested > *max)?;
+        Some(BudgetExceeded {
+            limit: "max-tool-calls",
+            value: max.to_string(),
+        })
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn run_agent(
+    container: Container,
+    model: Model,
+    max_tokens: u32,
+    cache: Option<LlmCache>,
+    transcript: Transcript,
+    input: InputOptions,
+    usage_log: UsageLog,
+    budget: Budget,
+    thinking: Thinking,
+    bash_timeout: Duration,
+) -> Result<()> {
     let client = Client::new_with_cache(cache)?;
 
     let mut stdout = std::io::stdout();
 
-    let mut messages: Vec<Message> = Vec::new();
-    let mut chat_history = String::new();
+    eprintln!("Session: {}", transcript.session_id());
+    let mut usage = SessionUsage::load(usage_log, transcript.session_id(), model)?;
+    let mut conversation = Conversation::load(transcript)?;
 
     // Read AGENTS.md once at startup to include project-specific instructions
-    let agents_md = read_agents_md(container_name);
+    let agents_md = read_agents_md(container);
     let system_prompt = build_system_prompt(agents_md.as_deref());
 
     let is_tty = std::io::stdin().is_terminal();
 
     // Non-TTY mode reads entire stdin upfront and exits after one response
-    let initial_prompt = if !is_tty {
+    let (initial_prompt, mut message_input) = if !is_tty {
         let mut input = String::new();
         std::io::stdin()
             .read_to_string(&mut input)
             .context("Failed to read stdin")?;
-        Some(input.trim().to_string())
+        (Some(input.trim().to_string()), None)
     } else {
-        None
+        (None, Some(MessageInput::new(input)?))
     };
 
+    // A resumed conversation that stopped before the agent responded to the user's
+    // turn picks up with that response
+    let mut awaiting_response = conversation
+        .messages
+        .last()
+        .is_some_and(|message| message.role == Role::User);
+    let mut prompted = false;
+    let mut turns = 0;
+    let mut tool_calls = 0;
+    // Raised while retrying a tool call cut off by the limit
+    let mut request_max_tokens = max_tokens;
+
     loop {
-        let user_input = if let Some(ref prompt) = initial_prompt {
-            if !messages.is_empty() {
-                break;
-            }
-            prompt.clone()
-        } else {
-            let input = get_input_via_vim(&chat_history)?;
-            if input.is_empty() {
-                if confirm_exit()? {
+        if !awaiting_response {
+            let user_input = if let Some(ref prompt) = initial_prompt {
+                if prompted {
                     break;
                 }
-                continue;
-            }
-            input
-        };
-
-        chat_println!(chat_history, "> {}", user_input);
-        stdout.flush()?;
+                prompted = true;
+                prompt.clone()
+            } else {
+                let message_input = message_input.as_mut().expect("TTY mode reads input");
+                match message_input.read(&conversation.chat_history)? {
+                    Some(input) => input,
+                    None => break,
+                }
+            };
 
-        messages.push(Message {
-            role: Role::User,
-            content: vec![ContentBlock::Text {
-                text: user_input,
-                cache_control: None,
-            }],
-        });
+            let user_line = format!("> {}", user_input);
+            if message_input
+                .as_ref()
+                .is_some_and(MessageInput::shows_message)
+            {
+                conversation.chat_history.push_str(&user_line);
+                conversation.chat_history.push('\n');
+            } else {
+                chat_println!(conversation.chat_history, "{}", user_line);
+            }
+            stdout.flush()?;
+
+            conversation.push(Message {
+                role: Role::User,
+                content: vec![ContentBlock::Text {
+                    text: user_input,
+                    cache_control: None,
+                }],
+            })?;
+        }
+        awaiting_response = false;
 
         loop {
-            // Cache conversation history by marking the last content block.
-            // Single breakpoint at the end is optimal for non-rewinding multi-turn agents.
-            let mut request_messages = messages.clone();
-            if let Some(last_msg) = request_messages.last_mut() {
-                if last_msg.role == Role::User {
-                    if let Some(last_content) = last_msg.content.last_mut() {
-                        match last_content {
-                            ContentBlock::Text { cache_control, .. } => {
-                                *cache_control = Some(CacheControl::default());
-                            }
-                            ContentBlock::ToolResult { cache_control, .. } => {
-                                *cache_control = Some(CacheControl::default());
-                            }
-                            _ => {}
-                        }
-                    }
-                }
+            if let Some(exceeded) = budget.check_request(turns, &usage.run) {
+                return stop_at_budget(&mut conversation, &mut usage, exceeded);
             }
 
-            let request = MessagesRequest {
-                model: model.api_model_id().to_string(),
-                max_tokens: MAX_TOKENS,
-                system: Some(SystemPrompt::Blocks(vec![SystemBlock::Text {
-                    text: system_prompt.clone(),
-                    cache_control: Some(CacheControl::default()),
-                }])),
-                messages: request_messages,
-                tools: Some(vec![
-                    bash_tool(),
-                    edit_tool(),
-                    write_tool(),
-                    websearch_tool(),
-                    fetch_tool(),
-                ]),
-                temperature: None,
-                top_p: None,
-                top_k: None,
-            };
+            let mut request = build_request(
+                model,
+                request_max_tokens,
+                thinking.budget_tokens,
+                &system_prompt,
+                &conversation.messages,
+            );
+            if conversation.estimated_tokens(&system_prompt) + u64::from(request_max_tokens)
+                > compaction_threshold(model)
+            {
+                compact(&client, container, request, &mut conversation, &mut usage)?;
+                request = build_request(
+                    model,
+                    request_max_tokens,
+                    thinking.budget_tokens,
+                    &system_prompt,
+                    &conversation.messages,
+                );
+            }
 
-            let response = client.messages(request)?;
+            let mut renderer = TextRenderer::new(thinking.show);
+            let response = client.messages_stream(request, &mut |event| renderer.render(event))?;
+            usage.record(&response.usage)?;
+            turns += 1;
+
+            // A response ending in a tool use cut off by the limit can't be used, so
+            // it's requested again with room for the complete tool call
+            if response.stop_reason == StopReason::MaxTokens
+                && matches!(response.content.last(), Some(ContentBlock::ToolUse { .. }))
+            {
+                let limit = model.max_output_tokens();
+                if request_max_tokens >= limit {
+                    bail!(
+                        "Tool call exceeds the model's limit of {} output tokens",
+                        limit
+                    );
+                }
+                request_max_tokens = (request_max_tokens * 2).min(limit);
+                chat_println!(
+                    conversation.chat_history,
+                    "[max tokens] Tool call cut off, retrying with max-tokens = {}",
+                    request_max_tokens
+                );
+                continue;
+            }
+            request_max_tokens = max_tokens;
+
+            // Stopping before any of the tools run leaves the conversation waiting for
+            // this response, so that a resumed session requests it again
+            let requested_tool_calls = response
+                .content
+                .iter()
+                .filter(|block| matches!(block, ContentBlock::ToolUse { .. }))
+                .count() as u32;
+            if let Some(exceeded) = budget.check_tool_calls(tool_calls, requested_tool_calls) {
+                return stop_at_budget(&mut conversation, &mut usage, exceeded);
+            }
+            tool_calls += requested_tool_calls;
 
             let mut has_tool_use = false;
             let mut tool_results: Vec<ContentBlock> = Vec::new();
@@ -562,7 +1196,9 @@ pub fn run_agent(container_name: &str, model: Model, cache: Option<LlmCache>) ->
             for block in &response.content {
                 match block {
                     ContentBlock::Text { text, .. } => {
-                        chat_println!(chat_history, "{}", text);
+                        // Already printed while streaming
+                        conversation.chat_history.push_str(text);
+                        conversation.chat_history.push('\n');
                     }
                     ContentBlock::ToolUse { id, name, input } => {
                         has_tool_use = true;
@@ -573,16 +1209,123 @@ pub fn run_agent(container_name: &str, model: Model, cache: Option<LlmCache>) ->
                             AgentToolName::Bash => {
                                 let command =
                                     input.get("command").and_then(|v| v.as_str()).unwrap_or("");
+                                let timeout = input
+                                    .get("timeout")
+                                    .and_then(|v| v.as_u64())
+                                    .map_or(bash_timeout, Duration::from_secs);
+                                let background = input
+                                    .get("run_in_background")
+                                    .and_then(|v| v.as_bool())
+                                    .unwrap_or(false);
+
+                                let (output, success) = if background {
+                                    chat_println!(conversation.chat_history, "$ {} &", command);
+                                    start_job_in_sandbox(container, command)?
+                                } else {
+                                    chat_println!(conversation.chat_history, "$ {}", command);
+                                    execute_bash_in_sandbox(container, command, timeout)?
+                                };
+
+                                if !output.is_empty() {
+                                    chat_println!(conversation.chat_history, "{}", output);
+                                }
 
-                                chat_println!(chat_history, "$ {}", command);
+                                (output, success)
+                            }
+                            AgentToolName::Job => {
+                                let action_name =
+                                    input.get("action").and_then(|v| v.as_str()).unwrap_or("");
+                                let id = input.get("id").and_then(|v| v.as_str()).unwrap_or("");
+
+                                chat_println!(
+                                    conversation.chat_history,
+                                    "[job] {}",
+                                    format!("{} {}", action_name, id).trim_end()
+                                );
+                                let action = match action_name {
+                                    "output" => Some(JobAction::Output(id)),
+                                    "kill" => Some(JobAction::Kill(id)),
+                                    "list" => Some(JobAction::List),
+                                    _ => None,
+                                };
+                                let (output, success) = match action {
+                                    Some(action) => execute_job_in_sandbox(container, action)?,
+                                    None => (
+                                        "action must be one of output, kill and list".to_string(),
+                                        false,
+                                    ),
+                                };
+
+                                chat_println!(conversation.chat_history, "{}", output);
+                                (output, success)
+                            }
+                            AgentToolName::Read => {
+                                let file_path = input
+                                    .get("file_path")
+                                    .and_then(|v| v.as_str())
+                                    .unwrap_or("");
+                                let offset = input
+                                    .get("offset")
+                                    .and_then(|v| v.as_u64())
+                                    .map(|v| v as usize);
+                                let limit = input
+                                    .get("limit")
+                                    .and_then(|v| v.as_u64())
+                                    .map(|v| v as usize);
 
                                 let (output, success) =
-                                    execute_bash_in_sandbox(container_name, command)?;
+                                    execute_read_in_sandbox(container, file_path, offset, limit)?;
 
-                                if !output.is_empty() {
-                                    chat_println!(chat_history, "{}", output);
+                                if success {
+                                    chat_println!(
+                                        conversation.chat_history,
+                                        "[read] {}",
+                                        file_path
+                                    );
+                                } else {
+                                    chat_println!(
+                                        conversation.chat_history,
+                                        "[read] {} (failed)",
+                                        file_path
+                                    );
+                                    chat_println!(conversation.chat_history, "{}", output);
                                 }
+                                (output, success)
+                            }
+                            AgentToolName::Glob => {
+                                let pattern =
+                                    input.get("pattern").and_then(|v| v.as_str()).unwrap_or("");
+                                let path = input.get("path").and_then(|v| v.as_str());
 
+                                chat_println!(conversation.chat_history, "[glob] {}", pattern);
+                                let (output, success) =
+                                    execute_glob_in_sandbox(container, pattern, path)?;
+                                if !success {
+                                    chat_println!(conversation.chat_history, "{}", output);
+                                }
+                                (output, success)
+                            }
+                            AgentToolName::Grep => {
+                                let pattern =
+                                    input.get("pattern").and_then(|v| v.as_str()).unwrap_or("");
+                                let path = input.get("path").and_then(|v| v.as_str());
+                                let include = input.get("include").and_then(|v| v.as_str());
+                                let ignore_case = input
+                                    .get("ignore_case")
+                                    .and_then(|v| v.as_bool())
+                                    .unwrap_or(false);
+
+                                chat_println!(conversation.chat_history, "[grep] {}", pattern);
+                                let (output, success) = execute_grep_in_sandbox(
+                                    container,
+                                    pattern,
+                                    path,
+                                    include,
+                                    ignore_case,
+                                )?;
+                                if !success {
+                                    chat_println!(conversation.chat_history, "{}", output);
+                                }
                                 (output, success)
                             }
                             AgentToolName::Edit => {
@@ -600,17 +1343,22 @@ pub fn run_agent(container_name: &str, model: Model, cache: Option<LlmCache>) ->
                                     .unwrap_or("");
 
                                 let (output, success) = execute_edit_in_sandbox(
-                                    container_name,
-                                    file_path,
-                                    old_string,
-                                    new_string,
+                                    container, file_path, old_string, new_string,
                                 )?;
 
                                 if success {
-                                    chat_println!(chat_history, "[edit] {}", file_path);
+                                    chat_println!(
+                                        conversation.chat_history,
+                                        "[edit] {}",
+                                        file_path
+                                    );
                                 } else {
-                                    chat_println!(chat_history, "[edit] {} (failed)", file_path);
-                                    chat_println!(chat_history, "{}", output);
+                                    chat_println!(
+                                        conversation.chat_history,
+                                        "[edit] {} (failed)",
+                                        file_path
+                                    );
+                                    chat_println!(conversation.chat_history, "{}", output);
                                 }
                                 (output, success)
                             }
@@ -623,13 +1371,21 @@ pub fn run_agent(container_name: &str, model: Model, cache: Option<LlmCache>) ->
                                     input.get("content").and_then(|v| v.as_str()).unwrap_or("");
 
                                 let (output, success) =
-                                    execute_write_in_sandbox(container_name, file_path, content)?;
+                                    execute_write_in_sandbox(container, file_path, content)?;
 
                                 if success {
-                                    chat_println!(chat_history, "[write] {}", file_path);
+                                    chat_println!(
+                                        conversation.chat_history,
+                                        "[write] {}",
+                                        file_path
+                                    );
                                 } else {
-                                    chat_println!(chat_history, "[write] {} (failed)", file_path);
-                                    chat_println!(chat_history, "{}", output);
+                                    chat_println!(
+                                        conversation.chat_history,
+                                        "[write] {} (failed)",
+                                        file_path
+                                    );
+                                    chat_println!(conversation.chat_history, "{}", output);
                                 }
                                 (output, success)
                             }
@@ -649,16 +1405,19 @@ pub fn run_agent(container_name: &str, model: Model, cache: Option<LlmCache>) ->
                             cache_control: None,
                         });
                     }
+                    // Shown while streaming if enabled, and kept in the history as
+                    // the API requires
+                    ContentBlock::Thinking { .. } | ContentBlock::RedactedThinking { .. } => {}
                     ContentBlock::ToolResult { .. } => {}
                     ContentBlock::Image { .. } => {}
                     // Server-side tools (web_search, web_fetch) are handled by the API
                     ContentBlock::ServerToolUse { name, input, .. } => {
                         if name == "web_search" {
                             let query = input.get("query").and_then(|v| v.as_str()).unwrap_or("");
-                            chat_println!(chat_history, "[search] {}", query);
+                            chat_println!(conversation.chat_history, "[search] {}", query);
                         } else if name == "web_fetch" {
                             let url = input.get("url").and_then(|v| v.as_str()).unwrap_or("");
-                            chat_println!(chat_history, "[fetch] {}", url);
+                            chat_println!(conversation.chat_history, "[fetch] {}", url);
                         }
                     }
                     ContentBlock::WebSearchToolResult { .. } => {}
@@ -666,29 +1425,76 @@ pub fn run_agent(container_name: &str, model: Model, cache: Option<LlmCache>) ->
                         if let crate::anthropic::WebFetchResult::WebFetchToolError { error_code } =
                             content
                         {
-                            chat_println!(chat_history, "[fetch] (failed: {})", error_code);
+                            chat_println!(
+                                conversation.chat_history,
+                                "[fetch] (failed: {})",
+                                error_code
+                            );
                         }
                     }
                 }
             }
 
-            messages.push(Message {
+            conversation.push(Message {
                 role: Role::Assistant,
                 content: response.content.clone(),
-            });
+            })?;
+            conversation.measure(&response.usage);
 
             if has_tool_use && !tool_results.is_empty() {
-                messages.push(Message {
+                conversation.push(Message {
                     role: Role::User,
                     content: tool_results,
-                });
+                })?;
             }
 
-            if response.stop_reason != StopReason::ToolUse {
-                break;
+            match response.stop_reason {
+                // Tool results go back to the model
+                _ if has_tool_use => {}
+                // A long running server tool paused the turn, sending the conversation
+                // back as is continues it
+                StopReason::PauseTurn => {}
+                StopReason::MaxTokens => {
+                    chat_println!(
+                        conversation.chat_history,
+                        "[max tokens] Response cut off at {} tokens",
+                        request_max_tokens
+                    );
+                    break;
+                }
+                StopReason::EndTurn | StopReason::StopSequence | StopReason::ToolUse => break,
             }
         }
+
+        eprintln!("{}", usage.end_turn());
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_number_lines() {
+        let content = "one\ntwo\nthree\n";
+        assert_eq!(
+            number_lines(content, 1, 10),
+            "     1\tone\n     2\ttwo\n     3\tthree\n"
+        );
+        assert_eq!(number_lines(content, 2, 1), "     2\ttwo\n");
+
+        let long_line = "é".repeat(MAX_READ_LINE_LENGTH + 1);
+        let numbered = number_lines(&long_line, 1, 1);
+        assert!(
+            numbered.ends_with("é... [line truncated]\n"),
+            "{}",
+            numbered
+        );
+        assert_eq!(
+            numbered.chars().filter(|&c| c == 'é').count(),
+            MAX_READ_LINE_LENGTH
+        );
+    }
+}
@@ -0,0 +1,223 @@
+//! Reading the user's messages to the agent in an interactive session.
+
+use anyhow::{bail, Context, Result};
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::Editor;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::config::InputMode;
+
+/// Editor used when neither the config nor the environment names one.
+const DEFAULT_EDITOR: &str = "vim";
+
+/// Settings for reading messages.
+pub struct InputOptions {
+    pub mode: InputMode,
+    /// Configured editor command.
+    pub editor: Option<String>,
+    /// Directory for the message file and input history, private to the sandbox.
+    pub state_dir: PathBuf,
+}
+
+/// Source of the user's messages.
+pub enum MessageInput {
+    /// An editor opened on the chat history, to which the message is appended.
+    Editor { command: String, state_dir: PathBuf },
+    /// A line editor prompt.
+    Inline {
+        editor: Box<Editor<(), DefaultHistory>>,
+        history_path: PathBuf,
+    },
+}
+
+impl MessageInput {
+    pub fn new(options: InputOptions) -> Result<Self> {
+        Ok(match options.mode {
+            InputMode::Editor => MessageInput::Editor {
+                command: resolve_editor(options.editor, |name| std::env::var(name).ok()),
+                state_dir: options.state_dir,
+            },
+            InputMode::Inline => {
+                let mut editor = Editor::new().context("Failed to initialize the line editor")?;
+                let history_path = options.state_dir.join("input-history");
+                if history_path.exists() {
+                    editor
+                        .load_history(&history_path)
+                        .with_context(|| format!("Failed to load {}", history_path.display()))?;
+                }
+                MessageInput::Inline {
+                    editor: Box::new(editor),
+                    history_path,
+                }
+            }
+        })
+    }
+
+    /// Whether the message stays on screen as typed, so it needn't be printed again.
+    pub fn shows_message(&self) -> bool {
+        matches!(self, MessageInput::Inline { .. })
+    }
+
+    /// Read the next message, or `None` if the user wants to exit.
+    pub fn read(&mut self, chat_history: &str) -> Result<Option<String>> {
+        match self {
+            MessageInput::Editor { command, state_dir } => loop {
+                let message = edit_message(command, state_dir, chat_history)?;
+                if !message.is_empty() {
+                    return Ok(Some(message));
+                }
+                if confirm_exit()? {
+                    return Ok(None);
+                }
+            },
+            MessageInput::Inline {
+                editor,
+                history_path,
+            } => loop {
+                // Pasted text keeps its newlines, Enter sends the message
+                match editor.readline("> ") {
+                    Ok(line) => {
+                        let message = line.trim();
+                        if message.is_empty() {
+                            continue;
+                        }
+                        editor.add_history_entry(message)?;
+                        editor.save_history(history_path).with_context(|| {
+                            format!("Failed to save {}", history_path.display())
+                        })?;
+                        return Ok(Some(message.to_string()));
+                    }
+                    // Ctrl-C discards the line being typed
+                    Err(ReadlineError::Interrupted) => continue,
+                    Err(ReadlineError::Eof) => return Ok(None),
+                    Err(e) => return Err(e).context("Failed to read input"),
+                }
+            },
+        }
+    }
+}
+
+/// The editor command: the configured one, then `$VISUAL`, then `$EDITOR`.
+fn resolve_editor(configured: Option<String>, env: impl Fn(&str) -> Option<String>) -> String {
+    let env = |name| env(name).filter(|command: &String| !command.trim().is_empty());
+    configured
+        .or_else(|| env("VISUAL"))
+        .or_else(|| env("EDITOR"))
+        .unwrap_or_else(|| DEFAULT_EDITOR.to_string())
+}
+
+/// Run an editor command on `path`. Like git, the command goes through the shell, so
+/// that it can include arguments (`code --wait`).
+fn run_editor(command: &str, path: &Path) -> Result<()> {
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", command))
+        .arg(command)
+        .arg(path)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .status()
+        .with_context(|| format!("Failed to launch editor: {}", command))?;
+
+    if !status.success() {
+        bail!("Editor exited with {}: {}", status, command);
+    }
+    Ok(())
+}
+
+/// Get a message by opening the editor on a file containing the chat history.
+/// Returns the new message (content after the chat history prefix).
+/// If the user doesn't preserve the chat history prefix, prompts to retry.
+fn edit_message(command: &str, dir: &Path, chat_history: &str) -> Result<String> {
+    loop {
+        let file = tempfile::Builder::new()
+            .prefix("chat-")
+            .suffix(".txt")
+            .tempfile_in(dir)
+            .context("Failed to create message file")?;
+        std::fs::write(file.path(), chat_history).context("Failed to write message file")?;
+
+        run_editor(command, file.path())?;
+
+        // Read by path, as editors may replace the file rather than write to it
+        let edited_content =
+            std::fs::read_to_string(file.path()).context("Failed to read message file")?;
+
+        // Prevent accidental editing of history
+        if !edited_content.starts_with(chat_history) {
+            eprintln!("Error: The chat history prefix was modified. Please keep it intact.");
+            eprint!("Press Enter to try again...");
+            std::io::stderr().flush()?;
+
+            let mut buf = [0u8; 1];
+            let _ = std::io::stdin().read(&mut buf);
+            continue;
+        }
+
+        return Ok(edited_content[chat_history.len()..].trim().to_string());
+    }
+}
+
+/// Prompts user to confirm exit when they submit empty input.
+/// Returns true if user wants to exit (Enter or 'y'), false otherwise.
+fn confirm_exit() -> Result<bool> {
+    eprintln!("Exit? [Y/n] ");
+    std::io::stderr().flush()?;
+
+    let mut buf = [0u8; 1];
+    let bytes_read = std::io::stdin().read(&mut buf)?;
+
+    if bytes_read == 0 || buf[0] == b'\n' || buf[0] == b'y' || buf[0] == b'Y' {
+        return Ok(true);
+    }
+
+    // Discard remaining input so it doesn't leak to the next prompt
+    let mut discard = String::new();
+    std::io::stdin().read_line(&mut discard)?;
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_resolve_editor() {
+        let env = |name: &str| match name {
+            "VISUAL" => Some("code --wait".to_string()),
+            "EDITOR" => Some("nano".to_string()),
+            _ => None,
+        };
+        assert_eq!(resolve_editor(Some("nvim".to_string()), env), "nvim");
+        assert_eq!(resolve_editor(None, env), "code --wait");
+
+        let editor_only = |name: &str| (name == "EDITOR").then(|| "nano".to_string());
+        assert_eq!(resolve_editor(None, editor_only), "nano");
+        assert_eq!(resolve_editor(None, |_| None), "vim");
+        let empty_visual = |name: &str| match name {
+            "VISUAL" => Some(String::new()),
+            _ => Some("nano".to_string()),
+        };
+        assert_eq!(resolve_editor(None, empty_visual), "nano");
+    }
+
+    #[test]
+    fn test_edit_message_with_editor_arguments() {
+        let dir = tempdir().unwrap();
+        let message = edit_message(
+            "sed -i -e '$a hello, world'",
+            dir.path(),
+            "> earlier message\nreply\n",
+        )
+        .unwrap();
+        assert_eq!(message, "hello, world");
+        // The message file is removed afterwards
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
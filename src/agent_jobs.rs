@@ -0,0 +1,210 @@
+//! Background jobs started by the agent's bash tool.
+//!
+//! Jobs run detached inside the container, each in its own session so that the whole
+//! process group can be killed. A job's state is kept in a numbered directory under
+//! [`JOBS_DIR`]: the command, the process group ID, the combined output, how much of
+//! the output was returned already, and the exit status once it finishes. Keeping it
+//! there rather than in the agent lets jobs outlive an agent session and be seen from
+//! a resumed one.
+
+/// Directory holding the state of the jobs in the container.
+pub const JOBS_DIR: &str = "/agent/jobs";
+
+/// Manages the jobs, run as `bash -c SCRIPT jobs <jobs dir> <action> [<arg>]`.
+const SCRIPT: &str = r#"
+set -u
+dir=$1 action=$2
+shift 2
+mkdir -p "$dir" || exit
+
+status() {
+    local job=$dir/$1
+    if [[ -e $job/exit-code ]]; then
+        echo "exited with status $(<"$job/exit-code")"
+    elif kill -0 -- "-$(<"$job/pgid")" 2>/dev/null; then
+        echo running
+    elif [[ -e $job/killed ]]; then
+        echo killed
+    else
+        echo stopped
+    fi
+}
+
+if [[ $action != start && $action != list && ( -z ${1:-} || ! -d $dir/$1 ) ]]; then
+    echo "No such job: ${1:-}" >&2
+    exit 1
+fi
+
+case $action in
+start)
+    id=1
+    while ! mkdir "$dir/$id" 2>/dev/null; do
+        id=$((id + 1))
+    done
+    job=$dir/$id
+    printf '%s\n' "$1" > "$job/command"
+    : > "$job/output"
+    echo 0 > "$job/offset"
+    # The job's shell leads the new session, so its PID is the process group ID
+    setsid bash -c '
+        echo $$ > "$1/pgid.tmp" && mv "$1/pgid.tmp" "$1/pgid"
+        bash -c "$2" >> "$1/output" 2>&1 < /dev/null
+        echo $? > "$1/exit-code"
+    ' job "$job" "$1" > /dev/null 2>&1 < /dev/null &
+    until [[ -e $job/pgid ]]; do
+        sleep 0.01
+    done
+    echo "$id"
+    ;;
+output)
+    job=$dir/$1
+    # The status is checked first, so that a finished job's output is complete
+    state=$(status "$1")
+    size=$(wc -c < "$job/output")
+    offset=$(<"$job/offset")
+    echo "[job $1 $state]"
+    if ((size > offset)); then
+        tail -c "+$((offset + 1))" "$job/output" | head -c "$((size - offset))"
+        echo "$size" > "$job/offset"
+    else
+        echo "(no new output)"
+    fi
+    ;;
+kill)
+    state=$(status "$1")
+    if [[ $state != running ]]; then
+        echo "Job $1 is not running ($state)"
+        exit 0
+    fi
+    pgid=$(<"$dir/$1/pgid")
+    touch "$dir/$1/killed"
+    kill -TERM -- "-$pgid" 2>/dev/null
+    for _ in {1..50}; do
+        kill -0 -- "-$pgid" 2>/dev/null || break
+        sleep 0.1
+    done
+    kill -KILL -- "-$pgid" 2>/dev/null
+    echo "Killed job $1"
+    ;;
+list)
+    ids=$(ls "$dir" | sort -n)
+    if [[ -z $ids ]]; then
+        echo "No jobs"
+    fi
+    for id in $ids; do
+        printf '%s\t%s\t%s\n' "$id" "$(status "$id")" "$(head -n 1 "$dir/$id/command")"
+    done
+    ;;
+*)
+    echo "Unknown action: $action" >&2
+    exit 1
+    ;;
+esac
+"#;
+
+/// What to do with the jobs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobAction<'a> {
+    /// Start a command, printing the job ID.
+    Start(&'a str),
+    /// Print the status of a job and the output since the previous call.
+    Output(&'a str),
+    /// Kill the process group of a job.
+    Kill(&'a str),
+    /// Print the ID, status and command of every job.
+    List,
+}
+
+/// The command performing `action` on the jobs in `jobs_dir`.
+pub fn job_command<'a>(jobs_dir: &'a str, action: JobAction<'a>) -> Vec<&'a str> {
+    let mut command = vec!["bash", "-c", SCRIPT, "jobs", jobs_dir];
+    match action {
+        JobAction::Start(arg) => command.extend(["start", arg]),
+        JobAction::Output(id) => command.extend(["output", id]),
+        JobAction::Kill(id) => command.extend(["kill", id]),
+        JobAction::List => command.push("list"),
+    }
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use std::time::{Duration, Instant};
+    use tempfile::tempdir;
+
+    fn run(jobs_dir: &str, action: JobAction) -> (String, bool) {
+        let command = job_command(jobs_dir, action);
+        let output = Command::new(command[0])
+            .args(&command[1..])
+            .output()
+            .unwrap();
+        let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+        text.push_str(&String::from_utf8_lossy(&output.stderr));
+        (text, output.status.success())
+    }
+
+    fn wait_until(mut condition: impl FnMut() -> bool) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(10), "Timed out");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn test_job_output_is_returned_once() {
+        let dir = tempdir().unwrap();
+        let jobs_dir = dir.path().to_str().unwrap();
+
+        let (id, success) = run(jobs_dir, JobAction::Start("echo one; echo two; exit 3"));
+        assert!(success, "{}", id);
+        assert_eq!(id, "1\n");
+        wait_until(|| dir.path().join("1/exit-code").exists());
+
+        let (output, _) = run(jobs_dir, JobAction::Output("1"));
+        assert_eq!(output, "[job 1 exited with status 3]\none\ntwo\n");
+        let (output, _) = run(jobs_dir, JobAction::Output("1"));
+        assert_eq!(output, "[job 1 exited with status 3]\n(no new output)\n");
+
+        let (output, success) = run(jobs_dir, JobAction::Output("2"));
+        assert!(!success);
+        assert_eq!(output, "No such job: 2\n");
+        let (output, success) = run(jobs_dir, JobAction::Kill(""));
+        assert!(!success);
+        assert_eq!(output, "No such job: \n");
+    }
+
+    #[test]
+    fn test_kill_job_process_group() {
+        let dir = tempdir().unwrap();
+        let jobs_dir = dir.path().to_str().unwrap();
+
+        // A child process in the background is killed with the job
+        let marker = dir.path().join("marker");
+        let command = format!(
+            "(sleep 0.5; touch {}) & echo started; sleep 30",
+            marker.display()
+        );
+        let (id, _) = run(jobs_dir, JobAction::Start(&command));
+        assert_eq!(id, "1\n");
+        let (id, _) = run(jobs_dir, JobAction::Start("true"));
+        assert_eq!(id, "2\n");
+        wait_until(|| run(jobs_dir, JobAction::Output("1")).0.contains("started"));
+
+        let (output, _) = run(jobs_dir, JobAction::Kill("1"));
+        assert_eq!(output, "Killed job 1\n");
+        std::thread::sleep(Duration::from_secs(1));
+        assert!(!marker.exists());
+
+        let (output, _) = run(jobs_dir, JobAction::Kill("1"));
+        assert_eq!(output, "Job 1 is not running (killed)\n");
+        wait_until(|| dir.path().join("2/exit-code").exists());
+        let (output, _) = run(jobs_dir, JobAction::List);
+        assert_eq!(
+            output,
+            format!("1\tkilled\t{}\n2\texited with status 0\ttrue\n", command)
+        );
+    }
+}
@@ -1,7 +1,9 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use log::{debug, warn};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
 use std::time::Duration;
 
 use crate::llm_cache::LlmCache;
@@ -90,6 +92,15 @@ pub enum ContentBlock {
         #[serde(skip_serializing_if = "Option::is_none")]
         cache_control: Option<CacheControl>,
     },
+    /// The model's reasoning, sent back unchanged in later requests. The signature
+    /// arrives last when streaming.
+    Thinking {
+        thinking: String,
+        #[serde(default)]
+        signature: String,
+    },
+    /// Reasoning flagged by safety systems, encrypted in `data`.
+    RedactedThinking { data: String },
     Image {
         source: ImageSource,
         #[serde(skip_serializing_if = "Option::is_none")]
@@ -273,9 +284,26 @@ pub struct MessagesRequest {
     pub top_p: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub top_k: Option<u32>,
+    /// Set by [`Client::messages_stream`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thinking: Option<ThinkingConfig>,
+}
+
+/// Extended thinking, where the model reasons before responding.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ThinkingConfig {
+    /// Think using up to `budget_tokens` of the output tokens (at least 1024, and
+    /// less than `max_tokens`).
+    Enabled {
+        budget_tokens: u32,
+    },
+    Disabled,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Usage {
     pub input_tokens: u32,
     pub output_tokens: u32,
@@ -285,7 +313,7 @@ pub struct Usage {
     pub cache_read_input_tokens: u32,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct MessagesResponse {
     pub id: String,
     #[serde(rename = "type")]
@@ -297,6 +325,302 @@ pub struct MessagesResponse {
     pub usage: Usage,
 }
 
+/// The message as announced by `message_start`, before any content.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageStart {
+    pub id: String,
+    pub role: Role,
+    pub model: String,
+    pub usage: Usage,
+}
+
+/// Incremental content of a block in a streamed response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentDelta {
+    TextDelta {
+        text: String,
+    },
+    /// Part of the JSON input of a tool use; the parts concatenate to the input.
+    InputJsonDelta {
+        partial_json: String,
+    },
+    ThinkingDelta {
+        thinking: String,
+    },
+    SignatureDelta {
+        signature: String,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageDelta {
+    pub stop_reason: Option<StopReason>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeltaUsage {
+    pub output_tokens: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamError {
+    #[serde(rename = "type")]
+    pub error_type: String,
+    pub message: String,
+}
+
+/// A server-sent event of a streamed response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamEvent {
+    MessageStart {
+        message: MessageStart,
+    },
+    /// A new content block; text and tool input are empty, and follow as deltas.
+    ContentBlockStart {
+        index: usize,
+        content_block: ContentBlock,
+    },
+    ContentBlockDelta {
+        index: usize,
+        delta: ContentDelta,
+    },
+    ContentBlockStop {
+        index: usize,
+    },
+    MessageDelta {
+        delta: MessageDelta,
+        usage: DeltaUsage,
+    },
+    MessageStop,
+    Ping,
+    Error {
+        error: StreamError,
+    },
+}
+
+/// Assembles the events of a streamed response into the complete message.
+#[derive(Debug, Default)]
+struct MessageAccumulator {
+    start: Option<MessageStart>,
+    content: Vec<ContentBlock>,
+    /// Tool input received so far, by block index.
+    partial_json: HashMap<usize, String>,
+    /// Tool input that isn't valid JSON, which is expected only if the response was
+    /// cut off by `max_tokens`.
+    invalid_input: Option<anyhow::Error>,
+    stop_reason: Option<StopReason>,
+    output_tokens: Option<u32>,
+}
+
+impl MessageAccumulator {
+    fn apply(&mut self, event: &StreamEvent) -> Result<()> {
+        match event {
+            StreamEvent::MessageStart { message } => self.start = Some(message.clone()),
+            StreamEvent::ContentBlockStart {
+                index,
+                content_block,
+            } => {
+                if *index != self.content.len() {
+                    bail!("Content block {} started out of order", index);
+                }
+                self.content.push(content_block.clone());
+            }
+            StreamEvent::ContentBlockDelta { index, delta } => {
+                let block = self
+                    .content
+                    .get_mut(*index)
+                    .with_context(|| format!("Delta for unknown content block {}", index))?;
+                match (block, delta) {
+                    (ContentBlock::Text { text, .. }, ContentDelta::TextDelta { text: delta }) => {
+                        text.push_str(delta);
+                    }
+                    (
+                        ContentBlock::Thinking { thinking, .. },
+                        ContentDelta::ThinkingDelta { thinking: delta },
+                    ) => thinking.push_str(delta),
+                    (
+                        ContentBlock::Thinking { signature, .. },
+                        ContentDelta::SignatureDelta { signature: delta },
+                    ) => signature.push_str(delta),
+                    (_, ContentDelta::InputJsonDelta { partial_json }) => {
+                        self.partial_json
+                            .entry(*index)
+                            .or_default()
+                            .push_str(partial_json);
+                    }
+                    _ => {}
+                }
+            }
+            StreamEvent::ContentBlockStop { index } => {
+                if let Some(json) = self.partial_json.remove(index) {
+                    let parsed: serde_json::Value = if json.is_empty() {
+                        serde_json::json!({})
+                    } else {
+                        match serde_json::from_str(&json) {
+                            Ok(parsed) => parsed,
+                            Err(e) => {
+                                self.invalid_input = Some(
+                                    anyhow::Error::new(e).context("Invalid tool input in stream"),
+                                );
+                                return Ok(());
+                            }
+                        }
+                    };
+                    match self.content.get_mut(*index) {
+                        Some(
+                            ContentBlock::ToolUse { input, .. }
+                            | ContentBlock::ServerToolUse { input, .. },
+                        ) => *input = parsed,
+                        _ => bail!("Tool input for content block {} without tool use", index),
+                    }
+                }
+            }
+            StreamEvent::MessageDelta { delta, usage } => {
+                if delta.stop_reason.is_some() {
+                    self.stop_reason = delta.stop_reason.clone();
+                }
+                self.output_tokens = Some(usage.output_tokens);
+            }
+            StreamEvent::MessageStop | StreamEvent::Ping => {}
+            StreamEvent::Error { error } => {
+                bail!(
+                    "Anthropic API stream error ({}): {}",
+                    error.error_type,
+                    error.message
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<MessagesResponse> {
+        let start = self.start.context("Stream ended without message_start")?;
+        let stop_reason = self
+            .stop_reason
+            .context("Stream ended without stop reason")?;
+        // A tool use cut off by max_tokens is left with empty input
+        if let Some(error) = self.invalid_input {
+            if stop_reason != StopReason::MaxTokens {
+                return Err(error);
+            }
+        }
+        let mut usage = start.usage;
+        if let Some(output_tokens) = self.output_tokens {
+            usage.output_tokens = output_tokens;
+        }
+        Ok(MessagesResponse {
+            id: start.id,
+            response_type: "message".to_string(),
+            role: start.role,
+            content: self.content,
+            model: start.model,
+            stop_reason,
+            usage,
+        })
+    }
+}
+
+/// Read the events of a server-sent event stream, until it ends.
+fn read_events(
+    reader: impl BufRead,
+    mut on_event: impl FnMut(StreamEvent) -> Result<()>,
+) -> Result<()> {
+    let mut data = String::new();
+    for line in reader.lines() {
+        let line = line.context("Failed to read response stream")?;
+        if line.is_empty() {
+            if !data.is_empty() {
+                let event = serde_json::from_str(&data)
+                    .with_context(|| format!("Failed to parse stream event: {}", data))?;
+                on_event(event)?;
+                data.clear();
+            }
+        } else if let Some(value) = line.strip_prefix("data:") {
+            if !data.is_empty() {
+                data.push('\n');
+            }
+            data.push_str(value.strip_prefix(' ').unwrap_or(value));
+        }
+        // `event:` lines repeat the type in the data; comments and ids are unused
+    }
+    Ok(())
+}
+
+/// The events of a stream that would produce `response`, for replaying cached responses.
+fn replay_events(response: &MessagesResponse) -> Vec<StreamEvent> {
+    let mut events = vec![StreamEvent::MessageStart {
+        message: MessageStart {
+            id: response.id.clone(),
+            role: response.role.clone(),
+            model: response.model.clone(),
+            usage: response.usage.clone(),
+        },
+    }];
+    for (index, block) in response.content.iter().enumerate() {
+        match block {
+            ContentBlock::Text {
+                text,
+                cache_control,
+            } => {
+                events.push(StreamEvent::ContentBlockStart {
+                    index,
+                    content_block: ContentBlock::Text {
+                        text: String::new(),
+                        cache_control: cache_control.clone(),
+                    },
+                });
+                events.push(StreamEvent::ContentBlockDelta {
+                    index,
+                    delta: ContentDelta::TextDelta { text: text.clone() },
+                });
+            }
+            ContentBlock::Thinking {
+                thinking,
+                signature,
+            } => {
+                events.push(StreamEvent::ContentBlockStart {
+                    index,
+                    content_block: ContentBlock::Thinking {
+                        thinking: String::new(),
+                        signature: String::new(),
+                    },
+                });
+                events.push(StreamEvent::ContentBlockDelta {
+                    index,
+                    delta: ContentDelta::ThinkingDelta {
+                        thinking: thinking.clone(),
+                    },
+                });
+                events.push(StreamEvent::ContentBlockDelta {
+                    index,
+                    delta: ContentDelta::SignatureDelta {
+                        signature: signature.clone(),
+                    },
+                });
+            }
+            _ => events.push(StreamEvent::ContentBlockStart {
+                index,
+                content_block: block.clone(),
+            }),
+        }
+        events.push(StreamEvent::ContentBlockStop { index });
+    }
+    events.push(StreamEvent::MessageDelta {
+        delta: MessageDelta {
+            stop_reason: Some(response.stop_reason.clone()),
+        },
+        usage: DeltaUsage {
+            output_tokens: response.usage.output_tokens,
+        },
+    });
+    events.push(StreamEvent::MessageStop);
+    events
+}
+
 pub struct Client {
     api_key: Option<String>,
     client: reqwest::blocking::Client,
@@ -306,7 +630,8 @@ pub struct Client {
 impl Client {
     pub fn new(api_key: String) -> Self {
         // Use 180s timeout as API requests with large context can take >30s to complete.
-        // This includes connection, sending request body, and receiving response.
+        // This bounds connecting and sending the request, then each read of the response:
+        // the whole body of non-streaming responses, or the wait for the next event.
         let client = reqwest::blocking::Client::builder()
             .timeout(Duration::from_secs(180))
             .build()
@@ -333,7 +658,8 @@ impl Client {
         }
 
         // Use 180s timeout as API requests with large context can take >30s to complete.
-        // This includes connection, sending request body, and receiving response.
+        // This bounds connecting and sending the request, then each read of the response:
+        // the whole body of non-streaming responses, or the wait for the next event.
         let client = reqwest::blocking::Client::builder()
             .timeout(Duration::from_secs(180))
             .build()
@@ -368,33 +694,107 @@ impl Client {
         headers
     }
 
-    /// Retry logic follows claude code's behavior: up to 10 retries, first retry instant
-    /// (unless rate-limited), then 2 minute delays with jitter.
-    pub fn messages(&self, request: MessagesRequest) -> Result<MessagesResponse> {
-        const MAX_RETRIES: u32 = 10;
-        const BASE_RETRY_DELAY: Duration = Duration::from_secs(120);
-        const MAX_JITTER: Duration = Duration::from_secs(30);
-
-        // Serialize request body to a string once
-        let body = serde_json::to_string(&request).context("Failed to serialize request")?;
-
-        // Build headers for cache key computation (excludes API key for consistent cache lookups)
+    /// Cache key of a request body. Excludes the API key so cache lookups work
+    /// regardless of whether an API key is set.
+    fn cache_key(&self, cache: &LlmCache, body: &str) -> String {
         let cache_headers = self.build_headers(true);
         let cache_header_refs: Vec<(&str, &str)> = cache_headers
             .iter()
             .map(|(k, v)| (*k, v.as_str()))
             .collect();
+        cache.compute_key(&cache_header_refs, body)
+    }
 
-        // Check cache first
+    /// The cached response to a request body, if any.
+    fn cached_response(&self, body: &str) -> Result<Option<MessagesResponse>> {
+        let Some(ref cache) = self.cache else {
+            return Ok(None);
+        };
+        let Some(cached_response) = cache.get(&self.cache_key(cache, body)) else {
+            return Ok(None);
+        };
+        let response =
+            serde_json::from_str(&cached_response).context("Failed to parse cached response")?;
+        Ok(Some(response))
+    }
+
+    fn cache_response(&self, body: &str, response_text: &str) -> Result<()> {
         if let Some(ref cache) = self.cache {
-            let cache_key = cache.compute_key(&cache_header_refs, &body);
-            if let Some(cached_response) = cache.get(&cache_key) {
-                let response: MessagesResponse = serde_json::from_str(&cached_response)
-                    .context("Failed to parse cached response")?;
-                return Ok(response);
+            cache.put(&self.cache_key(cache, body), response_text)?;
+        }
+        Ok(())
+    }
+
+    pub fn messages(&self, request: MessagesRequest) -> Result<MessagesResponse> {
+        // Serialize request body to a string once
+        let body = serde_json::to_string(&request).context("Failed to serialize request")?;
+
+        if let Some(response) = self.cached_response(&body)? {
+            return Ok(response);
+        }
+
+        let response = self.send(&body)?;
+        let response_text = response.text().context("Failed to read response body")?;
+        self.cache_response(&body, &response_text)?;
+
+        let response: MessagesResponse = serde_json::from_str(&response_text)
+            .context("Failed to parse Anthropic API response")?;
+        debug!(
+            "API request successful: {} input tokens, {} output tokens",
+            response.usage.input_tokens, response.usage.output_tokens
+        );
+        Ok(response)
+    }
+
+    /// Like [`Client::messages`], but streams the response: `on_event` receives each
+    /// event as it arrives, and the assembled message is returned at the end.
+    ///
+    /// The cache stores the assembled message under the key of the equivalent
+    /// non-streaming request; cached responses are replayed as events.
+    pub fn messages_stream(
+        &self,
+        mut request: MessagesRequest,
+        on_event: &mut dyn FnMut(&StreamEvent),
+    ) -> Result<MessagesResponse> {
+        request.stream = None;
+        let cache_body = serde_json::to_string(&request).context("Failed to serialize request")?;
+
+        if let Some(response) = self.cached_response(&cache_body)? {
+            for event in replay_events(&response) {
+                on_event(&event);
             }
+            return Ok(response);
         }
 
+        request.stream = Some(true);
+        let body = serde_json::to_string(&request).context("Failed to serialize request")?;
+        let response = self.send(&body)?;
+
+        let mut message = MessageAccumulator::default();
+        read_events(BufReader::new(response), |event| {
+            message.apply(&event)?;
+            on_event(&event);
+            Ok(())
+        })?;
+        let response = message.finish()?;
+        self.cache_response(&cache_body, &serde_json::to_string(&response)?)?;
+
+        debug!(
+            "API request successful: {} input tokens, {} output tokens",
+            response.usage.input_tokens, response.usage.output_tokens
+        );
+        Ok(response)
+    }
+
+    /// Send a request, returning the successful response with its body unread.
+    ///
+    /// Retry logic follows claude code's behavior: up to 10 retries, first retry instant
+    /// (unless rate-limited), then 2 minute delays with jitter.
+    fn send(&self, body: &str) -> Result<reqwest::blocking::Response> {
+        const MAX_RETRIES: u32 = 10;
+        const BASE_RETRY_DELAY: Duration = Duration::from_secs(120);
+        const MAX_JITTER: Duration = Duration::from_secs(30);
+
         // No cache hit - need API key to make the request
         if self.api_key.is_none() {
             anyhow::bail!("Cache miss and no ANTHROPIC_API_KEY set - cannot make API request");
@@ -407,7 +807,7 @@ impl Client {
 
         loop {
             debug!("Sending API request (attempt {})", attempt + 1);
-            let mut req = self.client.post(ANTHROPIC_API_URL).body(body.clone());
+            let mut req = self.client.post(ANTHROPIC_API_URL).body(body.to_string());
 
             for (name, value) in &request_headers {
                 req = req.header(*name, value);
@@ -445,19 +845,6 @@ impl Client {
             debug!("API response status: {}", status);
 
             if status.is_success() {
-                let response_text = response.text().context("Failed to read response body")?;
-
-                if let Some(ref cache) = self.cache {
-                    let cache_key = cache.compute_key(&cache_header_refs, &body);
-                    cache.put(&cache_key, &response_text)?;
-                }
-
-                let response: MessagesResponse = serde_json::from_str(&response_text)
-                    .context("Failed to parse Anthropic API response")?;
-                debug!(
-                    "API request successful: {} input tokens, {} output tokens",
-                    response.usage.input_tokens, response.usage.output_tokens
-                );
                 return Ok(response);
             }
 
@@ -499,3 +886,231 @@ impl Client {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::{formatdoc, indoc};
+
+    fn assemble(events: impl IntoIterator<Item = StreamEvent>) -> Result<MessagesResponse> {
+        let mut message = MessageAccumulator::default();
+        for event in events {
+            message.apply(&event)?;
+        }
+        message.finish()
+    }
+
+    fn assemble_stream(stream: &str) -> Result<MessagesResponse> {
+        let mut message = MessageAccumulator::default();
+        read_events(stream.as_bytes(), |event| message.apply(&event))?;
+        message.finish()
+    }
+
+    #[test]
+    fn test_stream_assembles_message() {
+        let stream = indoc! {r#"
+            event: message_start
+            data: {"type":"message_start","message":{"id":"msg_1","type":"message","role":"assistant","content":[],"model":"claude","stop_reason":null,"usage":{"input_tokens":10,"output_tokens":1}}}
+
+            event: content_block_start
+            data: {"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}
+
+            event: ping
+            data: {"type":"ping"}
+
+            event: content_block_delta
+            data: {"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Let me "}}
+
+            event: content_block_delta
+            data: {"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"check."}}
+
+            event: content_block_stop
+            data: {"type":"content_block_stop","index":0}
+
+            event: content_block_start
+            data: {"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"toolu_1","name":"bash","input":{}}}
+
+            event: content_block_delta
+            data: {"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{\"command\": "}}
+
+            event: content_block_delta
+            data: {"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"\"ls\"}"}}
+
+            event: content_block_stop
+            data: {"type":"content_block_stop","index":1}
+
+            event: message_delta
+            data: {"type":"message_delta","delta":{"stop_reason":"tool_use","stop_sequence":null},"usage":{"output_tokens":25}}
+
+            event: message_stop
+            data: {"type":"message_stop"}
+
+        "#};
+
+        let mut events = Vec::new();
+        read_events(stream.as_bytes(), |event| {
+            events.push(event);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(events.len(), 12);
+
+        let response = assemble(events).unwrap();
+        assert_eq!(response.id, "msg_1");
+        assert_eq!(response.stop_reason, StopReason::ToolUse);
+        assert_eq!(response.usage.input_tokens, 10);
+        assert_eq!(response.usage.output_tokens, 25);
+        match &response.content[..] {
+            [ContentBlock::Text { text, .. }, ContentBlock::ToolUse { name, input, .. }] => {
+                assert_eq!(text, "Let me check.");
+                assert_eq!(name, "bash");
+                assert_eq!(input, &serde_json::json!({"command": "ls"}));
+            }
+            content => panic!("Unexpected content: {:?}", content),
+        }
+    }
+
+    #[test]
+    fn test_stream_thinking() {
+        let stream = indoc! {r#"
+            event: message_start
+            data: {"type":"message_start","message":{"id":"msg_1","type":"message","role":"assistant","content":[],"model":"claude","stop_reason":null,"usage":{"input_tokens":10,"output_tokens":1}}}
+
+            event: content_block_start
+            data: {"type":"content_block_start","index":0,"content_block":{"type":"thinking","thinking":""}}
+
+            event: content_block_delta
+            data: {"type":"content_block_delta","index":0,"delta":{"type":"thinking_delta","thinking":"The user wants "}}
+
+            event: content_block_delta
+            data: {"type":"content_block_delta","index":0,"delta":{"type":"thinking_delta","thinking":"a greeting."}}
+
+            event: content_block_delta
+            data: {"type":"content_block_delta","index":0,"delta":{"type":"signature_delta","signature":"EqQBCgIYAhIM"}}
+
+            event: content_block_stop
+            data: {"type":"content_block_stop","index":0}
+
+            event: content_block_start
+            data: {"type":"content_block_start","index":1,"content_block":{"type":"redacted_thinking","data":"EmwKAhgBEgy"}}
+
+            event: content_block_stop
+            data: {"type":"content_block_stop","index":1}
+
+            event: content_block_start
+            data: {"type":"content_block_start","index":2,"content_block":{"type":"text","text":""}}
+
+            event: content_block_delta
+            data: {"type":"content_block_delta","index":2,"delta":{"type":"text_delta","text":"Hello!"}}
+
+            event: content_block_stop
+            data: {"type":"content_block_stop","index":2}
+
+            event: message_delta
+            data: {"type":"message_delta","delta":{"stop_reason":"end_turn","stop_sequence":null},"usage":{"output_tokens":30}}
+
+            event: message_stop
+            data: {"type":"message_stop"}
+
+        "#};
+
+        let response = assemble_stream(stream).unwrap();
+        match &response.content[..] {
+            [ContentBlock::Thinking {
+                thinking,
+                signature,
+            }, ContentBlock::RedactedThinking { data }, ContentBlock::Text { text, .. }] => {
+                assert_eq!(thinking, "The user wants a greeting.");
+                assert_eq!(signature, "EqQBCgIYAhIM");
+                assert_eq!(data, "EmwKAhgBEgy");
+                assert_eq!(text, "Hello!");
+            }
+            content => panic!("Unexpected content: {:?}", content),
+        }
+
+        // Sent back as received
+        let message = serde_json::to_value(&response.content[0]).unwrap();
+        assert_eq!(
+            message,
+            serde_json::json!({
+                "type": "thinking",
+                "thinking": "The user wants a greeting.",
+                "signature": "EqQBCgIYAhIM",
+            })
+        );
+    }
+
+    #[test]
+    fn test_stream_error_event() {
+        let stream = indoc! {r#"
+            event: error
+            data: {"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}
+
+        "#};
+        let mut message = MessageAccumulator::default();
+        let err = read_events(stream.as_bytes(), |event| message.apply(&event)).unwrap_err();
+        assert!(err.to_string().contains("Overloaded"), "{}", err);
+    }
+
+    #[test]
+    fn test_stream_truncated_tool_use() {
+        let stream = |stop_reason: &str| {
+            formatdoc! {r#"
+                event: message_start
+                data: {{"type":"message_start","message":{{"id":"msg_1","type":"message","role":"assistant","content":[],"model":"claude","stop_reason":null,"usage":{{"input_tokens":10,"output_tokens":1}}}}}}
+
+                event: content_block_start
+                data: {{"type":"content_block_start","index":0,"content_block":{{"type":"tool_use","id":"toolu_1","name":"write","input":{{}}}}}}
+
+                event: content_block_delta
+                data: {{"type":"content_block_delta","index":0,"delta":{{"type":"input_json_delta","partial_json":"{{\"file_path\": \"a\", \"cont"}}}}
+
+                event: content_block_stop
+                data: {{"type":"content_block_stop","index":0}}
+
+                event: message_delta
+                data: {{"type":"message_delta","delta":{{"stop_reason":"{stop_reason}","stop_sequence":null}},"usage":{{"output_tokens":16}}}}
+
+                event: message_stop
+                data: {{"type":"message_stop"}}
+
+            "#}
+        };
+
+        // Cut off by max_tokens, the tool use is kept with empty input
+        let response = assemble_stream(&stream("max_tokens")).unwrap();
+        assert_eq!(response.stop_reason, StopReason::MaxTokens);
+        match response.content.as_slice() {
+            [ContentBlock::ToolUse { input, .. }] => assert_eq!(input, &serde_json::json!({})),
+            content => panic!("Unexpected content: {:?}", content),
+        }
+
+        // Otherwise the input is invalid
+        let err = assemble_stream(&stream("tool_use")).unwrap_err();
+        assert!(err.to_string().contains("Invalid tool input"), "{}", err);
+    }
+
+    #[test]
+    fn test_replay_events_reassemble() {
+        let response: MessagesResponse = serde_json::from_value(serde_json::json!({
+            "id": "msg_2",
+            "type": "message",
+            "role": "assistant",
+            "content": [
+                {"type": "thinking", "thinking": "Write it.", "signature": "sig"},
+                {"type": "text", "text": "Done."},
+                {"type": "tool_use", "id": "toolu_2", "name": "write", "input": {"file_path": "a"}},
+            ],
+            "model": "claude",
+            "stop_reason": "end_turn",
+            "usage": {"input_tokens": 3, "output_tokens": 4},
+        }))
+        .unwrap();
+
+        let replayed = assemble(replay_events(&response)).unwrap();
+        assert_eq!(
+            serde_json::to_value(&replayed).unwrap(),
+            serde_json::to_value(&response).unwrap()
+        );
+    }
+}
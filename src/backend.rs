@@ -0,0 +1,286 @@
+//! Container backends: the engines sandboxes run on.
+//!
+//! All container, image and volume operations go through [`ContainerBackend`], so the
+//! rest of the code doesn't depend on how the engine is reached. The backend is
+//! selected with the `backend` config key (see [`Backend`]).
+
+use anyhow::{bail, Context, Result};
+#[cfg(any(test, feature = "fake-backend"))]
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+use crate::config::{Backend, Runtime};
+use crate::docker::DockerCli;
+use crate::docker_api::DockerApi;
+#[cfg(any(test, feature = "fake-backend"))]
+use crate::fake_backend::FakeBackend;
+use crate::rootless::RootlessBackend;
+
+/// An image built for sandboxes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageSummary {
+    /// Full image ID (`sha256:...`).
+    pub id: String,
+    /// `repository:tag` reference.
+    pub reference: String,
+    pub created_at: String,
+    pub size: String,
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Identity of a local image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageInfo {
+    /// Full image ID (`sha256:...`).
+    pub id: String,
+    /// `name@sha256:...` digests of the image in registries it was pulled from.
+    pub repo_digests: Vec<String>,
+}
+
+/// Inputs of an image build.
+#[derive(Debug, Clone)]
+pub struct BuildRequest {
+    pub dockerfile: PathBuf,
+    pub context: PathBuf,
+    pub tag: String,
+    pub args: BTreeMap<String, String>,
+    pub target: Option<String>,
+    pub platform: Option<String>,
+    /// Don't use the layer cache.
+    pub no_cache: bool,
+    /// Labels set on the image.
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Where a mount's content comes from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MountSource {
+    /// A host path bind-mounted into the container.
+    Bind { path: PathBuf, readonly: bool },
+    /// An overlayfs over a host directory, with writes going to `upper`.
+    /// Backends that need a named volume for this use `volume_name`.
+    Overlay {
+        lower: PathBuf,
+        upper: PathBuf,
+        work: PathBuf,
+        volume_name: String,
+    },
+}
+
+/// A mount in a container.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MountSpec {
+    pub source: MountSource,
+    pub target: PathBuf,
+}
+
+/// Everything needed to start a sandbox container.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContainerSpec {
+    pub name: String,
+    pub hostname: String,
+    pub image: String,
+    pub labels: BTreeMap<String, String>,
+    /// OCI runtime name (e.g. `runsc`).
+    pub runtime: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub workdir: PathBuf,
+    pub mounts: Vec<MountSpec>,
+    pub env: Vec<(String, String)>,
+    pub command: Vec<String>,
+}
+
+/// State of a container.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContainerState {
+    Missing,
+    Stopped,
+    Running,
+}
+
+/// Options for running a command in a container.
+#[derive(Debug, Clone, Default)]
+pub struct ExecOptions {
+    /// User to run as (default: the container's user).
+    pub user: Option<String>,
+    pub env: Vec<(String, String)>,
+    /// Data written to the command's stdin, which is closed afterwards.
+    /// Without it, stdin is empty.
+    pub stdin: Option<Vec<u8>>,
+}
+
+/// Result of a command run in a container.
+#[derive(Debug, Clone, Default)]
+pub struct ExecOutput {
+    /// Exit code, or `None` if the command was killed by a signal.
+    pub exit_code: Option<i32>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+impl ExecOutput {
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+}
+
+/// Operations on containers, images and volumes.
+pub trait ContainerBackend: Send + Sync {
+    /// Whether an image with this reference exists locally.
+    fn image_exists(&self, reference: &str) -> Result<bool> {
+        Ok(self.inspect_image(reference)?.is_some())
+    }
+
+    /// ID and digests of a local image, or `None` if it doesn't exist.
+    fn inspect_image(&self, reference: &str) -> Result<Option<ImageInfo>>;
+
+    /// All tags of the given repository, newest first.
+    fn list_images(&self, repository: &str) -> Result<Vec<ImageSummary>>;
+
+    /// Remove an image by reference.
+    fn remove_image(&self, reference: &str) -> Result<()>;
+
+    /// Build an image, passing each line of build output to `output`.
+    fn build_image(&self, request: &BuildRequest, output: &mut dyn FnMut(&str)) -> Result<()>;
+
+    /// Extract the filesystem of an image into the existing directory `dest`.
+    fn export_image(&self, reference: &str, dest: &Path) -> Result<()>;
+
+    /// Create and start a detached container.
+    fn run_container(&self, spec: &ContainerSpec) -> Result<()>;
+
+    fn container_state(&self, name: &str) -> Result<ContainerState>;
+
+    /// Check if a container with the given name exists (running or stopped).
+    fn container_exists(&self, name: &str) -> Result<bool> {
+        Ok(self.container_state(name)? != ContainerState::Missing)
+    }
+
+    /// Check if a container with the given name exists and is running.
+    fn container_is_running(&self, name: &str) -> Result<bool> {
+        Ok(self.container_state(name)? == ContainerState::Running)
+    }
+
+    /// Stop a running container. Silently succeeds if it is already stopped.
+    fn stop_container(&self, name: &str) -> Result<()>;
+
+    /// Remove a container, stopping it first if needed.
+    fn remove_container(&self, name: &str) -> Result<()>;
+
+    /// Wait for a container to stop.
+    fn wait_container(&self, name: &str) -> Result<()>;
+
+    /// Full IDs of the images used by all existing containers.
+    fn container_image_ids(&self) -> Result<Vec<String>>;
+
+    /// Run a command in a running container and capture its output.
+    fn exec(&self, container: &str, command: &[&str], options: &ExecOptions) -> Result<ExecOutput>;
+
+    /// Run a command in a running container attached to the terminal (with a TTY
+    /// if stdin is one). Returns the exit code.
+    fn exec_interactive(
+        &self,
+        container: &str,
+        command: &[&str],
+        env: &[(String, String)],
+    ) -> Result<Option<i32>>;
+
+    /// Names of the volumes whose name starts with `prefix`.
+    fn list_volumes(&self, prefix: &str) -> Result<Vec<String>>;
+
+    fn remove_volume(&self, name: &str) -> Result<()>;
+}
+
+/// Create the backend of the given kind, or the fake backend if tests selected it
+/// (only in builds with the `fake-backend` feature, see `fake_backend`).
+pub fn create(kind: Backend) -> Result<Arc<dyn ContainerBackend>> {
+    #[cfg(any(test, feature = "fake-backend"))]
+    if let Some(fake) = FakeBackend::from_env() {
+        warn!("Using the fake backend: sandboxes are host processes without isolation");
+        return Ok(Arc::new(fake));
+    }
+    Ok(match kind {
+        Backend::Docker => Arc::new(DockerCli::docker()),
+        Backend::Podman => Arc::new(DockerCli::podman()),
+        Backend::DockerApi => Arc::new(DockerApi::new(DockerApi::default_socket()?)?),
+    })
+}
+
+/// Create the backend running containers with `runtime`. The rootless runtime runs
+/// containers itself and only uses the backend of the given kind for images.
+pub fn create_for_runtime(kind: Backend, runtime: Runtime) -> Result<Arc<dyn ContainerBackend>> {
+    let engine = create(kind)?;
+    Ok(match runtime {
+        Runtime::Rootless => Arc::new(RootlessBackend::new(engine)?),
+        _ => engine,
+    })
+}
+
+/// Unpack an exported container filesystem (a tar stream) into `dest`.
+///
+/// Device nodes can't be created without privileges, so `/dev` is skipped; containers
+/// that need it get the host's bind-mounted. Files are owned by the current user.
+pub fn unpack_rootfs(mut tar: impl Read, dest: &Path) -> Result<()> {
+    let mut child = Command::new("tar")
+        .args(["-x", "--no-same-owner", "--exclude=dev/*", "-f", "-", "-C"])
+        .arg(dest)
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run tar")?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let copied = std::io::copy(&mut tar, &mut stdin);
+    drop(stdin);
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!(
+            "Failed to unpack image filesystem: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    copied.context("Failed to read image filesystem")?;
+    Ok(())
+}
+
+/// A container on a backend, for running commands in it.
+#[derive(Clone, Copy)]
+pub struct Container<'a> {
+    pub backend: &'a dyn ContainerBackend,
+    pub name: &'a str,
+}
+
+impl<'a> Container<'a> {
+    pub fn new(backend: &'a dyn ContainerBackend, name: &'a str) -> Self {
+        Container { backend, name }
+    }
+
+    /// Run a command with empty stdin and capture its output.
+    pub fn exec(&self, command: &[&str]) -> Result<ExecOutput> {
+        self.backend
+            .exec(self.name, command, &ExecOptions::default())
+    }
+
+    /// Run a command with the given data on stdin and capture its output.
+    pub fn exec_with_stdin(&self, command: &[&str], stdin: &[u8]) -> Result<ExecOutput> {
+        let options = ExecOptions {
+            stdin: Some(stdin.to_vec()),
+            ..Default::default()
+        };
+        self.backend.exec(self.name, command, &options)
+    }
+
+    /// Run a command attached to the terminal. Fails on a non-zero exit code.
+    pub fn exec_interactive(&self, command: &[&str], env: &[(String, String)]) -> Result<()> {
+        let exit_code = self.backend.exec_interactive(self.name, command, env)?;
+        if exit_code != Some(0) {
+            bail!("Container exec failed");
+        }
+        Ok(())
+    }
+}
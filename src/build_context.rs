@@ -0,0 +1,566 @@
+//! Content hashing of Docker build contexts, used to decide when an image needs rebuilding.
+//!
+//! Only the files the Dockerfile's `COPY` and `ADD` instructions read are hashed, so
+//! that editing other files of the context (typically the repository root) doesn't
+//! trigger a rebuild. Files excluded by the context's `.dockerignore` are skipped, as
+//! docker itself would not send them to the builder. Version control metadata (like
+//! `.git`) is skipped as well: it changes with every commit and fetch. To avoid
+//! re-reading large contexts on every run, file hashes are cached in
+//! `$XDG_CACHE_HOME/sandbox/context-hashes`, keyed by size and mtime.
+
+use anyhow::{Context, Result};
+use glob::{MatchOptions, Pattern};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::config::{get_cache_dir, hash_path};
+
+/// Cached hash of a single file, valid as long as size and mtime are unchanged.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+struct CachedFileHash {
+    size: u64,
+    mtime_ns: i128,
+    hash: String,
+}
+
+type HashCache = BTreeMap<String, CachedFileHash>;
+
+/// Version control metadata, left out of the hash at any depth.
+const VCS_DIRS: &[&str] = &[".git", ".hg", ".jj", ".svn"];
+
+/// A parsed `.dockerignore` file.
+struct DockerIgnore {
+    matcher: Gitignore,
+    /// Patterns re-including (`!`) paths, anchored at the context root.
+    whitelists: Vec<String>,
+}
+
+impl DockerIgnore {
+    /// Whether the path is excluded: the last pattern matching it or one of its
+    /// parents decides.
+    fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        self.matcher
+            .matched_path_or_any_parents(path, is_dir)
+            .is_ignore()
+    }
+
+    /// Whether a file below the excluded directory may be re-included.
+    fn may_reinclude_below(&self, dir: &Path) -> bool {
+        self.whitelists
+            .iter()
+            .any(|pattern| may_match_below(pattern, dir))
+    }
+}
+
+/// Options for matching patterns anchored at the context root: `*` doesn't cross `/`.
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Whether a pattern anchored at the context root may match `dir` (relative to the
+/// context root) or a path below it. Errs on the side of yes: only the components
+/// before the first `**` are compared.
+fn may_match_below(pattern: &str, dir: &Path) -> bool {
+    for (pattern, component) in pattern.split('/').zip(dir.components()) {
+        if pattern == "**" {
+            return true;
+        }
+        let component = component.as_os_str().to_string_lossy();
+        if !Pattern::new(pattern).map_or(true, |p| p.matches_with(&component, MATCH_OPTIONS)) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Whether a pattern anchored at the context root matches `path` (relative to the
+/// context root) or one of its parents.
+fn matches_path_or_parents(pattern: &Pattern, path: &Path) -> bool {
+    path.ancestors()
+        .filter(|p| !p.as_os_str().is_empty())
+        .any(|p| pattern.matches_path_with(p, MATCH_OPTIONS))
+}
+
+/// Parse a `.dockerignore` file.
+///
+/// Docker anchors patterns at the context root (unlike `.gitignore`, where a pattern
+/// without a slash matches at any depth), so every pattern is anchored here as well.
+fn load_dockerignore(context: &Path) -> Result<DockerIgnore> {
+    let mut builder = GitignoreBuilder::new(context);
+    let mut whitelists = Vec::new();
+    let path = context.join(".dockerignore");
+
+    if path.exists() {
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (negate, pattern) = match line.strip_prefix('!') {
+                Some(p) => ("!", p.trim()),
+                None => ("", line),
+            };
+            let pattern = pattern.trim_start_matches("./").trim_start_matches('/');
+            builder
+                .add_line(None, &format!("{}/{}", negate, pattern))
+                .with_context(|| format!("Invalid pattern in {}: {}", path.display(), line))?;
+            if !negate.is_empty() {
+                whitelists.push(pattern.trim_end_matches('/').to_string());
+            }
+        }
+    }
+
+    let matcher = builder
+        .build()
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    Ok(DockerIgnore {
+        matcher,
+        whitelists,
+    })
+}
+
+/// Context paths a Dockerfile reads: the sources of its `COPY` and `ADD` instructions,
+/// as patterns anchored at the context root. `None` if it may read any file of the
+/// context, or it can't tell: when a source is the whole context or given by a
+/// variable, a `RUN` bind-mounts the context, or the Dockerfile uses heredocs or
+/// another escape character.
+pub fn dockerfile_sources(dockerfile: &Path) -> Result<Option<Vec<String>>> {
+    let contents = std::fs::read_to_string(dockerfile)
+        .with_context(|| format!("Failed to read {}", dockerfile.display()))?;
+
+    let mut sources = Vec::new();
+    let mut instruction = String::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.starts_with('#') {
+            let directive: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+            if directive.to_ascii_lowercase().starts_with("#escape=") {
+                return Ok(None);
+            }
+            continue;
+        }
+        if let Some(continued) = line.strip_suffix('\\') {
+            instruction.push_str(continued);
+            instruction.push(' ');
+            continue;
+        }
+        instruction.push_str(line);
+        if !add_instruction_sources(&std::mem::take(&mut instruction), &mut sources) {
+            return Ok(None);
+        }
+    }
+    if !add_instruction_sources(&instruction, &mut sources) {
+        return Ok(None);
+    }
+    Ok(Some(sources))
+}
+
+/// Add the context paths an instruction reads to `sources`. Returns false if it may
+/// read any file of the context.
+fn add_instruction_sources(instruction: &str, sources: &mut Vec<String>) -> bool {
+    if instruction.contains("<<") {
+        return false;
+    }
+    let mut words = instruction.split_whitespace().peekable();
+    let Some(keyword) = words.next() else {
+        return true;
+    };
+    let keyword = keyword.to_ascii_uppercase();
+    let mut flags = Vec::new();
+    while let Some(flag) = words.next_if(|w| w.starts_with("--")) {
+        flags.push(flag);
+    }
+
+    match keyword.as_str() {
+        "RUN" => return !flags.iter().any(|flag| mounts_context(flag)),
+        "COPY" | "ADD" => {}
+        _ => return true,
+    }
+    // Copies from another stage or image don't read the context
+    if flags.iter().any(|flag| flag.starts_with("--from=")) {
+        return true;
+    }
+
+    let args = words.collect::<Vec<_>>().join(" ");
+    let args = if args.starts_with('[') {
+        match serde_json::from_str::<Vec<String>>(&args) {
+            Ok(args) => args,
+            Err(_) => return false,
+        }
+    } else {
+        args.split_whitespace().map(String::from).collect()
+    };
+    let Some((_destination, args)) = args.split_last() else {
+        return true;
+    };
+    for source in args {
+        if keyword == "ADD" && (source.contains("://") || source.starts_with("git@")) {
+            continue;
+        }
+        let source = source
+            .trim_start_matches("./")
+            .trim_start_matches('/')
+            .trim_end_matches('/');
+        if source.is_empty() || source == "." || source.contains('$') {
+            return false;
+        }
+        sources.push(source.to_string());
+    }
+    true
+}
+
+/// Whether a `RUN` flag bind-mounts the build context.
+fn mounts_context(flag: &str) -> bool {
+    let Some(options) = flag.strip_prefix("--mount=") else {
+        return false;
+    };
+    let options: Vec<&str> = options.split(',').collect();
+    let mount_type = options
+        .iter()
+        .find_map(|o| o.strip_prefix("type="))
+        .unwrap_or("bind");
+    mount_type == "bind" && !options.iter().any(|o| o.starts_with("from="))
+}
+
+/// Collect the files in the context that docker would send to the builder, as paths
+/// relative to the context root, in sorted order. With `skip_vcs`, version control
+/// metadata is left out as well. With `sources`, only files matching one of those
+/// patterns (or below a directory matching one) are collected.
+fn collect_context_files(
+    context: &Path,
+    ignore: &DockerIgnore,
+    skip_vcs: bool,
+    sources: Option<&[Pattern]>,
+) -> Result<Vec<PathBuf>> {
+    fn walk(
+        dir: &Path,
+        context: &Path,
+        ignore: &DockerIgnore,
+        skip_vcs: bool,
+        sources: Option<&[Pattern]>,
+        out: &mut Vec<PathBuf>,
+    ) -> Result<()> {
+        let mut entries = std::fs::read_dir(dir)
+            .with_context(|| format!("Failed to read directory: {}", dir.display()))?
+            .collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.file_name());
+
+        for entry in entries {
+            let path = entry.path();
+            if skip_vcs && VCS_DIRS.iter().any(|name| entry.file_name() == *name) {
+                continue;
+            }
+            let relative = path.strip_prefix(context)?;
+            let is_dir = entry.file_type()?.is_dir();
+            let ignored = ignore.is_excluded(&path, is_dir);
+            if is_dir {
+                // Excluded directories may still contain re-included (`!`) files
+                let walked = !ignored || ignore.may_reinclude_below(relative);
+                let selected = sources.is_none_or(|sources| {
+                    sources
+                        .iter()
+                        .any(|source| may_match_below(source.as_str(), relative))
+                });
+                if walked && selected {
+                    walk(&path, context, ignore, skip_vcs, sources, out)?;
+                }
+            } else if !ignored
+                && sources.is_none_or(|sources| {
+                    sources
+                        .iter()
+                        .any(|source| matches_path_or_parents(source, relative))
+                })
+            {
+                out.push(relative.to_path_buf());
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = Vec::new();
+    walk(context, context, ignore, skip_vcs, sources, &mut files)?;
+    Ok(files)
+}
+
+/// Files of the context that are sent to the builder (i.e. not excluded by
+/// `.dockerignore`), as paths relative to the context root, in sorted order.
+pub fn context_files(context: &Path) -> Result<Vec<PathBuf>> {
+    let ignore = load_dockerignore(context)?;
+    collect_context_files(context, &ignore, false, None)
+}
+
+fn cache_path(context: &Path) -> Result<PathBuf> {
+    Ok(get_cache_dir()?
+        .join("context-hashes")
+        .join(format!("{}.json", hash_path(context))))
+}
+
+fn load_cache(path: &Path) -> HashCache {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+fn save_cache(path: &Path, cache: &HashCache) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_json::to_string(cache)?)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Hash a single context entry: file contents, or the link target for symlinks.
+fn hash_entry(path: &Path, metadata: &std::fs::Metadata) -> Result<String> {
+    let mut hasher = Sha256::new();
+    if metadata.is_symlink() {
+        let target = std::fs::read_link(path)?;
+        hasher.update(target.to_string_lossy().as_bytes());
+    } else {
+        let contents = std::fs::read(path)
+            .with_context(|| format!("Failed to read file: {}", path.display()))?;
+        hasher.update(&contents);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Compute a hash over the part of the build context the Dockerfile reads: the
+/// relative path, permissions and content of every file its `COPY` and `ADD`
+/// instructions read (or, if that can't be told, of every file), except for files
+/// excluded by `.dockerignore` and version control metadata.
+pub fn hash_build_context(context: &Path, dockerfile: &Path) -> Result<String> {
+    let ignore = load_dockerignore(context)?;
+    // Sources that aren't valid patterns make the whole context count
+    let sources = dockerfile_sources(dockerfile)?.and_then(|sources| {
+        sources
+            .iter()
+            .map(|source| Pattern::new(source))
+            .collect::<Result<Vec<_>, _>>()
+            .ok()
+    });
+    let files = collect_context_files(context, &ignore, true, sources.as_deref())?;
+
+    let cache_file = cache_path(context)?;
+    let old_cache = load_cache(&cache_file);
+    let mut new_cache = HashCache::new();
+
+    let mut hasher = Sha256::new();
+    for relative in &files {
+        let path = context.join(relative);
+        let metadata = std::fs::symlink_metadata(&path)
+            .with_context(|| format!("Failed to stat {}", path.display()))?;
+        let key = relative.to_string_lossy().into_owned();
+        let mtime_ns = metadata.mtime() as i128 * 1_000_000_000 + metadata.mtime_nsec() as i128;
+
+        let hash = match old_cache.get(&key) {
+            Some(cached) if cached.size == metadata.len() && cached.mtime_ns == mtime_ns => {
+                cached.hash.clone()
+            }
+            _ => hash_entry(&path, &metadata)?,
+        };
+
+        hasher.update(key.as_bytes());
+        hasher.update(b"\0");
+        hasher.update(format!("{:o}", metadata.permissions().mode()).as_bytes());
+        hasher.update(b"\0");
+        hasher.update(hash.as_bytes());
+        hasher.update(b"\n");
+
+        new_cache.insert(
+            key,
+            CachedFileHash {
+                size: metadata.len(),
+                mtime_ns,
+                hash,
+            },
+        );
+    }
+
+    debug!(
+        "Hashed {} build context files in {}",
+        files.len(),
+        context.display()
+    );
+
+    if new_cache != old_cache {
+        save_cache(&cache_file, &new_cache)?;
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn context_files(dir: &Path) -> Vec<String> {
+        let ignore = load_dockerignore(dir).unwrap();
+        collect_context_files(dir, &ignore, false, None)
+            .unwrap()
+            .iter()
+            .map(|p| p.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    /// Hash a context whose Dockerfile copies all of it.
+    fn hash_whole_context(dir: &Path) -> String {
+        let dockerfile = dir.join("Dockerfile");
+        if !dockerfile.exists() {
+            fs::write(&dockerfile, "FROM scratch\nCOPY . /app\n").unwrap();
+        }
+        hash_build_context(dir, &dockerfile).unwrap()
+    }
+
+    #[test]
+    fn test_dockerignore() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir_all(dir.path().join("target/debug")).unwrap();
+        fs::create_dir_all(dir.path().join("src/target")).unwrap();
+        fs::write(dir.path().join("target/debug/bin"), "x").unwrap();
+        fs::write(dir.path().join("target/debug/keep"), "x").unwrap();
+        fs::write(dir.path().join("src/target/kept"), "x").unwrap();
+        fs::write(dir.path().join("src/main.rs"), "x").unwrap();
+        fs::write(dir.path().join("notes.md"), "x").unwrap();
+        fs::write(dir.path().join("README.md"), "x").unwrap();
+        fs::write(
+            dir.path().join(".dockerignore"),
+            "# comment\ntarget\n*.md\n!README.md\n!target/debug/keep\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            context_files(dir.path()),
+            vec![
+                ".dockerignore",
+                "README.md",
+                "src/main.rs",
+                "src/target/kept",
+                "target/debug/keep"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hash_changes_with_content() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("file.txt"), "one").unwrap();
+        fs::write(dir.path().join("ignored.txt"), "one").unwrap();
+        fs::write(dir.path().join(".dockerignore"), "ignored.txt\n").unwrap();
+
+        let hash1 = hash_whole_context(dir.path());
+        assert_eq!(hash1, hash_whole_context(dir.path()));
+
+        fs::write(dir.path().join("ignored.txt"), "two").unwrap();
+        assert_eq!(hash1, hash_whole_context(dir.path()));
+
+        fs::write(dir.path().join("file.txt"), "two!").unwrap();
+        assert_ne!(hash1, hash_whole_context(dir.path()));
+    }
+
+    #[test]
+    fn test_hash_skips_vcs_metadata() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir_all(dir.path().join(".git/refs")).unwrap();
+        fs::create_dir_all(dir.path().join("vendor/lib/.git")).unwrap();
+        fs::write(dir.path().join("file.txt"), "one").unwrap();
+
+        let hash = hash_whole_context(dir.path());
+        fs::write(dir.path().join(".git/refs/head"), "abc").unwrap();
+        fs::write(dir.path().join("vendor/lib/.git/HEAD"), "abc").unwrap();
+        assert_eq!(hash, hash_whole_context(dir.path()));
+
+        // Docker still sends them to the builder
+        assert!(context_files(dir.path()).contains(&".git/refs/head".to_string()));
+    }
+
+    #[test]
+    fn test_dockerfile_sources() {
+        let dir = TempDir::new().unwrap();
+        let dockerfile = dir.path().join("Dockerfile");
+        fs::write(
+            &dockerfile,
+            indoc! {r#"
+                FROM rust AS build
+                # COPY ignored /
+                copy --chown=1000:1000 ./Cargo.toml Cargo.lock \
+                    /src/
+                COPY ["config/app.toml", "/etc/app/"]
+                ADD https://example.com/tool.tar.gz scripts/*.sh /usr/local/bin/
+                RUN --mount=type=cache,target=/root/.cargo cargo build
+                FROM debian
+                COPY --from=build /src/target/app /usr/bin/app
+            "#},
+        )
+        .unwrap();
+        assert_eq!(
+            dockerfile_sources(&dockerfile).unwrap(),
+            Some(vec![
+                "Cargo.toml".to_string(),
+                "Cargo.lock".to_string(),
+                "config/app.toml".to_string(),
+                "scripts/*.sh".to_string(),
+            ])
+        );
+
+        // Instructions that may read any file of the context
+        for instruction in [
+            "COPY . /app",
+            "COPY ${SRC} /app",
+            "RUN --mount=target=/src make",
+            "COPY <<EOF /etc/motd",
+        ] {
+            fs::write(&dockerfile, format!("FROM debian\n{}\n", instruction)).unwrap();
+            assert_eq!(
+                dockerfile_sources(&dockerfile).unwrap(),
+                None,
+                "{}",
+                instruction
+            );
+        }
+    }
+
+    #[test]
+    fn test_hash_only_copied_files() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir_all(dir.path().join("config")).unwrap();
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("config/app.toml"), "one").unwrap();
+        fs::write(dir.path().join("src/main.rs"), "one").unwrap();
+        let dockerfile = dir.path().join("Dockerfile");
+        fs::write(&dockerfile, "FROM debian\nCOPY config /etc/app\n").unwrap();
+
+        let hash = hash_build_context(dir.path(), &dockerfile).unwrap();
+        fs::write(dir.path().join("src/main.rs"), "two").unwrap();
+        assert_eq!(hash, hash_build_context(dir.path(), &dockerfile).unwrap());
+
+        fs::write(dir.path().join("config/app.toml"), "two").unwrap();
+        assert_ne!(hash, hash_build_context(dir.path(), &dockerfile).unwrap());
+    }
+
+    #[test]
+    fn test_reinclusion_below_excluded_dirs() {
+        let dir = TempDir::new().unwrap();
+        fs::write(
+            dir.path().join(".dockerignore"),
+            "target\nnode_modules\n!target/*/keep\n",
+        )
+        .unwrap();
+        let ignore = load_dockerignore(dir.path()).unwrap();
+
+        assert!(ignore.may_reinclude_below(Path::new("target")));
+        assert!(ignore.may_reinclude_below(Path::new("target/debug")));
+        assert!(!ignore.may_reinclude_below(Path::new("node_modules")));
+    }
+}
@@ -1,17 +1,24 @@
-use anyhow::{bail, Result};
+use anyhow::Result;
 use chrono;
 use clap::{Parser, Subcommand};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use crate::agent;
-use crate::config::{Model, OverlayMode, Runtime, UserInfo};
+use crate::agent::{self, Budget, Thinking};
+use crate::agent_input::InputOptions;
+use crate::backend::{self, Container, ContainerState};
+use crate::config::{Backend, InputMode, Model, OverlayMode, Runtime, UserInfo};
 use crate::daemon;
-use crate::docker;
 use crate::git;
+use crate::hooks::{self, HostEvent, HostEventPayload};
+use crate::image;
 use crate::llm_cache::LlmCache;
 use crate::sandbox;
-use crate::sandbox_config::SandboxConfig;
+use crate::sandbox_config::{ConfigLayer, SandboxConfig};
 use crate::setup;
+use crate::transcript::Transcript;
+use crate::usage::{self, UsageLog, UsageTotals};
+use crate::userns;
 
 #[derive(Parser)]
 #[command(name = "sandbox")]
@@ -36,6 +43,10 @@ pub enum Commands {
         #[arg(short, long, value_enum)]
         overlay_mode: Option<OverlayMode>,
 
+        /// Config profile to apply (default: the profile the sandbox was created with)
+        #[arg(short, long)]
+        profile: Option<String>,
+
         /// Command to run inside the sandbox (default: interactive shell)
         #[arg(last = true)]
         command: Vec<String>,
@@ -51,6 +62,8 @@ pub enum Commands {
     },
 
     /// Run an LLM agent inside a sandbox
+    ///
+    /// Exits with status 3 when stopped by a --max-* limit.
     Agent {
         /// Name of the sandbox to use
         name: String,
@@ -63,16 +76,89 @@ pub enum Commands {
         #[arg(short, long, value_enum)]
         overlay_mode: Option<OverlayMode>,
 
+        /// Config profile to apply (default: the profile the sandbox was created with)
+        #[arg(short, long)]
+        profile: Option<String>,
+
         /// Claude model to use (overrides config file)
         #[arg(short, long, value_enum)]
         model: Option<Model>,
 
+        /// How to compose messages (overrides config file, default: editor)
+        #[arg(short, long, value_enum)]
+        input: Option<InputMode>,
+
+        /// Most output tokens per response (overrides config file, default: 4096)
+        #[arg(long)]
+        max_tokens: Option<u32>,
+
+        /// Stop after this many model responses (overrides config file)
+        #[arg(long)]
+        max_turns: Option<u32>,
+
+        /// Stop before running more than this many tool calls (overrides config file)
+        #[arg(long)]
+        max_tool_calls: Option<u32>,
+
+        /// Stop once this many input tokens are used (overrides config file)
+        #[arg(long)]
+        max_input_tokens: Option<u64>,
+
+        /// Stop once this many dollars are spent (overrides config file)
+        #[arg(long)]
+        max_cost: Option<f64>,
+
+        /// Enable extended thinking with this many output tokens, which must be less
+        /// than --max-tokens (overrides config file)
+        #[arg(long)]
+        thinking_budget: Option<u32>,
+
+        /// Print the model's thinking (overrides config file)
+        #[arg(long)]
+        show_thinking: bool,
+
+        /// Seconds after which bash commands are killed (overrides config file,
+        /// default: 120)
+        #[arg(long)]
+        bash_timeout: Option<u64>,
+
+        /// Continue the most recent session instead of starting a new one
+        #[arg(long)]
+        resume: bool,
+
+        /// Continue the session with this ID (implies --resume)
+        #[arg(long)]
+        session: Option<String>,
+
         /// LLM response cache directory for deterministic testing.
         /// See llm-cache/README.md for documentation.
         #[arg(long, hide = true)]
         cache: Option<PathBuf>,
     },
 
+    /// Inspect and validate the sandbox configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+
+    /// Manage the sandbox Docker image
+    Image {
+        #[command(subcommand)]
+        command: ImageCommands,
+    },
+
+    /// Show token usage and cost of agent sessions, by day and sandbox
+    Usage {
+        /// Include the sandboxes of all repositories
+        #[arg(long)]
+        all: bool,
+
+        /// Only count the last N days, including today
+        #[arg(long)]
+        days: Option<u32>,
+    },
+
     /// Run the sandbox daemon (manages sandboxes across all projects)
     Daemon,
 
@@ -81,6 +167,60 @@ pub enum Commands {
 
     /// Uninstall the sandbox daemon from systemd
     SystemUninstall,
+
+    /// Init process of a user namespace container (internal)
+    #[command(hide = true)]
+    UsernsInit { dir: PathBuf },
+
+    /// Exec session in a user namespace container (internal)
+    #[command(hide = true)]
+    UsernsExec {
+        dir: PathBuf,
+
+        #[arg(long)]
+        user: Option<String>,
+
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommands {
+    /// Validate .sandbox.toml, including that all mount sources exist
+    Check {
+        /// Config profile to validate (default: the base config)
+        #[arg(short, long)]
+        profile: Option<String>,
+    },
+
+    /// Print the effective configuration and which file each value came from
+    Show,
+}
+
+#[derive(Subcommand)]
+pub enum ImageCommands {
+    /// Rebuild the image from its Dockerfile, ignoring existing images and layer cache
+    Rebuild {
+        /// Config profile whose image to rebuild (default: the base config)
+        #[arg(short, long)]
+        profile: Option<String>,
+    },
+
+    /// Remove sandbox images no longer used by any repo config or container
+    Prune {
+        /// Keep this many of the most recent unused images
+        #[arg(short, long, default_value_t = 0)]
+        keep: usize,
+
+        /// Only show what would be removed
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+
+        /// Backend whose images are pruned
+        #[arg(long, value_enum, default_value_t)]
+        backend: Backend,
+    },
 }
 
 fn init_logging(_command: &Commands) -> Result<()> {
@@ -102,16 +242,23 @@ pub fn run() -> Result<()> {
         Commands::SystemUninstall => {
             setup::system_uninstall()?;
         }
+        Commands::UsernsInit { dir } => {
+            userns::run_init(&dir)?;
+        }
+        Commands::UsernsExec { dir, user, command } => {
+            userns::run_exec(&dir, user.as_deref(), &command)?;
+        }
         Commands::Enter {
             name,
             runtime,
             overlay_mode,
+            profile,
             command,
         } => {
             let repo_root = git::find_repo_root()?;
             let user_info = UserInfo::current()?;
-            let sandbox_config = SandboxConfig::load(&repo_root)?;
-            let env_vars = sandbox_config.resolve_env_vars()?;
+            let sandbox_config = load_config(&repo_root, &name, profile)?;
+            let env_vars = sandbox_config.resolve_env_vars(&repo_root)?;
             // CLI flags override config file values
             let runtime = runtime.or(sandbox_config.runtime).unwrap_or_default();
             let overlay_mode = overlay_mode
@@ -132,21 +279,62 @@ pub fn run() -> Result<()> {
             let repo_root = git::find_repo_root()?;
             list_sandboxes(&repo_root)?;
         }
+        Commands::Usage { all, days } => show_usage(all, days)?,
         Commands::Delete { name } => {
             let repo_root = git::find_repo_root()?;
             delete_sandbox(&repo_root, &name)?;
         }
+        Commands::Config { command } => {
+            let repo_root = git::find_repo_root()?;
+            match command {
+                ConfigCommands::Check { profile } => check_config(&repo_root, profile.as_deref())?,
+                ConfigCommands::Show => show_config(&repo_root)?,
+            }
+        }
+        Commands::Image { command } => match command {
+            ImageCommands::Rebuild { profile } => {
+                let repo_root = git::find_repo_root()?;
+                let config = SandboxConfig::load(&repo_root)?.with_profile(profile.as_deref())?;
+                let user_info = UserInfo::current()?;
+                let backend = backend::create(config.backend.unwrap_or_default())?;
+                let tag = image::resolve_image_tag(
+                    backend.as_ref(),
+                    &repo_root,
+                    &config,
+                    &user_info,
+                    true,
+                )?;
+                println!("Built image {}", tag);
+            }
+            ImageCommands::Prune {
+                keep,
+                dry_run,
+                backend,
+            } => prune_images(backend, keep, dry_run)?,
+        },
         Commands::Agent {
             name,
             runtime,
             overlay_mode,
+            profile,
             model,
+            input,
+            max_tokens,
+            max_turns,
+            max_tool_calls,
+            max_input_tokens,
+            max_cost,
+            thinking_budget,
+            show_thinking,
+            bash_timeout,
+            resume,
+            session,
             cache,
         } => {
             let repo_root = git::find_repo_root()?;
             let user_info = UserInfo::current()?;
-            let sandbox_config = SandboxConfig::load(&repo_root)?;
-            let env_vars = sandbox_config.resolve_env_vars()?;
+            let sandbox_config = load_config(&repo_root, &name, profile)?;
+            let env_vars = sandbox_config.resolve_env_vars(&repo_root)?;
             let llm_cache = cache
                 .map(|dir| LlmCache::new(&dir, "anthropic"))
                 .transpose()?;
@@ -156,6 +344,48 @@ pub fn run() -> Result<()> {
                 .or(sandbox_config.overlay_mode)
                 .unwrap_or_default();
             let model = model.or(sandbox_config.agent.model).unwrap_or_default();
+            let input_mode = input.or(sandbox_config.agent.input).unwrap_or_default();
+            let max_tokens = max_tokens
+                .or(sandbox_config.agent.max_tokens.get(&model).copied())
+                .unwrap_or(agent::DEFAULT_MAX_TOKENS);
+            if max_tokens > model.max_output_tokens() {
+                anyhow::bail!(
+                    "max-tokens {} exceeds the limit of {} for {}",
+                    max_tokens,
+                    model.max_output_tokens(),
+                    model.api_model_id()
+                );
+            }
+            let budget = Budget {
+                max_turns: max_turns.or(sandbox_config.agent.max_turns),
+                max_tool_calls: max_tool_calls.or(sandbox_config.agent.max_tool_calls),
+                max_input_tokens: max_input_tokens.or(sandbox_config.agent.max_input_tokens),
+                max_cost: max_cost.or(sandbox_config.agent.max_cost),
+            };
+            let thinking = Thinking {
+                budget_tokens: thinking_budget.or(sandbox_config.agent.thinking_budget),
+                show: show_thinking || sandbox_config.agent.show_thinking.unwrap_or(false),
+            };
+            if let Some(budget_tokens) = thinking.budget_tokens {
+                if budget_tokens < agent::MIN_THINKING_BUDGET || budget_tokens >= max_tokens {
+                    anyhow::bail!(
+                        "thinking-budget {} must be at least {} and less than max-tokens {}",
+                        budget_tokens,
+                        agent::MIN_THINKING_BUDGET,
+                        max_tokens
+                    );
+                }
+            }
+            let bash_timeout = Duration::from_secs(
+                bash_timeout
+                    .or(sandbox_config.agent.bash_timeout)
+                    .unwrap_or(agent::DEFAULT_BASH_TIMEOUT_SECS),
+            );
+            let session = match session {
+                Some(id) => AgentSession::Resume(Some(id)),
+                None if resume => AgentSession::Resume(None),
+                None => AgentSession::New,
+            };
             run_agent(
                 &repo_root,
                 &sandbox_config,
@@ -164,8 +394,14 @@ pub fn run() -> Result<()> {
                 runtime,
                 overlay_mode,
                 model,
+                max_tokens,
+                input_mode,
+                budget,
+                thinking,
+                bash_timeout,
                 &env_vars,
                 llm_cache,
+                session,
             )?;
         }
     }
@@ -173,45 +409,14 @@ pub fn run() -> Result<()> {
     Ok(())
 }
 
-/// Resolve the Docker image tag from config, building if necessary.
-fn resolve_image_tag(
-    repo_root: &Path,
-    config: &SandboxConfig,
-    user_info: &UserInfo,
-) -> Result<String> {
-    use crate::sandbox_config::ImageConfig;
-
-    match &config.image {
-        Some(ImageConfig::Tag(tag)) => Ok(tag.clone()),
-        Some(ImageConfig::Build {
-            dockerfile,
-            context,
-        }) => {
-            let dockerfile_path = repo_root.join(dockerfile);
-            if !dockerfile_path.exists() {
-                bail!("Dockerfile not found at {}", dockerfile_path.display());
-            }
-            let context_path = context
-                .as_ref()
-                .map(|p| repo_root.join(p))
-                .unwrap_or_else(|| repo_root.to_path_buf());
-            docker::build_image(&dockerfile_path, &context_path, user_info)
-        }
-        None => {
-            // Default: look for Dockerfile in repo root
-            let dockerfile_path = repo_root.join("Dockerfile");
-            if !dockerfile_path.exists() {
-                bail!(
-                    "No Dockerfile found at {}.\n\
-                     Either create a Dockerfile or specify an image in .sandbox.toml:\n\n\
-                     [image]\n\
-                     tag = \"your-image:tag\"\n",
-                    dockerfile_path.display()
-                );
-            }
-            docker::build_image(&dockerfile_path, repo_root, user_info)
-        }
-    }
+/// Load the sandbox config with a profile applied. Without an explicit profile,
+/// an existing sandbox keeps using the profile it was created with.
+fn load_config(repo_root: &Path, name: &str, profile: Option<String>) -> Result<SandboxConfig> {
+    let profile = match profile {
+        Some(p) => Some(p),
+        None => sandbox::recorded_profile(repo_root, name)?,
+    };
+    SandboxConfig::load(repo_root)?.with_profile(profile.as_deref())
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -225,10 +430,13 @@ fn run_sandbox(
     env_vars: &[(String, String)],
     command: Vec<String>,
 ) -> Result<()> {
-    let image_tag = resolve_image_tag(repo_root, config, user_info)?;
+    check_mounts(repo_root, config, user_info)?;
+    let backend = backend::create(config.backend.unwrap_or_default())?;
+    let image_tag =
+        image::resolve_image_tag(backend.as_ref(), repo_root, config, user_info, false)?;
 
     // Ensure sandbox is set up (saves mounts config for daemon to use)
-    let info = sandbox::ensure_sandbox(repo_root, name, config)?;
+    let info = sandbox::ensure_sandbox(repo_root, name, config, user_info, runtime)?;
 
     // Run the sandbox
     let cmd = if command.is_empty() {
@@ -248,6 +456,69 @@ fn run_sandbox(
     )
 }
 
+/// Fail early if a configured mount source is missing, before building images or
+/// contacting the daemon.
+fn check_mounts(repo_root: &Path, config: &SandboxConfig, user_info: &UserInfo) -> Result<()> {
+    let mounts = sandbox::config_mounts(repo_root, user_info, config)?;
+    sandbox::check_mount_sources(&mounts)
+}
+
+fn check_config(repo_root: &Path, profile: Option<&str>) -> Result<()> {
+    let config = SandboxConfig::load(repo_root)?.with_profile(profile)?;
+    let user_info = UserInfo::current()?;
+
+    config.resolve_env_vars(repo_root)?;
+    check_mounts(repo_root, &config, &user_info)?;
+
+    println!("Configuration OK.");
+    Ok(())
+}
+
+fn prune_images(backend: Backend, keep: usize, dry_run: bool) -> Result<()> {
+    let backend = backend::create(backend)?;
+    let results = image::prune_images(backend.as_ref(), keep, dry_run)?;
+
+    if results.is_empty() {
+        println!("No sandbox images found.");
+        return Ok(());
+    }
+
+    let removed_label = if dry_run { "would remove" } else { "removed" };
+    println!("{:<45} {:<10} {:<30} STATUS", "IMAGE", "SIZE", "CREATED");
+    println!("{}", "-".repeat(100));
+    for (image, decision) in &results {
+        let status = match decision {
+            image::PruneDecision::InUse(reason) => format!("in use ({})", reason),
+            image::PruneDecision::Kept => "kept".to_string(),
+            image::PruneDecision::Removed => removed_label.to_string(),
+        };
+        println!(
+            "{:<45} {:<10} {:<30} {}",
+            image.reference, image.size, image.created_at, status
+        );
+    }
+
+    Ok(())
+}
+
+fn show_config(repo_root: &Path) -> Result<()> {
+    let layered = SandboxConfig::load_layered(repo_root)?;
+
+    for layer in ConfigLayer::ALL {
+        let path = layer.path(repo_root)?;
+        let status = if layered.layers.iter().any(|(l, _)| *l == layer) {
+            ""
+        } else {
+            " (not found)"
+        };
+        println!("# {}: {}{}", layer, path.display(), status);
+    }
+    println!();
+    print!("{}", layered.display_with_sources());
+
+    Ok(())
+}
+
 fn list_sandboxes(repo_root: &Path) -> Result<()> {
     let mut sandboxes = sandbox::list_sandboxes(repo_root)?;
 
@@ -263,12 +534,13 @@ fn list_sandboxes(repo_root: &Path) -> Result<()> {
     println!("{}", "-".repeat(55));
 
     for info in sandboxes {
-        let status = if docker::container_is_running(&info.container_name)? {
-            "running"
-        } else if docker::container_exists(&info.container_name)? {
-            "stopped"
-        } else {
-            "not started"
+        let status = match info
+            .container_backend()?
+            .container_state(&info.container_name)?
+        {
+            ContainerState::Running => "running",
+            ContainerState::Stopped => "stopped",
+            ContainerState::Missing => "not started",
         };
 
         // Format date more human-friendly
@@ -282,6 +554,68 @@ fn list_sandboxes(repo_root: &Path) -> Result<()> {
     Ok(())
 }
 
+fn show_usage(all: bool, days: Option<u32>) -> Result<()> {
+    let mut sandboxes = Vec::new();
+    if all {
+        for repo_root in sandbox::known_repo_roots()? {
+            for info in sandbox::list_sandboxes(&repo_root)? {
+                let repo_name = repo_root.file_name().unwrap_or_default().to_string_lossy();
+                sandboxes.push((format!("{}/{}", repo_name, info.name), info));
+            }
+        }
+    } else {
+        let repo_root = git::find_repo_root()?;
+        for info in sandbox::list_sandboxes(&repo_root)? {
+            sandboxes.push((info.name.clone(), info));
+        }
+    }
+
+    let first_day = days.map(|days| {
+        chrono::Local::now().date_naive() - chrono::Days::new(days.saturating_sub(1).into())
+    });
+    let mut records = Vec::new();
+    for (label, info) in &sandboxes {
+        for record in UsageLog::new(&info.sandbox_dir).load()? {
+            if first_day.is_none_or(|first_day| record.day() >= first_day) {
+                records.push((label.as_str(), record));
+            }
+        }
+    }
+
+    if records.is_empty() {
+        println!("No agent usage recorded.");
+        return Ok(());
+    }
+
+    let totals = usage::totals_by_day(records.iter().map(|(label, record)| (*label, record)));
+    println!(
+        "{:<12} {:<30} {:>10} {:>12} {:>12} {:>10} {:>10}",
+        "DAY", "SANDBOX", "INPUT", "CACHE WRITE", "CACHE READ", "OUTPUT", "COST"
+    );
+    println!("{}", "-".repeat(102));
+    let mut total = UsageTotals::default();
+    let print_row = |day: &str, sandbox: &str, totals: &UsageTotals| {
+        println!(
+            "{:<12} {:<30} {:>10} {:>12} {:>12} {:>10} {:>10}",
+            day,
+            sandbox,
+            usage::format_tokens(totals.tokens.input_tokens),
+            usage::format_tokens(totals.tokens.cache_creation_input_tokens),
+            usage::format_tokens(totals.tokens.cache_read_input_tokens),
+            usage::format_tokens(totals.tokens.output_tokens),
+            usage::format_cost(totals.cost)
+        );
+    };
+    for ((day, sandbox), totals) in &totals {
+        print_row(&day.to_string(), sandbox, totals);
+        total += *totals;
+    }
+    println!("{}", "-".repeat(102));
+    print_row("TOTAL", "", &total);
+
+    Ok(())
+}
+
 fn delete_sandbox(repo_root: &Path, name: &str) -> Result<()> {
     let sandboxes = sandbox::list_sandboxes(repo_root)?;
 
@@ -290,17 +624,33 @@ fn delete_sandbox(repo_root: &Path, name: &str) -> Result<()> {
         .find(|s| s.name == name)
         .ok_or_else(|| anyhow::anyhow!("Sandbox '{}' not found", name))?;
 
-    if docker::container_is_running(&info.container_name)? {
+    let backend = info.container_backend()?;
+    if backend.container_is_running(&info.container_name)? {
         println!("Container is still running, waiting for it to stop...");
-        docker::wait_container(&info.container_name)?;
+        backend.wait_container(&info.container_name)?;
     }
 
     sandbox::delete_sandbox(&info)?;
     println!("Deleted sandbox: {}", name);
 
+    // A broken config shouldn't prevent deleting sandboxes, so hooks are best effort
+    if let Ok(config) =
+        SandboxConfig::load(repo_root).and_then(|c| c.with_profile(info.profile.as_deref()))
+    {
+        let payload = HostEventPayload::new(HostEvent::Delete, &info);
+        hooks::run_host_hooks(&config.host_hooks, &payload);
+    }
+
     Ok(())
 }
 
+/// Which conversation `sandbox agent` runs.
+enum AgentSession {
+    New,
+    /// Continue the given session, or the latest one.
+    Resume(Option<String>),
+}
+
 #[allow(clippy::too_many_arguments)]
 fn run_agent(
     repo_root: &Path,
@@ -310,12 +660,24 @@ fn run_agent(
     runtime: Runtime,
     overlay_mode: OverlayMode,
     model: Model,
+    max_tokens: u32,
+    input_mode: InputMode,
+    budget: Budget,
+    thinking: Thinking,
+    bash_timeout: Duration,
     env_vars: &[(String, String)],
     llm_cache: Option<LlmCache>,
+    session: AgentSession,
 ) -> Result<()> {
-    let image_tag = resolve_image_tag(repo_root, config, user_info)?;
-    let info = sandbox::ensure_sandbox(repo_root, name, config)?;
-
+    check_mounts(repo_root, config, user_info)?;
+    let backend = backend::create(config.backend.unwrap_or_default())?;
+    let image_tag =
+        image::resolve_image_tag(backend.as_ref(), repo_root, config, user_info, false)?;
+    let info = sandbox::ensure_sandbox(repo_root, name, config, user_info, runtime)?;
+    let transcript = match session {
+        AgentSession::New => Transcript::create(&info.transcripts_dir())?,
+        AgentSession::Resume(id) => Transcript::resume(&info.transcripts_dir(), id.as_deref())?,
+    };
     let _daemon_conn = sandbox::ensure_container_running(
         &info,
         &image_tag,
@@ -325,6 +687,23 @@ fn run_agent(
         env_vars,
     )?;
 
-    agent::run_agent(&info.container_name, model, llm_cache)
+    // Tools run on the backend the container was started on, not the image engine
+    let backend = info.container_backend()?;
+    agent::run_agent(
+        Container::new(backend.as_ref(), &info.container_name),
+        model,
+        max_tokens,
+        llm_cache,
+        transcript,
+        InputOptions {
+            mode: input_mode,
+            editor: config.agent.editor.clone(),
+            state_dir: info.sandbox_dir.clone(),
+        },
+        UsageLog::new(&info.sandbox_dir),
+        budget,
+        thinking,
+        bash_timeout,
+    )
     // _daemon_conn is dropped here, signaling disconnection to daemon
 }
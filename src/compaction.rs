@@ -0,0 +1,199 @@
+//! Keeping agent conversations within the model's context window.
+//!
+//! When the conversation approaches the limit, it's compacted: the older messages are
+//! replaced by a summary the model writes, and large tool outputs of the kept messages
+//! are replaced by references to files in the sandbox holding them. The system prompt
+//! and tools are left alone, so their cache entry survives compaction, and since the
+//! summary request extends the conversation as it was sent last, it's served from the
+//! cache too.
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::anthropic::{ContentBlock, Message, Role};
+
+/// Rough number of characters of JSON per token, for estimates.
+const CHARS_PER_TOKEN: usize = 4;
+
+/// Number of most recent messages kept as they are.
+pub const KEEP_RECENT_MESSAGES: usize = 6;
+
+/// Tool outputs larger than this are moved to files.
+pub const OFFLOAD_MIN_CHARS: usize = 2000;
+
+/// Fraction of the context window above which the conversation is compacted.
+pub const COMPACT_THRESHOLD: f64 = 0.8;
+
+/// Instruction appended to the conversation to have the model summarize it.
+pub const SUMMARY_PROMPT: &str = "The conversation is getting too long for the context window. \
+    Write a summary of it that will replace the earlier messages, so that you can continue \
+    the task from it. Include the user's requests, decisions made, the state of the work, \
+    files changed and anything still to be done. The most recent messages are kept as they \
+    are. Don't use any tools, reply with the summary only.";
+
+/// Estimated number of tokens of a request part.
+pub fn estimate_tokens(value: &impl Serialize) -> u64 {
+    let chars = serde_json::to_string(value).map_or(0, |json| json.len());
+    chars.div_ceil(CHARS_PER_TOKEN) as u64
+}
+
+/// Where the messages to summarize end: at an assistant message, so that no tool
+/// result is separated from its tool use, keeping at least the `keep` most recent
+/// messages. `None` if there's nothing to summarize.
+pub fn summary_end(messages: &[Message], keep: usize) -> Option<usize> {
+    let latest = messages.len().checked_sub(keep)?;
+    (2..=latest)
+        .rev()
+        .find(|&index| messages[index].role == Role::Assistant)
+}
+
+/// The conversation with `messages[..end]` replaced by `summary`.
+pub fn replace_with_summary(messages: &[Message], end: usize, summary: &str) -> Vec<Message> {
+    let mut compacted = vec![Message {
+        role: Role::User,
+        content: vec![ContentBlock::Text {
+            text: format!("Summary of the earlier conversation:\n\n{}", summary),
+            cache_control: None,
+        }],
+    }];
+    compacted.extend_from_slice(&messages[end..]);
+    compacted
+}
+
+/// Replace tool outputs larger than [`OFFLOAD_MIN_CHARS`] with a reference to the file
+/// `save` stores them in, except in the last message, whose results the model hasn't
+/// seen yet. Returns the number of outputs replaced.
+pub fn offload_tool_outputs(
+    messages: &mut [Message],
+    mut save: impl FnMut(&str) -> Result<String>,
+) -> Result<usize> {
+    let Some((_, older)) = messages.split_last_mut() else {
+        return Ok(0);
+    };
+    let mut offloaded = 0;
+    for message in older {
+        for block in &mut message.content {
+            if let ContentBlock::ToolResult { content, .. } = block {
+                if content.len() > OFFLOAD_MIN_CHARS {
+                    let path = save(content)?;
+                    *content = format!(
+                        "[Output of {} bytes moved to {} to save context]",
+                        content.len(),
+                        path
+                    );
+                    offloaded += 1;
+                }
+            }
+        }
+    }
+    Ok(offloaded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(role: Role, text: &str) -> Message {
+        Message {
+            role,
+            content: vec![ContentBlock::Text {
+                text: text.to_string(),
+                cache_control: None,
+            }],
+        }
+    }
+
+    fn tool_use(id: &str) -> Message {
+        Message {
+            role: Role::Assistant,
+            content: vec![ContentBlock::ToolUse {
+                id: id.to_string(),
+                name: "bash".to_string(),
+                input: serde_json::json!({"command": "ls"}),
+            }],
+        }
+    }
+
+    fn tool_result(id: &str, output: &str) -> Message {
+        Message {
+            role: Role::User,
+            content: vec![ContentBlock::ToolResult {
+                tool_use_id: id.to_string(),
+                content: output.to_string(),
+                is_error: None,
+                cache_control: None,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(&"a".repeat(398)), 100);
+    }
+
+    #[test]
+    fn test_summary_keeps_tool_results_with_their_use() {
+        let messages = vec![
+            text(Role::User, "first"),
+            tool_use("1"),
+            tool_result("1", "output"),
+            text(Role::Assistant, "done"),
+            text(Role::User, "second"),
+            tool_use("2"),
+            tool_result("2", "output"),
+        ];
+
+        // The kept messages start at an assistant message
+        assert_eq!(summary_end(&messages, 2), Some(5));
+        assert_eq!(summary_end(&messages, 3), Some(3));
+        assert_eq!(summary_end(&messages, 4), Some(3));
+        assert_eq!(summary_end(&messages, 5), None);
+        assert_eq!(summary_end(&messages, 10), None);
+
+        let compacted = replace_with_summary(&messages, 3, "Listed files.");
+        assert_eq!(compacted.len(), 5);
+        assert_eq!(compacted[0].role, Role::User);
+        assert_eq!(compacted[1].role, Role::Assistant);
+        match &compacted[0].content[0] {
+            ContentBlock::Text { text, .. } => assert!(text.ends_with("Listed files.")),
+            block => panic!("Unexpected block: {:?}", block),
+        }
+    }
+
+    #[test]
+    fn test_offload_tool_outputs() {
+        let large = "x".repeat(OFFLOAD_MIN_CHARS + 1);
+        let mut messages = vec![
+            tool_use("1"),
+            tool_result("1", &large),
+            tool_use("2"),
+            tool_result("2", "small"),
+            tool_use("3"),
+            tool_result("3", &large),
+        ];
+
+        let mut saved = Vec::new();
+        let offloaded = offload_tool_outputs(&mut messages, |output| {
+            saved.push(output.len());
+            Ok("/agent/bash-output-1".to_string())
+        })
+        .unwrap();
+        assert_eq!(offloaded, 1);
+        assert_eq!(saved, vec![OFFLOAD_MIN_CHARS + 1]);
+
+        let outputs: Vec<&str> = messages
+            .iter()
+            .filter_map(|message| match &message.content[0] {
+                ContentBlock::ToolResult { content, .. } => Some(content.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            outputs[0],
+            "[Output of 2001 bytes moved to /agent/bash-output-1 to save context]"
+        );
+        assert_eq!(outputs[1], "small");
+        // The latest results are left for the model to see
+        assert_eq!(outputs[2], large);
+    }
+}
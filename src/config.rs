@@ -4,7 +4,9 @@ use sha2::{Digest, Sha256};
 use std::path::{Path, PathBuf};
 
 /// Container runtime to use for sandboxing.
-#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, serde::Deserialize)]
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, serde::Serialize, serde::Deserialize,
+)]
 #[serde(rename_all = "kebab-case")]
 pub enum Runtime {
     /// gVisor runtime (default) - strong isolation via kernel syscall interception
@@ -14,6 +16,24 @@ pub enum Runtime {
     Runc,
     /// Sysbox runtime - enables Docker-in-Docker with VM-like isolation
     SysboxRunc,
+    /// No container engine - user namespaces on the exported image filesystem, with
+    /// an isolated network (the backend is only used for images)
+    Rootless,
+}
+
+/// Container engine backend, i.e. how containers, images and volumes are managed.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// The `docker` CLI (default)
+    #[default]
+    Docker,
+    /// The Docker Engine API on the daemon socket (`$DOCKER_HOST` or /var/run/docker.sock)
+    DockerApi,
+    /// The `podman` CLI, rootless, with the host user mapped into the container
+    Podman,
 }
 
 /// Strategy for copy-on-write mounts (writes inside container don't propagate to host).
@@ -28,7 +48,19 @@ pub enum OverlayMode {
 }
 
 /// Claude model to use for the agent.
-#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, serde::Deserialize)]
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+)]
 #[serde(rename_all = "lowercase")]
 pub enum Model {
     /// Claude Opus 4.5 - most capable model
@@ -52,6 +84,31 @@ impl Model {
             Model::Haiku => "claude-haiku-4-5-20251001",
         }
     }
+
+    /// Size of the model's context window in tokens.
+    pub fn context_window(&self) -> u64 {
+        match self {
+            Model::Opus | Model::Sonnet | Model::Haiku => 200_000,
+        }
+    }
+
+    /// The most output tokens the model can generate in one response.
+    pub fn max_output_tokens(&self) -> u32 {
+        match self {
+            Model::Opus | Model::Sonnet | Model::Haiku => 64_000,
+        }
+    }
+}
+
+/// How the user composes messages to the agent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputMode {
+    /// Append the message to the chat history in an editor
+    #[default]
+    Editor,
+    /// Type the message at a prompt, with history
+    Inline,
 }
 
 impl Runtime {
@@ -61,6 +118,8 @@ impl Runtime {
             Runtime::Runsc => "runsc",
             Runtime::Runc => "runc",
             Runtime::SysboxRunc => "sysbox-runc",
+            // Containers aren't run by Docker (see backend::create_for_runtime)
+            Runtime::Rootless => "runc",
         }
     }
 }
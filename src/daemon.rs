@@ -12,12 +12,13 @@ use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant};
 
+use crate::backend::ContainerBackend;
 use crate::config::{OverlayMode, Runtime, UserInfo};
 use crate::daemon_protocol::{self, server, SandboxParams};
-use crate::docker;
 use crate::git;
+use crate::hooks::{self, HostEvent, HostEventPayload, HostHookQueue};
 use crate::sandbox::SandboxInfo;
-use crate::sandbox_config::SandboxConfig;
+use crate::sandbox_config::{HostHooksConfig, SandboxConfig};
 
 /// Environment variable to override the daemon socket path (for testing).
 pub const SOCKET_PATH_ENV: &str = "SANDBOX_DAEMON_SOCKET";
@@ -63,6 +64,7 @@ pub fn connect(
         runtime: runtime.into(),
         overlay_mode: overlay_mode.into(),
         env_vars: env_vars.to_vec(),
+        profile: info.profile.clone(),
     };
 
     let stream = UnixStream::connect(&sock_path).with_context(|| {
@@ -109,11 +111,16 @@ pub struct GitSyncThread {
 
 impl GitSyncThread {
     /// Spawn a new git sync thread for the given sandbox.
-    pub fn spawn(info: SandboxInfo) -> Result<Self> {
+    /// Host hooks are notified when a sync moves the sandbox's branch.
+    pub fn spawn(
+        info: SandboxInfo,
+        host_hooks: HostHooksConfig,
+        hook_queue: HostHookQueue,
+    ) -> Result<Self> {
         let (stop_tx, stop_rx) = mpsc::channel();
 
         let handle = thread::spawn(move || {
-            if let Err(e) = run_git_sync_loop(info, stop_rx) {
+            if let Err(e) = run_git_sync_loop(info, host_hooks, hook_queue, stop_rx) {
                 error!("Git sync thread failed: {:#}", e);
             }
         });
@@ -140,9 +147,23 @@ impl Drop for GitSyncThread {
     }
 }
 
-fn run_full_git_sync(info: &SandboxInfo) -> Result<()> {
+fn run_full_git_sync(
+    info: &SandboxInfo,
+    host_hooks: &HostHooksConfig,
+    hook_queue: &HostHookQueue,
+) -> Result<()> {
+    let branch_ref = format!("refs/heads/{}", info.name);
+    let old_commit = git::resolve_ref(&info.meta_git_dir, &branch_ref)?;
     git::sync_sandbox_to_meta(&info.meta_git_dir, &info.clone_dir, &info.name)
         .context("syncing sandbox to meta.git")?;
+    let new_commit = git::resolve_ref(&info.meta_git_dir, &branch_ref)?;
+    if new_commit.is_some() && new_commit != old_commit {
+        let mut payload = HostEventPayload::new(HostEvent::BranchUpdate, info);
+        payload.old_commit = old_commit;
+        payload.new_commit = new_commit;
+        hook_queue.fire(host_hooks, payload);
+    }
+
     git::sync_main_to_meta(&info.repo_root, &info.meta_git_dir)
         .context("syncing main branch to meta.git")?;
     git::sync_meta_to_host(&info.repo_root, &info.meta_git_dir, &info.name)
@@ -152,7 +173,12 @@ fn run_full_git_sync(info: &SandboxInfo) -> Result<()> {
     Ok(())
 }
 
-fn run_git_sync_loop(info: SandboxInfo, stop_rx: mpsc::Receiver<GitSyncMessage>) -> Result<()> {
+fn run_git_sync_loop(
+    info: SandboxInfo,
+    host_hooks: HostHooksConfig,
+    hook_queue: HostHookQueue,
+    stop_rx: mpsc::Receiver<GitSyncMessage>,
+) -> Result<()> {
     let debounce = Duration::from_millis(500);
     let mut last_sync = Instant::now();
     let mut pending_sync = false;
@@ -196,7 +222,7 @@ fn run_git_sync_loop(info: SandboxInfo, stop_rx: mpsc::Receiver<GitSyncMessage>)
     );
 
     // Run initial sync
-    if let Err(e) = run_full_git_sync(&info) {
+    if let Err(e) = run_full_git_sync(&info, &host_hooks, &hook_queue) {
         error!("Initial git sync failed: {:#}", e);
     }
 
@@ -206,7 +232,7 @@ fn run_git_sync_loop(info: SandboxInfo, stop_rx: mpsc::Receiver<GitSyncMessage>)
             Ok(GitSyncMessage::Stop) => {
                 info!("Git sync thread received stop signal");
                 // Run final sync before exiting
-                if let Err(e) = run_full_git_sync(&info) {
+                if let Err(e) = run_full_git_sync(&info, &host_hooks, &hook_queue) {
                     error!("Final git sync failed: {:#}", e);
                 }
                 return Ok(());
@@ -238,7 +264,7 @@ fn run_git_sync_loop(info: SandboxInfo, stop_rx: mpsc::Receiver<GitSyncMessage>)
         let now = Instant::now();
 
         if pending_sync && now.duration_since(last_sync) > debounce {
-            if let Err(e) = run_full_git_sync(&info) {
+            if let Err(e) = run_full_git_sync(&info, &host_hooks, &hook_queue) {
                 error!("Git sync failed: {:#}", e);
             }
             last_sync = now;
@@ -252,7 +278,10 @@ fn run_git_sync_loop(info: SandboxInfo, stop_rx: mpsc::Receiver<GitSyncMessage>)
 /// State for a single sandbox managed by the daemon.
 struct SandboxState {
     info: SandboxInfo,
+    backend: Arc<dyn ContainerBackend>,
     git_sync: GitSyncThread,
+    host_hooks: HostHooksConfig,
+    hook_queue: HostHookQueue,
     /// Number of active client connections for this sandbox.
     client_count: usize,
 }
@@ -261,12 +290,19 @@ struct SandboxState {
 struct DaemonState {
     /// Active sandboxes, keyed by sandbox name.
     sandboxes: HashMap<String, SandboxState>,
+    /// Host hook queues, keyed by sandbox name. They outlive the sandbox's state, so
+    /// that the events of a sandbox stopping and starting again stay in order.
+    hook_queues: HashMap<String, HostHookQueue>,
+    /// Locks held while a client starts or attaches to a sandbox, keyed by sandbox name.
+    startup_locks: HashMap<String, Arc<Mutex<()>>>,
 }
 
 impl DaemonState {
     fn new() -> Self {
         DaemonState {
             sandboxes: HashMap::new(),
+            hook_queues: HashMap::new(),
+            startup_locks: HashMap::new(),
         }
     }
 }
@@ -334,10 +370,49 @@ fn handle_ensure_sandbox(
 ) {
     let key = sandbox_key(&params.project_dir, sandbox_name);
 
+    // Clients of a sandbox start or attach to it one at a time, so that a client
+    // arriving while it starts (e.g. during a long post-create hook) waits and then
+    // attaches, instead of starting it again
+    let startup_lock = state
+        .lock()
+        .unwrap()
+        .startup_locks
+        .entry(key.clone())
+        .or_default()
+        .clone();
+    let startup = startup_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    // Set up by the CLI, in its environment, before connecting
+    let mut info = match crate::sandbox::load_sandbox(&params.project_dir, sandbox_name) {
+        Ok(i) => i,
+        Err(e) => {
+            error!("Client {}: failed to load sandbox: {}", client_id, e);
+            let _ = server::send_error(
+                &mut stream,
+                -32000,
+                &format!("Failed to load sandbox: {:#}", e),
+            );
+            return;
+        }
+    };
+
     // Check if sandbox already exists or we need to create it
     let needs_creation = {
         let mut state = state.lock().unwrap();
         if let Some(sandbox_state) = state.sandboxes.get_mut(&key) {
+            // The container can't move to another backend or runtime under the
+            // sessions attached to it
+            if !sandbox_state.info.same_container_backend(&info) {
+                let message = format!(
+                    "Sandbox '{}' is running with another backend or runtime and has {} \
+                     session(s) attached; exit them before switching",
+                    sandbox_name, sandbox_state.client_count
+                );
+                warn!("Client {}: {}", client_id, message);
+                drop(state);
+                let _ = server::send_error(&mut stream, -32000, &message);
+                return;
+            }
             // Sandbox exists, increment client count
             sandbox_state.client_count += 1;
             info!(
@@ -359,7 +434,9 @@ fn handle_ensure_sandbox(
         let overlay_mode: OverlayMode = params.overlay_mode.into();
 
         // Load sandbox config and ensure sandbox is set up
-        let sandbox_config = match SandboxConfig::load(&params.project_dir) {
+        let sandbox_config = match SandboxConfig::load(&params.project_dir)
+            .and_then(|c| c.with_profile(params.profile.as_deref()))
+        {
             Ok(c) => c,
             Err(e) => {
                 error!("Client {}: failed to load sandbox config: {}", client_id, e);
@@ -372,19 +449,17 @@ fn handle_ensure_sandbox(
             }
         };
 
-        let info = match crate::sandbox::ensure_sandbox(
-            &params.project_dir,
-            sandbox_name,
-            &sandbox_config,
-        ) {
-            Ok(i) => i,
+        if let Err(e) = info.remove_previous_container() {
+            error!("Client {}: {:#}", client_id, e);
+            let _ = server::send_error(&mut stream, -32000, &format!("{:#}", e));
+            return;
+        }
+
+        let backend = match info.container_backend() {
+            Ok(b) => b,
             Err(e) => {
-                error!("Client {}: failed to ensure sandbox: {}", client_id, e);
-                let _ = server::send_error(
-                    &mut stream,
-                    -32000,
-                    &format!("Failed to ensure sandbox: {}", e),
-                );
+                error!("Client {}: failed to create backend: {:#}", client_id, e);
+                let _ = server::send_error(&mut stream, -32000, &format!("{:#}", e));
                 return;
             }
         };
@@ -407,8 +482,42 @@ fn handle_ensure_sandbox(
             return;
         }
 
+        let created = info.post_create_completed_at.is_none();
+        if let Err(e) =
+            hooks::run_container_hooks(backend.as_ref(), &mut info, &sandbox_config.hooks)
+        {
+            error!("Client {}: hooks failed: {:#}", client_id, e);
+            if let Err(e) = backend.stop_container(&info.container_name) {
+                error!("Failed to stop container: {}", e);
+            }
+            let _ = server::send_error(&mut stream, -32000, &format!("{:#}", e));
+            return;
+        }
+
+        let hook_queue = state
+            .lock()
+            .unwrap()
+            .hook_queues
+            .entry(key.clone())
+            .or_insert_with(HostHookQueue::spawn)
+            .clone();
+        if created {
+            hook_queue.fire(
+                &sandbox_config.host_hooks,
+                HostEventPayload::new(HostEvent::Create, &info),
+            );
+        }
+        hook_queue.fire(
+            &sandbox_config.host_hooks,
+            HostEventPayload::new(HostEvent::Start, &info),
+        );
+
         // Start git sync thread
-        let git_sync = match GitSyncThread::spawn(info.clone()) {
+        let git_sync = match GitSyncThread::spawn(
+            info.clone(),
+            sandbox_config.host_hooks.clone(),
+            hook_queue.clone(),
+        ) {
             Ok(g) => g,
             Err(e) => {
                 error!("Client {}: failed to start git sync: {}", client_id, e);
@@ -432,7 +541,10 @@ fn handle_ensure_sandbox(
                 key.clone(),
                 SandboxState {
                     info,
+                    backend,
                     git_sync,
+                    host_hooks: sandbox_config.host_hooks,
+                    hook_queue,
                     client_count: 1,
                 },
             );
@@ -443,6 +555,7 @@ fn handle_ensure_sandbox(
             client_id, key
         );
     }
+    drop(startup);
 
     // Send success response
     if let Err(e) = server::send_ensure_sandbox_ok(&mut stream) {
@@ -482,10 +595,18 @@ fn handle_ensure_sandbox(
                 sandbox_state.git_sync.stop();
 
                 // Stop container
-                if let Err(e) = docker::stop_container(&sandbox_state.info.container_name) {
+                if let Err(e) = sandbox_state
+                    .backend
+                    .stop_container(&sandbox_state.info.container_name)
+                {
                     error!("Failed to stop container: {}", e);
                 }
 
+                sandbox_state.hook_queue.fire(
+                    &sandbox_state.host_hooks,
+                    HostEventPayload::new(HostEvent::Stop, &sandbox_state.info),
+                );
+
                 info!("Sandbox '{}' cleaned up", key);
             }
         }
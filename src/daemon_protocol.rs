@@ -16,6 +16,8 @@ pub struct SandboxParams {
     pub runtime: RuntimeWire,
     pub overlay_mode: OverlayModeWire,
     pub env_vars: Vec<(String, String)>,
+    #[serde(default)]
+    pub profile: Option<String>,
 }
 
 /// Wire format for UserInfo (serializable).
@@ -56,6 +58,7 @@ pub enum RuntimeWire {
     Runsc,
     Runc,
     SysboxRunc,
+    Rootless,
 }
 
 impl From<Runtime> for RuntimeWire {
@@ -64,6 +67,7 @@ impl From<Runtime> for RuntimeWire {
             Runtime::Runsc => Self::Runsc,
             Runtime::Runc => Self::Runc,
             Runtime::SysboxRunc => Self::SysboxRunc,
+            Runtime::Rootless => Self::Rootless,
         }
     }
 }
@@ -74,6 +78,7 @@ impl From<RuntimeWire> for Runtime {
             RuntimeWire::Runsc => Self::Runsc,
             RuntimeWire::Runc => Self::Runc,
             RuntimeWire::SysboxRunc => Self::SysboxRunc,
+            RuntimeWire::Rootless => Self::Rootless,
         }
     }
 }
@@ -0,0 +1,448 @@
+//! Support for reading container settings from `.devcontainer/devcontainer.json`.
+//!
+//! Only the parts that have an equivalent in `.sandbox.toml` are used: the image or
+//! Dockerfile with its build args, `mounts`, `containerEnv`, `remoteUser` and
+//! `postCreateCommand`. See <https://containers.dev/implementors/json_reference/>.
+
+use anyhow::{bail, Context, Result};
+use log::warn;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::sandbox_config::{EnvConfig, EnvValue, ImageConfig, MountEntry, MountsConfig};
+
+/// The subset of `devcontainer.json` the sandbox understands.
+/// Unknown properties are ignored, as most of them are editor-specific.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Devcontainer {
+    pub image: Option<String>,
+    pub build: Option<DevcontainerBuild>,
+    /// Legacy top-level form of `build.dockerfile`.
+    pub docker_file: Option<PathBuf>,
+    /// Legacy top-level form of `build.context`.
+    pub context: Option<PathBuf>,
+    #[serde(default)]
+    pub mounts: Vec<DevcontainerMount>,
+    #[serde(default)]
+    pub container_env: BTreeMap<String, String>,
+    pub remote_user: Option<String>,
+    pub post_create_command: Option<LifecycleCommand>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DevcontainerBuild {
+    pub dockerfile: Option<PathBuf>,
+    pub context: Option<PathBuf>,
+    #[serde(default)]
+    pub args: BTreeMap<String, String>,
+    pub target: Option<String>,
+}
+
+/// A mount, either in Docker `--mount` string syntax or as an object.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum DevcontainerMount {
+    String(String),
+    Object {
+        source: Option<String>,
+        target: String,
+        #[serde(rename = "type")]
+        mount_type: Option<String>,
+    },
+}
+
+/// A lifecycle command: a shell string, an argument list, or named commands.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum LifecycleCommand {
+    Shell(String),
+    Args(Vec<String>),
+    Named(BTreeMap<String, Box<LifecycleCommand>>),
+}
+
+impl LifecycleCommand {
+    /// Convert to a single shell script. Named commands, which devcontainer tools
+    /// run in parallel, are run one after another.
+    pub fn to_shell(&self) -> String {
+        match self {
+            LifecycleCommand::Shell(s) => s.clone(),
+            LifecycleCommand::Args(args) => args
+                .iter()
+                .map(|a| shell_quote(a))
+                .collect::<Vec<_>>()
+                .join(" "),
+            LifecycleCommand::Named(commands) => commands
+                .values()
+                .map(|c| c.to_shell())
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Settings from a `devcontainer.json`, translated into sandbox config terms.
+#[derive(Debug, Clone)]
+pub struct DevcontainerSettings {
+    pub image: ImageConfig,
+    pub mounts: MountsConfig,
+    pub env: EnvConfig,
+    /// `postCreateCommand` as a shell script.
+    pub post_create: Option<String>,
+}
+
+impl Devcontainer {
+    /// Read a `devcontainer.json` file. Comments and trailing commas are allowed.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&strip_jsonc(&contents))
+            .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// Translate into sandbox settings. Relative paths are resolved against the
+    /// directory containing `devcontainer.json`, as devcontainer tools do.
+    pub fn into_settings(
+        self,
+        json_path: &Path,
+        repo_root: &Path,
+        username: &str,
+    ) -> Result<DevcontainerSettings> {
+        let json_dir = json_path.parent().unwrap_or(repo_root);
+        let vars = Variables { repo_root };
+
+        let build = match (self.build, self.docker_file) {
+            (Some(build), _) if build.dockerfile.is_some() => Some(build),
+            (build, Some(dockerfile)) => Some(DevcontainerBuild {
+                dockerfile: Some(dockerfile),
+                context: self.context,
+                ..build.unwrap_or_default()
+            }),
+            _ => None,
+        };
+
+        let image = match (self.image, build) {
+            (_, Some(build)) => {
+                let dockerfile = build.dockerfile.expect("checked above");
+                let context = build.context.unwrap_or_else(|| PathBuf::from("."));
+                let args = build
+                    .args
+                    .into_iter()
+                    .map(|(k, v)| Ok((k, vars.substitute(&v)?)))
+                    .collect::<Result<_>>()?;
+                ImageConfig::Build {
+                    dockerfile: json_dir.join(dockerfile),
+                    context: Some(json_dir.join(context)),
+                    args,
+                    target: build.target,
+                    platform: None,
+                }
+            }
+            (Some(image), None) => ImageConfig::Tag(vars.substitute(&image)?),
+            (None, None) => bail!(
+                "{} specifies neither `image` nor `build.dockerfile`",
+                json_path.display()
+            ),
+        };
+
+        let mut mounts = MountsConfig::default();
+        for mount in &self.mounts {
+            let Some((entry, readonly)) = parse_mount(mount, &vars)? else {
+                continue;
+            };
+            // Writes to the host need an explicit `unsafe-write` mount in `.sandbox.toml`
+            if readonly {
+                mounts.readonly.push(entry);
+            } else {
+                mounts.overlay.push(entry);
+            }
+        }
+
+        let mut env = EnvConfig::default();
+        for (name, value) in self.container_env {
+            env.0
+                .insert(name, EnvValue::Literal(vars.substitute(&value)?));
+        }
+
+        if let Some(remote_user) = &self.remote_user {
+            if remote_user != username {
+                warn!(
+                    "Ignoring remoteUser '{}' from {}: sandboxes always run as the host user '{}'",
+                    remote_user,
+                    json_path.display(),
+                    username
+                );
+            }
+        }
+
+        Ok(DevcontainerSettings {
+            image,
+            mounts,
+            env,
+            post_create: self.post_create_command.map(|c| c.to_shell()),
+        })
+    }
+}
+
+/// Convert a devcontainer mount into a mount entry and whether it is read-only.
+/// Returns `None` for mount types the sandbox doesn't support (e.g. volumes).
+fn parse_mount(mount: &DevcontainerMount, vars: &Variables) -> Result<Option<(MountEntry, bool)>> {
+    let (source, target, mount_type, readonly) = match mount {
+        DevcontainerMount::String(spec) => {
+            let mut source = None;
+            let mut target = None;
+            let mut mount_type = None;
+            let mut readonly = false;
+            for option in spec.split(',') {
+                let (key, value) = match option.split_once('=') {
+                    Some((k, v)) => (k.trim(), Some(v.trim())),
+                    None => (option.trim(), None),
+                };
+                match (key, value) {
+                    ("source" | "src", Some(v)) => source = Some(v.to_string()),
+                    ("target" | "dst" | "destination", Some(v)) => target = Some(v.to_string()),
+                    ("type", Some(v)) => mount_type = Some(v.to_string()),
+                    ("readonly" | "ro", None) => readonly = true,
+                    ("readonly" | "ro", Some(v)) => readonly = v != "false" && v != "0",
+                    _ => {}
+                }
+            }
+            let target = target.with_context(|| format!("Mount '{}' has no target", spec))?;
+            (source, target, mount_type, readonly)
+        }
+        DevcontainerMount::Object {
+            source,
+            target,
+            mount_type,
+        } => (source.clone(), target.clone(), mount_type.clone(), false),
+    };
+
+    match (mount_type.as_deref(), source) {
+        (Some("bind") | None, Some(source)) => Ok(Some((
+            MountEntry {
+                host: PathBuf::from(vars.substitute(&source)?),
+                container: Some(PathBuf::from(vars.substitute(&target)?)),
+                optional: false,
+            },
+            readonly,
+        ))),
+        (mount_type, _) => {
+            warn!(
+                "Skipping devcontainer mount of type '{}' at {}: only bind mounts are supported",
+                mount_type.unwrap_or("unknown"),
+                target
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// Substitution of devcontainer `${...}` variables.
+struct Variables<'a> {
+    repo_root: &'a Path,
+}
+
+impl Variables<'_> {
+    fn substitute(&self, s: &str) -> Result<String> {
+        let mut result = String::new();
+        let mut rest = s;
+        while let Some(start) = rest.find("${") {
+            result.push_str(&rest[..start]);
+            let end = rest[start..]
+                .find('}')
+                .with_context(|| format!("Unterminated variable in '{}'", s))?;
+            let name = &rest[start + 2..start + end];
+            result.push_str(&self.lookup(name, s)?);
+            rest = &rest[start + end + 1..];
+        }
+        result.push_str(rest);
+        Ok(result)
+    }
+
+    fn lookup(&self, name: &str, s: &str) -> Result<String> {
+        // The repo is mounted at the same path inside the sandbox
+        let workspace = self.repo_root.to_string_lossy().into_owned();
+        match name {
+            "localWorkspaceFolder" | "containerWorkspaceFolder" => Ok(workspace),
+            "localWorkspaceFolderBasename" | "containerWorkspaceFolderBasename" => Ok(self
+                .repo_root
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default()),
+            _ => {
+                let env = name
+                    .strip_prefix("localEnv:")
+                    .or_else(|| name.strip_prefix("env:"));
+                match env {
+                    Some(var) => {
+                        let (var, default) = match var.split_once(':') {
+                            Some((v, d)) => (v, d),
+                            None => (var, ""),
+                        };
+                        Ok(std::env::var(var).unwrap_or_else(|_| default.to_string()))
+                    }
+                    None => bail!(
+                        "Unsupported devcontainer variable '${{{}}}' in '{}'",
+                        name,
+                        s
+                    ),
+                }
+            }
+        }
+    }
+}
+
+/// Remove `//` and `/* */` comments and trailing commas from JSON with comments.
+fn strip_jsonc(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut chars = input.chars().peekable();
+    let mut in_string = false;
+
+    while let Some(c) = chars.next() {
+        if in_string {
+            output.push(c);
+            if c == '\\' {
+                if let Some(escaped) = chars.next() {
+                    output.push(escaped);
+                }
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                output.push(c);
+            }
+            ('/', Some('/')) => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        output.push('\n');
+                        break;
+                    }
+                }
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut prev = ' ';
+                for c in chars.by_ref() {
+                    if prev == '*' && c == '/' {
+                        break;
+                    }
+                    prev = c;
+                }
+            }
+            _ => output.push(c),
+        }
+    }
+
+    // Drop commas that are followed only by whitespace and a closing bracket
+    let mut result = String::with_capacity(output.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in output.char_indices() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+        } else if c == '"' {
+            in_string = true;
+        } else if c == ',' {
+            let next = output[i + 1..].trim_start().chars().next();
+            if matches!(next, Some('}') | Some(']')) {
+                continue;
+            }
+        }
+        result.push(c);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_strip_jsonc() {
+        let input = r#"{
+            // comment
+            "a": "http://x", /* block */
+            "b": [1, 2,],
+        }"#;
+        let value: serde_json::Value = serde_json::from_str(&strip_jsonc(input)).unwrap();
+        assert_eq!(value["a"], "http://x");
+        assert_eq!(value["b"], serde_json::json!([1, 2]));
+    }
+
+    #[test]
+    fn test_devcontainer_settings() {
+        let dir = TempDir::new().unwrap();
+        let json_dir = dir.path().join(".devcontainer");
+        fs::create_dir_all(&json_dir).unwrap();
+        let json_path = json_dir.join("devcontainer.json");
+        fs::write(
+            &json_path,
+            r#"{
+                "build": { "dockerfile": "Dockerfile", "context": "..", "args": { "VARIANT": "3.12" } },
+                "mounts": [
+                    "source=${localWorkspaceFolder}/data,target=/data,type=bind,readonly",
+                    { "source": "/tmp/cache", "target": "/cache", "type": "bind" },
+                    "source=vol,target=/vol,type=volume",
+                ],
+                "containerEnv": { "WORKSPACE": "${containerWorkspaceFolder}" },
+                "remoteUser": "vscode",
+                "postCreateCommand": ["pip", "install", "-r", "requirements dev.txt"],
+            }"#,
+        )
+        .unwrap();
+
+        let settings = Devcontainer::load(&json_path)
+            .unwrap()
+            .into_settings(&json_path, dir.path(), "testuser")
+            .unwrap();
+
+        match &settings.image {
+            ImageConfig::Build {
+                dockerfile,
+                context,
+                args,
+                ..
+            } => {
+                assert_eq!(dockerfile, &json_dir.join("Dockerfile"));
+                assert_eq!(context, &Some(json_dir.join("..")));
+                assert_eq!(args.get("VARIANT").map(String::as_str), Some("3.12"));
+            }
+            _ => panic!("Expected ImageConfig::Build"),
+        }
+
+        assert_eq!(settings.mounts.readonly.len(), 1);
+        assert_eq!(settings.mounts.readonly[0].host, dir.path().join("data"));
+        assert!(settings.mounts.unsafe_write.is_empty());
+        assert_eq!(settings.mounts.overlay.len(), 1);
+        assert_eq!(
+            settings.mounts.overlay[0].container,
+            Some(PathBuf::from("/cache"))
+        );
+
+        match settings.env.0.get("WORKSPACE") {
+            Some(EnvValue::Literal(v)) => assert_eq!(v, &dir.path().to_string_lossy()),
+            _ => panic!("Expected literal WORKSPACE"),
+        }
+        assert_eq!(
+            settings.post_create.as_deref(),
+            Some("'pip' 'install' '-r' 'requirements dev.txt'")
+        );
+    }
+}
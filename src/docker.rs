@@ -1,244 +1,536 @@
+//! Backend driving the `docker` CLI, or the compatible `podman` CLI.
+
 use anyhow::{bail, Context, Result};
-use log::{debug, info};
+use log::debug;
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
 use std::path::Path;
 use std::process::{Command, Stdio};
-
-use crate::config::{hash_file, UserInfo};
-
-/// Check if a Docker image with the given tag exists.
-pub fn image_exists(tag: &str) -> Result<bool> {
-    let output = Command::new("docker")
-        .args(["image", "inspect", tag])
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status()
-        .context("Failed to run docker image inspect")?;
-
-    Ok(output.success())
+use std::sync::mpsc;
+
+use crate::backend::{
+    unpack_rootfs, BuildRequest, ContainerBackend, ContainerSpec, ContainerState, ExecOptions,
+    ExecOutput, ImageInfo, ImageSummary, MountSource, MountSpec,
+};
+
+/// A container engine reached through its command line client.
+#[derive(Debug, Clone)]
+pub struct DockerCli {
+    program: &'static str,
+    /// Podman runs rootless: the host user is mapped into the container with
+    /// `--userns=keep-id` and overlays are mounted natively instead of via volumes.
+    podman: bool,
 }
 
-/// Build a Docker image from a Dockerfile.
-/// The image is tagged with a hash of the Dockerfile contents.
-/// Returns the image tag.
-pub fn build_image(dockerfile_path: &Path, context: &Path, user_info: &UserInfo) -> Result<String> {
-    let dockerfile_hash = hash_file(dockerfile_path)?;
-    let image_tag = format!("sandbox:{}", dockerfile_hash);
-
-    // Check if image already exists
-    if image_exists(&image_tag)? {
-        debug!("Using existing image: {}", image_tag);
-        return Ok(image_tag);
-    }
-
-    info!("Building Docker image: {}", image_tag);
-
-    let status = Command::new("docker")
-        .args([
-            "build",
-            "-f",
-            &dockerfile_path.to_string_lossy(),
-            "-t",
-            &image_tag,
-            "--build-arg",
-            &format!("USER_NAME={}", user_info.username),
-            "--build-arg",
-            &format!("USER_ID={}", user_info.uid),
-            "--build-arg",
-            &format!("GROUP_ID={}", user_info.gid),
-            &context.to_string_lossy(),
-        ])
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status()
-        .context("Failed to run docker build")?;
-
-    if !status.success() {
-        bail!("Docker build failed");
-    }
-
-    Ok(image_tag)
-}
+impl DockerCli {
+    pub fn docker() -> Self {
+        DockerCli {
+            program: "docker",
+            podman: false,
+        }
+    }
 
-/// Check if a container with the given name exists and is running.
-pub fn container_is_running(name: &str) -> Result<bool> {
-    let output = Command::new("docker")
-        .args(["container", "inspect", "-f", "{{.State.Running}}", name])
-        .output()
-        .context("Failed to run docker container inspect")?;
+    pub fn podman() -> Self {
+        DockerCli {
+            program: "podman",
+            podman: true,
+        }
+    }
 
-    if !output.status.success() {
-        return Ok(false);
+    fn command(&self) -> Command {
+        Command::new(self.program)
     }
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    Ok(stdout.trim() == "true")
-}
+    /// Run a command that produces no interesting output, returning whether it succeeded.
+    fn run_quiet(&self, args: &[&str]) -> Result<bool> {
+        let status = self
+            .command()
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .with_context(|| format!("Failed to run {} {}", self.program, args[0]))?;
+        Ok(status.success())
+    }
 
-/// Check if a container with the given name exists (running or stopped).
-pub fn container_exists(name: &str) -> Result<bool> {
-    let output = Command::new("docker")
-        .args(["container", "inspect", name])
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status()
-        .context("Failed to run docker container inspect")?;
+    /// Run a command and return its stdout, failing with `error` if it fails.
+    fn run_output(&self, args: &[&str], error: &str) -> Result<String> {
+        let output = self
+            .command()
+            .args(args)
+            .output()
+            .with_context(|| format!("Failed to run {} {}", self.program, args[0]))?;
+        if !output.status.success() {
+            bail!(
+                "{}: {}",
+                error,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
 
-    Ok(output.success())
+    /// Arguments for one mount of `run`, creating the overlay volume it needs if any.
+    fn mount_args(&self, mount: &MountSpec) -> Result<Vec<String>> {
+        let target = mount.target.display();
+        Ok(match &mount.source {
+            MountSource::Bind { path, readonly } => vec![
+                "--mount".to_string(),
+                format!(
+                    "type=bind,source={},target={}{}",
+                    path.display(),
+                    target,
+                    if *readonly { ",readonly" } else { "" }
+                ),
+            ],
+            MountSource::Overlay {
+                lower, upper, work, ..
+            } if self.podman => vec![
+                "--volume".to_string(),
+                format!(
+                    "{}:{}:O,upperdir={},workdir={}",
+                    lower.display(),
+                    target,
+                    upper.display(),
+                    work.display()
+                ),
+            ],
+            MountSource::Overlay {
+                lower,
+                upper,
+                work,
+                volume_name,
+            } => {
+                // The local volume driver mounts the overlayfs when the container starts
+                if !self.run_quiet(&["volume", "inspect", volume_name])? {
+                    let options = format!(
+                        "o=lowerdir={},upperdir={},workdir={}",
+                        lower.display(),
+                        upper.display(),
+                        work.display()
+                    );
+                    let created = self.run_quiet(&[
+                        "volume",
+                        "create",
+                        "-d",
+                        "local",
+                        "-o",
+                        "type=overlay",
+                        "-o",
+                        &options,
+                        "-o",
+                        "device=overlay",
+                        volume_name,
+                    ])?;
+                    if !created {
+                        bail!("Failed to create overlay volume '{}'", volume_name);
+                    }
+                }
+                vec![
+                    "--mount".to_string(),
+                    format!("type=volume,source={},target={}", volume_name, target),
+                ]
+            }
+        })
+    }
 }
 
-/// Remove a container by name.
-pub fn remove_container(name: &str) -> Result<()> {
-    let status = Command::new("docker")
-        .args(["rm", "-f", name])
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status()
-        .context("Failed to run docker rm")?;
-
-    if !status.success() {
-        bail!("Failed to remove container: {}", name);
+/// Image IDs in the `sha256:...` form docker uses; podman leaves out the algorithm.
+fn normalize_image_id(id: &str) -> String {
+    if id.contains(':') {
+        id.to_string()
+    } else {
+        format!("sha256:{}", id)
     }
-
-    Ok(())
 }
 
-/// List all Docker volumes with a specific prefix.
-pub fn list_volumes_with_prefix(prefix: &str) -> Result<Vec<String>> {
-    let output = Command::new("docker")
-        .args([
-            "volume",
-            "ls",
-            "-q",
-            "--filter",
-            &format!("name={}", prefix),
-        ])
-        .output()
-        .context("Failed to list Docker volumes")?;
-
-    if !output.status.success() {
-        bail!("Failed to list Docker volumes");
-    }
-
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    Ok(stdout.lines().map(String::from).collect())
-}
+impl ContainerBackend for DockerCli {
+    fn inspect_image(&self, reference: &str) -> Result<Option<ImageInfo>> {
+        let output = self
+            .command()
+            .args([
+                "image",
+                "inspect",
+                "--format",
+                "{{.Id}}{{range .RepoDigests}} {{.}}{{end}}",
+                reference,
+            ])
+            .output()
+            .context("Failed to run image inspect")?;
+
+        if !output.status.success() {
+            return Ok(None);
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut fields = stdout.split_whitespace();
+        Ok(Some(ImageInfo {
+            id: normalize_image_id(fields.next().unwrap_or_default()),
+            repo_digests: fields.map(String::from).collect(),
+        }))
+    }
+
+    fn list_images(&self, repository: &str) -> Result<Vec<ImageSummary>> {
+        let stdout = self.run_output(
+            &[
+                "images",
+                "--no-trunc",
+                "--filter",
+                &format!("reference={}", repository),
+                "--format",
+                "{{.ID}}\t{{.Repository}}:{{.Tag}}\t{{.CreatedAt}}\t{{.Size}}",
+            ],
+            "Failed to list images",
+        )?;
+
+        let mut images: Vec<ImageSummary> = stdout
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split('\t');
+                Some(ImageSummary {
+                    id: normalize_image_id(fields.next()?),
+                    reference: fields.next()?.to_string(),
+                    created_at: fields.next()?.to_string(),
+                    size: fields.next()?.to_string(),
+                    labels: BTreeMap::new(),
+                })
+            })
+            .collect();
+        if images.is_empty() {
+            return Ok(images);
+        }
+
+        // The listing has no labels, so they are inspected, one line per image
+        let mut args = vec!["image", "inspect", "--format", "{{json .Config.Labels}}"];
+        args.extend(images.iter().map(|image| image.reference.as_str()));
+        let stdout = self.run_output(&args, "Failed to inspect images")?;
+        for (image, labels) in images.iter_mut().zip(stdout.lines()) {
+            // `null` for images without labels
+            image.labels = serde_json::from_str::<Option<_>>(labels)
+                .context("Failed to parse image labels")?
+                .unwrap_or_default();
+        }
+        Ok(images)
+    }
 
-/// Remove a Docker volume.
-pub fn remove_volume(name: &str) -> Result<()> {
-    let status = Command::new("docker")
-        .args(["volume", "rm", name])
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status()
-        .context("Failed to run docker volume rm")?;
+    fn remove_image(&self, reference: &str) -> Result<()> {
+        if !self.run_quiet(&["rmi", reference])? {
+            bail!("Failed to remove image: {}", reference);
+        }
+        Ok(())
+    }
 
-    if !status.success() {
-        bail!("Failed to remove volume: {}", name);
+    fn build_image(&self, request: &BuildRequest, output: &mut dyn FnMut(&str)) -> Result<()> {
+        let mut args = vec![
+            "build".to_string(),
+            "-f".to_string(),
+            request.dockerfile.to_string_lossy().into_owned(),
+            "-t".to_string(),
+            request.tag.clone(),
+        ];
+        if request.no_cache {
+            args.push("--no-cache".to_string());
+        }
+        if let Some(target) = &request.target {
+            args.push("--target".to_string());
+            args.push(target.clone());
+        }
+        if let Some(platform) = &request.platform {
+            args.push("--platform".to_string());
+            args.push(platform.clone());
+        }
+        for (key, value) in &request.args {
+            args.push("--build-arg".to_string());
+            args.push(format!("{}={}", key, value));
+        }
+        for (key, value) in &request.labels {
+            args.push("--label".to_string());
+            args.push(format!("{}={}", key, value));
+        }
+        args.push(request.context.to_string_lossy().into_owned());
+
+        let mut child = self
+            .command()
+            .args(&args)
+            // Plain progress gives line-oriented output that works in a log file
+            .env("BUILDKIT_PROGRESS", "plain")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Failed to run build")?;
+
+        // Merge stdout and stderr into a single stream of lines
+        let (tx, rx) = mpsc::channel::<String>();
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+        let readers = [
+            spawn_line_reader(BufReader::new(stdout), tx.clone()),
+            spawn_line_reader(BufReader::new(stderr), tx),
+        ];
+        for line in rx {
+            output(&line);
+        }
+        for reader in readers {
+            let _ = reader.join();
+        }
+
+        let status = child.wait().context("Failed to wait for build")?;
+        if !status.success() {
+            bail!("{} build exited with {}", self.program, status);
+        }
+        Ok(())
     }
 
-    Ok(())
-}
+    fn export_image(&self, reference: &str, dest: &Path) -> Result<()> {
+        // Images are exported through a container created (but never started) from them
+        let id = self.run_output(
+            &["create", reference, "true"],
+            &format!("Failed to create container from {}", reference),
+        )?;
+        let id = id.trim();
+
+        let result = (|| {
+            let mut child = self
+                .command()
+                .args(["export", id])
+                .stdout(Stdio::piped())
+                .spawn()
+                .with_context(|| format!("Failed to run {} export", self.program))?;
+            let unpacked = unpack_rootfs(child.stdout.take().expect("stdout is piped"), dest);
+            if !child.wait()?.success() {
+                bail!("Failed to export the filesystem of {}", reference);
+            }
+            unpacked
+        })();
+
+        self.run_quiet(&["rm", id])?;
+        result
+    }
 
-/// Create a Docker volume.
-pub fn create_volume(name: &str) -> Result<()> {
-    let status = Command::new("docker")
-        .args(["volume", "create", name])
-        .stdout(Stdio::null())
-        .status()
-        .context("Failed to create Docker volume")?;
+    fn run_container(&self, spec: &ContainerSpec) -> Result<()> {
+        let mut args = vec![
+            "run".to_string(),
+            "-d".to_string(),
+            "--name".to_string(),
+            spec.name.clone(),
+            "--hostname".to_string(),
+            spec.hostname.clone(),
+            "--runtime".to_string(),
+            spec.runtime.clone(),
+            "--user".to_string(),
+            format!("{}:{}", spec.uid, spec.gid),
+            "--workdir".to_string(),
+            spec.workdir.to_string_lossy().into_owned(),
+        ];
+        if self.podman {
+            // Map the host user to the same uid inside the container, so that files
+            // written to bind mounts are owned by the user on the host
+            args.push("--userns=keep-id".to_string());
+        }
+        for (key, value) in &spec.labels {
+            args.push("--label".to_string());
+            args.push(format!("{}={}", key, value));
+        }
+        for mount in &spec.mounts {
+            args.extend(self.mount_args(mount)?);
+        }
+        for (name, value) in &spec.env {
+            args.push("-e".to_string());
+            args.push(format!("{}={}", name, value));
+        }
+        args.push(spec.image.clone());
+        args.extend(spec.command.iter().cloned());
+
+        let status = self
+            .command()
+            .args(&args)
+            .stdout(Stdio::null())
+            .status()
+            .context("Failed to start container")?;
+
+        if !status.success() {
+            bail!("Failed to start container");
+        }
+        Ok(())
+    }
 
-    if !status.success() {
-        bail!("Failed to create volume: {}", name);
+    fn container_state(&self, name: &str) -> Result<ContainerState> {
+        let output = self
+            .command()
+            .args(["container", "inspect", "-f", "{{.State.Running}}", name])
+            .stderr(Stdio::null())
+            .output()
+            .context("Failed to run container inspect")?;
+
+        if !output.status.success() {
+            return Ok(ContainerState::Missing);
+        }
+        if String::from_utf8_lossy(&output.stdout).trim() == "true" {
+            Ok(ContainerState::Running)
+        } else {
+            Ok(ContainerState::Stopped)
+        }
     }
 
-    Ok(())
-}
+    fn stop_container(&self, name: &str) -> Result<()> {
+        // Use -t 0 to skip the graceful shutdown period. Our containers run
+        // `sleep infinity` which ignores SIGTERM anyway, so waiting is pointless.
+        // The status isn't checked because stopping an already-stopped container is
+        // fine - we just want it stopped.
+        self.run_quiet(&["stop", "-t", "0", name])?;
+        Ok(())
+    }
 
-/// Attach to a running container and execute a command.
-pub fn exec_in_container(
-    name: &str,
-    command: &[&str],
-    env_vars: &[(String, String)],
-) -> Result<()> {
-    use std::io::IsTerminal;
+    fn remove_container(&self, name: &str) -> Result<()> {
+        if !self.run_quiet(&["rm", "-f", name])? {
+            bail!("Failed to remove container: {}", name);
+        }
+        Ok(())
+    }
 
-    let mut args = vec!["exec".to_string()];
+    fn wait_container(&self, name: &str) -> Result<()> {
+        if !self.run_quiet(&["wait", name])? {
+            bail!("{} wait failed for container '{}'", self.program, name);
+        }
+        Ok(())
+    }
 
-    // Only use -it flags when stdin is a TTY
-    if std::io::stdin().is_terminal() {
-        args.push("-it".to_string());
+    fn container_image_ids(&self) -> Result<Vec<String>> {
+        let stdout = self.run_output(&["ps", "-aq"], "Failed to list containers")?;
+        let ids: Vec<&str> = stdout.lines().collect();
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut args = vec!["container", "inspect", "--format", "{{.Image}}"];
+        args.extend(ids);
+        let stdout = self.run_output(&args, "Failed to inspect containers")?;
+        Ok(stdout.lines().map(normalize_image_id).collect())
     }
 
-    for (k, v) in env_vars {
-        args.push("-e".to_string());
-        args.push(format!("{}={}", k, v));
+    fn exec(&self, container: &str, command: &[&str], options: &ExecOptions) -> Result<ExecOutput> {
+        let mut args = vec!["exec".to_string()];
+        if options.stdin.is_some() {
+            args.push("-i".to_string());
+        }
+        if let Some(user) = &options.user {
+            args.push("--user".to_string());
+            args.push(user.clone());
+        }
+        for (k, v) in &options.env {
+            args.push("-e".to_string());
+            args.push(format!("{}={}", k, v));
+        }
+        args.push(container.to_string());
+        args.extend(command.iter().map(|s| s.to_string()));
+
+        debug!("{} {}", self.program, args.join(" "));
+        let mut child = self
+            .command()
+            .args(&args)
+            .stdin(if options.stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Failed to exec in container")?;
+
+        // Written on a separate thread so that the command can't block on a full
+        // output pipe while we block on its stdin
+        let writer = options.stdin.clone().map(|data| {
+            let mut stdin = child.stdin.take().expect("stdin is piped");
+            // stdin is closed when dropped, signaling EOF
+            std::thread::spawn(move || stdin.write_all(&data))
+        });
+
+        let output = child
+            .wait_with_output()
+            .context("Failed to wait for exec")?;
+        if let Some(writer) = writer {
+            writer
+                .join()
+                .expect("stdin writer panicked")
+                .context("Failed to write to exec stdin")?;
+        }
+        Ok(ExecOutput {
+            exit_code: output.status.code(),
+            stdout: output.stdout,
+            stderr: output.stderr,
+        })
     }
 
-    args.push(name.to_string());
-    args.extend(command.iter().map(|s| s.to_string()));
+    fn exec_interactive(
+        &self,
+        container: &str,
+        command: &[&str],
+        env: &[(String, String)],
+    ) -> Result<Option<i32>> {
+        use std::io::IsTerminal;
 
-    let status = Command::new("docker")
-        .args(&args)
-        .status()
-        .context("Failed to exec in container")?;
+        let mut args = vec!["exec".to_string()];
 
-    if !status.success() {
-        bail!("Container exec failed");
-    }
+        // Only use -it flags when stdin is a TTY
+        if std::io::stdin().is_terminal() {
+            args.push("-it".to_string());
+        }
 
-    Ok(())
-}
+        for (k, v) in env {
+            args.push("-e".to_string());
+            args.push(format!("{}={}", k, v));
+        }
 
-/// Stop a running container. Silently succeeds if container is already stopped.
-pub fn stop_container(name: &str) -> Result<()> {
-    // Use -t 0 to skip the graceful shutdown period. Our containers run
-    // `sleep infinity` which ignores SIGTERM anyway, so waiting is pointless.
-    let status = Command::new("docker")
-        .args(["stop", "-t", "0", name])
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status()
-        .context("Failed to run docker stop")?;
-
-    // We don't check status.success() because stopping an already-stopped
-    // container is fine - we just want it stopped.
-    let _ = status;
-    Ok(())
-}
+        args.push(container.to_string());
+        args.extend(command.iter().map(|s| s.to_string()));
 
-/// Wait for a container to stop.
-pub fn wait_container(name: &str) -> Result<()> {
-    let status = Command::new("docker")
-        .args(["wait", name])
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status()
-        .context("Failed to run docker wait")?;
+        let status = self
+            .command()
+            .args(&args)
+            .status()
+            .context("Failed to exec in container")?;
+
+        Ok(status.code())
+    }
+
+    fn list_volumes(&self, prefix: &str) -> Result<Vec<String>> {
+        let stdout = self.run_output(
+            &[
+                "volume",
+                "ls",
+                "-q",
+                "--filter",
+                &format!("name={}", prefix),
+            ],
+            "Failed to list volumes",
+        )?;
+        // The name filter matches substrings
+        Ok(stdout
+            .lines()
+            .filter(|name| name.starts_with(prefix))
+            .map(String::from)
+            .collect())
+    }
 
-    if !status.success() {
-        anyhow::bail!("docker wait failed for container '{}'", name);
+    fn remove_volume(&self, name: &str) -> Result<()> {
+        if !self.run_quiet(&["volume", "rm", name])? {
+            bail!("Failed to remove volume: {}", name);
+        }
+        Ok(())
     }
-    Ok(())
 }
 
-/// List all containers with a specific label.
-pub fn list_containers_with_label(label: &str) -> Result<Vec<String>> {
-    let output = Command::new("docker")
-        .args([
-            "ps",
-            "-a",
-            "--filter",
-            &format!("label={}", label),
-            "--format",
-            "{{.Names}}",
-        ])
-        .output()
-        .context("Failed to list containers")?;
-
-    if !output.status.success() {
-        bail!("Failed to list containers");
-    }
-
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    Ok(stdout.lines().map(String::from).collect())
+fn spawn_line_reader<R: BufRead + Send + 'static>(
+    reader: R,
+    tx: mpsc::Sender<String>,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        for line in reader.lines() {
+            let Ok(line) = line else { break };
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    })
 }
@@ -0,0 +1,1245 @@
+//! Backend talking to the Docker Engine API over its unix socket, without the `docker` CLI.
+//!
+//! Requests are plain HTTP/1.1 on a fresh connection each. Exec sessions use the
+//! connection hijacking of `/exec/{id}/start`, with stdout and stderr multiplexed into
+//! frames unless a TTY is allocated.
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::debug;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use crate::backend::{
+    unpack_rootfs, BuildRequest, ContainerBackend, ContainerSpec, ContainerState, ExecOptions,
+    ExecOutput, ImageInfo, ImageSummary, MountSource,
+};
+use crate::build_context;
+
+/// API version requests are made against (Docker 20.10 and later).
+const API_VERSION: &str = "v1.41";
+
+/// Characters left unencoded in paths and query values. Image references keep their
+/// `/`, `:` and `@` so that the daemon's routes match them.
+const URL_SAFE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'/')
+    .remove(b':')
+    .remove(b'@');
+
+/// Size of the chunks streamed request bodies are sent in.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Name of the Dockerfile in the build context tarball.
+const TAR_DOCKERFILE: &str = ".sandbox.Dockerfile";
+
+/// A Docker daemon reached through its API socket.
+#[derive(Debug, Clone)]
+pub struct DockerApi {
+    socket: PathBuf,
+}
+
+/// Status line and framing headers of an API response.
+struct ResponseHead {
+    status: u16,
+    content_length: Option<usize>,
+    chunked: bool,
+}
+
+/// Body of an API request.
+enum Body<'a> {
+    Bytes(&'a [u8]),
+    /// Written by the function while the request is sent, with chunked transfer
+    /// encoding, so that large bodies don't have to be held in memory.
+    Stream(&'a mut dyn FnMut(&mut dyn Write) -> Result<()>),
+}
+
+/// Status and body of an API response.
+struct Response {
+    status: u16,
+    body: Box<dyn Read + Send>,
+}
+
+impl Response {
+    fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    fn bytes(mut self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.body
+            .read_to_end(&mut data)
+            .context("Failed to read Docker API response")?;
+        Ok(data)
+    }
+
+    fn json<T: DeserializeOwned>(self) -> Result<T> {
+        let data = self.bytes()?;
+        serde_json::from_slice(&data).context("Failed to parse Docker API response")
+    }
+
+    /// Fail with the daemon's error message unless the request succeeded.
+    fn check(self, what: &str) -> Result<Self> {
+        if self.is_success() {
+            return Ok(self);
+        }
+        let status = self.status;
+        let data = self.bytes().unwrap_or_default();
+        let message = serde_json::from_slice::<serde_json::Value>(&data)
+            .ok()
+            .and_then(|v| v["message"].as_str().map(String::from))
+            .unwrap_or_else(|| String::from_utf8_lossy(&data).trim().to_string());
+        bail!("{} (HTTP {}): {}", what, status, message)
+    }
+}
+
+impl DockerApi {
+    pub fn new(socket: PathBuf) -> Result<Self> {
+        if !socket.exists() {
+            bail!(
+                "Docker socket not found at {}. Is the Docker daemon running?",
+                socket.display()
+            );
+        }
+        Ok(DockerApi { socket })
+    }
+
+    /// The daemon socket from `$DOCKER_HOST`, or `/var/run/docker.sock` if it isn't set.
+    pub fn default_socket() -> Result<PathBuf> {
+        match std::env::var("DOCKER_HOST") {
+            Ok(host) if !host.is_empty() => match host.strip_prefix("unix://") {
+                Some(path) => Ok(PathBuf::from(path)),
+                None => bail!(
+                    "DOCKER_HOST={} is not a unix socket, which the docker-api backend requires",
+                    host
+                ),
+            },
+            _ => Ok(PathBuf::from("/var/run/docker.sock")),
+        }
+    }
+
+    fn connect(&self) -> Result<UnixStream> {
+        UnixStream::connect(&self.socket)
+            .with_context(|| format!("Failed to connect to {}", self.socket.display()))
+    }
+
+    /// Send a request and read the response headers. Returns the headers, the reader
+    /// positioned at the body and the connection's write half.
+    fn send(
+        &self,
+        method: &str,
+        path: &str,
+        content_type: Option<&str>,
+        body: Body,
+        upgrade: bool,
+    ) -> Result<(ResponseHead, BufReader<UnixStream>, UnixStream)> {
+        let mut stream = self.connect()?;
+        let mut head = format!(
+            "{} /{}{} HTTP/1.1\r\nHost: docker\r\n",
+            method, API_VERSION, path
+        );
+        match &body {
+            Body::Bytes(bytes) => head.push_str(&format!("Content-Length: {}\r\n", bytes.len())),
+            Body::Stream(_) => head.push_str("Transfer-Encoding: chunked\r\n"),
+        }
+        if let Some(content_type) = content_type {
+            head.push_str(&format!("Content-Type: {}\r\n", content_type));
+        }
+        if upgrade {
+            head.push_str("Connection: Upgrade\r\nUpgrade: tcp\r\n");
+        } else {
+            head.push_str("Connection: close\r\n");
+        }
+        head.push_str("\r\n");
+
+        debug!("Docker API: {} {}", method, path);
+        stream.write_all(head.as_bytes())?;
+        match body {
+            Body::Bytes(bytes) => stream.write_all(bytes)?,
+            Body::Stream(write_body) => {
+                let mut chunked = std::io::BufWriter::with_capacity(
+                    STREAM_CHUNK_SIZE,
+                    ChunkedWriter { inner: &mut stream },
+                );
+                write_body(&mut chunked)?;
+                chunked.into_inner().map_err(|e| e.into_error())?.finish()?;
+            }
+        }
+        stream.flush()?;
+
+        let writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+
+        let mut raw = Vec::new();
+        loop {
+            let n = reader.read_until(b'\n', &mut raw)?;
+            if n == 0 {
+                bail!("Docker API closed the connection before responding");
+            }
+            if raw.ends_with(b"\r\n\r\n") {
+                break;
+            }
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut response = httparse::Response::new(&mut headers);
+        response
+            .parse(&raw)
+            .context("Invalid HTTP response from Docker API")?;
+        let status = response.code.context("HTTP response without status")?;
+
+        let mut content_length = None;
+        let mut chunked = false;
+        for header in response.headers.iter() {
+            let value = String::from_utf8_lossy(header.value);
+            if header.name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().ok();
+            } else if header.name.eq_ignore_ascii_case("transfer-encoding") {
+                chunked = value.to_ascii_lowercase().contains("chunked");
+            }
+        }
+
+        let head = ResponseHead {
+            status,
+            content_length,
+            chunked,
+        };
+        Ok((head, reader, writer))
+    }
+
+    fn request_raw(
+        &self,
+        method: &str,
+        path: &str,
+        content_type: Option<&str>,
+        body: Body,
+    ) -> Result<Response> {
+        let (head, reader, _) = self.send(method, path, content_type, body, false)?;
+        let body: Box<dyn Read + Send> = if head.chunked {
+            Box::new(ChunkedReader::new(reader))
+        } else if let Some(length) = head.content_length {
+            Box::new(reader.take(length as u64))
+        } else {
+            Box::new(reader)
+        };
+        Ok(Response {
+            status: head.status,
+            body,
+        })
+    }
+
+    fn request(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<Response> {
+        match body {
+            Some(body) => self.request_raw(
+                method,
+                path,
+                Some("application/json"),
+                Body::Bytes(&serde_json::to_vec(body)?),
+            ),
+            None => self.request_raw(method, path, None, Body::Bytes(&[])),
+        }
+    }
+
+    /// Start a hijacked session: after the response headers, the connection carries
+    /// the raw stream of the exec session in both directions.
+    fn upgrade(
+        &self,
+        path: &str,
+        body: &serde_json::Value,
+    ) -> Result<(BufReader<UnixStream>, UnixStream)> {
+        let (head, reader, writer) = self.send(
+            "POST",
+            path,
+            Some("application/json"),
+            Body::Bytes(&serde_json::to_vec(body)?),
+            true,
+        )?;
+        if !(head.status == 101 || (200..300).contains(&head.status)) {
+            bail!("Failed to attach to exec session (HTTP {})", head.status);
+        }
+        Ok((reader, writer))
+    }
+
+    /// Create an exec session and return its ID.
+    fn create_exec(
+        &self,
+        container: &str,
+        command: &[&str],
+        user: Option<&str>,
+        env: &[(String, String)],
+        attach_stdin: bool,
+        tty: bool,
+    ) -> Result<String> {
+        #[derive(Deserialize)]
+        struct Created {
+            #[serde(rename = "Id")]
+            id: String,
+        }
+
+        let mut body = json!({
+            "AttachStdin": attach_stdin,
+            "AttachStdout": true,
+            "AttachStderr": true,
+            "Tty": tty,
+            "Cmd": command,
+            "Env": env.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>(),
+        });
+        if let Some(user) = user {
+            body["User"] = json!(user);
+        }
+
+        let created: Created = self
+            .request(
+                "POST",
+                &format!("/containers/{}/exec", encode(container)),
+                Some(&body),
+            )?
+            .check("Failed to exec in container")?
+            .json()?;
+        Ok(created.id)
+    }
+
+    /// Set the TTY size of an exec session to that of our terminal.
+    fn resize_exec(&self, exec_id: &str) {
+        if let Some((rows, cols)) = terminal_size() {
+            // Best effort: the session may have exited already
+            let _ = self.request(
+                "POST",
+                &format!("/exec/{}/resize?h={}&w={}", exec_id, rows, cols),
+                None,
+            );
+        }
+    }
+
+    fn exec_exit_code(&self, exec_id: &str) -> Result<Option<i32>> {
+        let inspect: serde_json::Value = self
+            .request("GET", &format!("/exec/{}/json", exec_id), None)?
+            .check("Failed to inspect exec session")?
+            .json()?;
+        Ok(inspect["ExitCode"].as_i64().map(|c| c as i32))
+    }
+
+    /// Create the overlay volume of a mount unless it exists. The local driver
+    /// mounts the overlayfs when the container starts.
+    fn ensure_overlay_volume(
+        &self,
+        name: &str,
+        lower: &Path,
+        upper: &Path,
+        work: &Path,
+    ) -> Result<()> {
+        let response = self.request("GET", &format!("/volumes/{}", encode(name)), None)?;
+        if response.is_success() {
+            return Ok(());
+        }
+
+        let body = json!({
+            "Name": name,
+            "Driver": "local",
+            "DriverOpts": {
+                "type": "overlay",
+                "device": "overlay",
+                "o": format!(
+                    "lowerdir={},upperdir={},workdir={}",
+                    lower.display(),
+                    upper.display(),
+                    work.display()
+                ),
+            },
+        });
+        self.request("POST", "/volumes/create", Some(&body))?
+            .check(&format!("Failed to create overlay volume '{}'", name))?;
+        Ok(())
+    }
+}
+
+impl ContainerBackend for DockerApi {
+    fn inspect_image(&self, reference: &str) -> Result<Option<ImageInfo>> {
+        #[derive(Deserialize)]
+        struct Inspect {
+            #[serde(rename = "Id")]
+            id: String,
+            #[serde(rename = "RepoDigests", default)]
+            repo_digests: Option<Vec<String>>,
+        }
+
+        let response = self.request("GET", &format!("/images/{}/json", encode(reference)), None)?;
+        if response.status == 404 {
+            return Ok(None);
+        }
+        let inspect: Inspect = response.check("Failed to inspect image")?.json()?;
+        Ok(Some(ImageInfo {
+            id: inspect.id,
+            repo_digests: inspect.repo_digests.unwrap_or_default(),
+        }))
+    }
+
+    fn list_images(&self, repository: &str) -> Result<Vec<ImageSummary>> {
+        #[derive(Deserialize)]
+        struct Image {
+            #[serde(rename = "Id")]
+            id: String,
+            #[serde(rename = "RepoTags", default)]
+            repo_tags: Option<Vec<String>>,
+            #[serde(rename = "Created")]
+            created: i64,
+            #[serde(rename = "Size")]
+            size: u64,
+            #[serde(rename = "Labels", default)]
+            labels: Option<BTreeMap<String, String>>,
+        }
+
+        let filters = json!({ "reference": [repository] }).to_string();
+        let mut images: Vec<Image> = self
+            .request(
+                "GET",
+                &format!("/images/json?filters={}", encode(&filters)),
+                None,
+            )?
+            .check("Failed to list images")?
+            .json()?;
+        images.sort_by_key(|image| std::cmp::Reverse(image.created));
+
+        let prefix = format!("{}:", repository);
+        let mut summaries = Vec::new();
+        for image in images {
+            let created_at = chrono::DateTime::from_timestamp(image.created, 0)
+                .map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+                .unwrap_or_default();
+            for tag in image.repo_tags.unwrap_or_default() {
+                if tag.starts_with(&prefix) {
+                    summaries.push(ImageSummary {
+                        id: image.id.clone(),
+                        reference: tag,
+                        created_at: created_at.clone(),
+                        size: format_size(image.size),
+                        labels: image.labels.clone().unwrap_or_default(),
+                    });
+                }
+            }
+        }
+        Ok(summaries)
+    }
+
+    fn remove_image(&self, reference: &str) -> Result<()> {
+        self.request("DELETE", &format!("/images/{}", encode(reference)), None)?
+            .check(&format!("Failed to remove image: {}", reference))?;
+        Ok(())
+    }
+
+    fn build_image(&self, request: &BuildRequest, output: &mut dyn FnMut(&str)) -> Result<()> {
+        let mut query = vec![
+            ("t", request.tag.clone()),
+            ("dockerfile", TAR_DOCKERFILE.to_string()),
+            ("buildargs", serde_json::to_string(&request.args)?),
+            ("labels", serde_json::to_string(&request.labels)?),
+            ("rm", "1".to_string()),
+        ];
+        if request.no_cache {
+            query.push(("nocache", "1".to_string()));
+        }
+        if let Some(target) = &request.target {
+            query.push(("target", target.clone()));
+        }
+        if let Some(platform) = &request.platform {
+            query.push(("platform", platform.clone()));
+        }
+        let query: Vec<String> = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, encode(v)))
+            .collect();
+
+        let mut write_context =
+            |out: &mut dyn Write| build_context_tar(&request.context, &request.dockerfile, out);
+        let response = self
+            .request_raw(
+                "POST",
+                &format!("/build?{}", query.join("&")),
+                Some("application/x-tar"),
+                Body::Stream(&mut write_context),
+            )?
+            .check("Failed to build image")?;
+
+        // The body is a stream of JSON messages, one per line
+        let mut error = None;
+        for line in BufReader::new(response.body).lines() {
+            let line = line.context("Failed to read build output")?;
+            let Ok(message) = serde_json::from_str::<serde_json::Value>(&line) else {
+                continue;
+            };
+            if let Some(stream) = message["stream"].as_str() {
+                for line in stream.lines() {
+                    output(line);
+                }
+            }
+            if let Some(status) = message["status"].as_str() {
+                output(status);
+            }
+            if let Some(message) = message["error"].as_str() {
+                output(message);
+                error = Some(message.to_string());
+            }
+        }
+
+        if let Some(error) = error {
+            bail!("{}", error);
+        }
+        Ok(())
+    }
+
+    fn export_image(&self, reference: &str, dest: &Path) -> Result<()> {
+        // Images are exported through a container created (but never started) from them
+        let created: serde_json::Value = self
+            .request(
+                "POST",
+                "/containers/create",
+                Some(&json!({ "Image": reference, "Cmd": ["true"] })),
+            )?
+            .check(&format!("Failed to create container from {}", reference))?
+            .json()?;
+        let id = created["Id"]
+            .as_str()
+            .context("Docker API returned no container ID")?
+            .to_string();
+
+        let result = self
+            .request("GET", &format!("/containers/{}/export", id), None)
+            .and_then(|response| response.check("Failed to export container"))
+            .and_then(|response| unpack_rootfs(response.body, dest));
+
+        self.request("DELETE", &format!("/containers/{}?force=true", id), None)?
+            .check("Failed to remove container")?;
+        result
+    }
+
+    fn run_container(&self, spec: &ContainerSpec) -> Result<()> {
+        let mut mounts = Vec::new();
+        for mount in &spec.mounts {
+            let target = mount.target.to_string_lossy();
+            mounts.push(match &mount.source {
+                MountSource::Bind { path, readonly } => json!({
+                    "Type": "bind",
+                    "Source": path.to_string_lossy(),
+                    "Target": target,
+                    "ReadOnly": readonly,
+                }),
+                MountSource::Overlay {
+                    lower,
+                    upper,
+                    work,
+                    volume_name,
+                } => {
+                    self.ensure_overlay_volume(volume_name, lower, upper, work)?;
+                    json!({
+                        "Type": "volume",
+                        "Source": volume_name,
+                        "Target": target,
+                    })
+                }
+            });
+        }
+
+        let body = json!({
+            "Hostname": spec.hostname,
+            "User": format!("{}:{}", spec.uid, spec.gid),
+            "WorkingDir": spec.workdir.to_string_lossy(),
+            "Env": spec.env.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>(),
+            "Cmd": spec.command,
+            "Image": spec.image,
+            "Labels": spec.labels,
+            "HostConfig": {
+                "Runtime": spec.runtime,
+                "Mounts": mounts,
+            },
+        });
+        self.request(
+            "POST",
+            &format!("/containers/create?name={}", encode(&spec.name)),
+            Some(&body),
+        )?
+        .check("Failed to create container")?;
+
+        self.request(
+            "POST",
+            &format!("/containers/{}/start", encode(&spec.name)),
+            None,
+        )?
+        .check("Failed to start container")?;
+        Ok(())
+    }
+
+    fn container_state(&self, name: &str) -> Result<ContainerState> {
+        let response = self.request("GET", &format!("/containers/{}/json", encode(name)), None)?;
+        if response.status == 404 {
+            return Ok(ContainerState::Missing);
+        }
+        let inspect: serde_json::Value = response.check("Failed to inspect container")?.json()?;
+        if inspect["State"]["Running"].as_bool() == Some(true) {
+            Ok(ContainerState::Running)
+        } else {
+            Ok(ContainerState::Stopped)
+        }
+    }
+
+    fn stop_container(&self, name: &str) -> Result<()> {
+        // Our containers run `sleep infinity` which ignores SIGTERM, so don't wait.
+        // Stopping a stopped (304) or missing (404) container is fine too.
+        self.request(
+            "POST",
+            &format!("/containers/{}/stop?t=0", encode(name)),
+            None,
+        )?
+        .bytes()?;
+        Ok(())
+    }
+
+    fn remove_container(&self, name: &str) -> Result<()> {
+        self.request(
+            "DELETE",
+            &format!("/containers/{}?force=true", encode(name)),
+            None,
+        )?
+        .check(&format!("Failed to remove container: {}", name))?;
+        Ok(())
+    }
+
+    fn wait_container(&self, name: &str) -> Result<()> {
+        self.request("POST", &format!("/containers/{}/wait", encode(name)), None)?
+            .check(&format!("Failed to wait for container '{}'", name))?
+            .bytes()?;
+        Ok(())
+    }
+
+    fn container_image_ids(&self) -> Result<Vec<String>> {
+        let containers: Vec<serde_json::Value> = self
+            .request("GET", "/containers/json?all=true", None)?
+            .check("Failed to list containers")?
+            .json()?;
+        Ok(containers
+            .iter()
+            .filter_map(|c| c["ImageID"].as_str().map(String::from))
+            .collect())
+    }
+
+    fn exec(&self, container: &str, command: &[&str], options: &ExecOptions) -> Result<ExecOutput> {
+        let exec_id = self.create_exec(
+            container,
+            command,
+            options.user.as_deref(),
+            &options.env,
+            options.stdin.is_some(),
+            false,
+        )?;
+        let (reader, mut writer) = self.upgrade(
+            &format!("/exec/{}/start", exec_id),
+            &json!({ "Detach": false, "Tty": false }),
+        )?;
+
+        // Write stdin on a separate thread so that a command producing a lot of output
+        // before consuming its input can't deadlock us
+        let stdin = options.stdin.clone();
+        let stdin_writer = std::thread::spawn(move || {
+            if let Some(data) = stdin {
+                let _ = writer.write_all(&data);
+            }
+            let _ = writer.shutdown(Shutdown::Write);
+        });
+
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        demux(reader, &mut stdout, &mut stderr)?;
+        let _ = stdin_writer.join();
+
+        Ok(ExecOutput {
+            exit_code: self.exec_exit_code(&exec_id)?,
+            stdout,
+            stderr,
+        })
+    }
+
+    fn exec_interactive(
+        &self,
+        container: &str,
+        command: &[&str],
+        env: &[(String, String)],
+    ) -> Result<Option<i32>> {
+        use std::io::IsTerminal;
+
+        let tty = std::io::stdin().is_terminal();
+        let exec_id = self.create_exec(container, command, None, env, true, tty)?;
+        let (mut reader, mut writer) = self.upgrade(
+            &format!("/exec/{}/start", exec_id),
+            &json!({ "Detach": false, "Tty": tty }),
+        )?;
+
+        let _raw_mode = if tty {
+            let guard = RawMode::enable()?;
+            self.resize_exec(&exec_id);
+            Some(guard)
+        } else {
+            None
+        };
+        let _resize = if tty {
+            Some(ResizeForwarder::start(self.clone(), exec_id.clone())?)
+        } else {
+            None
+        };
+
+        // Not joined: reading our stdin blocks until the next input, even after the
+        // session has ended
+        std::thread::spawn(move || {
+            let _ = std::io::copy(&mut std::io::stdin(), &mut writer);
+            let _ = writer.shutdown(Shutdown::Write);
+        });
+
+        if tty {
+            let mut stdout = std::io::stdout();
+            copy_flushing(&mut reader, &mut stdout)?;
+        } else {
+            demux(reader, &mut std::io::stdout(), &mut std::io::stderr())?;
+        }
+        drop(_resize);
+        drop(_raw_mode);
+
+        self.exec_exit_code(&exec_id)
+    }
+
+    fn list_volumes(&self, prefix: &str) -> Result<Vec<String>> {
+        let filters = json!({ "name": [prefix] }).to_string();
+        let response: serde_json::Value = self
+            .request(
+                "GET",
+                &format!("/volumes?filters={}", encode(&filters)),
+                None,
+            )?
+            .check("Failed to list volumes")?
+            .json()?;
+        // The name filter matches substrings
+        Ok(response["Volumes"]
+            .as_array()
+            .map(|volumes| {
+                volumes
+                    .iter()
+                    .filter_map(|v| v["Name"].as_str())
+                    .filter(|name| name.starts_with(prefix))
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn remove_volume(&self, name: &str) -> Result<()> {
+        self.request("DELETE", &format!("/volumes/{}", encode(name)), None)?
+            .check(&format!("Failed to remove volume: {}", name))?;
+        Ok(())
+    }
+}
+
+fn encode(s: &str) -> String {
+    utf8_percent_encode(s, URL_SAFE).to_string()
+}
+
+/// Format a size in bytes the way the docker CLI does (decimal units).
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "kB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.1}{}", size, UNITS[unit])
+    }
+}
+
+/// Split the multiplexed stream of a non-TTY exec session into stdout and stderr.
+/// Each frame is an 8-byte header (stream type, 3 zero bytes, big-endian payload
+/// length) followed by the payload.
+fn demux(mut reader: impl Read, stdout: &mut impl Write, stderr: &mut impl Write) -> Result<()> {
+    let mut header = [0u8; 8];
+    loop {
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e).context("Failed to read exec output"),
+        }
+        let length = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let mut payload = vec![0u8; length];
+        reader
+            .read_exact(&mut payload)
+            .context("Failed to read exec output")?;
+        match header[0] {
+            2 => {
+                stderr.write_all(&payload)?;
+                stderr.flush()?;
+            }
+            _ => {
+                stdout.write_all(&payload)?;
+                stdout.flush()?;
+            }
+        }
+    }
+}
+
+/// Copy a TTY stream, flushing after every read so that output appears immediately.
+fn copy_flushing(reader: &mut impl Read, writer: &mut impl Write) -> Result<()> {
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e).context("Failed to read exec output"),
+        };
+        writer.write_all(&buf[..n])?;
+        writer.flush()?;
+    }
+}
+
+/// Reader for a `Transfer-Encoding: chunked` body.
+struct ChunkedReader<R> {
+    inner: R,
+    /// Bytes left in the current chunk.
+    remaining: usize,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    fn new(inner: R) -> Self {
+        ChunkedReader {
+            inner,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    fn read_line(&mut self) -> std::io::Result<String> {
+        let mut line = String::new();
+        self.inner.read_line(&mut line)?;
+        Ok(line.trim_end().to_string())
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            let line = self.read_line()?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            self.remaining = usize::from_str_radix(size, 16).map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Invalid chunk size: {:?}", line),
+                )
+            })?;
+            if self.remaining == 0 {
+                // Trailers are not used by the Docker API
+                self.done = true;
+                return Ok(0);
+            }
+        }
+
+        let max = buf.len().min(self.remaining);
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= n;
+        if self.remaining == 0 {
+            // CRLF after the chunk data
+            self.read_line()?;
+        }
+        Ok(n)
+    }
+}
+
+/// Writer for a `Transfer-Encoding: chunked` body, one chunk per write.
+struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    /// Write the last, empty chunk.
+    fn finish(mut self) -> std::io::Result<()> {
+        self.inner.write_all(b"0\r\n\r\n")
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // An empty chunk would end the body
+        if !buf.is_empty() {
+            write!(self.inner, "{:x}\r\n", buf.len())?;
+            self.inner.write_all(buf)?;
+            self.inner.write_all(b"\r\n")?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Write the build context as a tarball to `out`, skipping files excluded by
+/// `.dockerignore`. The Dockerfile is added as `TAR_DOCKERFILE`, since it may live
+/// outside the context or be excluded itself.
+fn build_context_tar(context: &Path, dockerfile: &Path, out: &mut dyn Write) -> Result<()> {
+    let mut tar = TarWriter { out };
+
+    for relative in build_context::context_files(context)? {
+        let path = context.join(&relative);
+        let metadata = std::fs::symlink_metadata(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let name = relative.to_string_lossy();
+        if metadata.is_symlink() {
+            let target = std::fs::read_link(&path)?;
+            tar.symlink(&name, &target.to_string_lossy(), metadata.mtime())?;
+        } else {
+            let file = std::fs::File::open(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            tar.file(
+                &name,
+                metadata.permissions().mode(),
+                metadata.mtime(),
+                metadata.len(),
+                file,
+            )
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        }
+    }
+
+    let contents = std::fs::read(dockerfile)
+        .with_context(|| format!("Failed to read {}", dockerfile.display()))?;
+    tar.file(
+        TAR_DOCKERFILE,
+        0o644,
+        0,
+        contents.len() as u64,
+        &contents[..],
+    )?;
+
+    tar.finish()
+}
+
+/// Minimal writer for ustar archives with GNU long names.
+struct TarWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TarWriter<W> {
+    /// Add a file of `size` bytes, read from `contents`.
+    fn file(
+        &mut self,
+        name: &str,
+        mode: u32,
+        mtime: i64,
+        size: u64,
+        contents: impl Read,
+    ) -> Result<()> {
+        self.entry(name, b'0', "", mode, mtime, size, contents)
+    }
+
+    fn symlink(&mut self, name: &str, target: &str, mtime: i64) -> Result<()> {
+        self.entry(name, b'2', target, 0o777, mtime, 0, std::io::empty())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn entry(
+        &mut self,
+        name: &str,
+        kind: u8,
+        link: &str,
+        mode: u32,
+        mtime: i64,
+        size: u64,
+        contents: impl Read,
+    ) -> Result<()> {
+        if name.len() > 100 {
+            self.long_name(b'L', name)?;
+        }
+        if link.len() > 100 {
+            self.long_name(b'K', link)?;
+        }
+        self.header(name, kind, link, mode, mtime, size)?;
+        let copied = std::io::copy(&mut contents.take(size), &mut self.out)?;
+        if copied != size {
+            bail!(
+                "File shrank while being read ({} of {} bytes)",
+                copied,
+                size
+            );
+        }
+        self.pad(size)
+    }
+
+    /// GNU extension: the following entry's name (`L`) or link target (`K`).
+    fn long_name(&mut self, kind: u8, name: &str) -> Result<()> {
+        let mut contents = name.as_bytes().to_vec();
+        contents.push(0);
+        let size = contents.len() as u64;
+        self.header("././@LongLink", kind, "", 0o644, 0, size)?;
+        self.out.write_all(&contents)?;
+        self.pad(size)
+    }
+
+    fn header(
+        &mut self,
+        name: &str,
+        kind: u8,
+        link: &str,
+        mode: u32,
+        mtime: i64,
+        size: u64,
+    ) -> Result<()> {
+        fn put(header: &mut [u8], offset: usize, len: usize, value: &[u8]) {
+            let n = value.len().min(len);
+            header[offset..offset + n].copy_from_slice(&value[..n]);
+        }
+        fn octal(header: &mut [u8], offset: usize, len: usize, value: u64) {
+            let s = format!("{:0width$o}\0", value, width = len - 1);
+            put(header, offset, len, s.as_bytes());
+        }
+
+        let mut header = [0u8; 512];
+        put(&mut header, 0, 100, name.as_bytes());
+        octal(&mut header, 100, 8, (mode & 0o7777) as u64);
+        octal(&mut header, 108, 8, 0);
+        octal(&mut header, 116, 8, 0);
+        octal(&mut header, 124, 12, size);
+        octal(&mut header, 136, 12, mtime.max(0) as u64);
+        header[156] = kind;
+        put(&mut header, 157, 100, link.as_bytes());
+        put(&mut header, 257, 6, b"ustar\0");
+        put(&mut header, 263, 2, b"00");
+
+        // The checksum is computed with its own field set to spaces
+        header[148..156].fill(b' ');
+        let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+        put(
+            &mut header,
+            148,
+            8,
+            format!("{:06o}\0 ", checksum).as_bytes(),
+        );
+
+        self.out.write_all(&header)?;
+        Ok(())
+    }
+
+    /// Pad an entry of `size` bytes to a whole number of blocks.
+    fn pad(&mut self, size: u64) -> Result<()> {
+        let padding = (512 - size % 512) % 512;
+        self.out.write_all(&[0u8; 512][..padding as usize])?;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        // End of archive: two zero blocks
+        self.out.write_all(&[0u8; 1024])?;
+        Ok(())
+    }
+}
+
+/// Puts the terminal in raw mode while alive, so keystrokes go to the container as-is.
+struct RawMode {
+    original: nix::sys::termios::Termios,
+}
+
+impl RawMode {
+    fn enable() -> Result<Self> {
+        use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+
+        let stdin = std::io::stdin();
+        let original =
+            tcgetattr(&stdin).map_err(|e| anyhow!("Failed to get terminal mode: {}", e))?;
+        let mut raw = original.clone();
+        cfmakeraw(&mut raw);
+        tcsetattr(&stdin, SetArg::TCSANOW, &raw)
+            .map_err(|e| anyhow!("Failed to set terminal mode: {}", e))?;
+        Ok(RawMode { original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        use nix::sys::termios::{tcsetattr, SetArg};
+        let _ = tcsetattr(std::io::stdin(), SetArg::TCSANOW, &self.original);
+    }
+}
+
+/// Write end of the pipe through which the SIGWINCH handler reports window size changes.
+static RESIZE_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn notify_resize(_: libc::c_int) {
+    let fd = RESIZE_PIPE.load(Ordering::Relaxed);
+    if fd >= 0 {
+        // SAFETY: write is async-signal-safe. The pipe is non-blocking, and when it
+        // is full a change is already pending.
+        unsafe { libc::write(fd, [0u8].as_ptr().cast(), 1) };
+    }
+}
+
+/// Passes changes of the terminal's window size on to an exec session while alive.
+struct ResizeForwarder {
+    write: Option<OwnedFd>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl ResizeForwarder {
+    fn start(api: DockerApi, exec_id: String) -> Result<Self> {
+        use nix::fcntl::{fcntl, FcntlArg, OFlag};
+        use nix::sys::signal::{signal, SigHandler, Signal};
+
+        let (read, write) = nix::unistd::pipe().context("Failed to create pipe")?;
+        fcntl(&write, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+        RESIZE_PIPE.store(write.as_raw_fd(), Ordering::Relaxed);
+        // SAFETY: the handler only calls write
+        unsafe { signal(Signal::SIGWINCH, SigHandler::Handler(notify_resize)) }?;
+
+        let thread = std::thread::spawn(move || {
+            let mut read = std::fs::File::from(read);
+            let mut buf = [0u8; 64];
+            loop {
+                match read.read(&mut buf) {
+                    Ok(0) => return,
+                    Ok(_) => api.resize_exec(&exec_id),
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(_) => return,
+                }
+            }
+        });
+        Ok(ResizeForwarder {
+            write: Some(write),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for ResizeForwarder {
+    fn drop(&mut self) {
+        use nix::sys::signal::{signal, SigHandler, Signal};
+
+        // SAFETY: restores the default disposition
+        let _ = unsafe { signal(Signal::SIGWINCH, SigHandler::SigDfl) };
+        RESIZE_PIPE.store(-1, Ordering::Relaxed);
+        // Closing the write end ends the thread
+        drop(self.write.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Rows and columns of the terminal on stdout.
+fn terminal_size() -> Option<(u16, u16)> {
+    let mut size = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: TIOCGWINSZ writes a winsize struct through the pointer
+    let result = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    (result == 0 && size.ws_row > 0).then_some((size.ws_row, size.ws_col))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunked_reader() {
+        let body = b"5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\n\r\n";
+        let mut reader = ChunkedReader::new(&body[..]);
+        let mut out = String::new();
+        reader.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "hello, world");
+    }
+
+    #[test]
+    fn test_demux() {
+        let mut stream = Vec::new();
+        stream.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 3]);
+        stream.extend_from_slice(b"out");
+        stream.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 3]);
+        stream.extend_from_slice(b"err");
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        demux(&stream[..], &mut stdout, &mut stderr).unwrap();
+        assert_eq!(stdout, b"out");
+        assert_eq!(stderr, b"err");
+    }
+
+    #[test]
+    fn test_tar_entries() {
+        let mut data = Vec::new();
+        let mut tar = TarWriter { out: &mut data };
+        tar.file("a.txt", 0o644, 0, 2, &b"hi"[..]).unwrap();
+        tar.file(&"d/".repeat(60), 0o644, 0, 0, std::io::empty())
+            .unwrap();
+        tar.finish().unwrap();
+
+        // header + padded content, long name header + name block, header, end blocks
+        assert_eq!(data.len(), 512 * 2 + 512 * 2 + 512 + 1024);
+        assert_eq!(&data[..5], b"a.txt");
+        assert_eq!(&data[257..263], b"ustar\0");
+        assert_eq!(data[1024 + 156], b'L');
+        let checksum: u32 = data[..512]
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| {
+                if (148..156).contains(&i) {
+                    b' ' as u32
+                } else {
+                    b as u32
+                }
+            })
+            .sum();
+        let stored = std::str::from_utf8(&data[148..154]).unwrap();
+        assert_eq!(u32::from_str_radix(stored, 8).unwrap(), checksum);
+    }
+
+    #[test]
+    fn test_chunked_writer() {
+        let mut body = Vec::new();
+        let mut writer = ChunkedWriter { inner: &mut body };
+        writer.write_all(b"hello").unwrap();
+        writer.write_all(b"").unwrap();
+        writer.write_all(&[b'x'; 16]).unwrap();
+        writer.finish().unwrap();
+
+        let mut expected = b"5\r\nhello\r\n10\r\n".to_vec();
+        expected.extend_from_slice(&[b'x'; 16]);
+        expected.extend_from_slice(b"\r\n0\r\n\r\n");
+        assert_eq!(body, expected);
+
+        // What the response reader parses back
+        let mut decoded = Vec::new();
+        ChunkedReader::new(&body[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded.len(), 21);
+    }
+}
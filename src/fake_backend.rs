@@ -0,0 +1,234 @@
+//! Fake container backend for hermetic tests, used instead of the configured backend
+//! when `$SANDBOX_FAKE_BACKEND_DIR` is set. Only built with the `fake-backend` feature,
+//! which the integration tests enable.
+//!
+//! "Containers" are host processes in user namespaces (see [`crate::userns`]), with a
+//! root made of the host's top-level directories and the host network. The mounts
+//! are applied as a real backend would. Images are only recorded, not built. As the
+//! host filesystem stays visible, this provides no isolation, which is why it can't be
+//! selected in the config and is left out of normal builds.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::backend::{
+    BuildRequest, ContainerBackend, ContainerSpec, ContainerState, ExecOptions, ExecOutput,
+    ImageInfo, ImageSummary,
+};
+use crate::userns::{RootFs, RunOptions, UsernsContainers};
+
+/// Environment variable selecting the fake backend, with its state in the given directory.
+pub const STATE_DIR_ENV: &str = "SANDBOX_FAKE_BACKEND_DIR";
+
+/// A fake image, in `images/<tag>.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FakeImage {
+    id: String,
+    reference: String,
+    created_at: String,
+    /// `name@sha256:...` digests, for images recorded as pulled from a registry.
+    #[serde(default)]
+    repo_digests: Vec<String>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+/// Backend running containers as host processes in namespaces.
+#[derive(Debug, Clone)]
+pub struct FakeBackend {
+    state_dir: PathBuf,
+    containers: UsernsContainers,
+}
+
+impl FakeBackend {
+    /// The backend with state in `$SANDBOX_FAKE_BACKEND_DIR`, if it is set.
+    pub fn from_env() -> Option<Self> {
+        Some(Self::new(PathBuf::from(std::env::var_os(STATE_DIR_ENV)?)))
+    }
+
+    /// The backend with state in `state_dir`.
+    pub fn new(state_dir: PathBuf) -> Self {
+        FakeBackend {
+            containers: UsernsContainers::new(state_dir.join("containers")),
+            state_dir,
+        }
+    }
+
+    /// Run the container helpers with the given `sandbox` binary, so that tests can
+    /// use containers started by the daemon.
+    pub fn with_helper(self, helper: PathBuf) -> Self {
+        FakeBackend {
+            containers: self.containers.with_helper(helper),
+            ..self
+        }
+    }
+
+    /// Record an image as pulled from a registry as `reference`, with the registry's
+    /// `digest` for it. Returns the image ID.
+    pub fn record_pulled_image(&self, reference: &str, digest: &str) -> Result<String> {
+        let repository = reference
+            .rsplit_once(':')
+            .filter(|(_, tag)| !tag.contains('/'))
+            .map_or(reference, |(repository, _)| repository);
+        let image = FakeImage {
+            id: format!("sha256:{}", hex::encode(Sha256::digest(digest.as_bytes()))),
+            reference: reference.to_string(),
+            created_at: chrono::Utc::now().to_rfc3339(),
+            repo_digests: vec![format!("{}@{}", repository, digest)],
+            labels: BTreeMap::new(),
+        };
+        self.save_image(&image)?;
+        Ok(image.id)
+    }
+
+    fn save_image(&self, image: &FakeImage) -> Result<()> {
+        let path = self.image_path(&image.reference);
+        std::fs::create_dir_all(path.parent().expect("image path has a parent"))?;
+        std::fs::write(&path, serde_json::to_string_pretty(image)?)?;
+        Ok(())
+    }
+
+    fn image_path(&self, reference: &str) -> PathBuf {
+        self.state_dir
+            .join("images")
+            .join(format!("{}.json", reference.replace(['/', ':', '@'], "-")))
+    }
+
+    fn load_images(&self) -> Result<Vec<FakeImage>> {
+        let dir = self.state_dir.join("images");
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut images = Vec::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let contents = std::fs::read_to_string(entry?.path())?;
+            images.push(serde_json::from_str(&contents)?);
+        }
+        Ok(images)
+    }
+}
+
+impl ContainerBackend for FakeBackend {
+    fn inspect_image(&self, reference: &str) -> Result<Option<ImageInfo>> {
+        // Like docker, look images up by tag, repo digest or ID
+        let path = self.image_path(reference);
+        let image: Option<FakeImage> = if path.exists() {
+            Some(serde_json::from_str(&std::fs::read_to_string(&path)?)?)
+        } else {
+            self.load_images()?.into_iter().find(|image| {
+                image.id == reference || image.repo_digests.iter().any(|d| d == reference)
+            })
+        };
+        Ok(image.map(|image| ImageInfo {
+            id: image.id,
+            repo_digests: image.repo_digests,
+        }))
+    }
+
+    fn list_images(&self, repository: &str) -> Result<Vec<ImageSummary>> {
+        let prefix = format!("{}:", repository);
+        let mut images: Vec<FakeImage> = self
+            .load_images()?
+            .into_iter()
+            .filter(|image| image.reference.starts_with(&prefix))
+            .collect();
+        images.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        Ok(images
+            .into_iter()
+            .map(|image| ImageSummary {
+                id: image.id,
+                reference: image.reference,
+                created_at: image.created_at,
+                size: "0B".to_string(),
+                labels: image.labels,
+            })
+            .collect())
+    }
+
+    fn remove_image(&self, reference: &str) -> Result<()> {
+        std::fs::remove_file(self.image_path(reference))
+            .with_context(|| format!("Failed to remove image: {}", reference))
+    }
+
+    fn build_image(&self, request: &BuildRequest, output: &mut dyn FnMut(&str)) -> Result<()> {
+        output(&format!(
+            "Recording fake image {} for {}",
+            request.tag,
+            request.dockerfile.display()
+        ));
+        let image = FakeImage {
+            id: format!(
+                "sha256:{}",
+                hex::encode(Sha256::digest(request.tag.as_bytes()))
+            ),
+            reference: request.tag.clone(),
+            created_at: chrono::Utc::now().to_rfc3339(),
+            repo_digests: Vec::new(),
+            labels: request.labels.clone(),
+        };
+        self.save_image(&image)
+    }
+
+    fn export_image(&self, reference: &str, _dest: &Path) -> Result<()> {
+        bail!("Fake image {} has no filesystem to export", reference)
+    }
+
+    fn run_container(&self, spec: &ContainerSpec) -> Result<()> {
+        let image_id = match self.inspect_image(&spec.image)? {
+            Some(image) => image.id,
+            // Like a pull of a prebuilt image
+            None => spec.image.clone(),
+        };
+        let options = RunOptions {
+            root: RootFs::Host,
+            isolate_network: false,
+        };
+        self.containers.run(spec, &image_id, options)
+    }
+
+    fn container_state(&self, name: &str) -> Result<ContainerState> {
+        self.containers.state(name)
+    }
+
+    fn stop_container(&self, name: &str) -> Result<()> {
+        self.containers.stop(name)
+    }
+
+    fn remove_container(&self, name: &str) -> Result<()> {
+        self.containers.remove(name)
+    }
+
+    fn wait_container(&self, name: &str) -> Result<()> {
+        self.containers.wait(name)
+    }
+
+    fn container_image_ids(&self) -> Result<Vec<String>> {
+        self.containers.image_ids()
+    }
+
+    fn exec(&self, container: &str, command: &[&str], options: &ExecOptions) -> Result<ExecOutput> {
+        self.containers.exec(container, command, options)
+    }
+
+    fn exec_interactive(
+        &self,
+        container: &str,
+        command: &[&str],
+        env: &[(String, String)],
+    ) -> Result<Option<i32>> {
+        self.containers.exec_interactive(container, command, env)
+    }
+
+    // Overlays are mounted by the init process, no volumes are involved
+
+    fn list_volumes(&self, _prefix: &str) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    fn remove_volume(&self, name: &str) -> Result<()> {
+        bail!("No such volume: {}", name)
+    }
+}
@@ -155,6 +155,23 @@ pub fn fetch_branch(repo: &Path, remote: &str, branch: &str) -> Result<()> {
     Ok(())
 }
 
+/// Resolve a ref to a commit hash. Returns `None` if the ref doesn't exist.
+pub fn resolve_ref(repo: &Path, refname: &str) -> Result<Option<String>> {
+    let output = Command::new("git")
+        .current_dir(repo)
+        .args(["rev-parse", "--verify", "--quiet", refname])
+        .output()
+        .context("Failed to run git rev-parse")?;
+
+    if !output.status.success() {
+        return Ok(None);
+    }
+
+    Ok(Some(
+        String::from_utf8_lossy(&output.stdout).trim().to_string(),
+    ))
+}
+
 /// Ensure the meta.git bare repository exists.
 /// Creates a bare clone of the host repo if it doesn't exist.
 /// Returns true if a new meta.git was created, false if it already existed.
@@ -0,0 +1,399 @@
+//! Hooks: commands run inside the sandbox container after it starts, and commands
+//! run on the host on sandbox lifecycle events.
+
+use anyhow::{bail, Context, Result};
+use log::{debug, error, info, warn};
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use serde::Serialize;
+use std::io::{Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use strum::Display;
+
+use crate::backend::{Container, ContainerBackend};
+use crate::sandbox::SandboxInfo;
+use crate::sandbox_config::{HooksConfig, HostHooksConfig};
+
+/// How long a host hook may run when the config sets no timeout.
+const DEFAULT_HOST_HOOK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Run the post-create hooks (unless they already completed for this sandbox) and
+/// then the post-start hooks. Completion of the post-create hooks is recorded in
+/// `sandbox.json`. Output of all hooks is appended to the sandbox's hook log.
+pub fn run_container_hooks(
+    backend: &dyn ContainerBackend,
+    info: &mut SandboxInfo,
+    hooks: &HooksConfig,
+) -> Result<()> {
+    if info.post_create_completed_at.is_none() {
+        for command in &hooks.post_create {
+            run_hook(backend, info, "post-create", command)?;
+        }
+        info.post_create_completed_at = Some(chrono::Utc::now().to_rfc3339());
+        info.save()?;
+    }
+
+    for command in &hooks.post_start {
+        run_hook(backend, info, "post-start", command)?;
+    }
+
+    Ok(())
+}
+
+/// Run a single hook command in the container with `sh -c`, logging its output.
+fn run_hook(
+    backend: &dyn ContainerBackend,
+    info: &SandboxInfo,
+    kind: &str,
+    command: &str,
+) -> Result<()> {
+    let log_path = info.hooks_log_path();
+    let mut log = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .with_context(|| format!("Failed to open hook log: {}", log_path.display()))?;
+    writeln!(
+        log,
+        "==> {} {} hook: {}",
+        chrono::Utc::now().to_rfc3339(),
+        kind,
+        command
+    )?;
+
+    info!(
+        "Running {} hook in {}: {}",
+        kind, info.container_name, command
+    );
+
+    let output = Container::new(backend, &info.container_name)
+        .exec(&["sh", "-c", command])
+        .with_context(|| format!("Failed to run {} hook", kind))?;
+    log.write_all(&output.stdout)?;
+    log.write_all(&output.stderr)?;
+
+    let status = match output.exit_code {
+        Some(code) => format!("exit status: {}", code),
+        None => "killed by signal".to_string(),
+    };
+    writeln!(log, "==> {}", status)?;
+
+    if !output.success() {
+        bail!(
+            "{} hook failed ({}): {}\nSee {} for its output",
+            kind,
+            status,
+            command,
+            log_path.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// A sandbox lifecycle event passed to host hooks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Display)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum HostEvent {
+    Create,
+    Start,
+    Stop,
+    Delete,
+    BranchUpdate,
+}
+
+/// JSON payload written to the stdin of host hooks.
+#[derive(Debug, Clone, Serialize)]
+pub struct HostEventPayload {
+    pub event: HostEvent,
+    pub sandbox: String,
+    pub repo_root: PathBuf,
+    pub container: String,
+    pub branch: String,
+    /// Branch commit before the update (`branch-update` only, `None` for a new branch).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_commit: Option<String>,
+    /// Branch commit after the update (`branch-update` only).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_commit: Option<String>,
+    pub timestamp: String,
+    /// The sandbox's hook log, which host hook runs are appended to.
+    #[serde(skip)]
+    pub log_path: PathBuf,
+}
+
+impl HostEventPayload {
+    pub fn new(event: HostEvent, info: &SandboxInfo) -> Self {
+        HostEventPayload {
+            event,
+            sandbox: info.name.clone(),
+            repo_root: info.repo_root.clone(),
+            container: info.container_name.clone(),
+            branch: info.name.clone(),
+            old_commit: None,
+            new_commit: None,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            log_path: info.hooks_log_path(),
+        }
+    }
+}
+
+impl HostHooksConfig {
+    fn commands(&self, event: HostEvent) -> &[String] {
+        match event {
+            HostEvent::Create => &self.on_create,
+            HostEvent::Start => &self.on_start,
+            HostEvent::Stop => &self.on_stop,
+            HostEvent::Delete => &self.on_delete,
+            HostEvent::BranchUpdate => &self.on_branch_update,
+        }
+    }
+
+    fn hook_timeout(&self) -> Duration {
+        self.timeout
+            .map_or(DEFAULT_HOST_HOOK_TIMEOUT, Duration::from_secs)
+    }
+}
+
+/// Runs host hooks on a background thread, so that slow hooks don't hold up the
+/// daemon. Events are handled one at a time, in the order they were fired.
+#[derive(Debug, Clone)]
+pub struct HostHookQueue {
+    tx: Sender<(Vec<String>, Duration, HostEventPayload)>,
+}
+
+impl HostHookQueue {
+    pub fn spawn() -> Self {
+        let (tx, rx) = mpsc::channel::<(Vec<String>, Duration, HostEventPayload)>();
+        // The thread exits once every handle to the queue is dropped
+        thread::spawn(move || {
+            for (commands, timeout, payload) in rx {
+                run_host_hook_commands(&commands, timeout, &payload);
+            }
+        });
+        HostHookQueue { tx }
+    }
+
+    /// Queue the host hooks for an event. Failures are logged, not returned.
+    pub fn fire(&self, hooks: &HostHooksConfig, payload: HostEventPayload) {
+        let commands = hooks.commands(payload.event).to_vec();
+        if !commands.is_empty() {
+            let _ = self.tx.send((commands, hooks.hook_timeout(), payload));
+        }
+    }
+}
+
+/// Run the host hooks for an event, waiting for them to finish. Failures are
+/// logged, not returned.
+pub fn run_host_hooks(hooks: &HostHooksConfig, payload: &HostEventPayload) {
+    run_host_hook_commands(hooks.commands(payload.event), hooks.hook_timeout(), payload);
+}
+
+fn run_host_hook_commands(commands: &[String], timeout: Duration, payload: &HostEventPayload) {
+    for command in commands {
+        if let Err(e) = run_host_hook(command, timeout, payload) {
+            error!("{} host hook failed: {:#}", payload.event, e);
+        }
+    }
+}
+
+/// Run a single host hook with `sh -c` in the repo directory, passing the event on stdin.
+/// After `timeout`, the hook is killed along with the processes it started. The run is
+/// appended to the sandbox's hook log.
+fn run_host_hook(command: &str, timeout: Duration, payload: &HostEventPayload) -> Result<()> {
+    info!(
+        "Running {} host hook for {}: {}",
+        payload.event, payload.sandbox, command
+    );
+
+    let mut child = Command::new("sh")
+        .args(["-c", command])
+        .current_dir(&payload.repo_root)
+        .env("SANDBOX_EVENT", payload.event.to_string())
+        .env("SANDBOX_NAME", &payload.sandbox)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // In a process group of its own, so that it can be killed as a whole
+        .process_group(0)
+        .spawn()
+        .context("Failed to run host hook")?;
+    let started = Instant::now();
+
+    let json = serde_json::to_string(payload)?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // The hook may not read stdin at all, so a broken pipe is fine
+        let _ = writeln!(stdin, "{}", json);
+    }
+    let stdout = read_in_background(child.stdout.take().expect("stdout is piped"));
+    let stderr = read_in_background(child.stderr.take().expect("stderr is piped"));
+
+    let mut timed_out = false;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if started.elapsed() >= timeout {
+            timed_out = true;
+            let _ = killpg(Pid::from_raw(child.id() as i32), Signal::SIGKILL);
+            break child.wait()?;
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    let stdout = String::from_utf8_lossy(&stdout.join().unwrap_or_default()).into_owned();
+    let stderr = String::from_utf8_lossy(&stderr.join().unwrap_or_default()).into_owned();
+    if !stdout.trim().is_empty() || !stderr.trim().is_empty() {
+        debug!(
+            "Host hook output:\n{}{}",
+            stdout.trim_end(),
+            stderr.trim_end()
+        );
+    }
+
+    let result = if timed_out {
+        format!("timed out after {} seconds, killed", timeout.as_secs())
+    } else {
+        status.to_string()
+    };
+    append_to_hook_log(
+        &payload.log_path,
+        &format!(
+            "==> {} {} host hook: {}\n{}{}==> {}\n",
+            chrono::Utc::now().to_rfc3339(),
+            payload.event,
+            command,
+            stdout,
+            stderr,
+            result
+        ),
+    );
+
+    if timed_out {
+        bail!("'{}' {}", command, result);
+    }
+    if !status.success() {
+        bail!("'{}' exited with {}: {}", command, status, stderr.trim());
+    }
+
+    Ok(())
+}
+
+/// Read a hook's output on a thread, so that a full pipe doesn't block it.
+fn read_in_background(mut pipe: impl Read + Send + 'static) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = pipe.read_to_end(&mut output);
+        output
+    })
+}
+
+/// Append an entry to a sandbox's hook log, unless the sandbox is gone (as it is for
+/// `delete` events).
+fn append_to_hook_log(path: &Path, entry: &str) {
+    if !path.parent().is_some_and(Path::exists) {
+        return;
+    }
+    let appended = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut log| log.write_all(entry.as_bytes()));
+    if let Err(e) = appended {
+        warn!("Failed to write hook log {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_host_hook_queue_keeps_order() {
+        let dir = tempdir().unwrap();
+        let events = dir.path().join("events");
+        let hooks = HostHooksConfig {
+            // The slow first hook must not be overtaken by the second event
+            on_create: vec![format!(
+                "sleep 0.2; echo $SANDBOX_EVENT >> {}",
+                events.display()
+            )],
+            on_start: vec![format!("echo $SANDBOX_EVENT >> {}", events.display())],
+            ..Default::default()
+        };
+        let payload = |event| HostEventPayload {
+            event,
+            sandbox: "test".to_string(),
+            repo_root: dir.path().to_path_buf(),
+            container: "sandbox-test".to_string(),
+            branch: "test".to_string(),
+            old_commit: None,
+            new_commit: None,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            log_path: dir.path().join("hooks.log"),
+        };
+
+        let queue = HostHookQueue::spawn();
+        queue.fire(&hooks, payload(HostEvent::Create));
+        queue.fire(&hooks, payload(HostEvent::Start));
+
+        let start = Instant::now();
+        let read = || std::fs::read_to_string(&events).unwrap_or_default();
+        while read().lines().count() < 2 {
+            assert!(start.elapsed() < Duration::from_secs(10), "Timed out");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(read(), "create\nstart\n");
+    }
+
+    #[test]
+    fn test_host_hook_timeout_kills_process_group() {
+        let dir = tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+        let payload = HostEventPayload {
+            event: HostEvent::Start,
+            sandbox: "test".to_string(),
+            repo_root: dir.path().to_path_buf(),
+            container: "sandbox-test".to_string(),
+            branch: "test".to_string(),
+            old_commit: None,
+            new_commit: None,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            log_path: dir.path().join("hooks.log"),
+        };
+
+        // The hook waits for a process it started in the background
+        let command = format!("sleep 60 & echo $! > {}; wait", pid_file.display());
+        let start = Instant::now();
+        let result = run_host_hook(&command, Duration::from_secs(1), &payload);
+        assert!(start.elapsed() < Duration::from_secs(10));
+        let error = format!("{:#}", result.unwrap_err());
+        assert!(error.contains("timed out after 1 seconds"), "{}", error);
+
+        // The background process was killed too (at most a zombie is left)
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let stat_path = format!("/proc/{}/stat", pid.trim());
+        let killed =
+            || std::fs::read_to_string(&stat_path).map_or(true, |stat| stat.contains(") Z "));
+        let start = Instant::now();
+        while !killed() {
+            assert!(start.elapsed() < Duration::from_secs(10), "Not killed");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        let log = std::fs::read_to_string(dir.path().join("hooks.log")).unwrap();
+        assert!(log.contains("start host hook: sleep 60"), "{}", log);
+        assert!(
+            log.ends_with("==> timed out after 1 seconds, killed\n"),
+            "{}",
+            log
+        );
+    }
+}
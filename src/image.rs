@@ -0,0 +1,518 @@
+//! Resolving, building and pruning the images sandboxes run on.
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::backend::{BuildRequest, ContainerBackend, ImageSummary};
+use crate::build_context;
+use crate::config::{get_cache_dir, hash_file, hash_path, UserInfo};
+use crate::sandbox;
+use crate::sandbox_config::{ImageConfig, SandboxConfig};
+
+/// Repository all built images are tagged in.
+const IMAGE_REPOSITORY: &str = "sandbox";
+
+/// Label of built images holding the hash (see [`hash_path`]) of the repository they
+/// were built for.
+const REPO_LABEL: &str = "sandbox.repo";
+
+/// Inputs of an image build.
+#[derive(Debug, Clone)]
+pub struct BuildOptions {
+    /// Repository the image is built for, recorded in its [`REPO_LABEL`] label.
+    pub repo_root: PathBuf,
+    pub dockerfile: PathBuf,
+    pub context: PathBuf,
+    /// Build args, in addition to `USER_NAME`, `USER_ID` and `GROUP_ID` (which they may override).
+    pub args: BTreeMap<String, String>,
+    /// Stage to build in a multi-stage Dockerfile.
+    pub target: Option<String>,
+    /// Target platform, e.g. `linux/amd64`.
+    pub platform: Option<String>,
+}
+
+impl BuildOptions {
+    /// All build args passed to the build, including the user args.
+    fn build_args(&self, user_info: &UserInfo) -> BTreeMap<String, String> {
+        let mut args = BTreeMap::from([
+            ("USER_NAME".to_string(), user_info.username.clone()),
+            ("USER_ID".to_string(), user_info.uid.to_string()),
+            ("GROUP_ID".to_string(), user_info.gid.to_string()),
+        ]);
+        args.extend(self.args.clone());
+        args
+    }
+
+    /// The tag `build_image` would give the image, without building it.
+    pub fn image_tag(&self, user_info: &UserInfo) -> Result<String> {
+        image_tag_for_build(self, &self.build_args(user_info))
+    }
+}
+
+/// Compute the tag for an image built with the given options.
+/// The tag changes whenever any input that could affect the image changes: the
+/// Dockerfile, build args, target, platform and the contents of the build context.
+pub fn image_tag_for_build(
+    options: &BuildOptions,
+    build_args: &BTreeMap<String, String>,
+) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(hash_file(&options.dockerfile)?.as_bytes());
+    hasher.update(b"\n");
+    for (key, value) in build_args {
+        hasher.update(format!("arg {}={}\n", key, value).as_bytes());
+    }
+    if let Some(target) = &options.target {
+        hasher.update(format!("target {}\n", target).as_bytes());
+    }
+    if let Some(platform) = &options.platform {
+        hasher.update(format!("platform {}\n", platform).as_bytes());
+    }
+    hasher.update(
+        build_context::hash_build_context(&options.context, &options.dockerfile)?.as_bytes(),
+    );
+
+    let hash = hasher.finalize();
+    Ok(format!("{}:{}", IMAGE_REPOSITORY, hex::encode(&hash[..16])))
+}
+
+/// Determine how to build the image for a config.
+/// Returns `None` if the config uses a prebuilt image tag.
+pub fn build_options(repo_root: &Path, config: &SandboxConfig) -> Result<Option<BuildOptions>> {
+    match &config.image {
+        Some(ImageConfig::Tag(_)) => Ok(None),
+        Some(ImageConfig::Devcontainer(path)) => {
+            bail!("devcontainer image {} was not resolved", path.display())
+        }
+        Some(ImageConfig::Build {
+            dockerfile,
+            context,
+            args,
+            target,
+            platform,
+        }) => {
+            let dockerfile_path = repo_root.join(dockerfile);
+            if !dockerfile_path.exists() {
+                bail!("Dockerfile not found at {}", dockerfile_path.display());
+            }
+            let context_path = context
+                .as_ref()
+                .map(|p| repo_root.join(p))
+                .unwrap_or_else(|| repo_root.to_path_buf());
+            Ok(Some(BuildOptions {
+                repo_root: repo_root.to_path_buf(),
+                dockerfile: dockerfile_path,
+                context: context_path,
+                args: args.clone(),
+                target: target.clone(),
+                platform: platform.clone(),
+            }))
+        }
+        None => {
+            // Default: look for Dockerfile in repo root
+            let dockerfile_path = repo_root.join("Dockerfile");
+            if !dockerfile_path.exists() {
+                bail!(
+                    "No Dockerfile found at {}.\n\
+                     Either create a Dockerfile or specify an image in .sandbox.toml:\n\n\
+                     [image]\n\
+                     tag = \"your-image:tag\"\n",
+                    dockerfile_path.display()
+                );
+            }
+            Ok(Some(BuildOptions {
+                repo_root: repo_root.to_path_buf(),
+                dockerfile: dockerfile_path,
+                context: repo_root.to_path_buf(),
+                args: BTreeMap::new(),
+                target: None,
+                platform: None,
+            }))
+        }
+    }
+}
+
+/// Resolve the image tag from config, building if necessary.
+/// With `force_rebuild`, the image is rebuilt even if it already exists.
+pub fn resolve_image_tag(
+    backend: &dyn ContainerBackend,
+    repo_root: &Path,
+    config: &SandboxConfig,
+    user_info: &UserInfo,
+    force_rebuild: bool,
+) -> Result<String> {
+    if let Some(ImageConfig::Tag(tag)) = &config.image {
+        if force_rebuild {
+            bail!(
+                "The image is configured as the prebuilt tag '{}', there is nothing to rebuild",
+                tag
+            );
+        }
+        return match split_image_digest(tag) {
+            (reference, Some(digest)) => verify_image_digest(backend, reference, digest),
+            (_, None) => Ok(tag.clone()),
+        };
+    }
+
+    let options = build_options(repo_root, config)?.expect("not a prebuilt tag");
+    build_image(backend, &options, user_info, force_rebuild)
+}
+
+/// Build an image from a Dockerfile.
+/// The image is tagged with a hash of all build inputs (see `image_tag_for_build`).
+/// An existing image with that tag is reused unless `force` is set, in which case it is
+/// rebuilt without layer cache.
+/// Returns the image tag.
+pub fn build_image(
+    backend: &dyn ContainerBackend,
+    options: &BuildOptions,
+    user_info: &UserInfo,
+    force: bool,
+) -> Result<String> {
+    let build_args = options.build_args(user_info);
+    let image_tag = image_tag_for_build(options, &build_args)?;
+
+    // Check if image already exists
+    if !force && backend.image_exists(&image_tag)? {
+        debug!("Using existing image: {}", image_tag);
+        return Ok(image_tag);
+    }
+
+    info!("Building image: {}", image_tag);
+
+    let request = BuildRequest {
+        dockerfile: options.dockerfile.clone(),
+        context: options.context.clone(),
+        tag: image_tag.clone(),
+        args: build_args,
+        target: options.target.clone(),
+        platform: options.platform.clone(),
+        no_cache: force,
+        labels: BTreeMap::from([(REPO_LABEL.to_string(), hash_path(&options.repo_root))]),
+    };
+    let log_path = build_log_path(&image_tag)?;
+    run_build(backend, &request, &log_path)?;
+
+    Ok(image_tag)
+}
+
+/// Split an image reference pinned to a digest (`name:tag@sha256:...`) into the
+/// reference and the digest. Returns `None` as digest for unpinned references.
+pub fn split_image_digest(reference: &str) -> (&str, Option<&str>) {
+    match reference.split_once('@') {
+        Some((name, digest)) => (name, Some(digest)),
+        None => (reference, None),
+    }
+}
+
+/// Verify that the local image `reference` has the given digest, which may be either
+/// the image ID or one of its repo digests. Returns the image ID, which should be used
+/// to start containers so that the verified image can't be swapped afterwards.
+///
+/// Without a tag (`name@sha256:...`), the reference doesn't name an image by itself:
+/// the image is the one with that repo digest, or that ID.
+pub fn verify_image_digest(
+    backend: &dyn ContainerBackend,
+    reference: &str,
+    digest: &str,
+) -> Result<String> {
+    let image = if has_tag(reference) {
+        backend.inspect_image(reference)?
+    } else {
+        match backend.inspect_image(&format!("{}@{}", reference, digest))? {
+            Some(image) => Some(image),
+            None => backend.inspect_image(digest)?,
+        }
+    };
+    let Some(image) = image else {
+        bail!(
+            "Image '{}' not found locally. Pull it with `docker pull {}@{}`",
+            reference,
+            reference,
+            digest
+        );
+    };
+
+    let matches = image.id == digest
+        || image
+            .repo_digests
+            .iter()
+            .any(|d| d.rsplit_once('@').map(|(_, d)| d) == Some(digest));
+    if !matches {
+        bail!(
+            "Image '{}' does not match the pinned digest {}.\n\
+             Local image ID: {}\n\
+             Local repo digests: {}",
+            reference,
+            digest,
+            image.id,
+            if image.repo_digests.is_empty() {
+                "(none)".to_string()
+            } else {
+                image.repo_digests.join(", ")
+            }
+        );
+    }
+
+    debug!("Verified image {} against digest {}", reference, digest);
+    Ok(image.id)
+}
+
+/// Whether an image reference has a tag. The last path component is checked, as the
+/// registry host may have a port.
+fn has_tag(reference: &str) -> bool {
+    reference
+        .rsplit('/')
+        .next()
+        .is_some_and(|name| name.contains(':'))
+}
+
+/// Number of log lines shown when a build fails.
+const BUILD_LOG_TAIL_LINES: usize = 30;
+
+/// Path of the build log for an image tag: `$XDG_CACHE_HOME/sandbox/build-logs/<tag>.log`.
+pub fn build_log_path(image_tag: &str) -> Result<PathBuf> {
+    Ok(get_cache_dir()?
+        .join("build-logs")
+        .join(format!("{}.log", image_tag.replace([':', '/'], "-"))))
+}
+
+/// Run a build, streaming its output to stderr and to a log file.
+/// On a terminal, a status line with a spinner and the elapsed time is kept below the
+/// output. On failure, the error includes the last lines of the log.
+fn run_build(
+    backend: &dyn ContainerBackend,
+    request: &BuildRequest,
+    log_path: &Path,
+) -> Result<()> {
+    use std::io::{IsTerminal, Write};
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    if let Some(parent) = log_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut log = std::fs::File::create(log_path)
+        .with_context(|| format!("Failed to create build log: {}", log_path.display()))?;
+
+    let interactive = std::io::stderr().is_terminal();
+    let spinner = ['|', '/', '-', '\\'];
+    let start = Instant::now();
+    let mut tick = 0;
+    let mut out = std::io::stderr();
+
+    // The build runs on its own thread so that the status line keeps ticking while
+    // there's no output
+    let result = std::thread::scope(|scope| -> Result<Result<()>> {
+        let (tx, rx) = mpsc::channel::<String>();
+        let build = scope.spawn(move || {
+            backend.build_image(request, &mut |line| {
+                let _ = tx.send(line.to_string());
+            })
+        });
+
+        loop {
+            match rx.recv_timeout(Duration::from_millis(100)) {
+                Ok(line) => {
+                    writeln!(log, "{}", line)?;
+                    if interactive {
+                        write!(out, "\r\x1b[K")?;
+                    }
+                    writeln!(out, "{}", line)?;
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+            if interactive {
+                tick += 1;
+                write!(
+                    out,
+                    "\r\x1b[K{} Building image {} ({}s)",
+                    spinner[tick % spinner.len()],
+                    request.tag,
+                    start.elapsed().as_secs()
+                )?;
+                out.flush()?;
+            }
+        }
+        if interactive {
+            write!(out, "\r\x1b[K")?;
+        }
+
+        Ok(build.join().expect("build thread panicked"))
+    })?;
+
+    if let Err(e) = result {
+        let contents = std::fs::read_to_string(log_path).unwrap_or_default();
+        let lines: Vec<&str> = contents.lines().collect();
+        let tail = &lines[lines.len().saturating_sub(BUILD_LOG_TAIL_LINES)..];
+        bail!(
+            "Image build failed ({:#}). Last lines of {}:\n{}",
+            e,
+            log_path.display(),
+            tail.join("\n")
+        );
+    }
+
+    info!(
+        "Built image {} in {}s (log: {})",
+        request.tag,
+        start.elapsed().as_secs(),
+        log_path.display()
+    );
+    Ok(())
+}
+
+/// Image tags the config of a repo currently resolves to, for the base config and
+/// every profile. Does not build anything.
+fn configured_image_tags(repo_root: &Path, user_info: &UserInfo) -> Result<Vec<String>> {
+    let base = SandboxConfig::load(repo_root)?;
+    let mut configs = vec![base.clone()];
+    for name in base.profile.keys() {
+        configs.push(base.clone().with_profile(Some(name))?);
+    }
+
+    let mut tags = Vec::new();
+    for config in &configs {
+        if let Some(options) = build_options(repo_root, config)? {
+            tags.push(options.image_tag(user_info)?);
+        }
+    }
+    Ok(tags)
+}
+
+/// What `prune_images` decided for a built image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PruneDecision {
+    /// Still referenced; the reason says by what.
+    InUse(String),
+    /// Not referenced, but among the most recent images kept by `--keep`.
+    Kept,
+    Removed,
+}
+
+/// Remove images built by `build_image` that are neither referenced by the current
+/// config of a known repo nor used by an existing container. The images of repos whose
+/// config can't be read are retained, as are those built before images were labeled
+/// with their repo if there is any such repo. The `keep` most recent unreferenced
+/// images are retained. With `dry_run`, nothing is removed.
+pub fn prune_images(
+    backend: &dyn ContainerBackend,
+    keep: usize,
+    dry_run: bool,
+) -> Result<Vec<(ImageSummary, PruneDecision)>> {
+    let user_info = UserInfo::current()?;
+
+    let mut referenced: BTreeMap<String, String> = BTreeMap::new();
+    // Repos whose config can't be read, by the hash their images are labeled with
+    let mut unreadable: BTreeMap<String, PathBuf> = BTreeMap::new();
+    for repo_root in sandbox::known_repo_roots()? {
+        if !repo_root.exists() {
+            continue;
+        }
+        match configured_image_tags(&repo_root, &user_info) {
+            Ok(tags) => {
+                for tag in tags {
+                    referenced
+                        .entry(tag)
+                        .or_insert_with(|| format!("config of {}", repo_root.display()));
+                }
+            }
+            // Don't remove anything that might belong to a repo we can't inspect
+            Err(e) => {
+                warn!(
+                    "Keeping the images of {}, as its image can't be determined: {:#}",
+                    repo_root.display(),
+                    e
+                );
+                unreadable.insert(hash_path(&repo_root), repo_root);
+            }
+        }
+    }
+    let container_images = backend.container_image_ids()?;
+
+    let mut results = Vec::new();
+    let mut unreferenced = 0;
+    // Images are listed newest first
+    for image in backend.list_images(IMAGE_REPOSITORY)? {
+        let decision = if let Some(reason) = referenced.get(&image.reference) {
+            PruneDecision::InUse(reason.clone())
+        } else if container_images.contains(&image.id) {
+            PruneDecision::InUse("existing container".to_string())
+        } else if let Some(reason) = unreadable_repo_reason(&image, &unreadable) {
+            PruneDecision::InUse(reason)
+        } else if unreferenced < keep {
+            unreferenced += 1;
+            PruneDecision::Kept
+        } else {
+            if !dry_run {
+                if let Err(e) = backend.remove_image(&image.reference) {
+                    warn!("Failed to remove {}: {:#}", image.reference, e);
+                    continue;
+                }
+            }
+            PruneDecision::Removed
+        };
+        results.push((image, decision));
+    }
+
+    Ok(results)
+}
+
+/// Why an image may belong to one of the `unreadable` repos, if it may.
+fn unreadable_repo_reason(
+    image: &ImageSummary,
+    unreadable: &BTreeMap<String, PathBuf>,
+) -> Option<String> {
+    match image.labels.get(REPO_LABEL) {
+        Some(repo) => unreadable
+            .get(repo)
+            .map(|repo_root| format!("unreadable config of {}", repo_root.display())),
+        // Built before images were labeled, so it may belong to any of them
+        None => unreadable
+            .values()
+            .next()
+            .map(|repo_root| format!("unlabeled, unreadable config of {}", repo_root.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_backend::FakeBackend;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_split_image_digest() {
+        assert_eq!(split_image_digest("ubuntu:24.04"), ("ubuntu:24.04", None));
+        assert_eq!(
+            split_image_digest("ubuntu:24.04@sha256:abc"),
+            ("ubuntu:24.04", Some("sha256:abc"))
+        );
+    }
+
+    #[test]
+    fn test_verify_image_digest() {
+        let dir = TempDir::new().unwrap();
+        let backend = FakeBackend::new(dir.path().to_path_buf());
+        let id = backend
+            .record_pulled_image("localhost:5000/tools:1.0", "sha256:1111")
+            .unwrap();
+
+        let verify = |reference, digest| verify_image_digest(&backend, reference, digest);
+        assert_eq!(
+            verify("localhost:5000/tools:1.0", "sha256:1111").unwrap(),
+            id
+        );
+        assert!(verify("localhost:5000/tools:1.0", "sha256:2222").is_err());
+
+        // Pinned by digest only, without a tag
+        assert_eq!(verify("localhost:5000/tools", "sha256:1111").unwrap(), id);
+        assert_eq!(verify("localhost:5000/tools", &id).unwrap(), id);
+        assert!(verify("localhost:5000/tools", "sha256:2222").is_err());
+        assert!(verify("localhost:5000/other", "sha256:1111").is_err());
+    }
+}
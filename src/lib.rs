@@ -1,15 +1,30 @@
 pub mod agent;
+pub mod agent_input;
+pub mod agent_jobs;
 pub mod anthropic;
+pub mod backend;
+pub mod build_context;
 pub mod cli;
+pub mod compaction;
 pub mod config;
 pub mod daemon;
 pub mod daemon_protocol;
+pub mod devcontainer;
 pub mod docker;
+pub mod docker_api;
+#[cfg(any(test, feature = "fake-backend"))]
+pub mod fake_backend;
 pub mod git;
+pub mod hooks;
+pub mod image;
 pub mod llm_cache;
 pub mod overlay;
+pub mod rootless;
 pub mod sandbox;
 pub mod sandbox_config;
 pub mod setup;
+pub mod transcript;
+pub mod usage;
+pub mod userns;
 
 pub use cli::run;
@@ -1,5 +1,7 @@
 use std::{env, process::ExitCode};
 
+use sandbox::agent::{BudgetExceeded, BUDGET_EXCEEDED_EXIT_CODE};
+
 fn main() -> ExitCode {
     match sandbox::run() {
         Ok(()) => ExitCode::SUCCESS,
@@ -10,6 +12,9 @@ fn main() -> ExitCode {
                 eprintln!("Backtrace:\n{}", e.backtrace());
             }
 
+            if e.downcast_ref::<BudgetExceeded>().is_some() {
+                return ExitCode::from(BUDGET_EXCEEDED_EXIT_CODE);
+            }
             ExitCode::FAILURE
         }
     }
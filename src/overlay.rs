@@ -1,8 +1,9 @@
-use anyhow::{bail, Context, Result};
+use anyhow::{Context, Result};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
 
-/// Configuration for an overlay mount using Docker volumes.
+use crate::backend::{ContainerBackend, MountSource, MountSpec};
+
+/// Configuration for an overlay mount.
 /// Overlay provides copy-on-write semantics: reads come from lower (original),
 /// writes go to upper, and the merged view is presented at the mount point.
 #[derive(Debug, Clone)]
@@ -15,7 +16,7 @@ pub struct Overlay {
     pub upper: PathBuf,
     /// Work directory required by overlayfs
     pub work: PathBuf,
-    /// Docker volume name for this overlay
+    /// Volume name for this overlay, for backends that mount overlays via volumes
     pub volume_name: String,
 }
 
@@ -26,7 +27,7 @@ impl Overlay {
     /// * `name` - Identifier for this overlay
     /// * `lower` - Source directory (will be read-only lower layer)
     /// * `overlay_base` - Base directory where upper/work dirs will be created
-    /// * `volume_prefix` - Prefix for the Docker volume name
+    /// * `volume_prefix` - Prefix for the volume name
     pub fn new(name: &str, lower: &Path, overlay_base: &Path, volume_prefix: &str) -> Self {
         let overlay_dir = overlay_base.join(name);
         Overlay {
@@ -47,101 +48,29 @@ impl Overlay {
         Ok(())
     }
 
-    /// Create the Docker volume with overlay configuration.
-    /// Docker will handle the overlayfs mount internally.
-    pub fn create_volume(&self) -> Result<()> {
-        self.create_dirs()?;
-
-        // Check if volume already exists
-        if self.volume_exists()? {
-            return Ok(());
-        }
-
-        let overlay_opts = format!(
-            "lowerdir={},upperdir={},workdir={}",
-            self.lower.display(),
-            self.upper.display(),
-            self.work.display()
-        );
-
-        let status = Command::new("docker")
-            .args([
-                "volume",
-                "create",
-                "-d",
-                "local",
-                "-o",
-                "type=overlay",
-                "-o",
-                &format!("o={}", overlay_opts),
-                "-o",
-                "device=overlay",
-                &self.volume_name,
-            ])
-            .stdout(Stdio::null())
-            .status()
-            .context("Failed to run docker volume create")?;
-
-        if !status.success() {
-            bail!(
-                "Failed to create overlay volume '{}' for {}",
-                self.volume_name,
-                self.name
-            );
-        }
-
-        Ok(())
-    }
-
-    /// Check if the Docker volume exists.
-    pub fn volume_exists(&self) -> Result<bool> {
-        let output = Command::new("docker")
-            .args(["volume", "inspect", &self.volume_name])
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .status()
-            .context("Failed to run docker volume inspect")?;
-
-        Ok(output.success())
-    }
-
-    /// Remove the Docker volume.
-    pub fn remove_volume(&self) -> Result<()> {
-        if !self.volume_exists()? {
-            return Ok(());
-        }
-
-        let status = Command::new("docker")
-            .args(["volume", "rm", &self.volume_name])
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .status()
-            .context("Failed to run docker volume rm")?;
-
-        if !status.success() {
-            bail!("Failed to remove volume: {}", self.volume_name);
+    /// Mount of this overlay at `target` in a container.
+    /// The backend sets up the overlayfs when the container starts.
+    pub fn mount_spec(&self, target: &Path) -> MountSpec {
+        MountSpec {
+            source: MountSource::Overlay {
+                lower: self.lower.clone(),
+                upper: self.upper.clone(),
+                work: self.work.clone(),
+                volume_name: self.volume_name.clone(),
+            },
+            target: target.to_path_buf(),
         }
-
-        Ok(())
-    }
-
-    /// Get Docker mount arguments for this overlay.
-    /// Returns the arguments to pass to `docker run`.
-    pub fn docker_mount_args(&self, target: &Path) -> Vec<String> {
-        vec![
-            "--mount".to_string(),
-            format!(
-                "type=volume,source={},target={}",
-                self.volume_name,
-                target.display()
-            ),
-        ]
     }
 
     /// Clean up the overlay: remove volume and optionally the upper/work directories.
-    pub fn cleanup(&self, remove_dirs: bool) -> Result<()> {
-        // Remove the Docker volume
-        let _ = self.remove_volume();
+    pub fn cleanup(&self, backend: &dyn ContainerBackend, remove_dirs: bool) -> Result<()> {
+        // Remove the volume, if the backend created one
+        if backend
+            .list_volumes(&self.volume_name)?
+            .contains(&self.volume_name)
+        {
+            let _ = backend.remove_volume(&self.volume_name);
+        }
 
         if remove_dirs {
             // Remove the overlay directory (contains upper, work)
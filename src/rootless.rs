@@ -0,0 +1,146 @@
+//! The rootless runtime: sandboxes run in user namespaces (see [`crate::userns`])
+//! instead of by a container engine, for machines that can't run a Docker daemon.
+//!
+//! Images are still built and stored by the configured backend (podman works without
+//! a daemon). Each image's filesystem is exported once into the cache and used as the
+//! lower layer of the container root. Containers get their own network namespace with
+//! only a loopback interface.
+
+use anyhow::{bail, Context, Result};
+use log::info;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::backend::{
+    BuildRequest, ContainerBackend, ContainerSpec, ContainerState, ExecOptions, ExecOutput,
+    ImageInfo, ImageSummary,
+};
+use crate::config::get_cache_dir;
+use crate::userns::{RootFs, RunOptions, UsernsContainers};
+
+/// Backend running containers itself, on images of another backend.
+pub struct RootlessBackend {
+    engine: Arc<dyn ContainerBackend>,
+    /// Exported image filesystems, by image ID.
+    rootfs_dir: PathBuf,
+    containers: UsernsContainers,
+}
+
+impl RootlessBackend {
+    pub fn new(engine: Arc<dyn ContainerBackend>) -> Result<Self> {
+        let state_dir = get_cache_dir()?.join("rootless");
+        Ok(RootlessBackend {
+            engine,
+            rootfs_dir: state_dir.join("rootfs"),
+            containers: UsernsContainers::new(state_dir.join("containers")),
+        })
+    }
+
+    fn rootfs_path(&self, image_id: &str) -> PathBuf {
+        let id = image_id.strip_prefix("sha256:").unwrap_or(image_id);
+        self.rootfs_dir.join(id)
+    }
+
+    /// The exported filesystem of an image, exporting it first if needed.
+    fn ensure_rootfs(&self, reference: &str, image_id: &str) -> Result<PathBuf> {
+        let path = self.rootfs_path(image_id);
+        if path.exists() {
+            return Ok(path);
+        }
+
+        info!("Exporting filesystem of image {}", reference);
+        // Exported next to its final path and renamed, so that an interrupted export
+        // isn't mistaken for a complete one
+        std::fs::create_dir_all(&self.rootfs_dir)?;
+        let partial = tempfile::tempdir_in(&self.rootfs_dir)?;
+        self.engine.export_image(reference, partial.path())?;
+        std::fs::rename(partial.keep(), &path)
+            .with_context(|| format!("Failed to store {}", path.display()))?;
+        Ok(path)
+    }
+}
+
+impl ContainerBackend for RootlessBackend {
+    fn inspect_image(&self, reference: &str) -> Result<Option<ImageInfo>> {
+        self.engine.inspect_image(reference)
+    }
+
+    fn list_images(&self, repository: &str) -> Result<Vec<ImageSummary>> {
+        self.engine.list_images(repository)
+    }
+
+    fn remove_image(&self, reference: &str) -> Result<()> {
+        if let Some(image) = self.engine.inspect_image(reference)? {
+            let path = self.rootfs_path(&image.id);
+            if path.exists() {
+                std::fs::remove_dir_all(&path)
+                    .with_context(|| format!("Failed to remove {}", path.display()))?;
+            }
+        }
+        self.engine.remove_image(reference)
+    }
+
+    fn build_image(&self, request: &BuildRequest, output: &mut dyn FnMut(&str)) -> Result<()> {
+        self.engine.build_image(request, output)
+    }
+
+    fn export_image(&self, reference: &str, dest: &Path) -> Result<()> {
+        self.engine.export_image(reference, dest)
+    }
+
+    fn run_container(&self, spec: &ContainerSpec) -> Result<()> {
+        let image = self
+            .engine
+            .inspect_image(&spec.image)?
+            .with_context(|| format!("Image not found: {}", spec.image))?;
+        let lower = self.ensure_rootfs(&spec.image, &image.id)?;
+        let options = RunOptions {
+            root: RootFs::Image { lower },
+            isolate_network: true,
+        };
+        self.containers.run(spec, &image.id, options)
+    }
+
+    fn container_state(&self, name: &str) -> Result<ContainerState> {
+        self.containers.state(name)
+    }
+
+    fn stop_container(&self, name: &str) -> Result<()> {
+        self.containers.stop(name)
+    }
+
+    fn remove_container(&self, name: &str) -> Result<()> {
+        self.containers.remove(name)
+    }
+
+    fn wait_container(&self, name: &str) -> Result<()> {
+        self.containers.wait(name)
+    }
+
+    fn container_image_ids(&self) -> Result<Vec<String>> {
+        self.containers.image_ids()
+    }
+
+    fn exec(&self, container: &str, command: &[&str], options: &ExecOptions) -> Result<ExecOutput> {
+        self.containers.exec(container, command, options)
+    }
+
+    fn exec_interactive(
+        &self,
+        container: &str,
+        command: &[&str],
+        env: &[(String, String)],
+    ) -> Result<Option<i32>> {
+        self.containers.exec_interactive(container, command, env)
+    }
+
+    // Overlays are mounted by the init process, no volumes are involved
+
+    fn list_volumes(&self, _prefix: &str) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    fn remove_volume(&self, name: &str) -> Result<()> {
+        bail!("No such volume: {}", name)
+    }
+}
@@ -1,24 +1,28 @@
 use anyhow::{bail, Context, Result};
 use log::{debug, info, warn};
+use nix::fcntl::{Flock, FlockArg};
 use reflink_copy::reflink_or_copy;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::Arc;
 
+use crate::backend::{
+    self, Container, ContainerBackend, ContainerSpec, ExecOptions, MountSource, MountSpec,
+};
 use crate::config::{
-    get_meta_git_dir, get_sandbox_base_dir, get_sandbox_instance_dir, OverlayMode, Runtime,
-    UserInfo,
+    get_cache_dir, get_meta_git_dir, get_sandbox_base_dir, get_sandbox_instance_dir, Backend,
+    OverlayMode, Runtime, UserInfo,
 };
 use crate::daemon::{self, DaemonConnection};
-use crate::docker;
 use crate::git;
 use crate::overlay::Overlay;
 
 /// Specifies how a path should be mounted into the sandbox.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum MountMode {
     /// Read-only bind mount. Changes inside the sandbox are not allowed.
     ReadOnly,
@@ -30,7 +34,7 @@ pub enum MountMode {
 }
 
 /// Configuration for a single mount point.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Mount {
     /// Path on the host filesystem.
     pub host_path: PathBuf,
@@ -38,6 +42,8 @@ pub struct Mount {
     pub container_path: Option<PathBuf>,
     /// How to mount this path.
     pub mode: MountMode,
+    /// Whether a missing host path is skipped instead of being an error.
+    pub optional: bool,
 }
 
 impl Mount {
@@ -47,6 +53,7 @@ impl Mount {
             host_path: host_path.into(),
             container_path: None,
             mode,
+            optional: false,
         }
     }
 
@@ -56,6 +63,17 @@ impl Mount {
         self
     }
 
+    /// Mark this mount as optional (skipped if the host path doesn't exist).
+    pub fn with_optional(mut self, optional: bool) -> Self {
+        self.optional = optional;
+        self
+    }
+
+    /// Whether this mount should be applied, i.e. its host path exists.
+    fn is_present(&self) -> bool {
+        self.host_path.exists()
+    }
+
     /// Get the effective container path.
     pub fn target_path(&self) -> &Path {
         self.container_path.as_ref().unwrap_or(&self.host_path)
@@ -118,11 +136,7 @@ fn copy_dir_reflink(src: &Path, dst: &Path) -> Result<()> {
 /// will create subdirectories inside it as root. This function pre-creates these directories
 /// with the correct ownership.
 fn precreate_mount_targets_in_clone(mounts: &[Mount], info: &SandboxInfo) -> Result<()> {
-    for mount in mounts {
-        if !mount.host_path.exists() {
-            continue;
-        }
-
+    for mount in mounts.iter().filter(|m| m.is_present()) {
         let target = mount.target_path();
 
         // Check if target path is inside repo_root (i.e., would be created inside the clone)
@@ -145,20 +159,55 @@ fn precreate_mount_targets_in_clone(mounts: &[Mount], info: &SandboxInfo) -> Res
     Ok(())
 }
 
-/// Process mounts and generate docker arguments.
+/// Check that the host path of every non-optional mount exists.
+/// Reports all missing paths at once rather than stopping at the first one.
+pub fn check_mount_sources(mounts: &[Mount]) -> Result<()> {
+    let missing: Vec<_> = mounts
+        .iter()
+        .filter(|m| !m.optional && !m.is_present())
+        .collect();
+
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let list = missing
+        .iter()
+        .map(|m| {
+            format!(
+                "  {} (-> {})",
+                m.host_path.display(),
+                m.target_path().display()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    bail!(
+        "Mount source(s) not found on the host:\n{}\n\n\
+         Fix the paths in .sandbox.toml, or add `optional = true` to mounts \
+         that may legitimately be absent.",
+        list
+    );
+}
+
+/// Process mounts into the mounts of the container.
+/// Fails if a non-optional mount's host path doesn't exist; missing optional mounts are skipped.
 pub fn process_mounts(
     mounts: &[Mount],
     info: &SandboxInfo,
     overlay_mode: OverlayMode,
-) -> Result<Vec<String>> {
+) -> Result<Vec<MountSpec>> {
+    check_mount_sources(mounts)?;
+
     // Pre-create mount target directories in clone to prevent Docker from creating them as root
     precreate_mount_targets_in_clone(mounts, info)?;
 
-    let mut docker_args = Vec::new();
+    let mut specs = Vec::new();
 
     for mount in mounts {
-        // Skip if host path doesn't exist
-        if !mount.host_path.exists() {
+        // Skip optional mounts whose host path doesn't exist
+        if !mount.is_present() {
+            debug!("Skipping optional mount: {}", mount.host_path.display());
             continue;
         }
 
@@ -166,24 +215,10 @@ pub fn process_mounts(
 
         match &mount.mode {
             MountMode::ReadOnly => {
-                docker_args.extend([
-                    "--mount".to_string(),
-                    format!(
-                        "type=bind,source={},target={},readonly",
-                        mount.host_path.display(),
-                        target.display()
-                    ),
-                ]);
+                specs.push(bind_mount(&mount.host_path, target, true));
             }
             MountMode::WriteThrough => {
-                docker_args.extend([
-                    "--mount".to_string(),
-                    format!(
-                        "type=bind,source={},target={}",
-                        mount.host_path.display(),
-                        target.display()
-                    ),
-                ]);
+                specs.push(bind_mount(&mount.host_path, target, false));
             }
             MountMode::Overlay => {
                 let name = mount.unique_name();
@@ -203,20 +238,13 @@ pub fn process_mounts(
                                     },
                                 )?;
                             }
-                            docker_args.extend([
-                                "--mount".to_string(),
-                                format!(
-                                    "type=bind,source={},target={}",
-                                    copy_dir.display(),
-                                    target.display()
-                                ),
-                            ]);
+                            specs.push(bind_mount(&copy_dir, target, false));
                         }
                         OverlayMode::Overlayfs => {
                             // Use overlayfs for directories
                             let overlay = info.create_overlay(&name, &mount.host_path);
-                            overlay.create_volume()?;
-                            docker_args.extend(overlay.docker_mount_args(&target));
+                            overlay.create_dirs()?;
+                            specs.push(overlay.mount_spec(&target));
                         }
                     }
                 } else {
@@ -229,20 +257,23 @@ pub fn process_mounts(
                             copy_path.display()
                         )
                     })?;
-                    docker_args.extend([
-                        "--mount".to_string(),
-                        format!(
-                            "type=bind,source={},target={}",
-                            copy_path.display(),
-                            target.display()
-                        ),
-                    ]);
+                    specs.push(bind_mount(&copy_path, target, false));
                 }
             }
         }
     }
 
-    Ok(docker_args)
+    Ok(specs)
+}
+
+fn bind_mount(path: &Path, target: PathBuf, readonly: bool) -> MountSpec {
+    MountSpec {
+        source: MountSource::Bind {
+            path: path.to_path_buf(),
+            readonly,
+        },
+        target,
+    }
 }
 
 /// Fix ownership of mount parent directories inside the container.
@@ -252,25 +283,18 @@ pub fn process_mounts(
 /// directories, if the corresponding host parent is owned by the user, we chown
 /// the container parent to the user (if it was created by Docker, i.e., owned by root).
 fn fix_mount_parent_ownership(
-    container_name: &str,
+    container: Container,
     mounts: &[Mount],
     user_info: &UserInfo,
 ) -> Result<()> {
     // Collect all (host_parent, container_parent) pairs by walking up both paths in parallel
     let mut container_parents_to_fix: HashSet<PathBuf> = HashSet::new();
 
-    for mount in mounts {
-        if !mount.host_path.exists() {
-            continue;
-        }
-
+    for mount in mounts.iter().filter(|m| m.is_present()) {
         let mut host_path = mount.host_path.clone();
         let mut container_path = mount.target_path().to_path_buf();
 
-        loop {
-            let Some(host_parent) = host_path.parent() else {
-                break;
-            };
+        while let Some(host_parent) = host_path.parent() {
             let Some(container_parent) = container_path.parent() else {
                 break;
             };
@@ -318,22 +342,16 @@ fn fix_mount_parent_ownership(
         user_info.gid
     );
 
-    let status = Command::new("docker")
-        .args([
-            "exec",
-            "--user",
-            "root",
-            container_name,
-            "sh",
-            "-c",
-            &script,
-        ])
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status()
+    let options = ExecOptions {
+        user: Some("root".to_string()),
+        ..Default::default()
+    };
+    let output = container
+        .backend
+        .exec(container.name, &["sh", "-c", &script], &options)
         .context("Failed to fix mount parent ownership")?;
 
-    if !status.success() {
+    if !output.success() {
         // Non-fatal: log but continue
         warn!("Failed to fix some mount parent directory ownership");
     }
@@ -355,6 +373,29 @@ pub struct SandboxInfo {
     pub pids_dir: PathBuf,
     pub container_name: String,
     pub created_at: String,
+    /// Config profile the sandbox was created with.
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// When the post-create hooks completed; they don't run again once set.
+    #[serde(default)]
+    pub post_create_completed_at: Option<String>,
+    /// Backend the container runs on.
+    #[serde(default)]
+    pub backend: Backend,
+    /// Runtime the container was last started with.
+    #[serde(default)]
+    pub runtime: Runtime,
+    /// Container left on a previous backend or runtime, which the daemon removes
+    /// before starting the sandbox on the current one.
+    #[serde(default)]
+    pub previous_container: Option<PreviousContainer>,
+}
+
+/// Where a sandbox's container ran before its backend or runtime changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreviousContainer {
+    pub backend: Backend,
+    pub runtime: Runtime,
 }
 
 impl SandboxInfo {
@@ -380,9 +421,40 @@ impl SandboxInfo {
             pids_dir,
             container_name,
             created_at,
+            profile: None,
+            post_create_completed_at: None,
+            backend: Backend::default(),
+            runtime: Runtime::default(),
+            previous_container: None,
         })
     }
 
+    /// The backend the sandbox's container runs on.
+    pub fn container_backend(&self) -> Result<Arc<dyn ContainerBackend>> {
+        backend::create_for_runtime(self.backend, self.runtime)
+    }
+
+    /// Whether the container runs on the same backend and runtime as in `other`.
+    pub fn same_container_backend(&self, other: &SandboxInfo) -> bool {
+        (self.backend, self.runtime) == (other.backend, other.runtime)
+    }
+
+    /// Remove the container left on a previous backend or runtime, if any.
+    pub fn remove_previous_container(&mut self) -> Result<()> {
+        let Some(previous) = self.previous_container else {
+            return Ok(());
+        };
+        let backend = backend::create_for_runtime(previous.backend, previous.runtime)?;
+        remove_container(backend.as_ref(), self).with_context(|| {
+            format!(
+                "Failed to remove container {} from its previous backend",
+                self.container_name
+            )
+        })?;
+        self.previous_container = None;
+        self.save()
+    }
+
     /// Load sandbox info from disk.
     pub fn load(sandbox_dir: &Path) -> Result<Self> {
         let info_path = sandbox_dir.join("sandbox.json");
@@ -410,6 +482,16 @@ impl SandboxInfo {
         )
     }
 
+    /// Get the path of the log file for hook output.
+    pub fn hooks_log_path(&self) -> PathBuf {
+        self.sandbox_dir.join("hooks.log")
+    }
+
+    /// Get the directory of agent session transcripts.
+    pub fn transcripts_dir(&self) -> PathBuf {
+        self.sandbox_dir.join("transcripts")
+    }
+
     /// Get the base directory for overlay mounts.
     pub fn overlays_dir(&self) -> PathBuf {
         self.sandbox_dir.join("overlays")
@@ -429,23 +511,32 @@ impl SandboxInfo {
         Overlay::new(name, lower, &self.overlays_dir(), &self.volume_prefix())
     }
 
-    /// Save the mounts configuration for this sandbox.
-    pub fn save_mounts_config(&self, config: &crate::sandbox_config::MountsConfig) -> Result<()> {
+    /// Save the configured mounts of this sandbox, as resolved on the host.
+    pub fn save_mounts(&self, mounts: &[Mount]) -> Result<()> {
         let path = self.sandbox_dir.join("mounts.json");
-        let contents = serde_json::to_string_pretty(config)?;
+        let contents = serde_json::to_string_pretty(mounts)?;
         std::fs::write(&path, contents)?;
         Ok(())
     }
 
-    /// Load the mounts configuration for this sandbox.
-    pub fn load_mounts_config(&self) -> Result<crate::sandbox_config::MountsConfig> {
+    /// Load the configured mounts of this sandbox, as resolved by [`ensure_sandbox`].
+    pub fn load_mounts(&self) -> Result<Vec<Mount>> {
         let path = self.sandbox_dir.join("mounts.json");
         let contents = std::fs::read_to_string(&path)
-            .with_context(|| format!("Failed to read mounts config: {}", path.display()))?;
-        serde_json::from_str(&contents).context("Failed to parse mounts config")
+            .with_context(|| format!("Failed to read mounts: {}", path.display()))?;
+        serde_json::from_str(&contents).context("Failed to parse mounts")
     }
 }
 
+/// Get the config profile an existing sandbox was created with.
+pub fn recorded_profile(repo_root: &Path, name: &str) -> Result<Option<String>> {
+    let sandbox_dir = get_sandbox_instance_dir(repo_root, name)?;
+    if !sandbox_dir.join("sandbox.json").exists() {
+        return Ok(None);
+    }
+    Ok(SandboxInfo::load(&sandbox_dir)?.profile)
+}
+
 /// List all sandbox instances for a repository.
 pub fn list_sandboxes(repo_root: &Path) -> Result<Vec<SandboxInfo>> {
     let base_dir = get_sandbox_base_dir(repo_root)?;
@@ -470,6 +561,29 @@ pub fn list_sandboxes(repo_root: &Path) -> Result<Vec<SandboxInfo>> {
     Ok(sandboxes)
 }
 
+/// List the repositories that have sandboxes, across all projects.
+pub fn known_repo_roots() -> Result<Vec<PathBuf>> {
+    let cache_dir = get_cache_dir()?;
+    if !cache_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut roots = std::collections::BTreeSet::new();
+    for entry in std::fs::read_dir(&cache_dir)? {
+        let base_dir = entry?.path();
+        if !base_dir.is_dir() {
+            continue;
+        }
+        for sandbox_entry in std::fs::read_dir(&base_dir)? {
+            if let Ok(info) = SandboxInfo::load(&sandbox_entry?.path()) {
+                roots.insert(info.repo_root);
+            }
+        }
+    }
+
+    Ok(roots.into_iter().collect())
+}
+
 /// Remove a directory and all its contents, fixing permissions as needed.
 /// This is similar to `std::fs::remove_dir_all` but handles permission issues
 /// by making directories/files writable before attempting deletion.
@@ -517,20 +631,29 @@ fn remove_dir_all_with_permissions(path: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Stop and remove the sandbox's container if it exists.
+fn remove_container(backend: &dyn ContainerBackend, info: &SandboxInfo) -> Result<()> {
+    if backend.container_exists(&info.container_name)? {
+        backend.remove_container(&info.container_name)?;
+    }
+    Ok(())
+}
+
 /// Delete a sandbox and its associated resources.
 pub fn delete_sandbox(info: &SandboxInfo) -> Result<()> {
     info!("Deleting sandbox: {}", info.name);
-
-    // Stop and remove container if it exists
-    if docker::container_exists(&info.container_name)? {
-        docker::remove_container(&info.container_name)?;
+    let backend = info.container_backend()?;
+    remove_container(backend.as_ref(), info)?;
+    if let Some(previous) = info.previous_container {
+        let previous = backend::create_for_runtime(previous.backend, previous.runtime)?;
+        remove_container(previous.as_ref(), info)?;
     }
 
-    // Remove overlay Docker volumes
+    // Remove overlay volumes
     let volume_prefix = info.volume_prefix();
-    if let Ok(volumes) = docker::list_volumes_with_prefix(&volume_prefix) {
+    if let Ok(volumes) = backend.list_volumes(&volume_prefix) {
         for vol in volumes {
-            let _ = docker::remove_volume(&vol);
+            let _ = backend.remove_volume(&vol);
         }
     }
 
@@ -581,14 +704,41 @@ pub fn delete_sandbox(info: &SandboxInfo) -> Result<()> {
     Ok(())
 }
 
-/// Build the list of mounts for a sandbox container.
-pub fn build_mount_list(
-    info: &SandboxInfo,
+/// Build the mounts declared in the `[mounts]` section of the sandbox config.
+pub fn config_mounts(
+    repo_root: &Path,
     user_info: &UserInfo,
     config: &crate::sandbox_config::SandboxConfig,
 ) -> Result<Vec<Mount>> {
     use crate::sandbox_config::SandboxConfig;
 
+    let sections = [
+        (&config.mounts.readonly, MountMode::ReadOnly),
+        (&config.mounts.unsafe_write, MountMode::WriteThrough),
+        (&config.mounts.overlay, MountMode::Overlay),
+    ];
+
+    let mut mounts = Vec::new();
+    for (entries, mode) in sections {
+        for entry in entries.iter().map(|e| e.expand(repo_root)) {
+            for entry in entry? {
+                let mut mount = Mount::new(&entry.host, mode.clone()).with_optional(entry.optional);
+                if let Some(ref container) = entry.container {
+                    let container_path =
+                        SandboxConfig::expand_container_path(container, &user_info.username)?;
+                    mount = mount.with_container_path(container_path);
+                }
+                mounts.push(mount);
+            }
+        }
+    }
+
+    Ok(mounts)
+}
+
+/// Build the list of mounts for a sandbox container, from the configured mounts
+/// resolved by [`config_mounts`].
+pub fn build_mount_list(info: &SandboxInfo, config_mounts: Vec<Mount>) -> Vec<Mount> {
     // Core mounts for the repository setup (always required)
     let mut mounts = vec![
         // meta.git at its actual path (read-only, for git alternates and sandbox remote)
@@ -599,62 +749,8 @@ pub fn build_mount_list(
         Mount::new(&info.clone_dir, MountMode::WriteThrough),
     ];
 
-    // Add read-only mounts from config
-    for entry in &config.mounts.readonly {
-        let host_path = SandboxConfig::expand_host_path(&entry.host, &info.repo_root)?;
-        let mut mount = Mount::new(&host_path, MountMode::ReadOnly);
-        if let Some(ref container) = entry.container {
-            let container_path =
-                SandboxConfig::expand_container_path(container, &user_info.username);
-            mount = mount.with_container_path(container_path);
-        } else {
-            // Default: expand host path for container too (handles ~ expansion)
-            let container_path =
-                SandboxConfig::expand_container_path(&entry.host, &user_info.username);
-            if container_path != entry.host {
-                mount = mount.with_container_path(container_path);
-            }
-        }
-        mounts.push(mount);
-    }
-
-    // Add write-through mounts from config
-    for entry in &config.mounts.unsafe_write {
-        let host_path = SandboxConfig::expand_host_path(&entry.host, &info.repo_root)?;
-        let mut mount = Mount::new(&host_path, MountMode::WriteThrough);
-        if let Some(ref container) = entry.container {
-            let container_path =
-                SandboxConfig::expand_container_path(container, &user_info.username);
-            mount = mount.with_container_path(container_path);
-        } else {
-            let container_path =
-                SandboxConfig::expand_container_path(&entry.host, &user_info.username);
-            if container_path != entry.host {
-                mount = mount.with_container_path(container_path);
-            }
-        }
-        mounts.push(mount);
-    }
-
-    // Add overlay mounts from config
-    for entry in &config.mounts.overlay {
-        let host_path = SandboxConfig::expand_host_path(&entry.host, &info.repo_root)?;
-        let mut mount = Mount::new(&host_path, MountMode::Overlay);
-        if let Some(ref container) = entry.container {
-            let container_path =
-                SandboxConfig::expand_container_path(container, &user_info.username);
-            mount = mount.with_container_path(container_path);
-        } else {
-            let container_path =
-                SandboxConfig::expand_container_path(&entry.host, &user_info.username);
-            if container_path != entry.host {
-                mount = mount.with_container_path(container_path);
-            }
-        }
-        mounts.push(mount);
-    }
-
-    Ok(mounts)
+    mounts.extend(config_mounts);
+    mounts
 }
 
 /// Ensure the container is running (start it if not), then exec a command into it.
@@ -693,7 +789,8 @@ pub fn run_sandbox(
 
     debug!("Executing in container: {}", info.container_name);
 
-    docker::exec_in_container(&info.container_name, &cmd, env_vars)
+    let backend = info.container_backend()?;
+    Container::new(backend.as_ref(), &info.container_name).exec_interactive(&cmd, env_vars)
     // _daemon_conn is dropped here, signaling disconnection to daemon
 }
 
@@ -719,36 +816,17 @@ pub fn ensure_container_running_internal(
     overlay_mode: OverlayMode,
     env_vars: &[(String, String)],
 ) -> Result<()> {
+    let backend = info.container_backend()?;
+
     // Remove stopped container if it exists
-    if docker::container_exists(&info.container_name)? {
-        docker::remove_container(&info.container_name)?;
-    }
-
-    let mut args = vec![
-        "run".to_string(),
-        "-d".to_string(),
-        "--name".to_string(),
-        info.container_name.clone(),
-        "--hostname".to_string(),
-        info.name.clone(),
-        "--label".to_string(),
-        "sandbox=true".to_string(),
-        "--runtime".to_string(),
-        runtime.docker_runtime_name().to_string(),
-        "--user".to_string(),
-        format!("{}:{}", user_info.uid, user_info.gid),
-        "--workdir".to_string(),
-        info.repo_root.to_string_lossy().to_string(),
-    ];
+    if backend.container_exists(&info.container_name)? {
+        backend.remove_container(&info.container_name)?;
+    }
 
-    // Load mounts config saved during ensure_sandbox
-    let mounts_config = info.load_mounts_config()?;
-    let config = crate::sandbox_config::SandboxConfig {
-        mounts: mounts_config,
-        ..Default::default()
-    };
-    let mounts = build_mount_list(info, user_info, &config)?;
-    args.extend(process_mounts(&mounts, info, overlay_mode)?);
+    // Mounts resolved in the environment of the CLI during ensure_sandbox, as the
+    // daemon's may lack variables the mount paths use
+    let mounts = build_mount_list(info, info.load_mounts()?);
+    let mut mount_specs = process_mounts(&mounts, info, overlay_mode)?;
 
     if let Some(home) = dirs::home_dir() {
         let claude_json = home.join(".claude.json");
@@ -757,53 +835,76 @@ pub fn ensure_container_running_internal(
             let filtered_json = filter_claude_json(&claude_json, &info.repo_root)?;
             std::fs::write(&copy_path, &filtered_json)
                 .with_context(|| format!("Failed to write filtered {}", copy_path.display()))?;
-            args.extend([
-                "--mount".to_string(),
-                format!(
-                    "type=bind,source={},target=/home/{}/.claude.json",
-                    copy_path.display(),
-                    user_info.username
-                ),
-            ]);
+            mount_specs.push(bind_mount(
+                &copy_path,
+                PathBuf::from(format!("/home/{}/.claude.json", user_info.username)),
+                false,
+            ));
         }
     }
 
-    for (name, value) in env_vars {
-        args.push("-e".to_string());
-        args.push(format!("{}={}", name, value));
-    }
-
-    args.push(image_tag.to_string());
-    args.push("sleep".to_string());
-    args.push("infinity".to_string());
+    let spec = ContainerSpec {
+        name: info.container_name.clone(),
+        hostname: info.name.clone(),
+        image: image_tag.to_string(),
+        labels: BTreeMap::from([("sandbox".to_string(), "true".to_string())]),
+        runtime: runtime.docker_runtime_name().to_string(),
+        uid: user_info.uid,
+        gid: user_info.gid,
+        workdir: info.repo_root.clone(),
+        mounts: mount_specs,
+        // Set the environment in the container config so that every exec session
+        // (including the agent's tool calls) sees it without passing it again.
+        env: env_vars.to_vec(),
+        command: vec!["sleep".to_string(), "infinity".to_string()],
+    };
 
     info!("Starting container: {}", info.container_name);
+    backend.run_container(&spec)?;
 
-    let status = Command::new("docker")
-        .args(&args)
-        .stdout(Stdio::null())
-        .status()
-        .context("Failed to start docker container")?;
-
-    if !status.success() {
-        bail!("Failed to start container");
-    }
-
-    fix_mount_parent_ownership(&info.container_name, &mounts, user_info)?;
+    fix_mount_parent_ownership(
+        Container::new(backend.as_ref(), &info.container_name),
+        &mounts,
+        user_info,
+    )?;
 
     Ok(())
 }
 
-/// Ensure a sandbox is set up and ready to use.
+/// Ensure a sandbox is set up and ready to use. Resolves the configured mounts, which
+/// the daemon then starts the container with.
 pub fn ensure_sandbox(
     repo_root: &Path,
     name: &str,
     config: &crate::sandbox_config::SandboxConfig,
+    user_info: &UserInfo,
+    runtime: Runtime,
 ) -> Result<SandboxInfo> {
-    let info = SandboxInfo::new(name, repo_root)?;
+    let mounts = config_mounts(repo_root, user_info, config)?;
+    let mut info = SandboxInfo::new(name, repo_root)?;
+    info.profile = config.active_profile.clone();
+    info.backend = config.backend.unwrap_or_default();
+    info.runtime = runtime;
 
-    // Create sandbox directory
+    // Create sandbox directory, and set it up one client at a time
     std::fs::create_dir_all(&info.sandbox_dir)?;
+    let _setup_lock = lock_sandbox_setup(&info.sandbox_dir)?;
+
+    if let Ok(existing) = SandboxInfo::load(&info.sandbox_dir) {
+        info.post_create_completed_at = existing.post_create_completed_at.clone();
+        // A container started with another backend or runtime can't be managed
+        // through the new one. The daemon removes it through the one it was started
+        // with, once no session is attached to it.
+        let previous = existing.previous_container.unwrap_or(PreviousContainer {
+            backend: existing.backend,
+            runtime: existing.runtime,
+        });
+        let current = PreviousContainer {
+            backend: info.backend,
+            runtime: info.runtime,
+        };
+        info.previous_container = (previous != current).then_some(previous);
+    }
 
     // Ensure meta.git bare repository exists (shared across all sandboxes for this repo)
     git::ensure_meta_git(&info.repo_root, &info.meta_git_dir)?;
@@ -826,13 +927,33 @@ pub fn ensure_sandbox(
     // Setup remotes for the sandbox repo (rename "origin" to "sandbox")
     git::setup_sandbox_remotes(&info.meta_git_dir, &info.clone_dir)?;
 
-    // Save sandbox info and mounts config (mounts config used by daemon)
+    // Save sandbox info and mounts (used by daemon)
     info.save()?;
-    info.save_mounts_config(&config.mounts)?;
+    info.save_mounts(&mounts)?;
 
     Ok(info)
 }
 
+/// Take an exclusive lock on a sandbox's setup, released when the returned lock is dropped.
+fn lock_sandbox_setup(sandbox_dir: &Path) -> Result<Flock<std::fs::File>> {
+    let path = sandbox_dir.join("setup.lock");
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    Flock::lock(file, FlockArg::LockExclusive)
+        .map_err(|(_, e)| e)
+        .with_context(|| format!("Failed to lock {}", path.display()))
+}
+
+/// Load a sandbox set up by [`ensure_sandbox`].
+pub fn load_sandbox(repo_root: &Path, name: &str) -> Result<SandboxInfo> {
+    let sandbox_dir = get_sandbox_instance_dir(repo_root, name)?;
+    SandboxInfo::load(&sandbox_dir).with_context(|| format!("Sandbox '{}' is not set up", name))
+}
+
 /// Filter ~/.claude.json to only include the project matching repo_root.
 /// This preserves key ordering in the JSON using serde_json's preserve_order feature.
 fn filter_claude_json(claude_json_path: &Path, repo_root: &Path) -> Result<String> {
@@ -2,25 +2,44 @@
 //!
 //! This file specifies sandbox settings: environment variables to pass through,
 //! mount configurations, image build settings, and agent options.
+//!
+//! The effective configuration is merged from `~/.config/sandbox/config.toml`
+//! (user-global defaults), `.sandbox.toml` and `.sandbox.local.toml` (untracked
+//! personal overrides), in that order of precedence.
 
 use anyhow::{bail, Context, Result};
+use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
+use strum::Display;
 
-use crate::config::{Model, OverlayMode, Runtime};
+use crate::config::{Backend, InputMode, Model, OverlayMode, Runtime};
+use crate::devcontainer::Devcontainer;
 
 /// Top-level configuration structure parsed from `.sandbox.toml`.
 #[derive(Debug, Clone, Deserialize, Default)]
 #[serde(deny_unknown_fields)]
 pub struct SandboxConfig {
-    /// Environment variables that must be set on the host and passed to the container.
+    /// Environment variables to set in the container.
+    /// Either a list of host variables that must be set and are passed through,
+    /// or an `[env]` table (see [`EnvValue`]).
     #[serde(default)]
-    pub env: Vec<String>,
+    pub env: EnvConfig,
+
+    /// `.env` files (relative to repo root) to load variables from.
+    /// Variables from `env` take precedence.
+    #[serde(default, rename = "env-files")]
+    pub env_files: Vec<PathBuf>,
 
     /// Container runtime (runsc, runc, sysbox-runc).
     #[serde(default)]
     pub runtime: Option<Runtime>,
 
+    /// Container engine backend (docker, docker-api, podman).
+    #[serde(default)]
+    pub backend: Option<Backend>,
+
     /// Strategy for copy-on-write mounts (overlayfs or copy).
     #[serde(default, rename = "overlay-mode")]
     pub overlay_mode: Option<OverlayMode>,
@@ -33,6 +52,219 @@ pub struct SandboxConfig {
 
     #[serde(default)]
     pub agent: AgentConfig,
+
+    /// Commands run inside the container by the daemon.
+    #[serde(default)]
+    pub hooks: HooksConfig,
+
+    /// Commands run on the host on sandbox lifecycle events.
+    #[serde(default, rename = "host-hooks")]
+    pub host_hooks: HostHooksConfig,
+
+    /// Named profiles, selected with `--profile <name>`.
+    #[serde(default)]
+    pub profile: BTreeMap<String, ProfileConfig>,
+
+    /// Name of the profile applied by [`SandboxConfig::with_profile`], if any.
+    #[serde(skip)]
+    pub active_profile: Option<String>,
+}
+
+/// Settings a named profile overrides on top of the base config.
+/// Lists are appended to the base config's lists, other values replace them.
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileConfig {
+    #[serde(default)]
+    pub env: EnvConfig,
+
+    #[serde(default, rename = "env-files")]
+    pub env_files: Vec<PathBuf>,
+
+    #[serde(default)]
+    pub runtime: Option<Runtime>,
+
+    #[serde(default)]
+    pub backend: Option<Backend>,
+
+    #[serde(default, rename = "overlay-mode")]
+    pub overlay_mode: Option<OverlayMode>,
+
+    #[serde(default)]
+    pub mounts: MountsConfig,
+
+    #[serde(default)]
+    pub image: Option<ImageConfig>,
+
+    #[serde(default)]
+    pub agent: AgentConfig,
+
+    #[serde(default)]
+    pub hooks: HooksConfig,
+
+    #[serde(default, rename = "host-hooks")]
+    pub host_hooks: HostHooksConfig,
+}
+
+/// Environment variables for the container, keyed by the name inside the container,
+/// in the order they were configured.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(from = "EnvConfigRepr")]
+pub struct EnvConfig(pub IndexMap<String, EnvValue>);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EnvConfigRepr {
+    List(Vec<String>),
+    Table(IndexMap<String, EnvValue>),
+}
+
+impl From<EnvConfigRepr> for EnvConfig {
+    fn from(repr: EnvConfigRepr) -> Self {
+        match repr {
+            // The list form passes through required host variables
+            EnvConfigRepr::List(names) => EnvConfig(
+                names
+                    .into_iter()
+                    .map(|name| (name, EnvValue::Source(EnvSource::default())))
+                    .collect(),
+            ),
+            EnvConfigRepr::Table(table) => EnvConfig(table),
+        }
+    }
+}
+
+/// Value of an entry in the `[env]` table.
+///
+/// ```toml
+/// [env]
+/// FOO = "bar"                                   # literal value
+/// ANTHROPIC_API_KEY = {}                        # pass through, must be set
+/// CONTAINER_NAME = { from = "HOST_NAME" }       # rename
+/// EDITOR = { optional = true }                  # skipped if unset on the host
+/// RUST_LOG = { default = "info" }               # default if unset on the host
+/// GITHUB_TOKEN = { command = "gh auth token" }  # stdout of a host command
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum EnvValue {
+    Literal(String),
+    Source(EnvSource),
+}
+
+/// Where to take the value of an environment variable from.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnvSource {
+    /// Host variable to read. Defaults to the container variable's name.
+    #[serde(default)]
+    pub from: Option<String>,
+
+    /// Shell command run on the host (in the repo root); its trimmed stdout is the value.
+    #[serde(default)]
+    pub command: Option<String>,
+
+    /// Value to use if the host variable is unset.
+    #[serde(default)]
+    pub default: Option<String>,
+
+    /// Skip the variable instead of failing if the host variable is unset.
+    #[serde(default)]
+    pub optional: bool,
+}
+
+impl EnvConfig {
+    /// Names of the variables set in the container.
+    pub fn names(&self) -> Vec<&str> {
+        self.0.keys().map(String::as_str).collect()
+    }
+
+    /// Add the entries of `other`, overriding entries with the same name.
+    pub fn extend(&mut self, other: EnvConfig) {
+        self.0.extend(other.0);
+    }
+}
+
+impl EnvSource {
+    /// Resolve the value on the host. Returns `None` for unset optional variables.
+    fn resolve(&self, name: &str, repo_root: &Path) -> Result<Option<String>> {
+        if let Some(ref command) = self.command {
+            if self.from.is_some() {
+                bail!(
+                    "Environment variable '{}': `from` and `command` are mutually exclusive",
+                    name
+                );
+            }
+            return run_env_command(name, command, repo_root).map(Some);
+        }
+
+        let host_name = self.from.as_deref().unwrap_or(name);
+        match (std::env::var(host_name), &self.default) {
+            (Ok(value), _) => Ok(Some(value)),
+            (Err(_), Some(default)) => Ok(Some(default.clone())),
+            (Err(_), None) if self.optional => Ok(None),
+            (Err(_), None) => bail!("Required environment variable '{}' is not set", host_name),
+        }
+    }
+}
+
+fn apply_devcontainer(
+    image: &mut Option<ImageConfig>,
+    env: &mut EnvConfig,
+    mounts: &mut MountsConfig,
+    hooks: &mut HooksConfig,
+    repo_root: &Path,
+) -> Result<()> {
+    let Some(ImageConfig::Devcontainer(path)) = image else {
+        return Ok(());
+    };
+    let json_path = repo_root.join(path);
+    let username = crate::config::UserInfo::current()?.username;
+    let settings =
+        Devcontainer::load(&json_path)?.into_settings(&json_path, repo_root, &username)?;
+
+    *image = Some(settings.image);
+    let mut merged_env = settings.env;
+    merged_env.extend(std::mem::take(env));
+    *env = merged_env;
+    // A mount in `.sandbox.toml` replaces the devcontainer's mount at the same path,
+    // e.g. to make a devcontainer bind mount write through to the host
+    let mut merged_mounts = settings.mounts;
+    let targets: Vec<PathBuf> = mounts.entries().map(MountEntry::target).collect();
+    merged_mounts.retain(|entry| !targets.contains(&entry.target()));
+    merged_mounts.extend(std::mem::take(mounts));
+    *mounts = merged_mounts;
+    if let Some(post_create) = settings.post_create {
+        hooks.post_create.insert(0, post_create);
+    }
+    Ok(())
+}
+
+fn run_env_command(name: &str, command: &str, repo_root: &Path) -> Result<String> {
+    let output = std::process::Command::new("sh")
+        .args(["-c", command])
+        .current_dir(repo_root)
+        .stdin(std::process::Stdio::null())
+        .output()
+        .with_context(|| format!("Failed to run command for environment variable '{}'", name))?;
+
+    if !output.status.success() {
+        bail!(
+            "Command for environment variable '{}' failed ({}): {}\n{}",
+            name,
+            output.status,
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    let stdout = String::from_utf8(output.stdout).with_context(|| {
+        format!(
+            "Command for environment variable '{}' printed invalid UTF-8",
+            name
+        )
+    })?;
+    Ok(stdout.trim().to_string())
 }
 
 /// Mount configuration with different mount types.
@@ -61,18 +293,25 @@ pub struct MountEntry {
     /// - Relative paths are relative to repo root
     /// - `~` prefix expands to user's home directory
     /// - Absolute paths are used as-is
+    /// - `$VAR` and `${VAR:-default}` expand to host environment variables
+    /// - `{a,b}` alternatives and `*`, `?`, `[...]` globs expand to several mounts
     pub host: PathBuf,
 
     /// Path inside the container. Defaults to host path if omitted.
     /// Same expansion rules apply.
     pub container: Option<PathBuf>,
+
+    /// Skip this mount if the host path doesn't exist instead of failing.
+    #[serde(default)]
+    pub optional: bool,
 }
 
 /// Docker image configuration - either a pre-built tag or build from Dockerfile.
 #[derive(Debug, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub enum ImageConfig {
-    /// Use a pre-built image tag.
+    /// Use a pre-built image tag. Pin it with `name:tag@sha256:...` (or
+    /// `name@sha256:...`) to verify the local image's digest before starting.
     #[serde(rename = "tag")]
     Tag(String),
 
@@ -83,7 +322,84 @@ pub enum ImageConfig {
         dockerfile: PathBuf,
         /// Build context directory (relative to repo root). Defaults to repo root.
         context: Option<PathBuf>,
+        /// Extra build args. May override `USER_NAME`, `USER_ID` and `GROUP_ID`.
+        #[serde(default)]
+        args: BTreeMap<String, String>,
+        /// Stage to build in a multi-stage Dockerfile.
+        target: Option<String>,
+        /// Target platform, e.g. `linux/amd64`.
+        platform: Option<String>,
     },
+
+    /// Use the image or Dockerfile of a `devcontainer.json` (relative to repo root),
+    /// along with its mounts and environment. Replaced by the resulting `Tag` or
+    /// `Build` when the config is loaded.
+    #[serde(rename = "devcontainer")]
+    Devcontainer(PathBuf),
+}
+
+/// Shell commands run inside the container (as the sandbox user, in the repo
+/// directory) after it has started. Output goes to `hooks.log` in the sandbox directory.
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct HooksConfig {
+    /// Run once, the first time the sandbox's container starts (e.g. `cargo fetch`).
+    #[serde(default, rename = "post-create")]
+    pub post_create: Vec<String>,
+
+    /// Run every time the container starts (e.g. starting a database).
+    #[serde(default, rename = "post-start")]
+    pub post_start: Vec<String>,
+}
+
+impl HooksConfig {
+    /// Append the hooks of `other` to this config.
+    pub fn extend(&mut self, other: HooksConfig) {
+        self.post_create.extend(other.post_create);
+        self.post_start.extend(other.post_start);
+    }
+}
+
+/// Shell commands run on the host (in the repo directory) when a sandbox event
+/// happens. Each command gets the event as a JSON object on stdin.
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct HostHooksConfig {
+    /// The sandbox's container started for the first time.
+    #[serde(default, rename = "on-create")]
+    pub on_create: Vec<String>,
+
+    /// The sandbox's container started.
+    #[serde(default, rename = "on-start")]
+    pub on_start: Vec<String>,
+
+    /// The sandbox's container stopped after its last client disconnected.
+    #[serde(default, rename = "on-stop")]
+    pub on_stop: Vec<String>,
+
+    /// The sandbox was deleted.
+    #[serde(default, rename = "on-delete")]
+    pub on_delete: Vec<String>,
+
+    /// Git sync moved the sandbox's branch.
+    #[serde(default, rename = "on-branch-update")]
+    pub on_branch_update: Vec<String>,
+
+    /// Seconds after which a host hook is killed, along with the processes it
+    /// started (default: 60).
+    pub timeout: Option<u64>,
+}
+
+impl HostHooksConfig {
+    /// Append the hooks of `other` to this config.
+    pub fn extend(&mut self, other: HostHooksConfig) {
+        self.on_create.extend(other.on_create);
+        self.on_start.extend(other.on_start);
+        self.on_stop.extend(other.on_stop);
+        self.on_delete.extend(other.on_delete);
+        self.on_branch_update.extend(other.on_branch_update);
+        self.timeout = other.timeout.or(self.timeout);
+    }
 }
 
 /// Agent configuration.
@@ -93,17 +409,161 @@ pub struct AgentConfig {
     /// Default model.
     pub model: Option<Model>,
 
-    /// Editor for composing messages.
+    /// Editor for composing messages, run by the shell so it can take arguments
+    /// (default: `$VISUAL`, then `$EDITOR`, then vim).
     pub editor: Option<String>,
+
+    /// How messages are composed.
+    pub input: Option<InputMode>,
+
+    /// Most output tokens per response, by model (default: 4096).
+    #[serde(default, rename = "max-tokens")]
+    pub max_tokens: BTreeMap<Model, u32>,
+
+    /// Stop after this many model responses.
+    #[serde(rename = "max-turns")]
+    pub max_turns: Option<u32>,
+
+    /// Stop before running more than this many tool calls.
+    #[serde(rename = "max-tool-calls")]
+    pub max_tool_calls: Option<u32>,
+
+    /// Stop once requests have used this many input tokens, cached or not.
+    #[serde(rename = "max-input-tokens")]
+    pub max_input_tokens: Option<u64>,
+
+    /// Stop once requests have cost this many dollars.
+    #[serde(rename = "max-cost")]
+    pub max_cost: Option<f64>,
+
+    /// Output tokens the model may spend thinking before each response, enabling
+    /// extended thinking. Must be less than max-tokens.
+    #[serde(rename = "thinking-budget")]
+    pub thinking_budget: Option<u32>,
+
+    /// Print the model's thinking, dimmed.
+    #[serde(rename = "show-thinking")]
+    pub show_thinking: Option<bool>,
+
+    /// Seconds after which bash commands are killed, unless the model asks for
+    /// another timeout (default: 120).
+    #[serde(rename = "bash-timeout")]
+    pub bash_timeout: Option<u64>,
+}
+
+impl MountsConfig {
+    /// Append the mounts of `other` to this config.
+    pub fn extend(&mut self, other: MountsConfig) {
+        self.readonly.extend(other.readonly);
+        self.unsafe_write.extend(other.unsafe_write);
+        self.overlay.extend(other.overlay);
+    }
+
+    /// All mount entries, of every type.
+    fn entries(&self) -> impl Iterator<Item = &MountEntry> {
+        self.readonly
+            .iter()
+            .chain(&self.unsafe_write)
+            .chain(&self.overlay)
+    }
+
+    /// Keep only the entries for which `keep` returns true.
+    fn retain(&mut self, mut keep: impl FnMut(&MountEntry) -> bool) {
+        self.readonly.retain(&mut keep);
+        self.unsafe_write.retain(&mut keep);
+        self.overlay.retain(&mut keep);
+    }
+}
+
+impl AgentConfig {
+    /// Override settings with those set in `other`.
+    pub fn merge(&mut self, other: AgentConfig) {
+        self.model = other.model.or(self.model);
+        self.editor = other.editor.or(self.editor.take());
+        self.input = other.input.or(self.input);
+        self.max_tokens.extend(other.max_tokens);
+        self.max_turns = other.max_turns.or(self.max_turns);
+        self.max_tool_calls = other.max_tool_calls.or(self.max_tool_calls);
+        self.max_input_tokens = other.max_input_tokens.or(self.max_input_tokens);
+        self.max_cost = other.max_cost.or(self.max_cost);
+        self.thinking_budget = other.thinking_budget.or(self.thinking_budget);
+        self.show_thinking = other.show_thinking.or(self.show_thinking);
+        self.bash_timeout = other.bash_timeout.or(self.bash_timeout);
+    }
+}
+
+/// A configuration file layer. Later layers take precedence over earlier ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum ConfigLayer {
+    /// User-global defaults in `$XDG_CONFIG_HOME/sandbox/config.toml`
+    /// (`~/.config/sandbox/config.toml` by default).
+    User,
+    /// Repository config in `<repo>/.sandbox.toml` (usually committed).
+    Repo,
+    /// Personal per-repo overrides in `<repo>/.sandbox.local.toml` (untracked).
+    Local,
+}
+
+impl ConfigLayer {
+    /// All layers in order of increasing precedence.
+    pub const ALL: [ConfigLayer; 3] = [ConfigLayer::User, ConfigLayer::Repo, ConfigLayer::Local];
+
+    /// Path of the config file for this layer.
+    pub fn path(&self, repo_root: &Path) -> Result<PathBuf> {
+        match self {
+            ConfigLayer::User => {
+                // `$XDG_CONFIG_HOME` is honored on every platform, so that tests and
+                // scripts can point the user config somewhere else.
+                let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+                    .map(PathBuf::from)
+                    .filter(|dir| dir.is_absolute())
+                    .or_else(dirs::config_dir)
+                    .context("Could not determine config directory")?;
+                Ok(config_dir.join("sandbox").join("config.toml"))
+            }
+            ConfigLayer::Repo => Ok(repo_root.join(".sandbox.toml")),
+            ConfigLayer::Local => Ok(repo_root.join(".sandbox.local.toml")),
+        }
+    }
+}
+
+/// The effective configuration merged from all layers, along with the layer
+/// each value came from.
+#[derive(Debug, Clone)]
+pub struct LayeredConfig {
+    pub config: SandboxConfig,
+    /// Layers that were found, with their file paths.
+    pub layers: Vec<(ConfigLayer, PathBuf)>,
+    /// The merged raw TOML, used for displaying the effective config.
+    merged: toml::Table,
+    /// Layer(s) each value came from, keyed by dotted path. Arrays have one entry per element.
+    sources: BTreeMap<String, Vec<ConfigLayer>>,
 }
 
 impl SandboxConfig {
-    /// Load config from the `.sandbox.toml` file in the given repo root.
-    /// Returns an error if the file doesn't exist.
+    /// Load the effective config for the given repo root, merging the user-global
+    /// config, `.sandbox.toml` and `.sandbox.local.toml`.
+    /// Returns an error if neither of the repo-level files exists.
     pub fn load(repo_root: &Path) -> Result<Self> {
-        let config_path = repo_root.join(".sandbox.toml");
+        Ok(Self::load_layered(repo_root)?.config)
+    }
+
+    /// Like [`SandboxConfig::load`], but also returns where each value came from.
+    pub fn load_layered(repo_root: &Path) -> Result<LayeredConfig> {
+        Self::load_layered_with_user_config(repo_root, &ConfigLayer::User.path(repo_root)?)
+    }
+
+    /// Like [`SandboxConfig::load_layered`], but reads the user-global config from
+    /// `user_config` instead of the default location.
+    pub fn load_layered_with_user_config(
+        repo_root: &Path,
+        user_config: &Path,
+    ) -> Result<LayeredConfig> {
+        let config_path = ConfigLayer::Repo.path(repo_root)?;
+        let local_path = ConfigLayer::Local.path(repo_root)?;
 
-        if !config_path.exists() {
+        if !config_path.exists() && !local_path.exists() {
             bail!(
                 "No .sandbox.toml config file found at {}.\n\
                  Please create a .sandbox.toml file to configure the sandbox.\n\
@@ -113,33 +573,150 @@ impl SandboxConfig {
             );
         }
 
-        let contents = std::fs::read_to_string(&config_path)
-            .with_context(|| format!("Failed to read {}", config_path.display()))?;
+        let mut layers = Vec::new();
+        for layer in ConfigLayer::ALL {
+            let path = match layer {
+                ConfigLayer::User => user_config.to_path_buf(),
+                _ => layer.path(repo_root)?,
+            };
+            if path.exists() {
+                layers.push((layer, path));
+            }
+        }
 
-        let config: SandboxConfig = toml::from_str(&contents)
-            .with_context(|| format!("Failed to parse {}", config_path.display()))?;
+        let mut layered = Self::load_layers(&layers)?;
+        layered.config.resolve_devcontainers(repo_root)?;
+        Ok(layered)
+    }
 
-        Ok(config)
+    /// Replace `devcontainer` images in the base config and profiles by the settings
+    /// read from the `devcontainer.json`. Settings from `.sandbox.toml` take precedence.
+    fn resolve_devcontainers(&mut self, repo_root: &Path) -> Result<()> {
+        apply_devcontainer(
+            &mut self.image,
+            &mut self.env,
+            &mut self.mounts,
+            &mut self.hooks,
+            repo_root,
+        )?;
+        for profile in self.profile.values_mut() {
+            apply_devcontainer(
+                &mut profile.image,
+                &mut profile.env,
+                &mut profile.mounts,
+                &mut profile.hooks,
+                repo_root,
+            )?;
+        }
+        Ok(())
     }
 
-    /// Resolve environment variables from the host.
-    /// Returns an error if any variable is not set.
-    pub fn resolve_env_vars(&self) -> Result<Vec<(String, String)>> {
-        self.env
-            .iter()
-            .map(|name| {
-                std::env::var(name)
-                    .map(|value| (name.clone(), value))
-                    .with_context(|| format!("Required environment variable '{}' is not set", name))
-            })
-            .collect()
+    /// Load and merge the given config files, in order of increasing precedence.
+    ///
+    /// Tables are merged key by key, lists are appended and other values are
+    /// overridden by later layers. The `image` setting is replaced as a whole.
+    pub fn load_layers(layers: &[(ConfigLayer, PathBuf)]) -> Result<LayeredConfig> {
+        let mut merged = toml::Table::new();
+        let mut sources = BTreeMap::new();
+
+        for (layer, path) in layers {
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+
+            // Validate each layer on its own so errors point at the offending file
+            let _: SandboxConfig = toml::from_str(&contents)
+                .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+            let table: toml::Table = toml::from_str(&contents)
+                .with_context(|| format!("Failed to parse {}", path.display()))?;
+            merge_tables(&mut merged, table, *layer, "", &mut sources);
+        }
+
+        let config = SandboxConfig::deserialize(toml::Value::Table(merged.clone()))
+            .context("Failed to merge configuration files")?;
+
+        Ok(LayeredConfig {
+            config,
+            layers: layers.to_vec(),
+            merged,
+            sources,
+        })
+    }
+
+    /// Apply the named profile, if any. Returns an error if the profile isn't defined.
+    pub fn with_profile(mut self, name: Option<&str>) -> Result<Self> {
+        let Some(name) = name else {
+            return Ok(self);
+        };
+
+        let profile = match self.profile.get(name) {
+            Some(p) => p.clone(),
+            None => {
+                let available: Vec<_> = self.profile.keys().map(String::as_str).collect();
+                bail!(
+                    "Profile '{}' is not defined. Available profiles: {}",
+                    name,
+                    if available.is_empty() {
+                        "(none)".to_string()
+                    } else {
+                        available.join(", ")
+                    }
+                );
+            }
+        };
+
+        self.env.extend(profile.env);
+        self.env_files.extend(profile.env_files);
+        self.runtime = profile.runtime.or(self.runtime);
+        self.backend = profile.backend.or(self.backend);
+        self.overlay_mode = profile.overlay_mode.or(self.overlay_mode);
+        self.mounts.extend(profile.mounts);
+        self.image = profile.image.or(self.image);
+        self.agent.merge(profile.agent);
+        self.hooks.extend(profile.hooks);
+        self.host_hooks.extend(profile.host_hooks);
+        self.active_profile = Some(name.to_string());
+
+        Ok(self)
+    }
+
+    /// Resolve the container environment on the host: variables from `env-files`
+    /// first, then the `env` entries.
+    /// Returns an error if a required variable is not set or a command fails.
+    pub fn resolve_env_vars(&self, repo_root: &Path) -> Result<Vec<(String, String)>> {
+        let mut vars = BTreeMap::new();
+
+        for env_file in &self.env_files {
+            let path = Self::expand_host_path(env_file, repo_root)?;
+            let entries = dotenvy::from_path_iter(&path)
+                .with_context(|| format!("Failed to read env file {}", path.display()))?;
+            for entry in entries {
+                let (name, value) = entry
+                    .with_context(|| format!("Failed to parse env file {}", path.display()))?;
+                vars.insert(name, value);
+            }
+        }
+
+        for (name, value) in &self.env.0 {
+            let value = match value {
+                EnvValue::Literal(value) => Some(value.clone()),
+                EnvValue::Source(source) => source.resolve(name, repo_root)?,
+            };
+            if let Some(value) = value {
+                vars.insert(name.clone(), value);
+            }
+        }
+
+        Ok(vars.into_iter().collect())
     }
 
     /// Expand a path according to the rules:
+    /// - `$VAR`, `${VAR}` and `${VAR:-default}` -> host environment variables
     /// - `~` prefix -> user's home directory
     /// - Relative path -> relative to repo root
     /// - Absolute path -> as-is
     pub fn expand_host_path(path: &Path, repo_root: &Path) -> Result<PathBuf> {
+        let path = PathBuf::from(expand_env_vars(&path.to_string_lossy())?);
         let path_str = path.to_string_lossy();
         if let Some(suffix) = path_str.strip_prefix("~/") {
             let home = dirs::home_dir().context("Could not determine home directory")?;
@@ -155,16 +732,396 @@ impl SandboxConfig {
 
     /// Expand a container path. Similar to expand_host_path but uses
     /// /home/<username> for ~ expansion inside the container.
-    pub fn expand_container_path(path: &Path, username: &str) -> PathBuf {
+    /// Environment variables are taken from the host.
+    pub fn expand_container_path(path: &Path, username: &str) -> Result<PathBuf> {
+        let path = PathBuf::from(expand_env_vars(&path.to_string_lossy())?);
         let path_str = path.to_string_lossy();
         if let Some(suffix) = path_str.strip_prefix("~/") {
-            PathBuf::from(format!("/home/{}/{}", username, suffix))
+            Ok(PathBuf::from(format!("/home/{}/{}", username, suffix)))
         } else if path_str == "~" {
-            PathBuf::from(format!("/home/{}", username))
+            Ok(PathBuf::from(format!("/home/{}", username)))
+        } else {
+            Ok(path.to_path_buf())
+        }
+    }
+}
+
+impl MountEntry {
+    /// The configured path inside the container, before expansion.
+    fn target(&self) -> PathBuf {
+        self.container.clone().unwrap_or_else(|| self.host.clone())
+    }
+
+    /// Expand brace alternatives (`{a,b}`) and glob patterns (`*`, `?`, `[...]`) in the
+    /// host path into concrete mount entries.
+    ///
+    /// The returned entries have absolute host paths. If the pattern was home-relative and
+    /// no container path is given, the container path stays home-relative so that it maps
+    /// to the container user's home. An explicit container path may use the same number of
+    /// brace alternatives as the host path, which are then paired up. A single container
+    /// path for several alternatives or glob matches names the directory they're mounted
+    /// into, each under its file name.
+    pub fn expand(&self, repo_root: &Path) -> Result<Vec<MountEntry>> {
+        let host_alternatives = expand_braces(&self.host.to_string_lossy());
+        let container_alternatives = self
+            .container
+            .as_ref()
+            .map(|c| expand_braces(&c.to_string_lossy()));
+
+        if let Some(ref containers) = container_alternatives {
+            if containers.len() != 1 && containers.len() != host_alternatives.len() {
+                bail!(
+                    "Mount container path '{}' has {} alternatives, but host path '{}' has {}",
+                    self.container.as_ref().unwrap().display(),
+                    containers.len(),
+                    self.host.display(),
+                    host_alternatives.len()
+                );
+            }
+        }
+
+        // A single container path shared by several host alternatives is a directory
+        let container_is_dir = host_alternatives.len() > 1
+            && container_alternatives
+                .as_ref()
+                .is_some_and(|c| c.len() == 1);
+
+        let mut entries = Vec::new();
+        for (i, alternative) in host_alternatives.iter().enumerate() {
+            let container = container_alternatives
+                .as_ref()
+                .map(|c| PathBuf::from(if c.len() == 1 { &c[0] } else { &c[i] }));
+            let host_path = SandboxConfig::expand_host_path(Path::new(alternative), repo_root)?;
+
+            if !is_glob_pattern(&host_path.to_string_lossy()) {
+                let container = match container {
+                    Some(dir) if container_is_dir => {
+                        Some(dir.join(host_path.file_name().unwrap_or_default()))
+                    }
+                    Some(path) => Some(path),
+                    None => home_relative(alternative, &host_path),
+                };
+                entries.push(MountEntry {
+                    host: host_path,
+                    container,
+                    optional: self.optional,
+                });
+                continue;
+            }
+
+            let pattern = host_path.to_string_lossy();
+            let mut matches = glob::glob(&pattern)
+                .with_context(|| format!("Invalid mount pattern '{}'", self.host.display()))?
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .with_context(|| format!("Failed to expand mount pattern '{}'", pattern))?;
+            matches.sort();
+
+            if matches.is_empty() && !self.optional {
+                bail!(
+                    "Mount pattern '{}' matched no paths on the host",
+                    self.host.display()
+                );
+            }
+
+            for matched in matches {
+                let container = match container {
+                    Some(ref dir) => Some(dir.join(matched.file_name().unwrap_or_default())),
+                    None => home_relative(alternative, &matched),
+                };
+                entries.push(MountEntry {
+                    host: matched,
+                    container,
+                    optional: self.optional,
+                });
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+impl LayeredConfig {
+    /// Render the effective config, one value per line, annotated with its layer.
+    /// List elements are shown as `key += value` to reflect that layers append to lists.
+    pub fn display_with_sources(&self) -> String {
+        let mut out = String::new();
+        self.render_table(&self.merged, "", &mut out);
+        out
+    }
+
+    fn render_table(&self, table: &toml::Table, prefix: &str, out: &mut String) {
+        for (key, value) in table {
+            let path = join_key(prefix, key);
+            let sources = self.sources.get(&path).map(Vec::as_slice).unwrap_or(&[]);
+            match value {
+                toml::Value::Table(t) if !replaces_wholesale(&path) && !t.is_empty() => {
+                    self.render_table(t, &path, out)
+                }
+                toml::Value::Array(items) => {
+                    for (i, item) in items.iter().enumerate() {
+                        let layer = sources.get(i).map(|l| l.to_string()).unwrap_or_default();
+                        out.push_str(&format!("{} += {}  # {}\n", path, item, layer));
+                    }
+                }
+                _ => {
+                    let layer = sources.last().map(|l| l.to_string()).unwrap_or_default();
+                    out.push_str(&format!("{} = {}  # {}\n", path, value, layer));
+                }
+            }
+        }
+    }
+}
+
+fn join_key(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+/// Tables that represent a single choice (like `[image]`, which is either a tag or a
+/// build, also within profiles) must be replaced rather than merged key by key.
+fn replaces_wholesale(path: &str) -> bool {
+    path == "image" || (path.starts_with("profile.") && path.ends_with(".image"))
+}
+
+/// Merge `overlay` into `base`, recording the layer of each merged value in `sources`.
+fn merge_tables(
+    base: &mut toml::Table,
+    overlay: toml::Table,
+    layer: ConfigLayer,
+    prefix: &str,
+    sources: &mut BTreeMap<String, Vec<ConfigLayer>>,
+) {
+    for (key, mut value) in overlay {
+        let path = join_key(prefix, &key);
+        if let Some(existing) = base.get_mut(&key).filter(|_| is_env_path(&path)) {
+            unify_env_forms(existing, &mut value, &path, sources);
+        }
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(existing)), toml::Value::Table(new))
+                if !replaces_wholesale(&path) =>
+            {
+                merge_tables(existing, new, layer, &path, sources);
+            }
+            (Some(toml::Value::Array(existing)), toml::Value::Array(new)) => {
+                sources
+                    .entry(path)
+                    .or_default()
+                    .extend(std::iter::repeat_n(layer, new.len()));
+                existing.extend(new);
+            }
+            (_, value) => {
+                // Drop sources recorded for anything this value replaces
+                let nested = format!("{}.", path);
+                sources.retain(|k, _| k != &path && !k.starts_with(&nested));
+                record_sources(&value, layer, &path, sources);
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Whether `path` is an `env` setting, in the base config or in a profile.
+fn is_env_path(path: &str) -> bool {
+    path == "env" || (path.starts_with("profile.") && path.ends_with(".env"))
+}
+
+/// `env` is either a list of names or a table. When two layers use different forms,
+/// turn the list into the equivalent table so that the layers merge instead of
+/// replacing each other.
+fn unify_env_forms(
+    existing: &mut toml::Value,
+    new: &mut toml::Value,
+    path: &str,
+    sources: &mut BTreeMap<String, Vec<ConfigLayer>>,
+) {
+    if existing.is_array() == new.is_array() {
+        return;
+    }
+    if let toml::Value::Array(names) = existing {
+        let layers = sources.remove(path).unwrap_or_default();
+        for (name, layer) in names.iter().zip(layers) {
+            if let Some(name) = name.as_str() {
+                sources.insert(join_key(path, name), vec![layer]);
+            }
+        }
+        *existing = env_list_to_table(names);
+    }
+    if let toml::Value::Array(names) = new {
+        *new = env_list_to_table(names);
+    }
+}
+
+/// The `[env]` table passing through the host variables named in `names`.
+fn env_list_to_table(names: &[toml::Value]) -> toml::Value {
+    toml::Value::Table(
+        names
+            .iter()
+            .filter_map(toml::Value::as_str)
+            .map(|name| (name.to_string(), toml::Value::Table(toml::Table::new())))
+            .collect(),
+    )
+}
+
+fn record_sources(
+    value: &toml::Value,
+    layer: ConfigLayer,
+    path: &str,
+    sources: &mut BTreeMap<String, Vec<ConfigLayer>>,
+) {
+    match value {
+        toml::Value::Table(table) if !replaces_wholesale(path) && !table.is_empty() => {
+            for (key, value) in table {
+                record_sources(value, layer, &join_key(path, key), sources);
+            }
+        }
+        toml::Value::Array(items) => {
+            sources.insert(path.to_string(), vec![layer; items.len()]);
+        }
+        _ => {
+            sources.insert(path.to_string(), vec![layer]);
+        }
+    }
+}
+
+/// If `pattern` is home-relative, express `expanded` as a `~/`-relative path.
+fn home_relative(pattern: &str, expanded: &Path) -> Option<PathBuf> {
+    if pattern != "~" && !pattern.starts_with("~/") {
+        return None;
+    }
+    let home = dirs::home_dir()?;
+    let relative = expanded.strip_prefix(&home).ok()?;
+    Some(Path::new("~").join(relative))
+}
+
+fn is_glob_pattern(s: &str) -> bool {
+    s.contains(['*', '?', '['])
+}
+
+/// Expand `$VAR`, `${VAR}` and `${VAR:-default}` using the host environment.
+/// `$$` produces a literal `$`. Referencing an unset variable without a default is an error.
+pub fn expand_env_vars(s: &str) -> Result<String> {
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(pos) = rest.find('$') {
+        result.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+
+        if let Some(after) = rest.strip_prefix('$') {
+            result.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let end = after
+                .find('}')
+                .with_context(|| format!("Unterminated '${{' in '{}'", s))?;
+            let expr = &after[..end];
+            let (name, default) = match expr.split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (expr, None),
+            };
+            let value = match default {
+                Some(default) => std::env::var(name)
+                    .ok()
+                    .filter(|v| !v.is_empty())
+                    .unwrap_or_else(|| default.to_string()),
+                None => std::env::var(name).with_context(|| {
+                    format!("Environment variable '{}' is not set (in '{}')", name, s)
+                })?,
+            };
+            result.push_str(&value);
+            rest = &after[end + 1..];
         } else {
-            path.to_path_buf()
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            if end == 0 {
+                // A lone `$` not followed by a name is kept literally.
+                result.push('$');
+                continue;
+            }
+            let name = &rest[..end];
+            let value = std::env::var(name).with_context(|| {
+                format!("Environment variable '{}' is not set (in '{}')", name, s)
+            })?;
+            result.push_str(&value);
+            rest = &rest[end..];
+        }
+    }
+
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Expand shell-style brace alternatives, e.g. `~/.config/{fish,nvim}`.
+/// Nested groups are supported; `${...}` variable references are left untouched.
+fn expand_braces(s: &str) -> Vec<String> {
+    let bytes = s.as_bytes();
+    let mut open = None;
+    let mut depth = 0;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'$' if bytes.get(i + 1) == Some(&b'{') => {
+                // Skip over variable references
+                match s[i..].find('}') {
+                    Some(end) => i += end,
+                    None => break,
+                }
+            }
+            b'{' => {
+                if depth == 0 {
+                    open = Some(i);
+                }
+                depth += 1;
+            }
+            b'}' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    let start = open.unwrap();
+                    let prefix = &s[..start];
+                    let suffix = &s[i + 1..];
+                    let alternatives = split_top_level_commas(&s[start + 1..i]);
+                    if alternatives.len() < 2 {
+                        // `{foo}` is not an alternation; keep scanning after it
+                        open = None;
+                        i += 1;
+                        continue;
+                    }
+                    return alternatives
+                        .iter()
+                        .flat_map(|alt| expand_braces(&format!("{}{}{}", prefix, alt, suffix)))
+                        .collect();
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    vec![s.to_string()]
+}
+
+fn split_top_level_commas(s: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
         }
     }
+    parts.push(&s[start..]);
+    parts
 }
 
 #[cfg(test)]
@@ -178,6 +1135,12 @@ mod tests {
         fs::write(dir.join(".sandbox.toml"), content).unwrap();
     }
 
+    /// Load the config without reading the real user-global config.
+    fn load_config(dir: &Path) -> Result<SandboxConfig> {
+        let user_config = dir.join("no-user-config.toml");
+        Ok(SandboxConfig::load_layered_with_user_config(dir, &user_config)?.config)
+    }
+
     #[test]
     fn test_minimal_config() {
         let dir = TempDir::new().unwrap();
@@ -188,8 +1151,8 @@ env = ["ANTHROPIC_API_KEY"]
 "#,
         );
 
-        let config = SandboxConfig::load(dir.path()).unwrap();
-        assert_eq!(config.env, vec!["ANTHROPIC_API_KEY"]);
+        let config = load_config(dir.path()).unwrap();
+        assert_eq!(config.env.names(), vec!["ANTHROPIC_API_KEY"]);
         assert!(config.mounts.readonly.is_empty());
         assert!(config.mounts.unsafe_write.is_empty());
         assert!(config.mounts.overlay.is_empty());
@@ -230,11 +1193,22 @@ context = "."
 [agent]
 model = "sonnet"
 editor = "vim"
+input = "inline"
+max-turns = 20
+max-cost = 1.5
+thinking-budget = 2048
+bash-timeout = 600
+
+[agent.max-tokens]
+opus = 32000
 "#,
         );
 
-        let config = SandboxConfig::load(dir.path()).unwrap();
-        assert_eq!(config.env, vec!["ANTHROPIC_API_KEY", "GITHUB_TOKEN"]);
+        let config = load_config(dir.path()).unwrap();
+        assert_eq!(
+            config.env.names(),
+            vec!["ANTHROPIC_API_KEY", "GITHUB_TOKEN"]
+        );
         assert_eq!(config.runtime, Some(Runtime::SysboxRunc));
         assert_eq!(config.overlay_mode, Some(OverlayMode::Copy));
         assert_eq!(config.mounts.readonly.len(), 2);
@@ -244,14 +1218,29 @@ editor = "vim"
             Some(ImageConfig::Build {
                 dockerfile,
                 context,
+                args,
+                target,
+                platform,
             }) => {
                 assert_eq!(dockerfile, &PathBuf::from("Dockerfile"));
                 assert_eq!(context, &Some(PathBuf::from(".")));
+                assert!(args.is_empty());
+                assert_eq!(target, &None);
+                assert_eq!(platform, &None);
             }
             _ => panic!("Expected ImageConfig::Build"),
         }
         assert_eq!(config.agent.model, Some(Model::Sonnet));
         assert_eq!(config.agent.editor, Some("vim".to_string()));
+        assert_eq!(config.agent.input, Some(InputMode::Inline));
+        assert_eq!(config.agent.max_turns, Some(20));
+        assert_eq!(config.agent.max_tool_calls, None);
+        assert_eq!(config.agent.max_cost, Some(1.5));
+        assert_eq!(config.agent.thinking_budget, Some(2048));
+        assert_eq!(config.agent.show_thinking, None);
+        assert_eq!(config.agent.bash_timeout, Some(600));
+        assert_eq!(config.agent.max_tokens.get(&Model::Opus), Some(&32000));
+        assert_eq!(config.agent.max_tokens.get(&Model::Haiku), None);
     }
 
     #[test]
@@ -265,17 +1254,94 @@ tag = "myimage:latest"
 "#,
         );
 
-        let config = SandboxConfig::load(dir.path()).unwrap();
+        let config = load_config(dir.path()).unwrap();
         match &config.image {
             Some(ImageConfig::Tag(tag)) => assert_eq!(tag, "myimage:latest"),
             _ => panic!("Expected ImageConfig::Tag"),
         }
     }
 
+    #[test]
+    fn test_image_build_options() {
+        let dir = TempDir::new().unwrap();
+        create_config(
+            dir.path(),
+            r#"
+[image.build]
+dockerfile = "docker/Dockerfile"
+args = { RUST_VERSION = "1.80", USER_NAME = "dev" }
+target = "dev"
+platform = "linux/amd64"
+"#,
+        );
+
+        let config = load_config(dir.path()).unwrap();
+        match &config.image {
+            Some(ImageConfig::Build {
+                args,
+                target,
+                platform,
+                ..
+            }) => {
+                assert_eq!(args.get("RUST_VERSION").map(String::as_str), Some("1.80"));
+                assert_eq!(args.get("USER_NAME").map(String::as_str), Some("dev"));
+                assert_eq!(target.as_deref(), Some("dev"));
+                assert_eq!(platform.as_deref(), Some("linux/amd64"));
+            }
+            _ => panic!("Expected ImageConfig::Build"),
+        }
+    }
+
+    #[test]
+    fn test_image_devcontainer() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir_all(dir.path().join(".devcontainer")).unwrap();
+        fs::write(
+            dir.path().join(".devcontainer/devcontainer.json"),
+            r#"{
+                "image": "mcr.microsoft.com/devcontainers/rust:1",
+                "containerEnv": { "FOO": "devcontainer", "BAR": "devcontainer" },
+                "mounts": [
+                    "source=/tmp,target=/host-tmp,type=bind",
+                    "source=/var/tmp,target=/var-tmp,type=bind"
+                ]
+            }"#,
+        )
+        .unwrap();
+        create_config(
+            dir.path(),
+            r#"
+[image]
+devcontainer = ".devcontainer/devcontainer.json"
+
+[env]
+FOO = "sandbox"
+
+[[mounts.unsafe-write]]
+host = "/tmp"
+container = "/host-tmp"
+"#,
+        );
+
+        let config = load_config(dir.path()).unwrap();
+        assert!(
+            matches!(config.image, Some(ImageConfig::Tag(ref t)) if t == "mcr.microsoft.com/devcontainers/rust:1")
+        );
+        assert!(matches!(config.env.0.get("FOO"), Some(EnvValue::Literal(v)) if v == "sandbox"));
+        assert!(
+            matches!(config.env.0.get("BAR"), Some(EnvValue::Literal(v)) if v == "devcontainer")
+        );
+        // Devcontainer bind mounts don't write through unless `.sandbox.toml` says so
+        let overlay: Vec<_> = config.mounts.overlay.iter().map(|m| &m.host).collect();
+        assert_eq!(overlay, vec![&PathBuf::from("/var/tmp")]);
+        let unsafe_write: Vec<_> = config.mounts.unsafe_write.iter().map(|m| &m.host).collect();
+        assert_eq!(unsafe_write, vec![&PathBuf::from("/tmp")]);
+    }
+
     #[test]
     fn test_missing_config_file() {
         let dir = TempDir::new().unwrap();
-        let result = SandboxConfig::load(dir.path());
+        let result = load_config(dir.path());
         assert!(result.is_err());
         assert!(result.unwrap_err().to_string().contains("No .sandbox.toml"));
     }
@@ -304,20 +1370,424 @@ tag = "myimage:latest"
     #[test]
     fn test_expand_container_path() {
         // Relative path stays as-is (will be relative to workdir in container)
-        let expanded = SandboxConfig::expand_container_path(&PathBuf::from("target"), "testuser");
+        let expanded =
+            SandboxConfig::expand_container_path(&PathBuf::from("target"), "testuser").unwrap();
         assert_eq!(expanded, PathBuf::from("target"));
 
         // Absolute path stays as-is
         let expanded =
-            SandboxConfig::expand_container_path(&PathBuf::from("/etc/hosts"), "testuser");
+            SandboxConfig::expand_container_path(&PathBuf::from("/etc/hosts"), "testuser").unwrap();
         assert_eq!(expanded, PathBuf::from("/etc/hosts"));
 
         // Home-relative path expands to /home/<username>
         let expanded =
-            SandboxConfig::expand_container_path(&PathBuf::from("~/.gitconfig"), "testuser");
+            SandboxConfig::expand_container_path(&PathBuf::from("~/.gitconfig"), "testuser")
+                .unwrap();
         assert_eq!(expanded, PathBuf::from("/home/testuser/.gitconfig"));
     }
 
+    #[test]
+    fn test_expand_env_vars() {
+        std::env::set_var("SANDBOX_TEST_EXPAND_VAR", "/opt/cargo");
+        std::env::remove_var("SANDBOX_TEST_UNSET_VAR");
+
+        assert_eq!(
+            expand_env_vars("$SANDBOX_TEST_EXPAND_VAR/registry").unwrap(),
+            "/opt/cargo/registry"
+        );
+        assert_eq!(
+            expand_env_vars("${SANDBOX_TEST_EXPAND_VAR}/registry").unwrap(),
+            "/opt/cargo/registry"
+        );
+        assert_eq!(
+            expand_env_vars("${SANDBOX_TEST_UNSET_VAR:-~/.cargo}/registry").unwrap(),
+            "~/.cargo/registry"
+        );
+        assert_eq!(expand_env_vars("cost$$").unwrap(), "cost$");
+
+        let err = expand_env_vars("$SANDBOX_TEST_UNSET_VAR/registry").unwrap_err();
+        assert!(err.to_string().contains("SANDBOX_TEST_UNSET_VAR"));
+    }
+
+    #[test]
+    fn test_expand_braces() {
+        assert_eq!(
+            expand_braces("~/.config/{fish,nvim}"),
+            vec!["~/.config/fish", "~/.config/nvim"]
+        );
+        assert_eq!(
+            expand_braces("{a,b}/{c,d}"),
+            vec!["a/c", "a/d", "b/c", "b/d"]
+        );
+        assert_eq!(expand_braces("${HOME:-x}/y"), vec!["${HOME:-x}/y"]);
+        assert_eq!(expand_braces("plain"), vec!["plain"]);
+    }
+
+    #[test]
+    fn test_mount_entry_expand_glob() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir_all(dir.path().join("data/one")).unwrap();
+        fs::create_dir_all(dir.path().join("data/two")).unwrap();
+
+        let entry = MountEntry {
+            host: PathBuf::from("data/*"),
+            container: Some(PathBuf::from("/mnt")),
+            optional: false,
+        };
+        let entries = entry.expand(dir.path()).unwrap();
+        let pairs: Vec<_> = entries
+            .iter()
+            .map(|e| (e.host.clone(), e.container.clone().unwrap()))
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                (dir.path().join("data/one"), PathBuf::from("/mnt/one")),
+                (dir.path().join("data/two"), PathBuf::from("/mnt/two")),
+            ]
+        );
+
+        // A pattern that matches nothing is an error unless the mount is optional
+        let entry = MountEntry {
+            host: PathBuf::from("missing/*"),
+            container: None,
+            optional: false,
+        };
+        assert!(entry.expand(dir.path()).is_err());
+        let entry = MountEntry {
+            optional: true,
+            ..entry
+        };
+        assert!(entry.expand(dir.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_mount_entry_expand_braces_into_directory() {
+        let entry = MountEntry {
+            host: PathBuf::from("config/{fish,nvim}"),
+            container: Some(PathBuf::from("/etc/xdg")),
+            optional: true,
+        };
+        let entries = entry.expand(Path::new("/repo")).unwrap();
+        let pairs: Vec<_> = entries
+            .iter()
+            .map(|e| (e.host.clone(), e.container.clone().unwrap()))
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                (
+                    PathBuf::from("/repo/config/fish"),
+                    PathBuf::from("/etc/xdg/fish")
+                ),
+                (
+                    PathBuf::from("/repo/config/nvim"),
+                    PathBuf::from("/etc/xdg/nvim")
+                ),
+            ]
+        );
+
+        // A single alternative still mounts at the container path itself
+        let entry = MountEntry {
+            host: PathBuf::from("config/{fish}"),
+            ..entry
+        };
+        let entries = entry.expand(Path::new("/repo")).unwrap();
+        assert_eq!(entries[0].container, Some(PathBuf::from("/etc/xdg")));
+    }
+
+    #[test]
+    fn test_mount_entry_expand_braces_home() {
+        let entry = MountEntry {
+            host: PathBuf::from("~/.config/{fish,nvim}"),
+            container: None,
+            optional: true,
+        };
+        let entries = entry.expand(Path::new("/repo")).unwrap();
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].host, home.join(".config/fish"));
+        assert_eq!(entries[0].container, Some(PathBuf::from("~/.config/fish")));
+        assert_eq!(entries[1].host, home.join(".config/nvim"));
+        assert_eq!(entries[1].container, Some(PathBuf::from("~/.config/nvim")));
+    }
+
+    #[test]
+    fn test_optional_mount() {
+        let dir = TempDir::new().unwrap();
+        create_config(
+            dir.path(),
+            r#"
+[[mounts.readonly]]
+host = "~/.gitconfig"
+
+[[mounts.readonly]]
+host = "~/.config/nvim"
+optional = true
+"#,
+        );
+
+        let config = load_config(dir.path()).unwrap();
+        assert!(!config.mounts.readonly[0].optional);
+        assert!(config.mounts.readonly[1].optional);
+    }
+
+    #[test]
+    fn test_layered_config_merge() {
+        let dir = TempDir::new().unwrap();
+        let user = dir.path().join("user.toml");
+        let repo = dir.path().join("repo.toml");
+        let local = dir.path().join("local.toml");
+        fs::write(
+            &user,
+            r#"
+env = ["GITHUB_TOKEN"]
+runtime = "runc"
+
+[[mounts.readonly]]
+host = "~/.config/fish"
+
+[image]
+tag = "user-image"
+
+[agent]
+editor = "nvim"
+"#,
+        )
+        .unwrap();
+        fs::write(
+            &repo,
+            r#"
+env = ["ANTHROPIC_API_KEY"]
+runtime = "sysbox-runc"
+
+[[mounts.readonly]]
+host = "~/.gitconfig"
+
+[image.build]
+dockerfile = "Dockerfile"
+
+[agent]
+model = "sonnet"
+"#,
+        )
+        .unwrap();
+        fs::write(&local, "runtime = \"runsc\"\n").unwrap();
+
+        let layered = SandboxConfig::load_layers(&[
+            (ConfigLayer::User, user),
+            (ConfigLayer::Repo, repo),
+            (ConfigLayer::Local, local),
+        ])
+        .unwrap();
+        let config = &layered.config;
+
+        // Lists append, in layer order
+        assert_eq!(
+            config.env.names(),
+            vec!["GITHUB_TOKEN", "ANTHROPIC_API_KEY"]
+        );
+        assert_eq!(config.mounts.readonly.len(), 2);
+        // Scalars override
+        assert_eq!(config.runtime, Some(Runtime::Runsc));
+        // Nested tables merge key by key
+        assert_eq!(config.agent.model, Some(Model::Sonnet));
+        assert_eq!(config.agent.editor, Some("nvim".to_string()));
+        // The image choice is replaced as a whole
+        assert!(matches!(config.image, Some(ImageConfig::Build { .. })));
+
+        let shown = layered.display_with_sources();
+        assert!(shown.contains("runtime = \"runsc\"  # local"));
+        assert!(shown.contains("env += \"GITHUB_TOKEN\"  # user"));
+        assert!(shown.contains("env += \"ANTHROPIC_API_KEY\"  # repo"));
+        assert!(shown.contains("agent.editor = \"nvim\"  # user"));
+        assert!(!shown.contains("user-image"));
+    }
+
+    #[test]
+    fn test_load_with_user_config() {
+        let dir = TempDir::new().unwrap();
+        let config_home = TempDir::new().unwrap();
+        let user_config = config_home.path().join("config.toml");
+        fs::write(&user_config, "runtime = \"runsc\"\n").unwrap();
+        create_config(dir.path(), "env = []\n");
+
+        let layered =
+            SandboxConfig::load_layered_with_user_config(dir.path(), &user_config).unwrap();
+        assert_eq!(layered.config.runtime, Some(Runtime::Runsc));
+        assert_eq!(layered.layers[0], (ConfigLayer::User, user_config));
+        assert_eq!(layered.layers[1].0, ConfigLayer::Repo);
+    }
+
+    #[test]
+    fn test_layered_env_list_and_table() {
+        let dir = TempDir::new().unwrap();
+        let user = dir.path().join("user.toml");
+        let repo = dir.path().join(".sandbox.toml");
+        let local = dir.path().join(".sandbox.local.toml");
+        fs::write(&user, "env = [\"GITHUB_TOKEN\"]\n").unwrap();
+        fs::write(
+            &repo,
+            r#"
+[env]
+FOO = "bar"
+RUST_LOG = { default = "info" }
+"#,
+        )
+        .unwrap();
+        fs::write(&local, "env = [\"ANTHROPIC_API_KEY\"]\n").unwrap();
+
+        let layered = SandboxConfig::load_layers(&[
+            (ConfigLayer::User, user),
+            (ConfigLayer::Repo, repo),
+            (ConfigLayer::Local, local),
+        ])
+        .unwrap();
+
+        // Neither form replaces the other
+        assert_eq!(
+            layered.config.env.names(),
+            vec!["GITHUB_TOKEN", "FOO", "RUST_LOG", "ANTHROPIC_API_KEY"]
+        );
+        assert!(matches!(
+            layered.config.env.0.get("FOO"),
+            Some(EnvValue::Literal(v)) if v == "bar"
+        ));
+
+        let shown = layered.display_with_sources();
+        assert!(shown.contains("env.GITHUB_TOKEN = {}  # user"), "{}", shown);
+        assert!(shown.contains("env.FOO = \"bar\"  # repo"), "{}", shown);
+        assert!(
+            shown.contains("env.ANTHROPIC_API_KEY = {}  # local"),
+            "{}",
+            shown
+        );
+    }
+
+    #[test]
+    fn test_layered_config_error_names_file() {
+        let dir = TempDir::new().unwrap();
+        let local = dir.path().join(".sandbox.local.toml");
+        fs::write(&local, "bogus = 1\n").unwrap();
+
+        let err = SandboxConfig::load_layers(&[(ConfigLayer::Local, local)]).unwrap_err();
+        assert!(err.to_string().contains(".sandbox.local.toml"));
+    }
+
+    #[test]
+    fn test_profile() {
+        let dir = TempDir::new().unwrap();
+        create_config(
+            dir.path(),
+            r#"
+env = ["ANTHROPIC_API_KEY"]
+runtime = "runsc"
+
+[[mounts.readonly]]
+host = "~/.gitconfig"
+
+[agent]
+model = "haiku"
+
+[profile.dev]
+env = ["GITHUB_TOKEN"]
+runtime = "sysbox-runc"
+overlay-mode = "copy"
+backend = "podman"
+
+[[profile.dev.mounts.overlay]]
+host = "~/.cargo/registry"
+
+[profile.dev.image]
+tag = "dev-image:latest"
+
+[profile.dev.agent]
+model = "opus"
+"#,
+        );
+
+        let config = load_config(dir.path()).unwrap();
+
+        let base = config.clone().with_profile(None).unwrap();
+        assert_eq!(base.runtime, Some(Runtime::Runsc));
+        assert_eq!(base.backend, None);
+        assert!(base.active_profile.is_none());
+
+        let dev = config.clone().with_profile(Some("dev")).unwrap();
+        assert_eq!(dev.env.names(), vec!["ANTHROPIC_API_KEY", "GITHUB_TOKEN"]);
+        assert_eq!(dev.runtime, Some(Runtime::SysboxRunc));
+        assert_eq!(dev.overlay_mode, Some(OverlayMode::Copy));
+        assert_eq!(dev.backend, Some(Backend::Podman));
+        assert_eq!(dev.mounts.readonly.len(), 1);
+        assert_eq!(dev.mounts.overlay.len(), 1);
+        assert!(matches!(dev.image, Some(ImageConfig::Tag(ref t)) if t == "dev-image:latest"));
+        assert_eq!(dev.agent.model, Some(Model::Opus));
+        assert_eq!(dev.active_profile, Some("dev".to_string()));
+
+        let err = config.with_profile(Some("missing")).unwrap_err();
+        assert!(err.to_string().contains("Available profiles: dev"));
+    }
+
+    #[test]
+    fn test_env_table() {
+        let dir = TempDir::new().unwrap();
+        fs::write(
+            dir.path().join(".env"),
+            "FROM_FILE=file\nLITERAL=overridden\n",
+        )
+        .unwrap();
+        create_config(
+            dir.path(),
+            r#"
+env-files = [".env"]
+
+[env]
+LITERAL = "bar"
+PASSED = {}
+RENAMED = { from = "SANDBOX_TEST_HOST_NAME" }
+OPTIONAL = { from = "SANDBOX_TEST_UNSET", optional = true }
+DEFAULTED = { from = "SANDBOX_TEST_UNSET", default = "fallback" }
+COMPUTED = { command = "echo computed" }
+"#,
+        );
+        std::env::set_var("PASSED", "passed");
+        std::env::set_var("SANDBOX_TEST_HOST_NAME", "renamed");
+        std::env::remove_var("SANDBOX_TEST_UNSET");
+
+        let config = load_config(dir.path()).unwrap();
+        let vars = config.resolve_env_vars(dir.path()).unwrap();
+        let get = |name: &str| {
+            vars.iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_str())
+        };
+
+        assert_eq!(get("FROM_FILE"), Some("file"));
+        assert_eq!(get("LITERAL"), Some("bar"));
+        assert_eq!(get("PASSED"), Some("passed"));
+        assert_eq!(get("RENAMED"), Some("renamed"));
+        assert_eq!(get("OPTIONAL"), None);
+        assert_eq!(get("DEFAULTED"), Some("fallback"));
+        assert_eq!(get("COMPUTED"), Some("computed"));
+    }
+
+    #[test]
+    fn test_env_missing_required() {
+        let dir = TempDir::new().unwrap();
+        create_config(
+            dir.path(),
+            r#"
+[env]
+RENAMED = { from = "SANDBOX_TEST_MISSING_XYZ" }
+"#,
+        );
+        std::env::remove_var("SANDBOX_TEST_MISSING_XYZ");
+
+        let config = load_config(dir.path()).unwrap();
+        let err = config.resolve_env_vars(dir.path()).unwrap_err();
+        assert!(err.to_string().contains("SANDBOX_TEST_MISSING_XYZ"));
+    }
+
     #[test]
     fn test_unknown_field_rejected() {
         let dir = TempDir::new().unwrap();
@@ -329,7 +1799,7 @@ unknown_field = "value"
 "#,
         );
 
-        let result = SandboxConfig::load(dir.path());
+        let result = load_config(dir.path());
         assert!(result.is_err());
     }
 }
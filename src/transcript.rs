@@ -0,0 +1,305 @@
+//! Agent conversation transcripts, so a session can be resumed after the agent exits.
+//!
+//! Each session is a JSONL file in the sandbox's `transcripts` directory, named after
+//! the session ID. Every message is appended as it's added to the conversation,
+//! together with the chat text shown to the user since the previous message. When the
+//! conversation is compacted, the messages replacing it are appended as a whole.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::anthropic::{ContentBlock, Message, Role};
+
+/// One line of a transcript.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TranscriptEntry {
+    #[serde(flatten)]
+    change: Change,
+    /// Chat text displayed before the change.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    chat: String,
+}
+
+/// A change to the conversation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Change {
+    /// A message added.
+    Message(Message),
+    /// The messages replacing the conversation after compaction.
+    Compaction(Vec<Message>),
+}
+
+/// A conversation read back from a transcript.
+#[derive(Debug, Default)]
+pub struct RecordedConversation {
+    pub messages: Vec<Message>,
+    pub chat_history: String,
+}
+
+/// A transcript file being appended to.
+pub struct Transcript {
+    session_id: String,
+    path: PathBuf,
+    file: File,
+}
+
+impl Transcript {
+    /// Start a new session in `dir`.
+    pub fn create(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        let session_id = format!(
+            "{}-{}",
+            chrono::Utc::now().format("%Y%m%dT%H%M%SZ"),
+            std::process::id()
+        );
+        Self::open(dir, &session_id)
+    }
+
+    /// Reopen session `session_id` in `dir`, or the most recently updated one.
+    pub fn resume(dir: &Path, session_id: Option<&str>) -> Result<Self> {
+        let session_id = match session_id {
+            Some(id) => {
+                if !transcript_path(dir, id).exists() {
+                    bail!("No such session: {}", id);
+                }
+                id.to_string()
+            }
+            None => latest_session(dir)?.context("No session to resume")?,
+        };
+        Self::open(dir, &session_id)
+    }
+
+    fn open(dir: &Path, session_id: &str) -> Result<Self> {
+        let path = transcript_path(dir, session_id);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open transcript {}", path.display()))?;
+        Ok(Transcript {
+            session_id: session_id.to_string(),
+            path,
+            file,
+        })
+    }
+
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    /// Read back the conversation recorded so far.
+    ///
+    /// A line cut short by a crash is ignored, as is an assistant message whose tool
+    /// calls have no recorded results, since the API rejects such a conversation. Both
+    /// are also removed from the file, so that later appends continue from a valid
+    /// conversation: the partial line is truncated, and the messages without the
+    /// unanswered tool calls are recorded as a compaction.
+    pub fn load(&mut self) -> Result<RecordedConversation> {
+        let contents = fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read transcript {}", self.path.display()))?;
+        let mut conversation = RecordedConversation::default();
+        let mut valid_len = 0;
+        let mut lines = contents.split_inclusive('\n').peekable();
+        while let Some(line) = lines.next() {
+            let entry: TranscriptEntry = match serde_json::from_str(line) {
+                Ok(entry) if line.ends_with('\n') => entry,
+                Err(e) if lines.peek().is_some() => {
+                    return Err(e)
+                        .with_context(|| format!("Corrupt transcript {}", self.path.display()))
+                }
+                // Only the last line can be cut short
+                _ => break,
+            };
+            valid_len += line.len();
+            conversation.chat_history.push_str(&entry.chat);
+            match entry.change {
+                Change::Message(message) => conversation.messages.push(message),
+                Change::Compaction(messages) => conversation.messages = messages,
+            }
+        }
+
+        if valid_len < contents.len() {
+            self.file
+                .set_len(valid_len as u64)
+                .with_context(|| format!("Failed to truncate {}", self.path.display()))?;
+        }
+
+        if let Some(last) = conversation.messages.last() {
+            let has_tool_use = last
+                .content
+                .iter()
+                .any(|block| matches!(block, ContentBlock::ToolUse { .. }));
+            if last.role == Role::Assistant && has_tool_use {
+                conversation.messages.pop();
+                self.append_compaction(&conversation.messages, "")?;
+            }
+        }
+        Ok(conversation)
+    }
+
+    /// Append a message, synced to disk so that it survives a crash.
+    pub fn append(&mut self, message: &Message, chat: &str) -> Result<()> {
+        self.write(Change::Message(message.clone()), chat)
+    }
+
+    /// Record the messages replacing the conversation after compaction.
+    pub fn append_compaction(&mut self, messages: &[Message], chat: &str) -> Result<()> {
+        self.write(Change::Compaction(messages.to_vec()), chat)
+    }
+
+    fn write(&mut self, change: Change, chat: &str) -> Result<()> {
+        let entry = TranscriptEntry {
+            change,
+            chat: chat.to_string(),
+        };
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        self.file
+            .write_all(line.as_bytes())
+            .and_then(|()| self.file.sync_data())
+            .with_context(|| format!("Failed to write transcript {}", self.path.display()))
+    }
+}
+
+fn transcript_path(dir: &Path, session_id: &str) -> PathBuf {
+    dir.join(format!("{}.jsonl", session_id))
+}
+
+/// The ID of the most recently updated session in `dir`.
+fn latest_session(dir: &Path) -> Result<Option<String>> {
+    if !dir.exists() {
+        return Ok(None);
+    }
+    let mut latest = None;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "jsonl") {
+            continue;
+        }
+        let modified = fs::metadata(&path)?.modified()?;
+        if latest.as_ref().is_none_or(|(time, _)| modified > *time) {
+            latest = Some((modified, path));
+        }
+    }
+    Ok(latest.and_then(|(_, path)| Some(path.file_stem()?.to_string_lossy().into_owned())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn text_message(role: Role, text: &str) -> Message {
+        Message {
+            role,
+            content: vec![ContentBlock::Text {
+                text: text.to_string(),
+                cache_control: None,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_resume_latest_session() {
+        let dir = tempdir().unwrap();
+
+        let mut first = Transcript::create(dir.path()).unwrap();
+        first
+            .append(&text_message(Role::User, "first"), "> first\n")
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        let mut second = Transcript::open(dir.path(), "second").unwrap();
+        second
+            .append(&text_message(Role::User, "second"), "> second\n")
+            .unwrap();
+        second
+            .append(&text_message(Role::Assistant, "reply"), "reply\n")
+            .unwrap();
+
+        let mut resumed = Transcript::resume(dir.path(), None).unwrap();
+        assert_eq!(resumed.session_id(), "second");
+        let conversation = resumed.load().unwrap();
+        assert_eq!(conversation.messages.len(), 2);
+        assert_eq!(conversation.messages[1].role, Role::Assistant);
+        assert_eq!(conversation.chat_history, "> second\nreply\n");
+
+        let mut resumed = Transcript::resume(dir.path(), Some(first.session_id())).unwrap();
+        assert_eq!(resumed.load().unwrap().messages.len(), 1);
+
+        assert!(Transcript::resume(dir.path(), Some("missing")).is_err());
+        assert!(Transcript::resume(&dir.path().join("none"), None).is_err());
+    }
+
+    #[test]
+    fn test_load_drops_incomplete_turn() {
+        let dir = tempdir().unwrap();
+        let mut transcript = Transcript::create(dir.path()).unwrap();
+        transcript
+            .append(&text_message(Role::User, "list files"), "")
+            .unwrap();
+        let tool_use = Message {
+            role: Role::Assistant,
+            content: vec![ContentBlock::ToolUse {
+                id: "toolu_1".to_string(),
+                name: "bash".to_string(),
+                input: serde_json::json!({"command": "ls"}),
+            }],
+        };
+        transcript.append(&tool_use, "").unwrap();
+        // Interrupted while writing the next line
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&transcript.path)
+            .unwrap()
+            .write_all(b"{\"message\":{\"ro")
+            .unwrap();
+
+        let messages = transcript.load().unwrap().messages;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].role, Role::User);
+
+        // The transcript was repaired, so the conversation continues from there
+        transcript
+            .append(&text_message(Role::Assistant, "reply"), "reply\n")
+            .unwrap();
+        let conversation = transcript.load().unwrap();
+        assert_eq!(conversation.messages.len(), 2);
+        assert_eq!(conversation.messages[0].role, Role::User);
+        assert_eq!(conversation.messages[1].role, Role::Assistant);
+        assert_eq!(conversation.chat_history, "reply\n");
+    }
+
+    #[test]
+    fn test_load_compacted_conversation() {
+        let dir = tempdir().unwrap();
+        let mut transcript = Transcript::create(dir.path()).unwrap();
+        transcript
+            .append(&text_message(Role::User, "first"), "> first\n")
+            .unwrap();
+        transcript
+            .append(&text_message(Role::Assistant, "reply"), "reply\n")
+            .unwrap();
+        transcript
+            .append_compaction(&[text_message(Role::User, "summary")], "[compact]\n")
+            .unwrap();
+        transcript
+            .append(&text_message(Role::Assistant, "continued"), "continued\n")
+            .unwrap();
+
+        let conversation = transcript.load().unwrap();
+        assert_eq!(conversation.messages.len(), 2);
+        match &conversation.messages[0].content[0] {
+            ContentBlock::Text { text, .. } => assert_eq!(text, "summary"),
+            block => panic!("Unexpected block: {:?}", block),
+        }
+        // The chat keeps everything shown
+        assert_eq!(
+            conversation.chat_history,
+            "> first\nreply\n[compact]\ncontinued\n"
+        );
+    }
+}
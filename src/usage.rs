@@ -0,0 +1,337 @@
+//! Token usage and cost accounting for agent sessions.
+//!
+//! Every API response's usage is appended to `usage.jsonl` in the sandbox directory,
+//! with the session and model. Costs are computed from the token counts when
+//! reporting, using the prices in [`Pricing::for_model`].
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::ops::AddAssign;
+use std::path::{Path, PathBuf};
+
+use crate::anthropic::Usage;
+use crate::config::Model;
+
+/// Prices in dollars per million tokens.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pricing {
+    pub input: f64,
+    pub output: f64,
+    /// Writing to the prompt cache (5 minute TTL).
+    pub cache_write: f64,
+    pub cache_read: f64,
+}
+
+impl Pricing {
+    pub fn for_model(model: Model) -> Self {
+        let (input, output) = match model {
+            Model::Opus => (5.0, 25.0),
+            Model::Sonnet => (3.0, 15.0),
+            Model::Haiku => (1.0, 5.0),
+        };
+        Pricing {
+            input,
+            output,
+            cache_write: input * 1.25,
+            cache_read: input * 0.1,
+        }
+    }
+}
+
+/// Token counts of one or more requests.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenCounts {
+    /// Input tokens not read from or written to the cache.
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    #[serde(default)]
+    pub cache_creation_input_tokens: u64,
+    #[serde(default)]
+    pub cache_read_input_tokens: u64,
+}
+
+impl From<&Usage> for TokenCounts {
+    fn from(usage: &Usage) -> Self {
+        TokenCounts {
+            input_tokens: usage.input_tokens.into(),
+            output_tokens: usage.output_tokens.into(),
+            cache_creation_input_tokens: usage.cache_creation_input_tokens.into(),
+            cache_read_input_tokens: usage.cache_read_input_tokens.into(),
+        }
+    }
+}
+
+impl AddAssign for TokenCounts {
+    fn add_assign(&mut self, other: Self) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.cache_creation_input_tokens += other.cache_creation_input_tokens;
+        self.cache_read_input_tokens += other.cache_read_input_tokens;
+    }
+}
+
+impl TokenCounts {
+    /// All input tokens, cached or not.
+    pub fn total_input_tokens(&self) -> u64 {
+        self.input_tokens + self.cache_creation_input_tokens + self.cache_read_input_tokens
+    }
+
+    /// Cost in dollars at the prices of `model`.
+    pub fn cost(&self, model: Model) -> f64 {
+        let pricing = Pricing::for_model(model);
+        (self.input_tokens as f64 * pricing.input
+            + self.output_tokens as f64 * pricing.output
+            + self.cache_creation_input_tokens as f64 * pricing.cache_write
+            + self.cache_read_input_tokens as f64 * pricing.cache_read)
+            / 1_000_000.0
+    }
+}
+
+/// Token counts with their cost, possibly across models.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct UsageTotals {
+    pub tokens: TokenCounts,
+    pub cost: f64,
+}
+
+impl AddAssign for UsageTotals {
+    fn add_assign(&mut self, other: Self) {
+        self.tokens += other.tokens;
+        self.cost += other.cost;
+    }
+}
+
+impl UsageTotals {
+    pub fn add(&mut self, model: Model, tokens: TokenCounts) {
+        self.tokens += tokens;
+        self.cost += tokens.cost(model);
+    }
+
+    /// One line summary, like `12.3k in (10.0k cached), 456 out, $0.0123`.
+    pub fn summary(&self) -> String {
+        format!(
+            "{} in ({} cached), {} out, {}",
+            format_tokens(self.tokens.total_input_tokens()),
+            format_tokens(self.tokens.cache_read_input_tokens),
+            format_tokens(self.tokens.output_tokens),
+            format_cost(self.cost)
+        )
+    }
+}
+
+/// The usage of one API response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageRecord {
+    pub timestamp: DateTime<Utc>,
+    pub session: String,
+    pub model: Model,
+    #[serde(flatten)]
+    pub tokens: TokenCounts,
+}
+
+impl UsageRecord {
+    /// The local day the request was made.
+    pub fn day(&self) -> NaiveDate {
+        self.timestamp.with_timezone(&Local).date_naive()
+    }
+}
+
+/// The usage log of a sandbox.
+pub struct UsageLog {
+    path: PathBuf,
+}
+
+impl UsageLog {
+    pub fn new(sandbox_dir: &Path) -> Self {
+        UsageLog {
+            path: sandbox_dir.join("usage.jsonl"),
+        }
+    }
+
+    pub fn append(&self, record: &UsageRecord) -> Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+
+    /// All records, skipping lines that can't be parsed (such as one cut short by a
+    /// crash) since usage is informational.
+    pub fn load(&self) -> Result<Vec<UsageRecord>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let contents = fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read {}", self.path.display()))?;
+        Ok(contents
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+}
+
+/// Running totals shown in the agent after each turn.
+pub struct SessionUsage {
+    log: UsageLog,
+    session: String,
+    model: Model,
+    pub turn: UsageTotals,
+    /// Usage since the agent started.
+    pub run: UsageTotals,
+    pub session_totals: UsageTotals,
+    pub sandbox_totals: UsageTotals,
+}
+
+impl SessionUsage {
+    /// Start counting for `session`, including what it and the sandbox used before.
+    pub fn load(log: UsageLog, session: &str, model: Model) -> Result<Self> {
+        let mut session_totals = UsageTotals::default();
+        let mut sandbox_totals = UsageTotals::default();
+        for record in log.load()? {
+            if record.session == session {
+                session_totals.add(record.model, record.tokens);
+            }
+            sandbox_totals.add(record.model, record.tokens);
+        }
+        Ok(SessionUsage {
+            log,
+            session: session.to_string(),
+            model,
+            turn: UsageTotals::default(),
+            run: UsageTotals::default(),
+            session_totals,
+            sandbox_totals,
+        })
+    }
+
+    /// Count the usage of a response.
+    pub fn record(&mut self, usage: &Usage) -> Result<()> {
+        let tokens = TokenCounts::from(usage);
+        self.log.append(&UsageRecord {
+            timestamp: Utc::now(),
+            session: self.session.clone(),
+            model: self.model,
+            tokens,
+        })?;
+        self.turn.add(self.model, tokens);
+        self.run.add(self.model, tokens);
+        self.session_totals.add(self.model, tokens);
+        self.sandbox_totals.add(self.model, tokens);
+        Ok(())
+    }
+
+    /// Summary of the turn and running totals, resetting the turn.
+    pub fn end_turn(&mut self) -> String {
+        let summary = format!(
+            "[usage] turn: {} | session: {} | sandbox: {}",
+            self.turn.summary(),
+            format_cost(self.session_totals.cost),
+            format_cost(self.sandbox_totals.cost)
+        );
+        self.turn = UsageTotals::default();
+        summary
+    }
+}
+
+/// Usage totals by day and sandbox.
+pub fn totals_by_day<'a>(
+    records: impl IntoIterator<Item = (&'a str, &'a UsageRecord)>,
+) -> BTreeMap<(NaiveDate, String), UsageTotals> {
+    let mut totals: BTreeMap<(NaiveDate, String), UsageTotals> = BTreeMap::new();
+    for (sandbox, record) in records {
+        totals
+            .entry((record.day(), sandbox.to_string()))
+            .or_default()
+            .add(record.model, record.tokens);
+    }
+    totals
+}
+
+/// A token count abbreviated to thousands or millions.
+pub fn format_tokens(count: u64) -> String {
+    match count {
+        0..1_000 => count.to_string(),
+        1_000..1_000_000 => format!("{:.1}k", count as f64 / 1_000.0),
+        _ => format!("{:.1}M", count as f64 / 1_000_000.0),
+    }
+}
+
+/// A cost in dollars, with more precision for small amounts.
+pub fn format_cost(cost: f64) -> String {
+    if cost >= 1.0 {
+        format!("${:.2}", cost)
+    } else {
+        format!("${:.4}", cost)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn usage(input: u32, output: u32, cache_write: u32, cache_read: u32) -> Usage {
+        Usage {
+            input_tokens: input,
+            output_tokens: output,
+            cache_creation_input_tokens: cache_write,
+            cache_read_input_tokens: cache_read,
+        }
+    }
+
+    #[test]
+    fn test_cost() {
+        let tokens = TokenCounts::from(&usage(1_000_000, 100_000, 200_000, 2_000_000));
+        // 3.00 + 1.50 + 0.75 + 0.60
+        assert!((tokens.cost(Model::Sonnet) - 5.85).abs() < 1e-9);
+        assert!((tokens.cost(Model::Haiku) - 1.95).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_session_usage_totals() {
+        let dir = tempdir().unwrap();
+
+        let mut first = SessionUsage::load(UsageLog::new(dir.path()), "a", Model::Haiku).unwrap();
+        first.record(&usage(1000, 100, 0, 0)).unwrap();
+        first.record(&usage(10, 100, 0, 1000)).unwrap();
+        assert_eq!(first.turn.tokens.total_input_tokens(), 2010);
+        assert!(first
+            .end_turn()
+            .starts_with("[usage] turn: 2.0k in (1.0k cached), 200 out"));
+        assert_eq!(first.turn, UsageTotals::default());
+
+        let mut second = SessionUsage::load(UsageLog::new(dir.path()), "b", Model::Opus).unwrap();
+        assert_eq!(second.session_totals, UsageTotals::default());
+        assert_eq!(second.sandbox_totals, first.sandbox_totals);
+        second.record(&usage(1000, 0, 0, 0)).unwrap();
+
+        // Resuming a session picks up its totals
+        let resumed = SessionUsage::load(UsageLog::new(dir.path()), "a", Model::Haiku).unwrap();
+        assert_eq!(resumed.session_totals, first.session_totals);
+        assert!((resumed.sandbox_totals.cost - (first.sandbox_totals.cost + 0.005)).abs() < 1e-9);
+
+        let records = UsageLog::new(dir.path()).load().unwrap();
+        let totals = totals_by_day(records.iter().map(|record| ("sandbox", record)));
+        assert_eq!(totals.len(), 1);
+        let day_totals = totals.values().next().unwrap();
+        assert_eq!(day_totals.tokens.output_tokens, 200);
+        assert_eq!(day_totals.cost, resumed.sandbox_totals.cost);
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(format_tokens(999), "999");
+        assert_eq!(format_tokens(12_345), "12.3k");
+        assert_eq!(format_tokens(2_500_000), "2.5M");
+        assert_eq!(format_cost(0.01234), "$0.0123");
+        assert_eq!(format_cost(12.346), "$12.35");
+    }
+}
@@ -0,0 +1,847 @@
+//! Containers run directly in Linux namespaces, without a container engine.
+//!
+//! The init process of a container enters new user, mount, PID and UTS (and
+//! optionally network) namespaces, assembles the container root, applies the
+//! container's mounts on top (bind, readonly and overlay, as a container engine would)
+//! and forks the init of the PID namespace, which mounts a fresh `/proc` and pivots
+//! into the root. The container gets its own `/dev` with only a few host device nodes,
+//! so that neither host processes nor host devices are visible in it. Commands run as
+//! the container user in a nested user namespace mapping that user to the host user.
+//! Exec sessions join the namespaces of the init process.
+//!
+//! Needs unprivileged user namespaces. The namespace setup requires a single-threaded
+//! process, so it runs in a helper process of the `sandbox` binary (the hidden
+//! `userns-init` and `userns-exec` commands).
+
+use anyhow::{bail, Context, Result};
+use log::debug;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::sched::{setns, unshare, CloneFlags};
+use nix::sys::signal::{killpg, pthread_sigmask, signal, SigHandler, SigSet, SigmaskHow, Signal};
+use nix::sys::statvfs::{statvfs, FsFlags};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{
+    chdir, fork, getgid, getpgrp, getuid, pivot_root, sethostname, setsid, tcgetpgrp, tcsetpgrp,
+    ForkResult, Pid,
+};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::io::{BufRead, BufReader, Write};
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Duration;
+
+use crate::backend::{ContainerSpec, ContainerState, ExecOptions, ExecOutput, MountSource};
+
+/// `PATH` of commands run in containers.
+const CONTAINER_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Line the init process prints once the container is set up.
+const READY: &str = "ready";
+
+/// Top-level host directories that are empty in containers on the host root rather
+/// than bound in, so that mount points are created in the container, not on the host.
+#[cfg(any(test, feature = "fake-backend"))]
+const PRIVATE_DIRS: &[&str] = &["home", "root", "tmp", "run", "mnt", "media"];
+
+/// Top-level directories set up for the container rather than taken from the host.
+#[cfg(any(test, feature = "fake-backend"))]
+const KERNEL_DIRS: &[&str] = &["dev", "proc", "sys"];
+
+/// Host device nodes bound into the container's `/dev`.
+const DEVICES: &[&str] = &["null", "zero", "full", "random", "urandom", "tty"];
+
+/// What a container's root filesystem is made of.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RootFs {
+    /// The host's top-level directories, standing in for an image. Only the fake
+    /// backend uses it, as it leaves the host filesystem visible.
+    #[cfg(any(test, feature = "fake-backend"))]
+    Host,
+    /// An overlay over an exported image filesystem, with writes kept in the container.
+    Image { lower: PathBuf },
+}
+
+/// How to run a container.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunOptions {
+    pub root: RootFs,
+    /// Give the container its own network namespace, with only a loopback interface.
+    pub isolate_network: bool,
+}
+
+/// State of a container, in `<name>/container.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ContainerRecord {
+    spec: ContainerSpec,
+    image_id: String,
+    options: RunOptions,
+    /// Init process, once started.
+    #[serde(default)]
+    init: Option<InitProcess>,
+}
+
+/// Identifies the init process; the start time guards against PID reuse.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct InitProcess {
+    pid: i32,
+    start_time: u64,
+}
+
+/// Containers with their state in a directory, one subdirectory per container.
+#[derive(Debug, Clone)]
+pub struct UsernsContainers {
+    dir: PathBuf,
+    /// The `sandbox` binary running the helper commands, if not this one.
+    helper: Option<PathBuf>,
+}
+
+impl UsernsContainers {
+    pub fn new(dir: PathBuf) -> Self {
+        UsernsContainers { dir, helper: None }
+    }
+
+    /// Run the helper commands with the given `sandbox` binary, for use from other
+    /// binaries, such as tests.
+    pub fn with_helper(self, helper: PathBuf) -> Self {
+        UsernsContainers {
+            helper: Some(helper),
+            ..self
+        }
+    }
+
+    fn container_dir(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    fn load(&self, name: &str) -> Result<Option<ContainerRecord>> {
+        load_record(&self.container_dir(name))
+    }
+
+    fn save(&self, name: &str, record: &ContainerRecord) -> Result<()> {
+        let dir = self.container_dir(name);
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(
+            dir.join("container.json"),
+            serde_json::to_string_pretty(record)?,
+        )?;
+        Ok(())
+    }
+
+    /// Command running the helper of the `sandbox` binary.
+    fn helper(&self, args: &[&str]) -> Result<Command> {
+        let exe = match &self.helper {
+            Some(helper) => helper.clone(),
+            None => std::env::current_exe().context("Failed to locate the sandbox binary")?,
+        };
+        let mut command = Command::new(exe);
+        command.args(args);
+        Ok(command)
+    }
+
+    /// Helper command for an exec session, with the environment of the container.
+    fn exec_command(
+        &self,
+        container: &str,
+        command: &[&str],
+        user: Option<&str>,
+        env: &[(String, String)],
+    ) -> Result<Command> {
+        let Some(record) = self.load(container)? else {
+            bail!("No such container: {}", container);
+        };
+
+        let dir = self.container_dir(container);
+        let mut args = vec!["userns-exec", dir.to_str().context("Invalid path")?];
+        if let Some(user) = user {
+            args.extend(["--user", user]);
+        }
+        args.push("--");
+        args.extend(command);
+
+        let mut helper = self.helper(&args)?;
+        helper.env_clear().env("PATH", CONTAINER_PATH);
+        if let Ok(term) = std::env::var("TERM") {
+            helper.env("TERM", term);
+        }
+        helper.envs(record.spec.env.iter().cloned());
+        helper.envs(env.iter().cloned());
+        Ok(helper)
+    }
+
+    /// Create and start a container, returning once its init process is ready.
+    pub fn run(&self, spec: &ContainerSpec, image_id: &str, options: RunOptions) -> Result<()> {
+        if self.load(&spec.name)?.is_some() {
+            bail!("Container name '{}' is already in use", spec.name);
+        }
+        let mut record = ContainerRecord {
+            spec: spec.clone(),
+            image_id: image_id.to_string(),
+            options,
+            init: None,
+        };
+        self.save(&spec.name, &record)?;
+
+        let dir = self.container_dir(&spec.name);
+        let log_path = dir.join("init.log");
+        let log = std::fs::File::create(&log_path)?;
+        let mut child = self
+            .helper(&["userns-init", dir.to_str().context("Invalid path")?])?
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(log)
+            .spawn()
+            .context("Failed to start container init process")?;
+
+        let mut line = String::new();
+        BufReader::new(child.stdout.take().expect("stdout is piped")).read_line(&mut line)?;
+        if line.trim() != READY {
+            let _ = child.wait();
+            bail!(
+                "Failed to start container: {}",
+                std::fs::read_to_string(&log_path)
+                    .unwrap_or_default()
+                    .trim()
+            );
+        }
+
+        let pid = child.id() as i32;
+        record.init = Some(InitProcess {
+            pid,
+            start_time: process_start_time(pid).context("Init process exited")?,
+        });
+        self.save(&spec.name, &record)?;
+        debug!("Started container {} (pid {})", spec.name, pid);
+        Ok(())
+    }
+
+    pub fn state(&self, name: &str) -> Result<ContainerState> {
+        Ok(match self.load(name)? {
+            None => ContainerState::Missing,
+            Some(record) if is_running(&record) => ContainerState::Running,
+            Some(_) => ContainerState::Stopped,
+        })
+    }
+
+    pub fn stop(&self, name: &str) -> Result<()> {
+        let Some(record) = self.load(name)? else {
+            return Ok(());
+        };
+        if let Some(init) = record.init.filter(|_| is_running(&record)) {
+            // The init process leads its own process group
+            let _ = killpg(Pid::from_raw(init.pid), Signal::SIGKILL);
+            // Reap it if it was started by this process
+            let _ = waitpid(Pid::from_raw(init.pid), Some(WaitPidFlag::WNOHANG));
+        }
+        Ok(())
+    }
+
+    pub fn remove(&self, name: &str) -> Result<()> {
+        self.stop(name)?;
+        let dir = self.container_dir(name);
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)
+                .with_context(|| format!("Failed to remove container: {}", name))?;
+        }
+        Ok(())
+    }
+
+    pub fn wait(&self, name: &str) -> Result<()> {
+        while self.state(name)? == ContainerState::Running {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        Ok(())
+    }
+
+    /// IDs of the images of all containers.
+    pub fn image_ids(&self) -> Result<Vec<String>> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut ids = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            if let Some(record) = load_record(&entry?.path())? {
+                ids.push(record.image_id);
+            }
+        }
+        Ok(ids)
+    }
+
+    pub fn exec(
+        &self,
+        container: &str,
+        command: &[&str],
+        options: &ExecOptions,
+    ) -> Result<ExecOutput> {
+        let mut helper =
+            self.exec_command(container, command, options.user.as_deref(), &options.env)?;
+        let mut child = helper
+            .stdin(if options.stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Failed to exec in container")?;
+
+        if let Some(data) = &options.stdin {
+            let mut stdin = child.stdin.take().expect("stdin is piped");
+            // Written on a separate thread so that large outputs can't deadlock us
+            let data = data.clone();
+            std::thread::spawn(move || {
+                let _ = stdin.write_all(&data);
+            });
+        }
+
+        let output = child
+            .wait_with_output()
+            .context("Failed to wait for exec")?;
+        Ok(ExecOutput {
+            exit_code: output.status.code(),
+            stdout: output.stdout,
+            stderr: output.stderr,
+        })
+    }
+
+    pub fn exec_interactive(
+        &self,
+        container: &str,
+        command: &[&str],
+        env: &[(String, String)],
+    ) -> Result<Option<i32>> {
+        let status = self
+            .exec_command(container, command, None, env)?
+            .status()
+            .context("Failed to exec in container")?;
+        Ok(status.code())
+    }
+}
+
+fn load_record(dir: &Path) -> Result<Option<ContainerRecord>> {
+    let path = dir.join("container.json");
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents = std::fs::read_to_string(&path)?;
+    Ok(Some(serde_json::from_str(&contents).with_context(
+        || format!("Failed to parse {}", path.display()),
+    )?))
+}
+
+fn is_running(record: &ContainerRecord) -> bool {
+    record
+        .init
+        .is_some_and(|init| process_start_time(init.pid) == Some(init.start_time))
+}
+
+/// Start time of a live (not zombie) process, from `/proc/<pid>/stat`.
+fn process_start_time(pid: i32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name may contain spaces; the fields after it don't
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    if fields.first() == Some(&"Z") {
+        return None;
+    }
+    // Field 22 of the file, counting the pid and command name
+    fields.get(19)?.parse().ok()
+}
+
+fn write_id_maps(inner_uid: u32, outer_uid: u32, inner_gid: u32, outer_gid: u32) -> Result<()> {
+    std::fs::write("/proc/self/setgroups", "deny")?;
+    std::fs::write(
+        "/proc/self/uid_map",
+        format!("{} {} 1", inner_uid, outer_uid),
+    )?;
+    std::fs::write(
+        "/proc/self/gid_map",
+        format!("{} {} 1", inner_gid, outer_gid),
+    )?;
+    Ok(())
+}
+
+/// Run commands as the container user: in a nested user namespace that maps the
+/// user to root of the container's namespace, i.e. to the host user.
+fn run_as_user(command: &mut Command, uid: u32, gid: u32) {
+    // SAFETY: the helper process is single-threaded, so the forked child may allocate
+    unsafe {
+        command.pre_exec(move || {
+            unshare(CloneFlags::CLONE_NEWUSER)?;
+            write_id_maps(uid, 0, gid, 0).map_err(std::io::Error::other)
+        });
+    }
+}
+
+/// Home directory of `uid` in the container's `/etc/passwd`.
+fn home_dir(uid: u32) -> Option<String> {
+    let passwd = std::fs::read_to_string("/etc/passwd").ok()?;
+    let uid = uid.to_string();
+    passwd
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.get(2) == Some(&uid.as_str()))
+        .and_then(|fields| fields.get(5).map(|home| home.to_string()))
+}
+
+/// Bring up the loopback interface of a new network namespace.
+fn loopback_up() -> Result<()> {
+    // SAFETY: plain socket and ioctl calls on a zeroed, NUL-terminated request
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error()).context("Failed to open socket");
+        }
+        let socket = OwnedFd::from_raw_fd(fd);
+        let mut request: libc::ifreq = std::mem::zeroed();
+        for (dst, src) in request.ifr_name.iter_mut().zip(b"lo") {
+            *dst = *src as libc::c_char;
+        }
+        if libc::ioctl(socket.as_raw_fd(), libc::SIOCGIFFLAGS, &mut request) < 0 {
+            return Err(std::io::Error::last_os_error()).context("Failed to query loopback");
+        }
+        request.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+        if libc::ioctl(socket.as_raw_fd(), libc::SIOCSIFFLAGS, &request) < 0 {
+            return Err(std::io::Error::last_os_error()).context("Failed to bring up loopback");
+        }
+    }
+    Ok(())
+}
+
+fn bind(source: &Path, target: &Path) -> Result<()> {
+    mount(
+        Some(source),
+        target,
+        None::<&str>,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        None::<&str>,
+    )
+    .with_context(|| {
+        format!(
+            "Failed to bind {} to {}",
+            source.display(),
+            target.display()
+        )
+    })
+}
+
+/// Remount a bind mount read-only. Flags of the underlying mount that are locked in a
+/// user namespace have to be kept.
+fn remount_readonly(target: &Path) -> Result<()> {
+    let current = statvfs(target)?.flags();
+    let mut flags = MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY;
+    for (fs_flag, ms_flag) in [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ] {
+        if current.contains(fs_flag) {
+            flags |= ms_flag;
+        }
+    }
+    mount(None::<&str>, target, None::<&str>, flags, None::<&str>)
+        .with_context(|| format!("Failed to make {} read-only", target.display()))
+}
+
+fn mount_overlay(lower: &Path, upper: &Path, work: &Path, target: &Path) -> Result<()> {
+    let options = format!(
+        "lowerdir={},upperdir={},workdir={}",
+        lower.display(),
+        upper.display(),
+        work.display()
+    );
+    mount(
+        Some("overlay"),
+        target,
+        Some("overlay"),
+        MsFlags::empty(),
+        Some(options.as_str()),
+    )
+    .with_context(|| format!("Failed to mount overlay at {}", target.display()))
+}
+
+/// Create the mount point for `source` at `target`: a directory or an empty file.
+fn create_mount_point(source_is_dir: bool, target: &Path) -> Result<()> {
+    if source_is_dir {
+        std::fs::create_dir_all(target)?;
+    } else if !target.exists() {
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::File::create(target)?;
+    }
+    Ok(())
+}
+
+/// Populate a fresh `/dev` with the host's harmless device nodes and a new devpts
+/// instance, instead of exposing all of the host's devices.
+fn setup_dev(dev: &Path) -> Result<()> {
+    std::fs::create_dir_all(dev)?;
+    mount(
+        Some("tmpfs"),
+        dev,
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID,
+        Some("mode=755"),
+    )
+    .context("Failed to mount /dev")?;
+    for device in DEVICES {
+        let target = dev.join(device);
+        create_mount_point(false, &target)?;
+        bind(&Path::new("/dev").join(device), &target)?;
+    }
+
+    let pts = dev.join("pts");
+    std::fs::create_dir(&pts)?;
+    mount(
+        Some("devpts"),
+        &pts,
+        Some("devpts"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+        Some("newinstance,ptmxmode=0666,mode=0620"),
+    )
+    .context("Failed to mount /dev/pts")?;
+    std::os::unix::fs::symlink("pts/ptmx", dev.join("ptmx"))?;
+
+    let shm = dev.join("shm");
+    std::fs::create_dir(&shm)?;
+    mount(
+        Some("tmpfs"),
+        &shm,
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Some("mode=1777"),
+    )
+    .context("Failed to mount /dev/shm")?;
+
+    for (link, target) in [
+        ("fd", "/proc/self/fd"),
+        ("stdin", "/proc/self/fd/0"),
+        ("stdout", "/proc/self/fd/1"),
+        ("stderr", "/proc/self/fd/2"),
+    ] {
+        std::os::unix::fs::symlink(target, dev.join(link))?;
+    }
+    Ok(())
+}
+
+/// Mount `/sys`: a new sysfs showing the container's own network namespace if it has
+/// one, otherwise (or if the kernel refuses it) the host's, read-only.
+fn setup_sys(sys: &Path, isolate_network: bool) -> Result<()> {
+    std::fs::create_dir_all(sys)?;
+    if isolate_network {
+        let mounted = mount(
+            Some("sysfs"),
+            sys,
+            Some("sysfs"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC | MsFlags::MS_RDONLY,
+            None::<&str>,
+        );
+        match mounted {
+            Ok(()) => return Ok(()),
+            Err(err) => debug!("Failed to mount sysfs, binding the host's: {}", err),
+        }
+    }
+    bind(Path::new("/sys"), sys)?;
+    remount_readonly(sys)
+}
+
+/// Assemble the container root in `<dir>/rootfs`, with the container's mounts.
+fn setup_rootfs(dir: &Path, spec: &ContainerSpec, options: &RunOptions) -> Result<PathBuf> {
+    let rootfs = dir.join("rootfs");
+    std::fs::create_dir_all(&rootfs)?;
+
+    match &options.root {
+        #[cfg(any(test, feature = "fake-backend"))]
+        RootFs::Host => {
+            mount(
+                Some("tmpfs"),
+                &rootfs,
+                Some("tmpfs"),
+                MsFlags::empty(),
+                None::<&str>,
+            )
+            .context("Failed to mount the container root")?;
+            for entry in std::fs::read_dir("/")? {
+                let entry = entry?;
+                let target = rootfs.join(entry.file_name());
+                let file_type = entry.file_type()?;
+                if file_type.is_symlink() {
+                    std::os::unix::fs::symlink(std::fs::read_link(entry.path())?, &target)?;
+                } else if file_type.is_dir() {
+                    std::fs::create_dir(&target)?;
+                    if !PRIVATE_DIRS
+                        .iter()
+                        .chain(KERNEL_DIRS)
+                        .any(|dir| entry.file_name() == *dir)
+                    {
+                        bind(&entry.path(), &target)?;
+                    }
+                }
+            }
+        }
+        RootFs::Image { lower } => {
+            let (upper, work) = (dir.join("upper"), dir.join("work"));
+            std::fs::create_dir_all(&upper)?;
+            std::fs::create_dir_all(&work)?;
+            mount_overlay(lower, &upper, &work, &rootfs)?;
+        }
+    }
+    setup_dev(&rootfs.join("dev"))?;
+    setup_sys(&rootfs.join("sys"), options.isolate_network)?;
+    // Mounted by the init of the PID namespace, as /proc shows the mounting process's
+    std::fs::create_dir_all(rootfs.join("proc"))?;
+
+    for mount_spec in &spec.mounts {
+        let target = rootfs.join(
+            mount_spec
+                .target
+                .strip_prefix("/")
+                .unwrap_or(&mount_spec.target),
+        );
+        match &mount_spec.source {
+            MountSource::Bind { path, readonly } => {
+                create_mount_point(path.is_dir(), &target)?;
+                bind(path, &target)?;
+                if *readonly {
+                    remount_readonly(&target)?;
+                }
+            }
+            MountSource::Overlay {
+                lower, upper, work, ..
+            } => {
+                create_mount_point(true, &target)?;
+                mount_overlay(lower, upper, work, &target)?;
+            }
+        }
+    }
+    Ok(rootfs)
+}
+
+/// Init process of a container (`sandbox userns-init <dir>`): set up the namespaces
+/// and root, report readiness on stdout, then run the container command until it exits.
+pub fn run_init(dir: &Path) -> Result<()> {
+    let record = load_record(dir)?.context("Container state not found")?;
+    let spec = record.spec;
+    let (uid, gid) = (getuid().as_raw(), getgid().as_raw());
+
+    // Lead a process group, so that stopping the container kills all of it
+    setsid()?;
+    let mut namespaces = CloneFlags::CLONE_NEWUSER
+        | CloneFlags::CLONE_NEWNS
+        | CloneFlags::CLONE_NEWPID
+        | CloneFlags::CLONE_NEWUTS;
+    if record.options.isolate_network {
+        namespaces |= CloneFlags::CLONE_NEWNET;
+    }
+    unshare(namespaces)
+        .context("Failed to create namespaces (are unprivileged user namespaces enabled?)")?;
+    write_id_maps(0, uid, 0, gid)?;
+    sethostname(&spec.hostname)?;
+    if record.options.isolate_network {
+        loopback_up()?;
+    }
+    mount(
+        None::<&str>,
+        "/",
+        None::<&str>,
+        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+        None::<&str>,
+    )?;
+
+    let rootfs = setup_rootfs(dir, &spec, &record.options)?;
+
+    // Only children enter the new PID namespace; the first one is its init. Killing
+    // it kills everything in the namespace.
+    // SAFETY: the helper process is single-threaded
+    match unsafe { fork() }? {
+        ForkResult::Parent { child } => {
+            let code = match waitpid(child, None)? {
+                WaitStatus::Exited(_, code) => code,
+                _ => 1,
+            };
+            std::process::exit(code);
+        }
+        ForkResult::Child => {
+            let err = run_namespace_init(&rootfs, &spec).unwrap_err();
+            eprintln!("Error: {:?}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Init of a container's PID namespace: mount `/proc`, switch to the container root
+/// and run the container command, reaping orphaned processes until it exits.
+fn run_namespace_init(rootfs: &Path, spec: &ContainerSpec) -> Result<Infallible> {
+    mount(
+        Some("proc"),
+        &rootfs.join("proc"),
+        Some("proc"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        None::<&str>,
+    )
+    .context("Failed to mount /proc")?;
+
+    // Not chroot: the kernel refuses new user namespaces to chrooted processes
+    chdir(rootfs)?;
+    pivot_root(".", ".").context("Failed to switch to the container root")?;
+    umount2(".", MntFlags::MNT_DETACH)?;
+    chdir("/")?;
+
+    let (program, args) = spec.command.split_first().context("No container command")?;
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .env_clear()
+        .env("PATH", CONTAINER_PATH)
+        .envs(spec.env.iter().cloned())
+        .current_dir(&spec.workdir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null());
+    run_as_user(&mut command, spec.uid, spec.gid);
+    let child = Pid::from_raw(
+        command
+            .spawn()
+            .context("Failed to run container command")?
+            .id() as i32,
+    );
+
+    println!("{}", READY);
+    std::io::stdout().flush()?;
+
+    loop {
+        match waitpid(Pid::from_raw(-1), None)? {
+            WaitStatus::Exited(pid, code) if pid == child => std::process::exit(code),
+            WaitStatus::Signaled(pid, signal, _) if pid == child => {
+                std::process::exit(128 + signal as i32)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Exec session of a container (`sandbox userns-exec <dir> -- <command>`): join the
+/// init process's namespaces, then run the command and exit as it does. `user` is
+/// `root` (or `0`) to run as root of the container; otherwise the command runs as the
+/// container user.
+pub fn run_exec(dir: &Path, user: Option<&str>, command: &[String]) -> Result<()> {
+    let record = load_record(dir)?.context("Container state not found")?;
+    let init = match record.init {
+        Some(init) if is_running(&record) => init,
+        _ => bail!("Container {} is not running", record.spec.name),
+    };
+
+    let open_ns = |kind: &str| {
+        std::fs::File::open(format!("/proc/{}/ns/{}", init.pid, kind))
+            .with_context(|| format!("Failed to open {} namespace", kind))
+    };
+    let (user_ns, mount_ns, uts_ns, net_ns, pid_ns) = (
+        open_ns("user")?,
+        open_ns("mnt")?,
+        open_ns("uts")?,
+        open_ns("net")?,
+        open_ns("pid_for_children")?,
+    );
+    setns(user_ns, CloneFlags::CLONE_NEWUSER).context("Failed to join user namespace")?;
+    // Also switches to the container root
+    setns(mount_ns, CloneFlags::CLONE_NEWNS).context("Failed to join mount namespace")?;
+    setns(uts_ns, CloneFlags::CLONE_NEWUTS).context("Failed to join UTS namespace")?;
+    if record.options.isolate_network {
+        setns(net_ns, CloneFlags::CLONE_NEWNET).context("Failed to join network namespace")?;
+    }
+    // Only applies to children, so the command is spawned rather than exec'd
+    setns(pid_ns, CloneFlags::CLONE_NEWPID).context("Failed to join PID namespace")?;
+
+    let as_root = matches!(user, Some("root") | Some("0"));
+    let (program, args) = command.split_first().context("No command given")?;
+    let mut cmd = Command::new(program);
+    cmd.args(args).current_dir(&record.spec.workdir);
+    if std::env::var_os("HOME").is_none() {
+        if let Some(home) = home_dir(if as_root { 0 } else { record.spec.uid }) {
+            cmd.env("HOME", home);
+        }
+    }
+    // In a process group of its own, so that signalling its group can't reach the
+    // host processes in ours. If we are in the terminal's foreground, so is it.
+    cmd.process_group(0);
+    let stdin = std::io::stdin();
+    let foreground = tcgetpgrp(&stdin).is_ok_and(|group| group == getpgrp());
+    if foreground {
+        // SAFETY: only async-signal-safe calls, on the inherited stdin
+        unsafe {
+            cmd.pre_exec(|| {
+                let stdin = BorrowedFd::borrow_raw(libc::STDIN_FILENO);
+                with_sigttou_blocked(|| tcsetpgrp(stdin, getpgrp()))?;
+                Ok(())
+            });
+        }
+    }
+    if !as_root {
+        run_as_user(&mut cmd, record.spec.uid, record.spec.gid);
+    }
+    let mut child = cmd
+        .spawn()
+        .with_context(|| format!("Failed to run {}", program))?;
+
+    EXEC_GROUP.store(child.id() as i32, Ordering::Relaxed);
+    for sig in FORWARDED_SIGNALS {
+        // SAFETY: the handler only calls kill
+        unsafe { signal(sig, SigHandler::Handler(forward_signal)) }?;
+    }
+    let status = child.wait()?;
+    if foreground {
+        with_sigttou_blocked(|| tcsetpgrp(&stdin, getpgrp()))?;
+    }
+
+    // Like a container engine, report death by a signal as 128 + the signal
+    let code = status.code().or(status.signal().map(|sig| 128 + sig));
+    std::process::exit(code.unwrap_or(1))
+}
+
+/// Signals to the exec helper that are passed on to the command's process group.
+const FORWARDED_SIGNALS: [Signal; 4] = [
+    Signal::SIGHUP,
+    Signal::SIGINT,
+    Signal::SIGQUIT,
+    Signal::SIGTERM,
+];
+
+/// Process group of the command of an exec session.
+static EXEC_GROUP: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward_signal(sig: libc::c_int) {
+    // SAFETY: kill is async-signal-safe
+    unsafe { libc::kill(-EXEC_GROUP.load(Ordering::Relaxed), sig) };
+}
+
+/// Run `f` with SIGTTOU blocked, as changing the terminal's foreground process group
+/// from the background raises it.
+fn with_sigttou_blocked<T>(f: impl FnOnce() -> nix::Result<T>) -> nix::Result<T> {
+    let mut mask = SigSet::empty();
+    mask.add(Signal::SIGTTOU);
+    let mut previous = SigSet::empty();
+    pthread_sigmask(SigmaskHow::SIG_BLOCK, Some(&mask), Some(&mut previous))?;
+    let result = f();
+    pthread_sigmask(SigmaskHow::SIG_SETMASK, Some(&previous), None)?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_process_start_time() {
+        let pid = std::process::id() as i32;
+        let start_time = process_start_time(pid).expect("own process is running");
+        assert_eq!(process_start_time(pid), Some(start_time));
+        assert_eq!(process_start_time(i32::MAX), None);
+    }
+}
@@ -220,10 +220,12 @@ fn test_agent_vim_input() {
     let mut cmd = CommandBuilder::new(&sandbox_bin);
     cmd.cwd(&fixture.repo.dir);
     cmd.env("PATH", &new_path);
-    cmd.env(
-        "SANDBOX_DAEMON_SOCKET",
-        fixture.daemon.socket_path.to_str().unwrap(),
-    );
+    // vim is the fallback editor
+    cmd.env_remove("VISUAL");
+    cmd.env_remove("EDITOR");
+    for (key, value) in fixture.daemon.envs() {
+        cmd.env(key, value);
+    }
     cmd.args([
         "agent",
         &fixture.name,
@@ -7,7 +7,7 @@
 
 use std::fs;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::{Child, Command, Output, Stdio};
 use std::time::{Duration, Instant};
 
@@ -22,11 +22,26 @@ const DEFAULT_SANDBOX_CONFIG: &str = indoc! {r#"
 /// Environment variable used to configure the daemon socket path.
 const SOCKET_PATH_ENV: &str = "SANDBOX_DAEMON_SOCKET";
 
+/// Environment variable selecting the fake container backend, with its state in the
+/// given directory.
+const FAKE_BACKEND_ENV: &str = "SANDBOX_FAKE_BACKEND_DIR";
+
+/// Environment variable pointing to the directory of the user-global config.
+pub const CONFIG_HOME_ENV: &str = "XDG_CONFIG_HOME";
+
+/// Config directory without a user-global config, so that tests don't read the
+/// config of the user running them.
+pub fn empty_config_home() -> PathBuf {
+    PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("empty-config-home")
+}
+
 /// A test daemon that manages sandboxes for integration tests.
 /// Each test gets its own daemon with an isolated socket to enable parallel execution.
 /// On drop, the daemon process is terminated.
 pub struct TestDaemon {
     pub socket_path: PathBuf,
+    /// State of the fake backend, if the daemon and its clients use it.
+    fake_backend_dir: Option<PathBuf>,
     process: Child,
     #[allow(dead_code)]
     temp_dir: tempfile::TempDir,
@@ -35,11 +50,21 @@ pub struct TestDaemon {
 impl TestDaemon {
     /// Start a new test daemon with an isolated socket.
     pub fn start() -> Self {
+        Self::start_with(false)
+    }
+
+    /// Start a new test daemon running sandboxes on the fake backend.
+    pub fn start_with_fake_backend() -> Self {
+        Self::start_with(true)
+    }
+
+    fn start_with(fake_backend: bool) -> Self {
         let temp_dir = tempfile::tempdir().expect("Failed to create temp directory");
         let socket_path = temp_dir.path().join("sandbox.sock");
+        let fake_backend_dir = fake_backend.then(|| temp_dir.path().join("fake-backend"));
 
         let process = Command::new(assert_cmd::cargo::cargo_bin!("sandbox"))
-            .env(SOCKET_PATH_ENV, &socket_path)
+            .envs(daemon_envs(&socket_path, fake_backend_dir.as_deref()))
             .arg("daemon")
             .stdin(Stdio::null())
             .stdout(Stdio::null())
@@ -63,10 +88,43 @@ impl TestDaemon {
 
         TestDaemon {
             socket_path,
+            fake_backend_dir,
             process,
             temp_dir,
         }
     }
+
+    /// Environment variables connecting a `sandbox` process to this daemon and its
+    /// backend.
+    pub fn envs(&self) -> Vec<(&'static str, PathBuf)> {
+        daemon_envs(&self.socket_path, self.fake_backend_dir.as_deref())
+    }
+
+    /// State directory of the fake backend, if the daemon uses it.
+    pub fn fake_backend_dir(&self) -> Option<&Path> {
+        self.fake_backend_dir.as_deref()
+    }
+
+    /// A `sandbox` command connecting to this daemon.
+    pub fn command(&self) -> Command {
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("sandbox"));
+        cmd.envs(self.envs());
+        cmd
+    }
+}
+
+fn daemon_envs(
+    socket_path: &Path,
+    fake_backend_dir: Option<&Path>,
+) -> Vec<(&'static str, PathBuf)> {
+    let mut envs = vec![
+        (SOCKET_PATH_ENV, socket_path.to_path_buf()),
+        (CONFIG_HOME_ENV, empty_config_home()),
+    ];
+    if let Some(dir) = fake_backend_dir {
+        envs.push((FAKE_BACKEND_ENV, dir.to_path_buf()));
+    }
+    envs
 }
 
 impl Drop for TestDaemon {
@@ -177,6 +235,7 @@ pub fn run_sandbox_in_with_socket(
     Command::new(assert_cmd::cargo::cargo_bin!("sandbox"))
         .current_dir(working_dir)
         .env(SOCKET_PATH_ENV, socket_path)
+        .env(CONFIG_HOME_ENV, empty_config_home())
         .args(args)
         .output()
         .expect("Failed to run sandbox command")
@@ -188,6 +247,7 @@ pub fn run_sandbox_in_with_socket(
 pub fn run_sandbox_in(working_dir: &PathBuf, args: &[&str]) -> Output {
     Command::new(assert_cmd::cargo::cargo_bin!("sandbox"))
         .current_dir(working_dir)
+        .env(CONFIG_HOME_ENV, empty_config_home())
         .args(args)
         .output()
         .expect("Failed to run sandbox command")
@@ -259,6 +319,19 @@ impl SandboxFixture {
         }
     }
 
+    /// Create a sandbox fixture on the fake backend, which runs sandboxes as host
+    /// processes, so that tests don't need docker.
+    pub fn with_fake_backend(sandbox_name: &str) -> Self {
+        let repo = TestRepo::init();
+        repo.add_dockerfile();
+        let daemon = TestDaemon::start_with_fake_backend();
+        SandboxFixture {
+            repo,
+            name: sandbox_name.to_string(),
+            daemon,
+        }
+    }
+
     /// Run a command inside this sandbox.
     pub fn run(&self, command: &[&str]) -> Output {
         self.run_in_sandbox(command)
@@ -271,7 +344,12 @@ impl SandboxFixture {
 
     /// Run the sandbox binary with the given arguments.
     pub fn run_sandbox(&self, args: &[&str]) -> Output {
-        run_sandbox_in_with_socket(&self.repo.dir, &self.daemon.socket_path, args)
+        self.daemon
+            .command()
+            .current_dir(&self.repo.dir)
+            .args(args)
+            .output()
+            .expect("Failed to run sandbox command")
     }
 
     fn run_in_sandbox(&self, command: &[&str]) -> Output {
@@ -308,11 +386,7 @@ impl SandboxFixture {
 impl Drop for SandboxFixture {
     fn drop(&mut self) {
         // Try to delete the sandbox, ignore errors
-        let _ = run_sandbox_in_with_socket(
-            &self.repo.dir,
-            &self.daemon.socket_path,
-            &["delete", &self.name],
-        );
+        let _ = self.run_sandbox(&["delete", &self.name]);
         // daemon is dropped automatically after this, killing the process
     }
 }
@@ -321,6 +395,7 @@ impl Drop for SandboxFixture {
 pub struct AgentBuilder<'a> {
     fixture: &'a SandboxFixture,
     env_vars: Vec<(&'a str, &'a str)>,
+    args: Vec<&'a str>,
 }
 
 impl<'a> AgentBuilder<'a> {
@@ -328,9 +403,16 @@ impl<'a> AgentBuilder<'a> {
         Self {
             fixture,
             env_vars: Vec::new(),
+            args: Vec::new(),
         }
     }
 
+    /// Add a command line argument to the agent.
+    pub fn arg(mut self, arg: &'a str) -> Self {
+        self.args.push(arg);
+        self
+    }
+
     /// Add an environment variable to the agent process.
     pub fn env(mut self, key: &'a str, value: &'a str) -> Self {
         self.env_vars.push((key, value));
@@ -340,9 +422,8 @@ impl<'a> AgentBuilder<'a> {
     /// Spawn the agent process with the given prompt.
     pub fn run_with_prompt(self, prompt: &str) -> Output {
         let cache_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("llm-cache");
-        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("sandbox"));
+        let mut cmd = self.fixture.daemon.command();
         cmd.current_dir(&self.fixture.repo.dir);
-        cmd.env(SOCKET_PATH_ENV, &self.fixture.daemon.socket_path);
         cmd.args([
             "agent",
             &self.fixture.name,
@@ -353,6 +434,7 @@ impl<'a> AgentBuilder<'a> {
             "--cache",
             cache_dir.to_str().unwrap(),
         ]);
+        cmd.args(&self.args);
         cmd.stdin(Stdio::piped());
         cmd.stdout(Stdio::piped());
         cmd.stderr(Stdio::piped());
@@ -0,0 +1,103 @@
+//! Integration tests for the `sandbox config` subcommand.
+
+mod common;
+
+use std::fs;
+use std::process::Command;
+
+use indoc::indoc;
+
+use common::{run_sandbox_in, TestRepo, CONFIG_HOME_ENV};
+
+#[test]
+fn test_config_check_ok() {
+    let repo = TestRepo::init();
+
+    let output = run_sandbox_in(&repo.dir, &["config", "check"]);
+    assert!(
+        output.status.success(),
+        "config check should succeed for the default config: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn test_config_check_missing_mount() {
+    let repo = TestRepo::init();
+
+    fs::write(
+        repo.dir.join(".sandbox.toml"),
+        indoc! {r#"
+            [[mounts.readonly]]
+            host = "does-not-exist-required"
+
+            [[mounts.readonly]]
+            host = "does-not-exist-optional"
+            optional = true
+        "#},
+    )
+    .expect("Failed to write .sandbox.toml");
+
+    let output = run_sandbox_in(&repo.dir, &["config", "check"]);
+    assert!(
+        !output.status.success(),
+        "config check should fail when a mount source is missing"
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("does-not-exist-required"),
+        "Error should mention the missing mount source. Got: '{}'",
+        stderr
+    );
+    assert!(
+        !stderr.contains("does-not-exist-optional"),
+        "Error should not mention optional mounts. Got: '{}'",
+        stderr
+    );
+}
+
+#[test]
+fn test_config_show_layers() {
+    let repo = TestRepo::init();
+    let config_home = tempfile::tempdir().expect("Failed to create temp directory");
+
+    fs::create_dir_all(config_home.path().join("sandbox")).expect("Failed to create config dir");
+    fs::write(
+        config_home.path().join("sandbox/config.toml"),
+        indoc! {r#"
+            env = ["USER_LEVEL_VAR"]
+        "#},
+    )
+    .expect("Failed to write user config");
+    fs::write(
+        repo.dir.join(".sandbox.local.toml"),
+        indoc! {r#"
+            runtime = "runc"
+        "#},
+    )
+    .expect("Failed to write .sandbox.local.toml");
+
+    let output = Command::new(assert_cmd::cargo::cargo_bin!("sandbox"))
+        .current_dir(&repo.dir)
+        .env(CONFIG_HOME_ENV, config_home.path())
+        .args(["config", "show"])
+        .output()
+        .expect("Failed to run sandbox config show");
+
+    assert!(
+        output.status.success(),
+        "config show failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("env += \"USER_LEVEL_VAR\"  # user"),
+        "Expected user-level env var in output. Got: '{}'",
+        stdout
+    );
+    assert!(
+        stdout.contains("runtime = \"runc\"  # local"),
+        "Expected local runtime override in output. Got: '{}'",
+        stdout
+    );
+}
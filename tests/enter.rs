@@ -7,7 +7,7 @@ use std::process::Command;
 
 use indoc::indoc;
 
-use common::{run_git, SandboxFixture};
+use common::{empty_config_home, run_git, SandboxFixture, CONFIG_HOME_ENV};
 
 #[test]
 fn smoke_test_sandbox_enter() {
@@ -87,6 +87,7 @@ fn test_enter_passthrough_env() {
     let output = Command::new(assert_cmd::cargo::cargo_bin!("sandbox"))
         .current_dir(&fixture.repo.dir)
         .env("SANDBOX_DAEMON_SOCKET", &fixture.daemon.socket_path)
+        .env(CONFIG_HOME_ENV, empty_config_home())
         .env("MY_TEST_VAR", env_value)
         .args([
             "enter",
@@ -127,6 +128,7 @@ fn test_enter_passthrough_env() {
     let output = Command::new(assert_cmd::cargo::cargo_bin!("sandbox"))
         .current_dir(&fixture.repo.dir)
         .env("SANDBOX_DAEMON_SOCKET", &fixture.daemon.socket_path)
+        .env(CONFIG_HOME_ENV, empty_config_home())
         .args([
             "enter",
             &fixture.name,
@@ -163,6 +165,7 @@ fn test_enter_passthrough_env() {
     let output = Command::new(assert_cmd::cargo::cargo_bin!("sandbox"))
         .current_dir(&fixture.repo.dir)
         .env("SANDBOX_DAEMON_SOCKET", &fixture.daemon.socket_path)
+        .env(CONFIG_HOME_ENV, empty_config_home())
         .env("VAR_ONE", "value1")
         .env("VAR_TWO", "value2")
         .args([
@@ -0,0 +1,574 @@
+//! Integration tests running sandboxes on the fake backend (no docker needed).
+
+mod common;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+
+use indoc::indoc;
+use portable_pty::{native_pty_system, CommandBuilder, PtySize};
+use sandbox::agent::execute_bash_in_sandbox;
+use sandbox::backend::{Container, ContainerBackend, ContainerState};
+use sandbox::fake_backend::FakeBackend;
+
+use common::{run_git, wait_for, AgentBuilder, SandboxFixture};
+
+/// Read from a PTY until `pattern` appears in the output read by this call.
+fn read_until(reader: &mut dyn Read, output: &mut Vec<u8>, pattern: &str) {
+    let start = output.len();
+    let mut buf = [0u8; 4096];
+    while !String::from_utf8_lossy(&output[start..]).contains(pattern) {
+        let n = reader.read(&mut buf).unwrap_or(0);
+        assert!(
+            n > 0,
+            "Agent exited before printing '{}'.\noutput: {}",
+            pattern,
+            String::from_utf8_lossy(output)
+        );
+        output.extend_from_slice(&buf[..n]);
+    }
+}
+
+fn assert_success(output: &std::process::Output, what: &str) {
+    assert!(
+        output.status.success(),
+        "{}: {}",
+        what,
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn test_enter_isolates_working_tree() {
+    let fixture = SandboxFixture::with_fake_backend("test-fake-enter");
+
+    let output = fixture.run(&[
+        "sh",
+        "-c",
+        "hostname && echo sandbox > file.txt && cat README.md",
+    ]);
+    assert_success(&output, "Failed to run command in sandbox");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "test-fake-enter\nTEST"
+    );
+
+    // The file persists in the sandbox, but doesn't appear on the host
+    let output = fixture.run(&["cat", "file.txt"]);
+    assert_success(&output, "Failed to read file in sandbox");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "sandbox\n");
+    assert!(!fixture.repo.dir.join("file.txt").exists());
+}
+
+#[test]
+fn test_commits_sync_to_host() {
+    let fixture = SandboxFixture::with_fake_backend("test-fake-sync");
+
+    let output = fixture.run(&[
+        "sh",
+        "-c",
+        "git config user.email 'test@example.com' && git config user.name 'Test User' && \
+         echo content > file.txt && git add file.txt && git commit -q -m 'Sandbox commit' && \
+         git rev-parse HEAD",
+    ]);
+    assert_success(&output, "Failed to commit in sandbox");
+    let commit = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    let ref_name = format!("refs/remotes/sandbox/{}", fixture.name);
+    let synced = wait_for(Duration::from_secs(5), Duration::from_millis(100), || {
+        let output = run_git(&fixture.repo.dir, &["rev-parse", &ref_name]);
+        String::from_utf8_lossy(&output.stdout).trim() == commit
+    });
+    assert!(
+        synced,
+        "Sandbox commit should be synced to host within timeout"
+    );
+}
+
+#[test]
+fn test_hooks_run_in_container() {
+    let fixture = SandboxFixture::with_fake_backend("test-fake-hooks");
+
+    fs::write(
+        fixture.repo.dir.join(".sandbox.toml"),
+        indoc! {r#"
+            [hooks]
+            post-create = ["hostname > .hook-created"]
+        "#},
+    )
+    .expect("Failed to write .sandbox.toml");
+
+    let output = fixture.run(&["cat", ".hook-created"]);
+    assert_success(&output, "Failed to read hook output");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "test-fake-hooks\n");
+}
+
+#[test]
+fn test_concurrent_enter_waits_for_hooks() {
+    let fixture = SandboxFixture::with_fake_backend("test-fake-concurrent");
+
+    fs::write(
+        fixture.repo.dir.join(".sandbox.toml"),
+        indoc! {r#"
+            [hooks]
+            post-create = ["echo run >> .post-create-runs && sleep 3"]
+        "#},
+    )
+    .expect("Failed to write .sandbox.toml");
+
+    // The second client arrives while the first one's post-create hook runs
+    let first = fixture
+        .daemon
+        .command()
+        .current_dir(&fixture.repo.dir)
+        .args([
+            "enter",
+            &fixture.name,
+            "--runtime",
+            "runc",
+            "--",
+            "sleep",
+            "5",
+        ])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to enter sandbox");
+    std::thread::sleep(Duration::from_secs(1));
+    let second = fixture.run(&["cat", ".post-create-runs"]);
+
+    let first = first
+        .wait_with_output()
+        .expect("Failed to wait for first client");
+    assert_success(&first, "First client failed");
+    assert_success(&second, "Second client failed");
+    assert_eq!(String::from_utf8_lossy(&second.stdout), "run\n");
+}
+
+#[test]
+fn test_runtime_switch_refused_while_attached() {
+    let fixture = SandboxFixture::with_fake_backend("test-fake-switch");
+
+    let first = fixture
+        .daemon
+        .command()
+        .current_dir(&fixture.repo.dir)
+        .args([
+            "enter",
+            &fixture.name,
+            "--runtime",
+            "runc",
+            "--",
+            "sleep",
+            "3",
+        ])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to enter sandbox");
+    std::thread::sleep(Duration::from_secs(1));
+
+    // The container stays on its runtime while a session is attached to it
+    let switch = ["enter", &fixture.name, "--runtime", "runsc", "--", "true"];
+    let refused = fixture.run_sandbox(&switch);
+    let stderr = String::from_utf8_lossy(&refused.stderr);
+    assert!(
+        !refused.status.success() && stderr.contains("session(s) attached"),
+        "Runtime switch should be refused while attached: {}",
+        stderr
+    );
+
+    let first = first
+        .wait_with_output()
+        .expect("Failed to wait for first client");
+    assert_success(&first, "First client failed");
+
+    let switched = fixture.run_sandbox(&switch);
+    assert_success(&switched, "Runtime switch failed after the session ended");
+}
+
+#[test]
+fn test_prune_keeps_images_of_unreadable_configs() {
+    let fixture = SandboxFixture::with_fake_backend("test-fake-prune");
+    // An image no other test's config refers to
+    let dockerfile = fixture.repo.dir.join("Dockerfile");
+    let contents = fs::read_to_string(&dockerfile).expect("Failed to read Dockerfile");
+    let contents = format!("{}# {}\n", contents, fixture.repo.dir.display());
+    fs::write(&dockerfile, &contents).expect("Failed to write Dockerfile");
+    let output = fixture.run(&["true"]);
+    assert_success(&output, "Failed to start sandbox");
+    // Only configs refer to the image, not containers
+    let state_dir = fixture
+        .daemon
+        .fake_backend_dir()
+        .expect("Daemon uses the fake backend");
+    let backend = FakeBackend::new(state_dir.to_path_buf());
+    for entry in fs::read_dir(state_dir.join("containers")).expect("No containers") {
+        let name = entry.expect("Failed to list containers").file_name();
+        backend
+            .remove_container(&name.to_string_lossy())
+            .expect("Failed to remove container");
+    }
+
+    // The image can't be told from a broken config, so it is kept
+    let config = fixture.repo.dir.join(".sandbox.toml");
+    let config_contents = fs::read_to_string(&config).expect("Failed to read .sandbox.toml");
+    fs::write(&config, "image = [\n").expect("Failed to write .sandbox.toml");
+    let output = fixture.run_sandbox(&["image", "prune"]);
+    assert_success(&output, "Prune failed with a broken config");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("in use (unreadable config of"),
+        "Image should be kept: {}",
+        stdout
+    );
+
+    // Once the config no longer uses it, it is removed
+    fs::write(&config, config_contents).expect("Failed to write .sandbox.toml");
+    fs::write(&dockerfile, contents + "# changed\n").expect("Failed to write Dockerfile");
+    let output = fixture.run_sandbox(&["image", "prune"]);
+    assert_success(&output, "Prune failed");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("removed"),
+        "Image should be removed: {}",
+        stdout
+    );
+}
+
+#[test]
+fn test_env_in_container_config() {
+    let fixture = SandboxFixture::with_fake_backend("test-fake-env");
+
+    // Hooks are run without passing the environment, so they only see it if it is
+    // part of the container config
+    fs::write(
+        fixture.repo.dir.join(".sandbox.local.toml"),
+        indoc! {r#"
+            [env]
+            GREETING = "hello"
+
+            [hooks]
+            post-create = ["echo $GREETING > .hook-env"]
+        "#},
+    )
+    .expect("Failed to write .sandbox.local.toml");
+
+    let output = fixture.run(&["cat", ".hook-env"]);
+    assert_success(&output, "Failed to read hook output");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "hello\n");
+}
+
+#[test]
+fn test_host_processes_hidden() {
+    let fixture = SandboxFixture::with_fake_backend("test-fake-pidns");
+
+    // A host process of the same user, which the sandbox must not see or signal, also
+    // not through a process group shared with the host
+    let mut host_process = std::process::Command::new("sleep")
+        .arg("60")
+        .spawn()
+        .expect("Failed to start host process");
+    let pid = host_process.id().to_string();
+
+    let output = fixture.run(&[
+        "sh",
+        "-c",
+        "test -e /proc/$1 && echo visible; kill -0 $1 2>/dev/null && echo signalled; \
+         test $(cut -d ' ' -f 5 /proc/$$/stat) = $$ || echo shared-group; ls /dev",
+        "sh",
+        &pid,
+    ]);
+    host_process.kill().expect("Failed to stop host process");
+    let _ = host_process.wait();
+    assert_success(&output, "Failed to look for the host process");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // Nothing about the host process, and only the sandbox's own devices
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        [
+            "fd", "full", "null", "ptmx", "pts", "random", "shm", "stderr", "stdin", "stdout",
+            "tty", "urandom", "zero"
+        ]
+    );
+}
+
+#[test]
+fn test_mount_paths_expand_in_cli_environment() {
+    let fixture = SandboxFixture::with_fake_backend("test-fake-mount-env");
+
+    let data_dir = tempfile::tempdir().expect("Failed to create temp directory");
+    fs::write(data_dir.path().join("data.txt"), "from the host\n").expect("Failed to write data");
+    fs::write(
+        fixture.repo.dir.join(".sandbox.toml"),
+        indoc! {r#"
+            [[mounts.readonly]]
+            host = "$SANDBOX_TEST_DATA_DIR/*.txt"
+            container = "/mnt/data"
+        "#},
+    )
+    .expect("Failed to write .sandbox.toml");
+
+    // The variable is only set for the CLI, not for the daemon starting the container
+    let output = fixture
+        .daemon
+        .command()
+        .current_dir(&fixture.repo.dir)
+        .env("SANDBOX_TEST_DATA_DIR", data_dir.path())
+        .args([
+            "enter",
+            &fixture.name,
+            "--runtime",
+            "runc",
+            "--",
+            "cat",
+            "/mnt/data/data.txt",
+        ])
+        .output()
+        .expect("Failed to run sandbox command");
+    assert_success(&output, "Failed to read mounted file");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "from the host\n");
+}
+
+#[test]
+fn test_delete_stops_sandbox() {
+    let fixture = SandboxFixture::with_fake_backend("test-fake-delete");
+
+    let output = fixture.run(&["true"]);
+    assert_success(&output, "Failed to enter sandbox");
+
+    let output = fixture.run_sandbox(&["list"]);
+    assert_success(&output, "Failed to list sandboxes");
+    let listing = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        listing.contains("test-fake-delete") && listing.contains("running"),
+        "Sandbox should be listed as running. Got: '{}'",
+        listing
+    );
+
+    fixture.delete();
+    let output = fixture.run_sandbox(&["list"]);
+    assert_success(&output, "Failed to list sandboxes");
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("test-fake-delete"));
+}
+
+#[test]
+fn test_bash_tool_timeout() {
+    let fixture = SandboxFixture::with_fake_backend("test-fake-timeout");
+
+    // Keep a session attached, as the daemon stops the sandbox when the last one ends
+    let mut session = fixture
+        .daemon
+        .command()
+        .current_dir(&fixture.repo.dir)
+        .args([
+            "enter",
+            &fixture.name,
+            "--runtime",
+            "runc",
+            "--",
+            "sleep",
+            "60",
+        ])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to enter sandbox");
+
+    // Run the tool in the container the daemon started
+    let state_dir = fixture
+        .daemon
+        .fake_backend_dir()
+        .expect("Daemon uses the fake backend");
+    let backend = FakeBackend::new(state_dir.to_path_buf())
+        .with_helper(assert_cmd::cargo::cargo_bin!("sandbox").to_path_buf());
+    let mut name = String::new();
+    let running = wait_for(Duration::from_secs(10), Duration::from_millis(100), || {
+        let Some(Ok(entry)) = fs::read_dir(state_dir.join("containers"))
+            .ok()
+            .and_then(|mut entries| entries.next())
+        else {
+            return false;
+        };
+        name = entry.file_name().to_string_lossy().into_owned();
+        backend.container_state(&name).ok() == Some(ContainerState::Running)
+    });
+    assert!(running, "Sandbox container did not start");
+    let container = Container::new(&backend, &name);
+    let bash = |command: &str, timeout_secs: u64| {
+        execute_bash_in_sandbox(container, command, Duration::from_secs(timeout_secs))
+            .expect("Failed to run bash tool")
+    };
+
+    // Stopped by SIGTERM (124), keeping the output so far
+    assert_eq!(
+        bash("echo started; sleep 30", 1),
+        ("started\n\n[Timed out after 1 seconds]".to_string(), false)
+    );
+    // Killed when ignoring SIGTERM (137)
+    assert_eq!(
+        bash("trap '' TERM; sleep 30", 1),
+        ("[Timed out after 1 seconds]".to_string(), false)
+    );
+    // The same exit codes before the timeout are ordinary failures
+    assert_eq!(
+        bash("exit 124", 30),
+        ("exited with status 124".to_string(), false)
+    );
+    assert_eq!(
+        bash("echo done; kill -KILL $$", 30),
+        ("done\n".to_string(), false)
+    );
+
+    let _ = session.kill();
+    let _ = session.wait();
+}
+
+#[test]
+fn test_agent_runs_tools_in_container() {
+    let fixture = SandboxFixture::with_fake_backend("test-fake-agent");
+
+    let secret_content = "SECRET_VALUE_12345";
+    fs::write(fixture.repo.dir.join("secret.txt"), secret_content)
+        .expect("Failed to write secret.txt");
+    run_git(&fixture.repo.dir, &["add", "secret.txt"]);
+    run_git(&fixture.repo.dir, &["commit", "--amend", "--no-edit"]);
+
+    let output = AgentBuilder::new(&fixture)
+        .run_with_prompt("Run `cat secret.txt` and tell me what it contains.");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains(secret_content),
+        "Agent output should contain the secret content.\nstdout: {}\nstderr: {}",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    // Token usage is recorded for the sandbox
+    let output = fixture.run_sandbox(&["usage"]);
+    assert_success(&output, "Failed to show usage");
+    let report = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        report.contains("test-fake-agent") && report.contains("TOTAL"),
+        "Usage should list the sandbox. Got: '{}'",
+        report
+    );
+}
+
+#[test]
+fn test_agent_inline_input() {
+    // The inline line editor needs a terminal, so the agent runs in a PTY
+    let fixture = SandboxFixture::with_fake_backend("test-fake-inline");
+
+    let secret_content = "SECRET_VALUE_12345";
+    fs::write(fixture.repo.dir.join("secret.txt"), secret_content)
+        .expect("Failed to write secret.txt");
+    run_git(&fixture.repo.dir, &["add", "secret.txt"]);
+    run_git(&fixture.repo.dir, &["commit", "--amend", "--no-edit"]);
+
+    let pair = native_pty_system()
+        .openpty(PtySize {
+            rows: 24,
+            cols: 200,
+            pixel_width: 0,
+            pixel_height: 0,
+        })
+        .expect("Failed to open PTY");
+
+    let cache_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("llm-cache");
+    let mut cmd = CommandBuilder::new(assert_cmd::cargo::cargo_bin!("sandbox"));
+    cmd.cwd(&fixture.repo.dir);
+    for (key, value) in fixture.daemon.envs() {
+        cmd.env(key, value);
+    }
+    cmd.args([
+        "agent",
+        &fixture.name,
+        "--runtime",
+        "runc",
+        "--model",
+        "haiku",
+        "--input",
+        "inline",
+        "--cache",
+        cache_dir.to_str().unwrap(),
+    ]);
+
+    let mut child = pair
+        .slave
+        .spawn_command(cmd)
+        .expect("Failed to spawn agent in PTY");
+    drop(pair.slave);
+
+    let mut writer = pair.master.take_writer().expect("Failed to get PTY writer");
+    let mut reader = pair
+        .master
+        .try_clone_reader()
+        .expect("Failed to get PTY reader");
+
+    // Input typed before the line editor is ready would be echoed twice, so each
+    // line is only sent once its prompt is shown
+    let mut output = Vec::new();
+    read_until(&mut reader, &mut output, "> ");
+    writer
+        .write_all(b"Run `cat secret.txt` and tell me what it contains.\r")
+        .expect("Failed to write to PTY");
+    read_until(&mut reader, &mut output, "The file `secret.txt` contains");
+    read_until(&mut reader, &mut output, "> ");
+    writer.write_all(b"\x04").expect("Failed to write to PTY");
+
+    let status = child.wait().expect("Failed to wait for agent");
+    let output = String::from_utf8_lossy(&output);
+    assert!(status.success(), "Agent failed.\noutput: {}", output);
+    assert!(
+        output.contains(secret_content),
+        "Agent output should contain the secret content.\noutput: {}",
+        output
+    );
+}
+
+#[test]
+fn test_agent_stops_at_budget() {
+    let fixture = SandboxFixture::with_fake_backend("test-fake-budget");
+
+    fs::write(fixture.repo.dir.join("secret.txt"), "SECRET_VALUE_12345")
+        .expect("Failed to write secret.txt");
+    run_git(&fixture.repo.dir, &["add", "secret.txt"]);
+    run_git(&fixture.repo.dir, &["commit", "--amend", "--no-edit"]);
+    let prompt = "Run `cat secret.txt` and tell me what it contains.";
+
+    // The first response runs a tool, the turn limit stops the second request
+    let output = AgentBuilder::new(&fixture)
+        .arg("--max-turns")
+        .arg("1")
+        .run_with_prompt(prompt);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(3), "stdout: {}", stdout);
+    assert!(stdout.contains("$ cat secret.txt"), "stdout: {}", stdout);
+    assert!(
+        stdout.contains("[budget] Agent stopped: reached max-turns = 1"),
+        "stdout: {}",
+        stdout
+    );
+
+    // The tool call limit stops before the tool runs
+    let output = AgentBuilder::new(&fixture)
+        .arg("--max-tool-calls")
+        .arg("0")
+        .run_with_prompt(prompt);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(3), "stdout: {}", stdout);
+    assert!(!stdout.contains("$ cat secret.txt"), "stdout: {}", stdout);
+    assert!(
+        stdout.contains("reached max-tool-calls = 0"),
+        "stdout: {}",
+        stdout
+    );
+}
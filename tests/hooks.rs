@@ -0,0 +1,122 @@
+//! Integration tests for `[hooks]` run inside the container.
+
+mod common;
+
+use std::fs;
+use std::time::Duration;
+
+use indoc::{formatdoc, indoc};
+
+use common::{wait_for, SandboxFixture};
+
+#[test]
+fn test_post_create_runs_once_post_start_every_time() {
+    let fixture = SandboxFixture::new("test-hooks");
+
+    fs::write(
+        fixture.repo.dir.join(".sandbox.toml"),
+        indoc! {r#"
+            [hooks]
+            post-create = ["echo created >> .hook-created"]
+            post-start = ["echo started >> .hook-started"]
+        "#},
+    )
+    .expect("Failed to write .sandbox.toml");
+
+    for _ in 0..2 {
+        let output = fixture.run(&["true"]);
+        assert!(
+            output.status.success(),
+            "Failed to enter sandbox: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    let output = fixture.run(&["cat", ".hook-created", ".hook-started"]);
+    assert!(
+        output.status.success(),
+        "Failed to read hook output: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "created\nstarted\nstarted\nstarted\n"
+    );
+}
+
+#[test]
+fn test_failing_hook_reports_error() {
+    let fixture = SandboxFixture::new("test-hooks-fail");
+
+    fs::write(
+        fixture.repo.dir.join(".sandbox.toml"),
+        indoc! {r#"
+            [hooks]
+            post-create = ["echo hook-output; exit 3"]
+        "#},
+    )
+    .expect("Failed to write .sandbox.toml");
+
+    let output = fixture.run(&["true"]);
+    assert!(
+        !output.status.success(),
+        "Enter should fail when a hook fails"
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("post-create hook failed"),
+        "Error should mention the failed hook. Got: '{}'",
+        stderr
+    );
+}
+
+#[test]
+fn test_host_hooks_receive_events() {
+    let fixture = SandboxFixture::new("test-host-hooks");
+    let events_file = fixture.repo.dir.join("events.jsonl");
+
+    fs::write(
+        fixture.repo.dir.join(".sandbox.toml"),
+        formatdoc! {r#"
+            [host-hooks]
+            on-create = ["cat >> {events}"]
+            on-start = ["cat >> {events}"]
+            on-delete = ["cat >> {events}"]
+        "#, events = events_file.display()},
+    )
+    .expect("Failed to write .sandbox.toml");
+
+    let output = fixture.run(&["true"]);
+    assert!(
+        output.status.success(),
+        "Failed to enter sandbox: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    // Hooks run in the background, so wait for both events to arrive
+    let received = wait_for(Duration::from_secs(5), Duration::from_millis(100), || {
+        fs::read_to_string(&events_file)
+            .map(|s| s.lines().count() >= 2)
+            .unwrap_or(false)
+    });
+    assert!(received, "Create and start events should be delivered");
+
+    let output = fixture.run_sandbox(&["delete", &fixture.name]);
+    assert!(
+        output.status.success(),
+        "Failed to delete sandbox: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let contents = fs::read_to_string(&events_file).expect("Failed to read events");
+    let events: Vec<serde_json::Value> = contents
+        .lines()
+        .map(|l| serde_json::from_str(l).expect("Event should be JSON"))
+        .collect();
+    let kinds: Vec<&str> = events
+        .iter()
+        .map(|e| e["event"].as_str().unwrap())
+        .collect();
+    assert_eq!(kinds, vec!["create", "start", "delete"]);
+    assert_eq!(events[0]["sandbox"], fixture.name);
+}